@@ -0,0 +1,153 @@
+//! Writes markers and thread scheduling in the Chrome trace event format
+//!
+//! The format is described in
+//! https://docs.google.com/document/d/1CvAClvFfyA5R-PhYUmn5OOQtYMH4h6I0nSsKchNAySU and can be
+//! opened in chrome://tracing and https://ui.perfetto.dev. Besides the markers that also go into
+//! the Firefox profile, every thread we trace gets "Running" and "Sleeping" slices that are
+//! reconstructed from its context switches.
+use std::io::{self, Write};
+
+use fxprof_processed_profile::{MarkerTiming, Timestamp};
+use serde_json::{json, Value};
+
+use super::types::FastHashMap;
+
+/// When a marker happened, in nanoseconds since the start of the profile
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TraceTiming {
+    Instant(u64),
+    Interval(u64, u64),
+    IntervalStart(u64),
+    IntervalEnd(u64),
+}
+
+impl TraceTiming {
+    pub fn marker_timing(self) -> MarkerTiming {
+        let timestamp = Timestamp::from_nanos_since_reference;
+        match self {
+            TraceTiming::Instant(time) => MarkerTiming::Instant(timestamp(time)),
+            TraceTiming::Interval(start, end) => MarkerTiming::Interval(timestamp(start), timestamp(end)),
+            TraceTiming::IntervalStart(start) => MarkerTiming::IntervalStart(timestamp(start)),
+            TraceTiming::IntervalEnd(end) => MarkerTiming::IntervalEnd(timestamp(end)),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+struct SchedulingState {
+    since: u64,
+    running: bool,
+}
+
+#[derive(Debug, Default)]
+pub struct ChromeTrace {
+    events: Vec<Value>,
+    /// The state of each thread since its last context switch, by thread id
+    scheduling: FastHashMap<u32, SchedulingState>,
+}
+
+/// Trace event timestamps are in microseconds
+fn micros(nanos: u64) -> f64 {
+    nanos as f64 / 1000.
+}
+
+impl ChromeTrace {
+    pub fn add_marker(&mut self, process_id: u32, thread_id: u32, category: &str, name: &str, args: Value, timing: TraceTiming) {
+        let mut event = json!({
+            "name": name,
+            "cat": category,
+            "pid": process_id,
+            "tid": thread_id,
+            "args": args,
+        });
+        match timing {
+            TraceTiming::Instant(time) => {
+                event["ph"] = "i".into();
+                event["s"] = "t".into();
+                event["ts"] = micros(time).into();
+            }
+            TraceTiming::Interval(start, end) => {
+                event["ph"] = "X".into();
+                event["ts"] = micros(start).into();
+                event["dur"] = micros(end.saturating_sub(start)).into();
+            }
+            TraceTiming::IntervalStart(start) => {
+                event["ph"] = "B".into();
+                event["ts"] = micros(start).into();
+            }
+            TraceTiming::IntervalEnd(end) => {
+                event["ph"] = "E".into();
+                event["ts"] = micros(end).into();
+            }
+        }
+        self.events.push(event);
+    }
+
+    /// Adds an instant event that isn't tied to a thread, like a VSync
+    pub fn add_global_instant(&mut self, category: &str, name: &str, time: u64) {
+        self.events.push(json!({
+            "name": name,
+            "cat": category,
+            "ph": "i",
+            "s": "g",
+            "pid": 0,
+            "tid": 0,
+            "ts": micros(time),
+        }));
+    }
+
+    pub fn switch_in(&mut self, process_id: u32, thread_id: u32, time: u64) {
+        self.switch(process_id, thread_id, time, true);
+    }
+
+    pub fn switch_out(&mut self, process_id: u32, thread_id: u32, time: u64) {
+        self.switch(process_id, thread_id, time, false);
+    }
+
+    /// Ends the thread's current slice and starts a new one. We don't know what a thread was
+    /// doing before its first context switch so that time is left out.
+    fn switch(&mut self, process_id: u32, thread_id: u32, time: u64, running: bool) {
+        let previous = self.scheduling.insert(thread_id, SchedulingState { since: time, running });
+        if let Some(SchedulingState { since, running: was_running }) = previous {
+            if was_running == running {
+                return;
+            }
+            let name = if was_running { "Running" } else { "Sleeping" };
+            self.add_marker(process_id, thread_id, "scheduling", name, json!({}), TraceTiming::Interval(since, time));
+        }
+    }
+
+    pub fn set_process_name(&mut self, process_id: u32, name: &str) {
+        self.events.push(json!({ "name": "process_name", "ph": "M", "pid": process_id, "args": { "name": name } }));
+    }
+
+    pub fn set_thread_name(&mut self, process_id: u32, thread_id: u32, name: &str) {
+        self.events.push(json!({ "name": "thread_name", "ph": "M", "pid": process_id, "tid": thread_id, "args": { "name": name } }));
+    }
+
+    pub fn write<W: Write>(&self, writer: W) -> io::Result<()> {
+        serde_json::to_writer(writer, &json!({
+            "traceEvents": self.events,
+            "displayTimeUnit": "ms",
+        })).map_err(io::Error::from)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::ChromeTrace;
+
+    #[test]
+    fn scheduling_slices() {
+        let mut trace = ChromeTrace::default();
+        trace.switch_in(1, 2, 1000);
+        trace.switch_out(1, 2, 3000);
+        // A duplicated switch-out doesn't end the slice
+        trace.switch_out(1, 2, 3000);
+        trace.switch_in(1, 2, 7000);
+        let slices: Vec<_> = trace.events.iter()
+            .map(|event| (event["name"].as_str().unwrap(), event["ts"].as_f64().unwrap(), event["dur"].as_f64().unwrap()))
+            .collect();
+        assert_eq!(slices, [("Running", 1., 2.), ("Sleeping", 3., 4.)]);
+    }
+}
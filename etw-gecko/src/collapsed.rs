@@ -0,0 +1,133 @@
+//! Collapsed stacks for flame graphs
+//!
+//! Samples are aggregated into one set of stacks per thread and written either as Brendan Gregg's
+//! folded format (one `root;...;leaf weight` line per stack, for flamegraph.pl and inferno) or as a
+//! speedscope JSON file (https://github.com/jlfwong/speedscope/wiki/Importing-from-custom-sources).
+use std::{collections::BTreeMap, io::{self, Write}};
+
+use serde_json::json;
+
+use super::types::FastHashMap;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CollapsedWeight {
+    /// The number of samples
+    Samples,
+    /// The running time of the samples, in microseconds
+    CpuTime,
+}
+
+pub struct CollapsedStacks {
+    weight: CollapsedWeight,
+    /// The stacks of each (process, thread), root first, with their accumulated weight
+    threads: BTreeMap<(String, String), FastHashMap<Vec<String>, u64>>,
+}
+
+impl CollapsedStacks {
+    pub fn new(weight: CollapsedWeight) -> Self {
+        CollapsedStacks { weight, threads: BTreeMap::new() }
+    }
+
+    pub fn weight(&self) -> CollapsedWeight {
+        self.weight
+    }
+
+    /// Adds `weight` to the stack `frames` (root first). Samples from threads without a name
+    /// (e.g. when threads are merged) are put together.
+    pub fn add(&mut self, process: &str, thread: Option<&str>, frames: Vec<String>, weight: u64) {
+        let thread = thread.unwrap_or("all threads");
+        let stacks = self.threads.entry((process.to_owned(), thread.to_owned())).or_default();
+        *stacks.entry(frames).or_insert(0) += weight;
+    }
+
+    /// Writes one line per stack, starting with the process and thread. Lines are sorted so
+    /// that the output of a trace is always the same.
+    pub fn write_folded<W: Write>(&self, mut writer: W) -> io::Result<()> {
+        for ((process, thread), stacks) in &self.threads {
+            let mut lines: Vec<String> = stacks.iter().map(|(frames, weight)| {
+                let mut line = folded_frame(process);
+                line.push(';');
+                line.push_str(&folded_frame(thread));
+                for frame in frames {
+                    line.push(';');
+                    line.push_str(&folded_frame(frame));
+                }
+                format!("{} {}", line, weight)
+            }).collect();
+            lines.sort();
+            for line in lines {
+                writeln!(writer, "{}", line)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Writes a speedscope file with a "sampled" profile for each thread
+    pub fn write_speedscope<W: Write>(&self, writer: W) -> io::Result<()> {
+        let mut frames = Vec::new();
+        let mut frame_indices: FastHashMap<&str, usize> = FastHashMap::default();
+        let unit = match self.weight {
+            CollapsedWeight::Samples => "none",
+            CollapsedWeight::CpuTime => "microseconds",
+        };
+        let mut profiles = Vec::new();
+        for ((process, thread), stacks) in &self.threads {
+            let mut stacks: Vec<_> = stacks.iter().collect();
+            stacks.sort();
+            let mut samples = Vec::new();
+            let mut weights = Vec::new();
+            for (stack, weight) in stacks {
+                samples.push(stack.iter().map(|frame| {
+                    *frame_indices.entry(frame.as_str()).or_insert_with(|| {
+                        frames.push(json!({ "name": frame }));
+                        frames.len() - 1
+                    })
+                }).collect::<Vec<_>>());
+                weights.push(*weight);
+            }
+            profiles.push(json!({
+                "type": "sampled",
+                "name": format!("{} {}", process, thread),
+                "unit": unit,
+                "startValue": 0,
+                "endValue": weights.iter().sum::<u64>(),
+                "samples": samples,
+                "weights": weights,
+            }));
+        }
+        let file = json!({
+            "$schema": "https://www.speedscope.app/file-format-schema.json",
+            "exporter": "etw-gecko",
+            "shared": { "frames": frames },
+            "profiles": profiles,
+        });
+        serde_json::to_writer(writer, &file).map_err(io::Error::from)
+    }
+}
+
+/// Frames can't contain the separator or line breaks
+fn folded_frame(name: &str) -> String {
+    name.replace(';', ":").replace('\n', " ")
+}
+
+#[cfg(test)]
+mod test {
+    use super::{CollapsedStacks, CollapsedWeight};
+
+    #[test]
+    fn folded() {
+        let mut collapsed = CollapsedStacks::new(CollapsedWeight::Samples);
+        let stack = |frames: &[&str]| frames.iter().map(|frame| frame.to_string()).collect();
+        collapsed.add("firefox.exe (100)", Some("Main (101)"), stack(&["xul.dll+0x20", "xul.dll+0x10"]), 1);
+        collapsed.add("firefox.exe (100)", Some("Main (101)"), stack(&["xul.dll+0x20", "a;b_[j]"]), 2);
+        collapsed.add("firefox.exe (100)", Some("Main (101)"), stack(&["xul.dll+0x20", "xul.dll+0x10"]), 3);
+        collapsed.add("firefox.exe (100)", None, stack(&["ntoskrnl.exe+0x5_[k]"]), 1);
+        let mut out = Vec::new();
+        collapsed.write_folded(&mut out).unwrap();
+        assert_eq!(String::from_utf8(out).unwrap(), "\
+firefox.exe (100);Main (101);xul.dll+0x20;a:b_[j] 2
+firefox.exe (100);Main (101);xul.dll+0x20;xul.dll+0x10 4
+firefox.exe (100);all threads;ntoskrnl.exe+0x5_[k] 1
+");
+    }
+}
@@ -33,6 +33,14 @@
 /// Does the accumulated time cross an "off-cpu sampling" threshold?
 /// If yes, turn it into an off-cpu sampling group and consume a multiple of the interval.
 /// If no, don't emit any samples. The next sample's cpu delta will just be smaller.
+///
+/// # Ready time
+///
+/// A sleep ends with the thread being readied, e.g. by a ReadyThread event, and the thread
+/// waits for a processor from then on until it's switched in. That part of the sleep is the
+/// thread's ready time (or scheduler latency) and is accumulated and sampled separately, see
+/// [ContextSwitchHandler::consume_ready_time]. Threads that are switched out while they're still
+/// ready, e.g. because they were preempted, are ready for the whole sleep.
 pub struct ContextSwitchHandler {
     off_cpu_sampling_interval: u64,
 }
@@ -44,7 +52,16 @@ impl ContextSwitchHandler {
         }
     }
 
-    pub fn handle_switch_out(&self, timestamp: u64, thread: &mut ThreadContextSwitchData) {
+    /// The off-cpu sampling interval, in the same units as the timestamps
+    pub fn interval(&self) -> u64 {
+        self.off_cpu_sampling_interval
+    }
+
+    /// `reason` is why the thread left the CPU, if the event says so. It goes into the off-cpu
+    /// sample group that this sleep completes.
+    pub fn handle_switch_out(&self, timestamp: u64, reason: Option<OffCpuReason>, thread: &mut ThreadContextSwitchData) {
+        thread.off_cpu_reason = reason;
+        let ready_timestamp = reason.filter(OffCpuReason::is_ready).map(|_| timestamp);
         match &thread.state {
             ThreadState::Unknown => {
                 // This "switch-out" is the first time we've heard of the thread. So it must
@@ -53,6 +70,7 @@ impl ContextSwitchHandler {
                 // Just store the new state.
                 thread.state = ThreadState::Off {
                     off_switch_timestamp: timestamp,
+                    ready_timestamp,
                 };
             }
 
@@ -67,6 +85,7 @@ impl ContextSwitchHandler {
 
                 thread.state = ThreadState::Off {
                     off_switch_timestamp: timestamp,
+                    ready_timestamp,
                 };
             }
             ThreadState::Off { .. } => {
@@ -83,6 +102,13 @@ impl ContextSwitchHandler {
         }
     }
 
+    /// The thread was made ready to run, so the rest of its sleep counts as ready time
+    pub fn handle_ready(&self, timestamp: u64, thread: &mut ThreadContextSwitchData) {
+        if let ThreadState::Off { ready_timestamp: ready_timestamp @ None, .. } = &mut thread.state {
+            *ready_timestamp = Some(timestamp);
+        }
+    }
+
     pub fn handle_switch_in(
         &self,
         timestamp: u64,
@@ -104,16 +130,16 @@ impl ContextSwitchHandler {
             }
             ThreadState::Off {
                 off_switch_timestamp,
+                ready_timestamp,
             } => {
                 // The thread was sleeping and is now starting to run again.
                 // Accumulate the off-cpu time.
-                let off_duration = timestamp - off_switch_timestamp;
-                thread.off_cpu_duration_since_last_off_cpu_sample += off_duration;
+                let waiting_end = self.end_sleep(timestamp, off_switch_timestamp, ready_timestamp, thread);
 
                 // We just added some off-cpu time. If the accumulated off-cpu time exceeds the
                 // off-cpu sampling interval, we want to consume some of it and turn it into an
                 // off-cpu sampling group.
-                self.maybe_consume_off_cpu(timestamp, thread)
+                self.maybe_consume_off_cpu(waiting_end, thread)
             }
             ThreadState::Unknown => {
                 // This "switch-in" is the first time we've heard of the thread.
@@ -151,17 +177,17 @@ impl ContextSwitchHandler {
             }
             ThreadState::Off {
                 off_switch_timestamp,
+                ready_timestamp,
             } => {
                 // The last time we heard from this thread, it was being context switched away from.
                 // We are processing a sample on it so we know it is running again. Treat this sample
                 // as a switch-in event.
-                let off_duration = timestamp - off_switch_timestamp;
-                thread.off_cpu_duration_since_last_off_cpu_sample += off_duration;
+                let waiting_end = self.end_sleep(timestamp, off_switch_timestamp, ready_timestamp, thread);
 
                 // We just added some off-cpu time. If the accumulated off-cpu time exceeds the
                 // off-cpu sampling interval, we want to consume some of it and turn it into an
                 // off-cpu sampling group.
-                self.maybe_consume_off_cpu(timestamp, thread)
+                self.maybe_consume_off_cpu(waiting_end, thread)
             }
             ThreadState::Unknown => {
                 // This sample is the first time we've ever head from a thread.
@@ -179,28 +205,73 @@ impl ContextSwitchHandler {
         off_cpu_sample
     }
 
+    /// Splits the sleep that ends at `timestamp` into the time the thread was waiting and the
+    /// time it was ready, and accumulates both. Returns when the waiting part ended.
+    fn end_sleep(
+        &self,
+        timestamp: u64,
+        off_switch_timestamp: u64,
+        ready_timestamp: Option<u64>,
+        thread: &mut ThreadContextSwitchData,
+    ) -> u64 {
+        let ready_timestamp = ready_timestamp.map(|ready| ready.clamp(off_switch_timestamp, timestamp));
+        let waiting_end = ready_timestamp.unwrap_or(timestamp);
+        thread.off_cpu_duration_since_last_off_cpu_sample += waiting_end - off_switch_timestamp;
+        thread.ready_duration_since_last_ready_sample += timestamp - waiting_end;
+        if ready_timestamp.is_some() {
+            thread.ready_latency = Some(timestamp - waiting_end);
+        }
+        waiting_end
+    }
+
     fn maybe_consume_off_cpu(
         &self,
         timestamp: u64,
         thread: &mut ThreadContextSwitchData,
+    ) -> Option<OffCpuSampleGroup> {
+        let reason = thread.off_cpu_reason;
+        self.maybe_consume(timestamp, &mut thread.off_cpu_duration_since_last_off_cpu_sample, reason, false)
+    }
+
+    /// Turns the accumulated ready time into a sample group ending at `timestamp` once it
+    /// exceeds the off-cpu sampling interval. Call this after switch-ins.
+    pub fn consume_ready_time(
+        &self,
+        timestamp: u64,
+        thread: &mut ThreadContextSwitchData,
+    ) -> Option<OffCpuSampleGroup> {
+        let reason = thread.off_cpu_reason;
+        self.maybe_consume(timestamp, &mut thread.ready_duration_since_last_ready_sample, reason, true)
+    }
+
+    /// The time between the thread being readied and running for the sleep that ended last, if
+    /// we saw it being readied. Only returned once.
+    pub fn take_ready_latency(&self, thread: &mut ThreadContextSwitchData) -> Option<u64> {
+        thread.ready_latency.take()
+    }
+
+    fn maybe_consume(
+        &self,
+        timestamp: u64,
+        accumulated_duration: &mut u64,
+        reason: Option<OffCpuReason>,
+        ready: bool,
     ) -> Option<OffCpuSampleGroup> {
         // If the accumulated off-cpu time exceeds the off-cpu sampling interval,
         // we want to consume some of it and turn it into an off-cpu sampling group.
         let interval = self.off_cpu_sampling_interval;
-        if thread.off_cpu_duration_since_last_off_cpu_sample < interval {
+        if *accumulated_duration < interval {
             return None;
         }
 
         // Let's turn the accumulated off-cpu time into an off-cpu sample group.
-        let sample_count = thread.off_cpu_duration_since_last_off_cpu_sample / interval;
+        let sample_count = *accumulated_duration / interval;
         debug_assert!(sample_count >= 1);
 
         let consumed_duration = sample_count * interval;
-        let remaining_duration =
-            thread.off_cpu_duration_since_last_off_cpu_sample - consumed_duration;
+        let remaining_duration = *accumulated_duration - consumed_duration;
 
-        let begin_timestamp =
-            timestamp - (thread.off_cpu_duration_since_last_off_cpu_sample - interval);
+        let begin_timestamp = timestamp - (*accumulated_duration - interval);
         let end_timestamp = timestamp - remaining_duration;
         debug_assert_eq!(
             end_timestamp - begin_timestamp,
@@ -208,12 +279,14 @@ impl ContextSwitchHandler {
         );
 
         // Consume the consumed duration and save the leftover duration.
-        thread.off_cpu_duration_since_last_off_cpu_sample = remaining_duration;
+        *accumulated_duration = remaining_duration;
 
         Some(OffCpuSampleGroup {
             begin_timestamp,
             end_timestamp,
             sample_count,
+            reason,
+            ready,
         })
     }
 
@@ -227,6 +300,11 @@ pub struct OffCpuSampleGroup {
     pub begin_timestamp: u64,
     pub end_timestamp: u64,
     pub sample_count: u64,
+    /// Why the thread left the CPU the last time before the group was emitted. The group can
+    /// also contain the time of earlier sleeps, which may have had other reasons.
+    pub reason: Option<OffCpuReason>,
+    /// Whether the group is for the time the thread was ready to run rather than waiting
+    pub ready: bool,
 }
 
 #[derive(Default, Clone, Debug, PartialEq, Eq)]
@@ -234,12 +312,64 @@ pub struct ThreadContextSwitchData {
     state: ThreadState,
     on_cpu_duration_since_last_sample: u64,
     off_cpu_duration_since_last_off_cpu_sample: u64,
+    off_cpu_reason: Option<OffCpuReason>,
+    ready_duration_since_last_ready_sample: u64,
+    ready_latency: Option<u64>,
+}
+
+/// The KTHREAD_STATE names, by value
+const THREAD_STATES: [&str; 10] = [
+    "Initialized", "Ready", "Running", "Standby", "Terminated", "Waiting", "Transition",
+    "DeferredReady", "GateWaitObsolete", "WaitingForProcessInSwap",
+];
+
+/// The KWAIT_REASON names, by value
+const WAIT_REASONS: [&str; 43] = [
+    "Executive", "FreePage", "PageIn", "PoolAllocation", "DelayExecution", "Suspended",
+    "UserRequest", "WrExecutive", "WrFreePage", "WrPageIn", "WrPoolAllocation",
+    "WrDelayExecution", "WrSuspended", "WrUserRequest", "WrEventPair", "WrQueue",
+    "WrLpcReceive", "WrLpcReply", "WrVirtualMemory", "WrPageOut", "WrRendezvous",
+    "WrKeyedEvent", "WrTerminated", "WrProcessInSwap", "WrCpuRateControl", "WrCalloutStack",
+    "WrKernel", "WrResource", "WrPushLock", "WrMutex", "WrQuantumEnd", "WrDispatchInt",
+    "WrPreempted", "WrYieldExecution", "WrFastMutex", "WrGuardedMutex", "WrRundown",
+    "WrAlertByThreadId", "WrDeferredPreempt", "WrPhysicalFault", "WrIoRing", "WrMdlCache",
+    "WrRcu",
+];
+
+/// Why a thread left the CPU, from the `OldThreadState` and `OldThreadWaitReason` of the CSwitch
+/// event that switched it out
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct OffCpuReason {
+    pub thread_state: u8,
+    pub wait_reason: u8,
+}
+
+impl OffCpuReason {
+    /// Whether the thread could have kept running, i.e. it was preempted or yielded rather than
+    /// waiting for something
+    pub fn is_ready(&self) -> bool {
+        // Ready, Standby and DeferredReady
+        matches!(self.thread_state, 1 | 3 | 7)
+    }
+
+    /// Names the state and the wait reason, e.g. `Waiting: UserRequest` or `Ready: WrPreempted`
+    pub fn label(&self) -> String {
+        let state = match THREAD_STATES.get(self.thread_state as usize) {
+            Some(state) => state.to_string(),
+            None => format!("State {}", self.thread_state),
+        };
+        match WAIT_REASONS.get(self.wait_reason as usize) {
+            Some(reason) => format!("{}: {}", state, reason),
+            None => format!("{}: WaitReason {}", state, self.wait_reason),
+        }
+    }
 }
 
 #[derive(Clone, Debug, PartialEq, Eq)]
 enum ThreadState {
     Unknown,
-    Off { off_switch_timestamp: u64 },
+    /// `ready_timestamp` is when the thread was readied, if it has been
+    Off { off_switch_timestamp: u64, ready_timestamp: Option<u64> },
     On { last_observed_on_timestamp: u64 },
 }
 
@@ -249,9 +379,45 @@ impl Default for ThreadState {
     }
 }
 
+/// The upper bounds of the [LatencyHistogram] buckets, in nanoseconds. The last bucket has the
+/// latencies that are longer than all of these.
+pub const LATENCY_BUCKETS: [u64; 6] = [1_000, 10_000, 100_000, 1_000_000, 10_000_000, 100_000_000];
+
+/// The ready latencies of a thread, i.e. how long it took to be switched in after being readied
+#[derive(Default, Clone, Debug, PartialEq, Eq)]
+pub struct LatencyHistogram {
+    pub buckets: [u64; LATENCY_BUCKETS.len() + 1],
+    pub count: u64,
+    pub total: u64,
+    pub max: u64,
+}
+
+impl LatencyHistogram {
+    /// Adds a latency in nanoseconds
+    pub fn add(&mut self, latency: u64) {
+        let bucket = LATENCY_BUCKETS.iter().position(|bound| latency < *bound).unwrap_or(LATENCY_BUCKETS.len());
+        self.buckets[bucket] += 1;
+        self.count += 1;
+        self.total += latency;
+        self.max = self.max.max(latency);
+    }
+
+    /// Names the buckets, e.g. `<1us` and `>=100ms`
+    pub fn bucket_names() -> [String; LATENCY_BUCKETS.len() + 1] {
+        let format = |ns: u64| match ns {
+            ns if ns >= 1_000_000 => format!("{}ms", ns / 1_000_000),
+            ns => format!("{}us", ns / 1_000),
+        };
+        std::array::from_fn(|i| match LATENCY_BUCKETS.get(i) {
+            Some(bound) => format!("<{}", format(*bound)),
+            None => format!(">={}", format(LATENCY_BUCKETS[LATENCY_BUCKETS.len() - 1])),
+        })
+    }
+}
+
 #[cfg(test)]
 mod test {
-    use super::{ContextSwitchHandler, OffCpuSampleGroup, ThreadContextSwitchData};
+    use super::{ContextSwitchHandler, LatencyHistogram, OffCpuReason, OffCpuSampleGroup, ThreadContextSwitchData};
 
     #[test]
     fn it_works() {
@@ -272,39 +438,43 @@ mod test {
         let handler = ContextSwitchHandler::new(10);
         let s = handler.handle_switch_in(0, &mut thread);
         assert_eq!(s, None);
-        handler.handle_switch_out(3, &mut thread);
+        handler.handle_switch_out(3, None, &mut thread);
         let s = handler.handle_switch_in(5, &mut thread);
         assert_eq!(s, None);
         let s = handler.handle_on_cpu_sample(12, &mut thread);
         let delta = handler.consume_cpu_delta(&mut thread);
         assert_eq!(s, None);
         assert_eq!(delta, 10);
-        handler.handle_switch_out(13, &mut thread);
+        handler.handle_switch_out(13, None, &mut thread);
         let s = handler.handle_switch_in(15, &mut thread);
         assert_eq!(s, None);
-        handler.handle_switch_out(16, &mut thread);
+        handler.handle_switch_out(16, None, &mut thread);
         let s = handler.handle_switch_in(21, &mut thread);
         assert_eq!(s, None);
-        handler.handle_switch_out(23, &mut thread);
+        handler.handle_switch_out(23, None, &mut thread);
         let s = handler.handle_switch_in(27, &mut thread);
         assert_eq!(
             s,
             Some(OffCpuSampleGroup {
                 begin_timestamp: 24,
                 end_timestamp: 24,
-                sample_count: 1
+                sample_count: 1,
+                reason: None,
+                ready: false,
             })
         );
         let delta = handler.consume_cpu_delta(&mut thread);
         assert_eq!(delta, 4);
-        handler.handle_switch_out(30, &mut thread);
+        handler.handle_switch_out(30, None, &mut thread);
         let s = handler.handle_switch_in(48, &mut thread);
         assert_eq!(
             s,
             Some(OffCpuSampleGroup {
                 begin_timestamp: 37,
                 end_timestamp: 47,
-                sample_count: 2
+                sample_count: 2,
+                reason: None,
+                ready: false,
             })
         );
         let delta = handler.consume_cpu_delta(&mut thread);
@@ -318,4 +488,54 @@ mod test {
         assert_eq!(s, None);
         assert_eq!(delta, 10);
     }
+
+    #[test]
+    fn off_cpu_reason() {
+        let mut thread = ThreadContextSwitchData::default();
+        let handler = ContextSwitchHandler::new(10);
+        let preempted = OffCpuReason { thread_state: 1, wait_reason: 32 };
+        handler.handle_switch_in(0, &mut thread);
+        handler.handle_switch_out(5, Some(preempted), &mut thread);
+        let s = handler.handle_switch_in(20, &mut thread);
+        assert_eq!(s, None);
+        let s = handler.consume_ready_time(20, &mut thread);
+        assert_eq!(s.as_ref().and_then(|s| s.reason), Some(preempted));
+        assert!(preempted.is_ready());
+        assert_eq!(preempted.label(), "Ready: WrPreempted");
+
+        let waiting = OffCpuReason { thread_state: 5, wait_reason: 6 };
+        assert!(!waiting.is_ready());
+        assert_eq!(waiting.label(), "Waiting: UserRequest");
+        assert_eq!(OffCpuReason { thread_state: 12, wait_reason: 99 }.label(), "State 12: WaitReason 99");
+    }
+
+    #[test]
+    fn ready_time() {
+        // Waiting from 10 to 40, then ready until 55
+        let mut thread = ThreadContextSwitchData::default();
+        let handler = ContextSwitchHandler::new(10);
+        handler.handle_switch_in(0, &mut thread);
+        handler.handle_switch_out(10, Some(OffCpuReason { thread_state: 5, wait_reason: 6 }), &mut thread);
+        handler.handle_ready(40, &mut thread);
+        // Only the first ReadyThread counts
+        handler.handle_ready(45, &mut thread);
+        let s = handler.handle_switch_in(55, &mut thread);
+        assert_eq!(s.map(|s| (s.begin_timestamp, s.end_timestamp, s.sample_count, s.ready)), Some((20, 40, 3, false)));
+        let s = handler.consume_ready_time(55, &mut thread);
+        assert_eq!(s.map(|s| (s.begin_timestamp, s.end_timestamp, s.sample_count, s.ready)), Some((50, 50, 1, true)));
+        assert_eq!(handler.take_ready_latency(&mut thread), Some(15));
+        assert_eq!(handler.take_ready_latency(&mut thread), None);
+    }
+
+    #[test]
+    fn latency_histogram() {
+        let mut histogram = LatencyHistogram::default();
+        histogram.add(500);
+        histogram.add(1_000);
+        histogram.add(2_000_000);
+        histogram.add(500_000_000);
+        assert_eq!(histogram.buckets, [1, 1, 0, 0, 1, 0, 1]);
+        assert_eq!((histogram.count, histogram.total, histogram.max), (4, 502_001_500, 500_000_000));
+        assert_eq!(LatencyHistogram::bucket_names(), ["<1us", "<10us", "<100us", "<1ms", "<10ms", "<100ms", ">=100ms"]);
+    }
 }
@@ -0,0 +1,221 @@
+//! Per-CPU scheduling tracks, see [ConvertOptions::cpu_tracks](crate::ConvertOptions::cpu_tracks)
+//!
+//! Every logical processor that has context switches gets a thread in a "CPUs" process. Its
+//! markers show which thread was running on the processor, named after the thread's process, and
+//! its counter shows the processor's utilization, i.e. the share of each [UTILIZATION_INTERVAL]
+//! in which a thread other than the idle thread was running. Counter samples are deltas, so the
+//! graph adds them up to the utilization in percent.
+use fxprof_processed_profile::{
+    CategoryHandle, CounterHandle, MarkerDynamicField, MarkerFieldFormat, MarkerLocation, MarkerSchema, MarkerSchemaField,
+    MarkerStaticField, ProcessHandle, Profile, ProfilerMarker, ThreadHandle, Timestamp,
+};
+use serde_json::json;
+
+use super::{chrome_trace::TraceTiming, types::FastHashMap};
+
+/// The length of the intervals that the utilization is computed over, in nanoseconds
+pub const UTILIZATION_INTERVAL: u64 = 10_000_000;
+
+/// The thread id of the idle threads
+const IDLE_THREAD_ID: u32 = 0;
+
+/// The pid of the "CPUs" process. Windows process ids are multiples of 4, so it can't be a real
+/// process, and it's not 1, which the "All processes" process of merged threads uses.
+const CPUS_PROCESS_ID: u32 = 2;
+
+#[derive(Debug)]
+struct CpuTrack {
+    thread: ThreadHandle,
+    utilization: CounterHandle,
+    /// The thread that was last switched in and when
+    running: Option<(u32, u64)>,
+    /// The start of the current utilization interval
+    interval_start: u64,
+    /// How much of the current interval a thread was running, up to `updated`
+    busy: u64,
+    updated: u64,
+    /// The utilization in percent that the counter is at
+    last_utilization: f64,
+}
+
+impl CpuTrack {
+    fn is_busy(&self) -> bool {
+        self.running.is_some_and(|(thread_id, _)| thread_id != IDLE_THREAD_ID)
+    }
+
+    /// Accounts the time up to `time` and adds counter samples for the intervals that ended
+    fn advance(&mut self, profile: &mut Profile, time: u64) {
+        loop {
+            let interval_end = self.interval_start + UTILIZATION_INTERVAL;
+            let end = time.min(interval_end);
+            if self.is_busy() {
+                self.busy += end.saturating_sub(self.updated);
+            }
+            self.updated = self.updated.max(end);
+            if end < interval_end {
+                break;
+            }
+            let utilization = (self.busy * 100) as f64 / UTILIZATION_INTERVAL as f64;
+            if utilization != self.last_utilization {
+                let timestamp = Timestamp::from_nanos_since_reference(self.interval_start);
+                profile.add_counter_sample(self.utilization, timestamp, utilization - self.last_utilization, 1);
+                self.last_utilization = utilization;
+            }
+            self.interval_start = interval_end;
+            self.busy = 0;
+        }
+    }
+}
+
+#[derive(Debug)]
+pub struct CpuTracks {
+    start: Timestamp,
+    process: Option<ProcessHandle>,
+    /// By processor index
+    cpus: FastHashMap<u16, CpuTrack>,
+    /// The process of every thread that we've seen start, by thread id
+    thread_processes: FastHashMap<u32, u32>,
+    /// The image name of every process that we've seen start, by process id
+    process_names: FastHashMap<u32, String>,
+}
+
+impl CpuTracks {
+    pub fn new(start: Timestamp) -> Self {
+        CpuTracks {
+            start,
+            process: None,
+            cpus: FastHashMap::default(),
+            thread_processes: FastHashMap::default(),
+            process_names: FastHashMap::default(),
+        }
+    }
+
+    pub fn thread_started(&mut self, thread_id: u32, process_id: u32) {
+        self.thread_processes.insert(thread_id, process_id);
+    }
+
+    pub fn process_started(&mut self, process_id: u32, name: String) {
+        self.process_names.insert(process_id, name);
+    }
+
+    /// Handles a context switch to `thread_id` on processor `cpu` at `time`, in nanoseconds
+    pub fn switch(&mut self, profile: &mut Profile, cpu: u16, thread_id: u32, time: u64) {
+        let start = self.start;
+        let process = *self.process.get_or_insert_with(|| profile.add_process("CPUs", CPUS_PROCESS_ID, start));
+        let track = self.cpus.entry(cpu).or_insert_with(|| {
+            let thread = profile.add_thread(process, cpu as u32, start, false);
+            profile.set_thread_name(thread, &format!("CPU {}", cpu));
+            let name = format!("CPU {} utilization", cpu);
+            let utilization = profile.add_counter(process, &name, "CPU", "Share of the time a thread was running on this processor, in percent");
+            let interval_start = time - time % UTILIZATION_INTERVAL;
+            CpuTrack { thread, utilization, running: None, interval_start, busy: 0, updated: interval_start, last_utilization: 0. }
+        });
+        track.advance(profile, time);
+        if let Some((running, since)) = track.running.replace((thread_id, time)) {
+            if running != IDLE_THREAD_ID {
+                add_running_marker(profile, track.thread, running, &self.thread_processes, &self.process_names, since, time);
+            }
+        }
+    }
+
+    /// Ends the threads that are still running at `time`, in nanoseconds
+    pub fn finish(mut self, profile: &mut Profile, time: u64) {
+        for track in self.cpus.values_mut() {
+            track.advance(profile, time);
+            if let Some((running, since)) = track.running.take().filter(|(thread_id, _)| *thread_id != IDLE_THREAD_ID) {
+                add_running_marker(profile, track.thread, running, &self.thread_processes, &self.process_names, since, time);
+            }
+        }
+    }
+}
+
+fn add_running_marker(
+    profile: &mut Profile,
+    cpu_thread: ThreadHandle,
+    thread_id: u32,
+    thread_processes: &FastHashMap<u32, u32>,
+    process_names: &FastHashMap<u32, String>,
+    start: u64,
+    end: u64,
+) {
+    let process_id = thread_processes.get(&thread_id).copied();
+    let name = match process_id {
+        Some(process_id) => match process_names.get(&process_id) {
+            Some(process_name) => format!("{} ({})", process_name, process_id),
+            None => format!("pid {}", process_id),
+        },
+        None => "unknown process".to_owned(),
+    };
+    let marker = RunningThreadMarker { thread_id, process_id };
+    profile.add_marker(cpu_thread, CategoryHandle::OTHER, &name, marker, TraceTiming::Interval(start, end).marker_timing());
+}
+
+/// A thread running on a processor
+#[derive(Debug, Clone)]
+pub struct RunningThreadMarker {
+    pub thread_id: u32,
+    pub process_id: Option<u32>,
+}
+
+impl ProfilerMarker for RunningThreadMarker {
+    const MARKER_TYPE_NAME: &'static str = "RunningThread";
+
+    fn json_marker_data(&self) -> serde_json::Value {
+        json!({
+            "type": Self::MARKER_TYPE_NAME,
+            "thread": self.thread_id,
+            "process": self.process_id,
+        })
+    }
+
+    fn schema() -> MarkerSchema {
+        MarkerSchema {
+            type_name: Self::MARKER_TYPE_NAME,
+            locations: vec![MarkerLocation::MarkerChart, MarkerLocation::MarkerTable, MarkerLocation::TimelineOverview],
+            chart_label: Some("{marker.name} {marker.data.thread}"),
+            tooltip_label: Some("{marker.name} thread {marker.data.thread}"),
+            table_label: Some("{marker.name} thread {marker.data.thread}"),
+            fields: vec![
+                MarkerSchemaField::Dynamic(MarkerDynamicField {
+                    key: "thread",
+                    label: "Thread",
+                    format: MarkerFieldFormat::Integer,
+                    searchable: true,
+                }),
+                MarkerSchemaField::Dynamic(MarkerDynamicField {
+                    key: "process",
+                    label: "Process",
+                    format: MarkerFieldFormat::Integer,
+                    searchable: true,
+                }),
+                MarkerSchemaField::Static(MarkerStaticField {
+                    label: "Description",
+                    value: "Emitted for the time between the context switches to and from a thread.",
+                }),
+            ],
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use fxprof_processed_profile::{Profile, ReferenceTimestamp, SamplingInterval, Timestamp};
+
+    use super::{CpuTracks, UTILIZATION_INTERVAL};
+
+    #[test]
+    fn utilization() {
+        let mut profile = Profile::new("test", ReferenceTimestamp::from_millis_since_unix_epoch(0.), SamplingInterval::from_millis(1));
+        let mut tracks = CpuTracks::new(Timestamp::from_nanos_since_reference(0));
+        tracks.switch(&mut profile, 0, 10, 0);
+        // Idle for the last quarter of the first interval and all of the second
+        tracks.switch(&mut profile, 0, 0, UTILIZATION_INTERVAL * 3 / 4);
+        tracks.switch(&mut profile, 0, 10, UTILIZATION_INTERVAL * 2);
+        let track = &tracks.cpus[&0];
+        assert_eq!(track.last_utilization, 0.);
+        assert_eq!(track.interval_start, UTILIZATION_INTERVAL * 2);
+        tracks.switch(&mut profile, 0, 0, UTILIZATION_INTERVAL * 5 / 2);
+        tracks.switch(&mut profile, 0, 10, UTILIZATION_INTERVAL * 3);
+        assert_eq!(tracks.cpus[&0].last_utilization, 50.);
+    }
+}
@@ -0,0 +1,114 @@
+//! Errors that can happen while converting a trace
+//!
+//! Most of them come from a single malformed or unexpected event. Unless
+//! [ConvertOptions::strict](crate::ConvertOptions::strict) is set, the event is skipped and counted
+//! in [TraceConverter::skipped_events](crate::TraceConverter::skipped_events) instead.
+use std::{collections::BTreeMap, fmt, io};
+
+use etw_reader::{parser::{Parser, ParserError, TryParse}, GUID};
+
+#[derive(Debug)]
+pub enum ConvertError {
+    Io(io::Error),
+    /// A property is missing or doesn't have the expected type or length
+    Property { property: String, error: ParserError },
+    /// A property has a value that we don't know how to handle
+    UnexpectedValue { property: &'static str, value: String },
+    /// An event refers to a process whose start we haven't seen
+    MissingProcess(u32),
+    /// An image event refers to an image base without a KernelTraceControl/ImageID event
+    UnknownImageBase(u64),
+    /// There's no schema for the event with id `event_id` of `provider`, so it can't be decoded
+    UnknownSchema { provider: GUID, event_id: u16 },
+    /// An event handler failed on the event called `event`
+    Event { event: String, error: Box<ConvertError> },
+}
+
+impl ConvertError {
+    /// Describes the error without the ids and values that are specific to one event, so that
+    /// errors of the same kind can be counted together
+    pub fn kind(&self) -> String {
+        match self {
+            ConvertError::Io(_) => "I/O error".to_owned(),
+            ConvertError::Property { property, error } => format!("bad property {} ({:?})", property, error),
+            ConvertError::UnexpectedValue { property, .. } => format!("unexpected value for {}", property),
+            ConvertError::MissingProcess(_) => "missing process".to_owned(),
+            ConvertError::UnknownImageBase(_) => "unknown image base".to_owned(),
+            ConvertError::UnknownSchema { provider, .. } => format!("no schema for events of provider {:?}", provider),
+            ConvertError::Event { event, error } => format!("{}: {}", event, error.kind()),
+        }
+    }
+}
+
+impl fmt::Display for ConvertError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ConvertError::Io(err) => write!(f, "{}", err),
+            ConvertError::Property { property, error } => write!(f, "couldn't parse property {}: {:?}", property, error),
+            ConvertError::UnexpectedValue { property, value } => write!(f, "unexpected value {} for {}", value, property),
+            ConvertError::MissingProcess(process_id) => write!(f, "process {} hasn't started", process_id),
+            ConvertError::UnknownImageBase(image_base) => write!(f, "no image was seen at 0x{:x}", image_base),
+            ConvertError::UnknownSchema { provider, event_id } => write!(f, "no schema for event {} of provider {:?}", event_id, provider),
+            ConvertError::Event { event, error } => write!(f, "{}: {}", event, error),
+        }
+    }
+}
+
+impl std::error::Error for ConvertError {}
+
+impl From<io::Error> for ConvertError {
+    fn from(err: io::Error) -> Self {
+        ConvertError::Io(err)
+    }
+}
+
+/// Like [TryParse::try_parse] but with the property name in the error
+pub trait ParseProperty {
+    fn property<T>(&mut self, name: &str) -> Result<T, ConvertError> where Self: TryParse<T>;
+}
+
+impl ParseProperty for Parser<'_> {
+    fn property<T>(&mut self, name: &str) -> Result<T, ConvertError> where Self: TryParse<T> {
+        self.try_parse(name).map_err(|error| ConvertError::Property { property: name.to_owned(), error })
+    }
+}
+
+/// The events that were skipped because of an error, counted by [ConvertError::kind]
+#[derive(Debug, Clone, Default)]
+pub struct SkippedEvents {
+    counts: BTreeMap<String, u64>,
+}
+
+impl SkippedEvents {
+    pub fn add(&mut self, error: &ConvertError) {
+        *self.counts.entry(error.kind()).or_insert(0) += 1;
+    }
+
+    pub fn total(&self) -> u64 {
+        self.counts.values().sum()
+    }
+
+    /// The kinds of errors with the number of events they happened in
+    pub fn iter(&self) -> impl Iterator<Item = (&str, u64)> {
+        self.counts.iter().map(|(kind, count)| (kind.as_str(), *count))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{ConvertError, SkippedEvents};
+
+    #[test]
+    fn skipped_events() {
+        let in_event = |error| ConvertError::Event { event: "MSNT_SystemTrace/Image/Load".to_owned(), error: Box::new(error) };
+        let mut skipped = SkippedEvents::default();
+        skipped.add(&in_event(ConvertError::MissingProcess(1)));
+        skipped.add(&in_event(ConvertError::MissingProcess(2)));
+        skipped.add(&in_event(ConvertError::UnknownImageBase(0x1000)));
+        assert_eq!(skipped.total(), 3);
+        assert_eq!(skipped.iter().collect::<Vec<_>>(), [
+            ("MSNT_SystemTrace/Image/Load: missing process", 2),
+            ("MSNT_SystemTrace/Image/Load: unknown image base", 1),
+        ]);
+    }
+}
@@ -0,0 +1,1946 @@
+//! Converts ETW traces into profiles for the Firefox Profiler
+//!
+//! [TraceConverter] holds all of the state that's built up while walking a trace. Each event is
+//! dispatched by name to an [EventHandler], and handlers can be replaced or added with
+//! [TraceConverter::register_handler] to support other providers.
+use std::{collections::{BTreeMap, HashMap, HashSet, hash_map::Entry, VecDeque}, convert::TryInto, ops::{ControlFlow, Range}, path::{Path, PathBuf}, time::{Duration, SystemTime}, sync::Arc};
+
+use context_switch::{OffCpuReason, OffCpuSampleGroup, ThreadContextSwitchData};
+use etw_reader::{GUID, open_trace_until, parser::{Parser, TryParse, Address}, print_property, schema::{SchemaLocator, TypedEvent}, write_property};
+use lib_mappings::{LibMappingOpQueue, LibMappingOp, LibMappingAdd};
+use serde_json::{Value, json};
+use fxprof_processed_profile::{debugid, CategoryColor, CategoryHandle, CategoryPairHandle, CounterHandle, FrameFlags, FrameInfo, LibMappings, LibraryHandle, LibraryInfo, MarkerDynamicField, MarkerFieldFormat, MarkerLocation, MarkerSchema, MarkerSchemaField, MarkerTiming, ProcessHandle, Profile, ProfilerMarker, ReferenceTimestamp, SamplingInterval, Symbol, SymbolTable, ThreadHandle, Timestamp};
+use debugid::DebugId;
+use bitflags::bitflags;
+use rangemap::RangeSet;
+
+
+pub mod collapsed;
+pub mod chrome_trace;
+mod context_switch;
+mod cpu_tracks;
+pub mod error;
+mod jit_category_manager;
+mod jit_function_add_marker;
+mod lib_mappings;
+pub mod marker_file;
+pub mod pprof;
+mod process_sample_data;
+mod rundown;
+mod stack_converter;
+mod stack_depth_limiting_frame_iter;
+pub mod symbol_store;
+pub mod symbolication;
+pub mod timestamp_converter;
+mod types;
+mod unresolved_samples;
+
+use jit_category_manager::JitCategoryManager;
+use lib_mappings::LibMappingInfo;
+use types::{FastHashMap, StackFrame, StackMode};
+use unresolved_samples::{UnresolvedSamples, UnresolvedStacks};
+use uuid::Uuid;
+use process_sample_data::{ExportContext, ProcessSampleData};
+use stack_converter::{OffCpuLabels, READY_FRAME_NAME};
+
+pub use context_switch::LatencyHistogram;
+
+use crate::{chrome_trace::{ChromeTrace, TraceTiming}, collapsed::{CollapsedStacks, CollapsedWeight}, context_switch::ContextSwitchHandler, cpu_tracks::CpuTracks, error::{ConvertError, ParseProperty, SkippedEvents}, jit_function_add_marker::JitFunctionAddMarker, marker_file::MarkerSpan, pprof::PprofBuilder, process_sample_data::{UnblockedMarker, UserTimingMarker}, rundown::Rundown, symbol_store::SymbolStore, symbolication::PdbSymbols, timestamp_converter::TimestampConverter};
+
+/// An example marker type with some text content.
+#[derive(Debug, Clone)]
+pub struct TextMarker(pub String);
+
+impl ProfilerMarker for TextMarker {
+    const MARKER_TYPE_NAME: &'static str = "Text";
+
+    fn json_marker_data(&self) -> serde_json::Value {
+        json!({
+            "type": Self::MARKER_TYPE_NAME,
+            "name": self.0
+        })
+    }
+
+    fn schema() -> MarkerSchema {
+        MarkerSchema {
+            type_name: Self::MARKER_TYPE_NAME,
+            locations: vec![MarkerLocation::MarkerChart, MarkerLocation::MarkerTable],
+            chart_label: Some("{marker.data.name}"),
+            tooltip_label: Some("{marker.data.name}"),
+            table_label: Some("{marker.name} - {marker.data.name}"),
+            fields: vec![MarkerSchemaField::Dynamic(MarkerDynamicField {
+                key: "name",
+                label: "Name",
+                format: MarkerFieldFormat::String,
+                searchable: true,
+            })],
+        }
+    }
+}
+
+fn is_kernel_address(ip: u64, pointer_size: u32) -> bool {
+    if pointer_size == 4 {
+        return ip >= 0x80000000;
+    }
+    return ip >= 0xFFFF000000000000;        // TODO I don't know what the true cutoff is.
+}
+
+fn stack_mode_for_address(address: u64, pointer_size: u32) -> StackMode {
+    if is_kernel_address(address, pointer_size) {
+        StackMode::Kernel
+    } else {
+        StackMode::User
+    }
+}
+
+/// Reads the addresses of a StackWalk/Stack event, starting with the instruction pointer
+fn read_stack(buffer: &[u8]) -> Vec<StackFrame> {
+    let mut stack: Vec<StackFrame> = Vec::with_capacity(buffer.len() / 8);
+    let mut address_iter = buffer.chunks_exact(8).map(|a| u64::from_ne_bytes(a.try_into().unwrap()));
+    let Some(first_frame_address) = address_iter.next() else { return stack };
+    let first_frame_stack_mode = stack_mode_for_address(first_frame_address, 8);
+    stack.push(StackFrame::InstructionPointer(first_frame_address, first_frame_stack_mode));
+    for frame_address in address_iter {
+        let stack_mode = stack_mode_for_address(first_frame_address, 8);
+        stack.push(StackFrame::ReturnAddress(frame_address, stack_mode));
+    }
+    stack
+}
+
+/// Whether the outermost frame of `stack` isn't in ntdll.dll. We can't tell if we haven't seen
+/// ntdll being loaded.
+fn is_truncated_user_stack(stack: &[StackFrame], ntdll_ranges: &[Range<u64>]) -> bool {
+    match stack.last() {
+        Some(StackFrame::InstructionPointer(address, _) | StackFrame::ReturnAddress(address, _)) => {
+            !ntdll_ranges.is_empty() && !ntdll_ranges.iter().any(|range| range.contains(address))
+        }
+        _ => false,
+    }
+}
+
+/// An on- or off-cpu-sample for which the user stack is not known yet.
+/// Consumed once the user stack arrives.
+#[derive(Debug, Clone)]
+struct PendingStack {
+    /// The timestamp of the SampleProf or CSwitch event
+    timestamp: u64,
+    /// Starts out as None. Once we encounter the kernel stack (if any), we put it here.
+    kernel_stack: Option<Vec<StackFrame>>,
+    off_cpu_sample_group: Option<OffCpuSampleGroup>,
+    /// The time before the off-cpu samples during which the thread was ready to run
+    ready_sample_group: Option<OffCpuSampleGroup>,
+    /// The running time since the previous sample, in nanoseconds
+    on_cpu_sample_cpu_delta: Option<u64>,
+}
+
+/// The thread that made a waiting thread ready to run, from a ReadyThread event
+#[derive(Debug, Clone)]
+struct ReadyingThread {
+    timestamp: u64,
+    thread_id: u32,
+    process_id: u32,
+    /// The stack of the ReadyThread event, innermost frame first. Filled in as its stacks arrive.
+    stack: Vec<StackFrame>,
+}
+
+struct PendingMarker {
+    text: String,
+    /// In nanoseconds
+    start: u64,
+}
+
+pub struct ThreadState {
+    // When merging threads `handle` is the global thread handle and we use `merge_name` to store the name
+    pub handle: ThreadHandle,
+    pub merge_name: Option<String>,
+    pending_stacks: VecDeque<PendingStack>,
+    pending_markers: HashMap<String, PendingMarker>,
+    context_switch_data: ThreadContextSwitchData,
+    /// Who readied the thread since it was last switched in
+    readied_by: Option<ReadyingThread>,
+    /// The thread never runs user code, so its kernel stacks are all the stacks it gets
+    kernel_only: bool,
+    pub process_id: u32,
+    pub thread_id: u32
+}
+
+impl ThreadState {
+    fn new(handle: ThreadHandle, pid: u32, tid: u32) -> Self {
+        ThreadState {
+            handle,
+            pending_stacks: VecDeque::new(),
+            pending_markers: HashMap::new(),
+            context_switch_data: ThreadContextSwitchData::default(),
+            readied_by: None,
+            kernel_only: pid == IDLE_PROCESS_ID || pid == SYSTEM_PROCESS_ID,
+            merge_name: None,
+            process_id: pid,
+            thread_id: tid
+        }
+    }
+}
+
+/// The process of the idle threads. The idle threads of all processors have thread id 0.
+const IDLE_PROCESS_ID: u32 = 0;
+/// The process of the kernel's worker threads
+const SYSTEM_PROCESS_ID: u32 = 4;
+
+/// The Idle process has no image name in the process events
+fn process_name(process_id: u32, image_file_name: String) -> String {
+    if image_file_name.is_empty() && process_id == IDLE_PROCESS_ID {
+        return "Idle".to_owned();
+    }
+    image_file_name
+}
+
+
+fn strip_thread_numbers(name: &str) -> &str {
+    if let Some(hash) = name.find('#') {
+        let (prefix, suffix) = name.split_at(hash);
+        if suffix[1..].parse::<i32>().is_ok() {
+            return prefix.trim();
+        }
+    }
+    return name;
+}
+
+/// Event names start with the provider name, e.g. `Provider/Task/Opcode`, which markers leave out
+/// because it's already their category
+fn strip_provider_name(event_name: &str) -> &str {
+    event_name.split_once('/').map_or(event_name, |(_, name)| name)
+}
+
+struct MemoryUsage {
+    counter: CounterHandle,
+    value: f64
+}
+
+struct ProcessJitInfo {
+    lib_handle: LibraryHandle,
+    jit_mapping_ops: LibMappingOpQueue,
+    next_relative_address: u32,
+    symbols: Vec<Symbol>,
+}
+
+pub struct ProcessState {
+    pub process_handle: ProcessHandle,
+    /// The image file name, e.g. firefox.exe
+    pub name: String,
+    unresolved_samples: UnresolvedSamples,
+    regular_lib_mapping_ops: LibMappingOpQueue,
+    pub main_thread_handle: Option<ThreadHandle>,
+    pending_libraries: HashMap<u64, LibraryInfo>,
+}
+
+impl ProcessState {
+    pub fn new(process_handle: ProcessHandle, name: String) -> Self {
+        Self {
+            process_handle,
+            name,
+            unresolved_samples: UnresolvedSamples::default(),
+            regular_lib_mapping_ops: LibMappingOpQueue::default(),
+            main_thread_handle: None,
+            pending_libraries: HashMap::new(),
+        }
+    }
+}
+
+/// Builds the [LibraryInfo] for an image from its KernelTraceControl/ImageID/ and
+/// KernelTraceControl/ImageID/DbgID_RSDS events
+fn library_info(path: &str, image_size: u32, timestamp: u32, guid: GUID, age: u32, pdb_path: String) -> LibraryInfo {
+    let debug_id = DebugId::from_parts(Uuid::from_fields(guid.data1, guid.data2, guid.data3, &guid.data4), age);
+    let code_id = Some(format!("{timestamp:08X}{image_size:x}"));
+    let file_name = |path: &str| Path::new(path).file_name().and_then(|name| name.to_str()).unwrap_or(path).to_owned();
+    let name = file_name(path);
+    let debug_name = file_name(&pdb_path);
+    LibraryInfo {
+        name,
+        debug_name,
+        path: path.to_owned(),
+        code_id,
+        symbol_table: None,
+        debug_path: pdb_path,
+        debug_id,
+        arch: Some("x86_64".into())
+    }
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct ConvertOptions {
+    /// Put the samples from all threads on a single thread, labelled with the thread name
+    pub merge_threads: bool,
+    /// Include samples from the idle thread and from threads we aren't tracing. Needs `merge_threads`.
+    pub include_idle: bool,
+    /// Add a sample for each DemandZeroFault event
+    pub demand_zero_faults: bool,
+    /// The ids of the processes to trace
+    pub process_targets: HashSet<u32>,
+    /// Trace processes whose image name contains this string
+    pub process_target_name: Option<String>,
+    /// Trace every process, including the Idle and System processes, instead of the ones in
+    /// `process_targets` and `process_target_name`
+    pub all_processes: bool,
+    /// Collect the markers and context switches for [TraceConverter::finish_chrome_trace]
+    pub chrome_trace: bool,
+    /// Directories with PDBs, either directly or in the symstore layout. Libraries whose PDB is
+    /// found are symbolicated by [TraceConverter::finish].
+    pub symbol_dirs: Vec<PathBuf>,
+    /// A symbol path like `_NT_SYMBOL_PATH`, which is searched after `symbol_dirs`. See
+    /// [symbol_store] for what's supported.
+    pub symbol_path: Option<String>,
+    /// Stop at the first event that can't be converted instead of skipping it
+    pub strict: bool,
+    /// Add a track for each processor with the threads that ran on it and its utilization
+    pub cpu_tracks: bool,
+}
+
+#[derive(Debug, Clone, Copy, Default)]
+pub struct ConversionStats {
+    pub events: u64,
+    pub samples: u64,
+    pub dropped_samples: u64,
+    pub stack_samples: u64,
+    /// Stack samples whose user stack doesn't reach ntdll.dll, usually because stack walking
+    /// stopped at code without unwind info
+    pub truncated_stacks: u64,
+}
+
+/// Handles the events with a particular name. See [TraceConverter::register_handler].
+/// Errors make the converter skip the event, see [ConvertOptions::strict].
+pub trait EventHandler {
+    fn handle_event(&mut self, converter: &mut TraceConverter, s: &TypedEvent) -> Result<(), ConvertError>;
+}
+
+impl<F: FnMut(&mut TraceConverter, &TypedEvent) -> Result<(), ConvertError>> EventHandler for F {
+    fn handle_event(&mut self, converter: &mut TraceConverter, s: &TypedEvent) -> Result<(), ConvertError> {
+        self(converter, s)
+    }
+}
+
+pub struct TraceConverter {
+    options: ConvertOptions,
+    schema_locator: SchemaLocator,
+    /// `None` while the handler is running
+    handlers: HashMap<String, Option<Box<dyn EventHandler>>>,
+    stats: ConversionStats,
+    skipped_events: SkippedEvents,
+
+    profile: Profile,
+    profile_start_instant: Timestamp,
+    user_category: CategoryPairHandle,
+    kernel_category: CategoryPairHandle,
+    /// The categories of the frames that say why off-cpu samples were off the CPU
+    waiting_category: CategoryPairHandle,
+    ready_category: CategoryPairHandle,
+    categories: HashMap<String, CategoryHandle>,
+
+    process_targets: HashSet<u32>,
+    process_target_name: Option<String>,
+    threads: HashMap<u32, ThreadState>,
+    processes: HashMap<u32, ProcessState>,
+    global_thread: Option<ThreadHandle>,
+    global_process: Option<ProcessHandle>,
+    gpu_thread: Option<ThreadHandle>,
+
+    kernel_pending_libraries: HashMap<u64, LibraryInfo>,
+    libs: HashMap<u64, (String, u32, u32)>,
+    /// (info, image size) of every library that has been added to the profile, for pprof output
+    lib_infos: HashMap<LibraryHandle, (LibraryInfo, u64)>,
+    kernel_lib_mappings: LibMappings<LibMappingInfo>,
+    /// Where ntdll.dll is loaded. Complete user stacks start in it.
+    ntdll_ranges: Vec<Range<u64>>,
+    memory_usage: HashMap<u32, MemoryUsage>,
+    jit_category_manager: JitCategoryManager,
+    jscript_symbols: HashMap<u32, ProcessJitInfo>,
+    jscript_sources: HashMap<u64, String>,
+    unresolved_stacks: UnresolvedStacks,
+    context_switch_handler: ContextSwitchHandler,
+    /// The readied thread of the ReadyThread events whose stacks haven't arrived yet, by readying
+    /// thread and timestamp
+    ready_stacks: HashMap<(u32, u64), u32>,
+    /// How long threads took to run after being readied, by process and thread id
+    ready_latencies: BTreeMap<(u32, u32), LatencyHistogram>,
+
+    timer_resolution: u32, // Resolution of the hardware timer, in units of 100 nanoseconds.
+    timestamp_converter: TimestampConverter,
+    clock_type: u32, // ReservedFlags of the header event, 1 means QPC
+    last_event_timestamp: u64,
+
+    rundown: Option<Rundown>,
+    rundown_images: HashSet<(u32, u64)>,
+
+    chrome_trace: Option<ChromeTrace>,
+    cpu_tracks: Option<CpuTracks>,
+}
+
+impl TraceConverter {
+    pub fn new(options: ConvertOptions) -> Self {
+        let profile_start_instant = Timestamp::from_nanos_since_reference(0);
+        let profile_start_system = SystemTime::now();
+
+        let mut schema_locator = SchemaLocator::new();
+        etw_reader::add_custom_schemas(&mut schema_locator);
+
+        let command_name = match &options.process_target_name {
+            _ if options.all_processes => "All processes",
+            Some(process_target_name) => process_target_name,
+            None => "firefox",
+        };
+        let mut profile = Profile::new(command_name, ReferenceTimestamp::from_system_time(profile_start_system),  SamplingInterval::from_nanos(122100)); // 8192Hz
+
+        let user_category: CategoryPairHandle = profile.add_category("User", fxprof_processed_profile::CategoryColor::Yellow).into();
+        let kernel_category: CategoryPairHandle = profile.add_category("Kernel", fxprof_processed_profile::CategoryColor::Orange).into();
+        let waiting_category: CategoryPairHandle = profile.add_category("Waiting", fxprof_processed_profile::CategoryColor::Gray).into();
+        let ready_category: CategoryPairHandle = profile.add_category("Ready", fxprof_processed_profile::CategoryColor::LightBlue).into();
+
+        let (global_thread, global_process) = if options.merge_threads {
+            let global_process = profile.add_process("All processes", 1, profile_start_instant);
+            (Some(profile.add_thread(global_process, 1, profile_start_instant, true)), Some(global_process))
+        } else {
+            (None, None)
+        };
+
+        let chrome_trace = options.chrome_trace.then(ChromeTrace::default);
+        let cpu_tracks = options.cpu_tracks.then(|| CpuTracks::new(profile_start_instant));
+        let mut converter = TraceConverter {
+            process_targets: options.process_targets.clone(),
+            process_target_name: options.process_target_name.clone(),
+            options,
+            schema_locator,
+            handlers: HashMap::new(),
+            stats: ConversionStats::default(),
+            skipped_events: SkippedEvents::default(),
+            profile,
+            profile_start_instant,
+            user_category,
+            kernel_category,
+            waiting_category,
+            ready_category,
+            categories: HashMap::new(),
+            threads: HashMap::new(),
+            processes: HashMap::new(),
+            global_thread,
+            global_process,
+            gpu_thread: None,
+            kernel_pending_libraries: HashMap::new(),
+            libs: HashMap::new(),
+            lib_infos: HashMap::new(),
+            kernel_lib_mappings: LibMappings::default(),
+            ntdll_ranges: Vec::new(),
+            memory_usage: HashMap::new(),
+            jit_category_manager: JitCategoryManager::new(),
+            jscript_symbols: HashMap::new(),
+            jscript_sources: HashMap::new(),
+            unresolved_stacks: UnresolvedStacks::default(),
+            context_switch_handler: ContextSwitchHandler::new(122100),
+            ready_stacks: HashMap::new(),
+            ready_latencies: BTreeMap::new(),
+            timer_resolution: 0,
+            // Make a dummy TimestampConverter. Once we've parsed the header, this will have correct values.
+            timestamp_converter: TimestampConverter {
+                reference_raw: 0,
+                raw_to_ns_factor: 1,
+            },
+            clock_type: 0,
+            last_event_timestamp: 0,
+            rundown: None,
+            rundown_images: HashSet::new(),
+            chrome_trace,
+            cpu_tracks,
+        };
+
+        converter.register_handler("MSNT_SystemTrace/EventTrace/Header", Self::handle_header);
+        converter.register_handler("MSNT_SystemTrace/PerfInfo/CollectionStart", Self::handle_collection_start);
+        converter.register_handler("MSNT_SystemTrace/Thread/SetName", Self::handle_thread_set_name);
+        converter.register_handler("MSNT_SystemTrace/Thread/DCStart", Self::handle_thread_start);
+        converter.register_handler("MSNT_SystemTrace/Thread/Start", Self::handle_thread_start);
+        converter.register_handler("MSNT_SystemTrace/Thread/DCEnd", Self::handle_thread_end);
+        converter.register_handler("MSNT_SystemTrace/Thread/End", Self::handle_thread_end);
+        converter.register_handler("MSNT_SystemTrace/Process/DCStart", Self::handle_process_start);
+        converter.register_handler("MSNT_SystemTrace/Process/Start", Self::handle_process_start);
+        converter.register_handler("MSNT_SystemTrace/StackWalk/Stack", Self::handle_stack);
+        converter.register_handler("MSNT_SystemTrace/PerfInfo/SampleProf", Self::handle_sample_prof);
+        converter.register_handler("MSNT_SystemTrace/PageFault/DemandZeroFault", Self::handle_demand_zero_fault);
+        converter.register_handler("MSNT_SystemTrace/PageFault/VirtualFree", Self::handle_virtual_free);
+        converter.register_handler("MSNT_SystemTrace/PageFault/VirtualAlloc", Self::handle_virtual_alloc);
+        converter.register_handler("KernelTraceControl/ImageID/", Self::handle_image_id);
+        converter.register_handler("KernelTraceControl/ImageID/DbgID_RSDS", Self::handle_dbg_id);
+        converter.register_handler("MSNT_SystemTrace/Image/Load", Self::handle_image_load);
+        converter.register_handler("MSNT_SystemTrace/Image/DCStart", Self::handle_image_load);
+        converter.register_handler("Microsoft-Windows-DxgKrnl/VSyncDPC/Info ", Self::handle_vsync);
+        converter.register_handler("MSNT_SystemTrace/Thread/CSwitch", Self::handle_cswitch);
+        converter.register_handler("MSNT_SystemTrace/Thread/ReadyThread", Self::handle_ready_thread);
+        converter.register_handler("V8.js/MethodLoad/", Self::handle_jit_method_load);
+        converter.register_handler("Microsoft-JScript/MethodRuntime/MethodDCStart", Self::handle_jit_method_load);
+        converter.register_handler("Microsoft-JScript/MethodRuntime/MethodLoad", Self::handle_jit_method_load);
+        converter.register_handler("V8.js/SourceLoad/", Self::handle_js_source_load);
+        converter.register_handler("Microsoft-Windows-Direct3D11/ID3D11VideoContext_SubmitDecoderBuffers/win:Start", Self::handle_d3d11_decode_start);
+        converter.register_handler("Microsoft-Windows-Direct3D11/ID3D11VideoContext_SubmitDecoderBuffers/win:Stop", Self::handle_d3d11_decode_stop);
+        converter
+    }
+
+    /// Sets the handler for events called `name`, replacing any existing one. Events that
+    /// don't have a handler are turned into text markers if they're on a thread we're tracing.
+    pub fn register_handler(&mut self, name: &str, handler: impl EventHandler + 'static) {
+        self.handlers.insert(name.to_owned(), Some(Box::new(handler)));
+    }
+
+    /// The schema locator used by [TraceConverter::process_trace]. Add manifests or load schemas
+    /// here before processing the trace.
+    pub fn schema_locator(&mut self) -> &mut SchemaLocator {
+        &mut self.schema_locator
+    }
+
+    pub fn options(&self) -> &ConvertOptions {
+        &self.options
+    }
+
+    pub fn stats(&self) -> ConversionStats {
+        self.stats
+    }
+
+    /// Only has the right values once the EventTrace/Header event has been handled
+    pub fn timestamp_converter(&self) -> TimestampConverter {
+        self.timestamp_converter
+    }
+
+    pub fn profile_mut(&mut self) -> &mut Profile {
+        &mut self.profile
+    }
+
+    pub fn thread(&self, thread_id: u32) -> Option<&ThreadState> {
+        self.threads.get(&thread_id)
+    }
+
+    pub fn process(&self, process_id: u32) -> Option<&ProcessState> {
+        self.processes.get(&process_id)
+    }
+
+    /// Reads all of the events in the trace at `path`. In strict mode the events after the first
+    /// one that fails are ignored and its error is returned.
+    pub fn process_trace(&mut self, path: &Path) -> Result<(), ConvertError> {
+        // Traces recorded into a circular buffer need a pass over the DCEnd rundown before we can
+        // make sense of the samples. See rundown.rs
+        self.rundown = match etw_reader::trace_log_file_mode(path) {
+            Ok(log_file_mode) if rundown::is_circular(log_file_mode) => {
+                println!("circular buffer trace, collecting rundown");
+                Some(rundown::collect_rundown(path, &mut self.schema_locator)?)
+            }
+            _ => None,
+        };
+
+        let mut error = None;
+        open_trace_until(path, |e| {
+            self.stats.events += 1;
+            let Ok(s) = self.schema_locator.event_schema(e) else {
+                // Not fatal in strict mode either, traces usually have some events that we can't decode
+                self.skipped_events.add(&ConvertError::UnknownSchema { provider: e.provider_id, event_id: e.descriptor.id });
+                return ControlFlow::Continue(());
+            };
+            match self.handle_event(&s) {
+                Ok(()) => ControlFlow::Continue(()),
+                // Only happens in strict mode, there's no point in reading the rest of the trace
+                Err(err) => {
+                    error = Some(err);
+                    ControlFlow::Break(())
+                }
+            }
+        })?;
+        error.map_or(Ok(()), Err)
+    }
+
+    /// Dispatches `s` to the handler registered for its name. If the handler fails, the event is
+    /// counted in [TraceConverter::skipped_events], or the error is returned in strict mode.
+    pub fn handle_event(&mut self, s: &TypedEvent) -> Result<(), ConvertError> {
+        self.last_event_timestamp = self.last_event_timestamp.max(s.record().timestamp as u64);
+        let result = match self.handlers.get_mut(s.name()).map(Option::take) {
+            Some(Some(mut handler)) => {
+                let result = handler.handle_event(self, s);
+                // Put the handler back unless it replaced itself
+                if let Some(slot @ None) = self.handlers.get_mut(s.name()) {
+                    *slot = Some(handler);
+                }
+                result
+            }
+            // The handler for this event is already running
+            Some(None) => Ok(()),
+            None => self.handle_other(s),
+        };
+        let Err(error) = result else { return Ok(()) };
+        let error = ConvertError::Event { event: s.name().to_owned(), error: Box::new(error) };
+        if self.options.strict {
+            return Err(error);
+        }
+        self.skipped_events.add(&error);
+        Ok(())
+    }
+
+    /// The events that were skipped because their handler failed
+    pub fn skipped_events(&self) -> &SkippedEvents {
+        &self.skipped_events
+    }
+
+    /// The ready latencies of the threads that were readied and switched in afterwards, with a
+    /// description of each thread like `firefox.exe (100) Main Thread (101)`
+    pub fn ready_latencies(&self) -> impl Iterator<Item = (String, &LatencyHistogram)> + '_ {
+        self.ready_latencies.iter().map(|((process_id, thread_id), histogram)| {
+            let process_name = self.processes.get(process_id).map_or("unknown", |process| process.name.as_str());
+            let thread_name = self.threads.get(thread_id).and_then(|thread| thread.merge_name.as_deref()).unwrap_or("thread");
+            (format!("{} ({}) {} ({})", process_name, process_id, thread_name, thread_id), histogram)
+        })
+    }
+
+    /// Pushes the queued samples into the profile and returns it
+    pub fn finish(mut self, marker_spans: &[MarkerSpan], sample_ranges: Option<&RangeSet<Timestamp>>) -> Profile {
+        // Push queued samples into the profile.
+        // We queue them so that we can get symbolicated JIT function names. To get symbolicated JIT function names,
+        // we have to call profile.add_sample after we call profile.set_lib_symbol_table, and we don't have the
+        // complete JIT symbol table before we've seen all JIT symbols.
+        // (This is a rather weak justification. The better justification is that this is consistent with what
+        // samply does on Linux and macOS, where the queued samples also want to respect JIT function names from
+        // a /tmp/perf-1234.map file, and this file may not exist until the profiled process finishes.)
+        if let Some(cpu_tracks) = self.cpu_tracks.take() {
+            cpu_tracks.finish(&mut self.profile, self.timestamp_converter.raw_to_nanos(self.last_event_timestamp));
+        }
+
+        let mut stack_frame_scratch_buf = Vec::new();
+        let mut process_sample_datas = Vec::new();
+        for (process_id, process) in std::mem::take(&mut self.processes) {
+            let ProcessState { unresolved_samples, regular_lib_mapping_ops, main_thread_handle, .. } = process;
+            let jitdump_lib_mapping_op_queues = match self.jscript_symbols.remove(&process_id) {
+                Some(jit_info) => {
+                    self.profile.set_lib_symbol_table(jit_info.lib_handle, Arc::new(SymbolTable::new(jit_info.symbols)));
+                    vec![jit_info.jit_mapping_ops]
+                },
+                None => Vec::new(),
+            };
+            process_sample_datas.push(ProcessSampleData::new(unresolved_samples, regular_lib_mapping_ops, jitdump_lib_mapping_op_queues, None, main_thread_handle));
+        }
+
+        let mut inline_infos = FastHashMap::default();
+        for (lib_handle, PdbSymbols { symbol_table, inline_info }) in self.symbolicate_libs(&process_sample_datas, &mut stack_frame_scratch_buf, sample_ranges) {
+            self.profile.set_lib_symbol_table(lib_handle, Arc::new(symbol_table));
+            if !inline_info.is_empty() {
+                inline_infos.insert(lib_handle, inline_info);
+            }
+        }
+
+        let mut off_cpu_labels = OffCpuLabels::new(self.waiting_category, self.ready_category, self.profile.intern_string(READY_FRAME_NAME));
+        for process_sample_data in process_sample_datas {
+            process_sample_data.flush_samples_to_profile(&mut self.profile, self.user_category, self.kernel_category, &mut stack_frame_scratch_buf, &mut self.unresolved_stacks, &[], marker_spans, sample_ranges, &inline_infos, &mut off_cpu_labels)
+        }
+
+        /*if merge_threads {
+            profile.add_thread(global_thread);
+        } else {
+            for (_, thread) in threads.drain() { profile.add_thread(thread.builder); }
+        }*/
+
+        self.profile
+    }
+
+    /// Reads the symbols of the libraries that the samples in `process_sample_datas` hit from the
+    /// PDBs in the symbol directories and symbol path of the options
+    fn symbolicate_libs<'a>(&self, process_sample_datas: impl IntoIterator<Item = &'a ProcessSampleData>, stack_frame_scratch_buf: &mut Vec<StackFrame>, sample_ranges: Option<&RangeSet<Timestamp>>) -> FastHashMap<LibraryHandle, PdbSymbols> {
+        let mut symbol_store = SymbolStore::default();
+        for dir in &self.options.symbol_dirs {
+            symbol_store.add_dir(dir.clone());
+        }
+        if let Some(symbol_path) = &self.options.symbol_path {
+            symbol_store.add_symbol_path(symbol_path);
+        }
+        let mut lib_symbols = FastHashMap::default();
+        if symbol_store.is_empty() {
+            return lib_symbols;
+        }
+
+        let mut hit_addresses = FastHashMap::default();
+        for process_sample_data in process_sample_datas {
+            process_sample_data.add_hit_addresses(&mut hit_addresses, stack_frame_scratch_buf, &self.unresolved_stacks, &self.kernel_lib_mappings, sample_ranges);
+        }
+        for (lib_handle, mut addresses) in hit_addresses {
+            let Some((info, _)) = self.lib_infos.get(&lib_handle) else { continue };
+            let pdb_path = match symbolication::find_pdb(&symbol_store, info) {
+                Ok(Some(pdb_path)) => pdb_path,
+                Ok(None) => continue,
+                Err(err) => {
+                    eprintln!("WARNING: couldn't get the PDB for {}: {:?}", info.name, err);
+                    continue;
+                }
+            };
+            addresses.sort_unstable();
+            addresses.dedup();
+            match symbolication::load_pdb_symbols(&pdb_path, info.debug_id, &addresses) {
+                Ok(symbols) => {
+                    lib_symbols.insert(lib_handle, symbols);
+                }
+                Err(err) => eprintln!("WARNING: couldn't read symbols for {} from {}: {:?}", info.name, pdb_path.display(), err),
+            }
+        }
+        lib_symbols
+    }
+
+    /// Like [TraceConverter::finish] but builds a pprof profile. Samples are labelled with their
+    /// process id and thread name; markers aren't included.
+    pub fn finish_pprof(self, sample_ranges: Option<&RangeSet<Timestamp>>) -> PprofBuilder {
+        let raw_to_ns_factor = self.timestamp_converter.raw_to_ns_factor;
+        let mut pprof = PprofBuilder::new(self.context_switch_handler.interval() * raw_to_ns_factor);
+        pprof.set_duration_nanos(self.last_event_timestamp.saturating_sub(self.timestamp_converter.reference_raw) * raw_to_ns_factor);
+        self.export_samples(sample_ranges, |process_sample_data, context, stack_frame_scratch_buf, stacks| {
+            process_sample_data.flush_samples_to_pprof(&mut pprof, context, stack_frame_scratch_buf, stacks, sample_ranges);
+        });
+        pprof
+    }
+
+    /// Like [TraceConverter::finish] but aggregates the samples of each thread into collapsed stacks
+    pub fn finish_collapsed(self, weight: CollapsedWeight, sample_ranges: Option<&RangeSet<Timestamp>>) -> CollapsedStacks {
+        let mut collapsed = CollapsedStacks::new(weight);
+        self.export_samples(sample_ranges, |process_sample_data, context, stack_frame_scratch_buf, stacks| {
+            process_sample_data.flush_samples_to_collapsed(&mut collapsed, context, stack_frame_scratch_buf, stacks, sample_ranges);
+        });
+        collapsed
+    }
+
+    /// Calls `f` with the samples of each process and what's needed to describe them without
+    /// the Firefox profile. Native libraries are symbolicated like in [TraceConverter::finish].
+    fn export_samples(mut self, sample_ranges: Option<&RangeSet<Timestamp>>, mut f: impl FnMut(ProcessSampleData, &ExportContext, &mut Vec<StackFrame>, &UnresolvedStacks)) {
+        let thread_names: FastHashMap<_, _> = self.threads.values()
+            .filter(|thread| Some(thread.handle) != self.global_thread)
+            .map(|thread| {
+                let name = thread.merge_name.as_deref().unwrap_or("thread");
+                (thread.handle, format!("{} ({})", name, thread.thread_id))
+            })
+            .collect();
+
+        let mut stack_frame_scratch_buf = Vec::new();
+        let mut processes = Vec::new();
+        for (process_id, process) in std::mem::take(&mut self.processes) {
+            let ProcessState { name, unresolved_samples, regular_lib_mapping_ops, main_thread_handle, .. } = process;
+            let (jitdump_lib_mapping_op_queues, jit_symbols) = match self.jscript_symbols.remove(&process_id) {
+                Some(ProcessJitInfo { lib_handle, jit_mapping_ops, symbols, .. }) => (vec![jit_mapping_ops], Some((lib_handle, symbols))),
+                None => (Vec::new(), None),
+            };
+            let process_sample_data = ProcessSampleData::new(unresolved_samples, regular_lib_mapping_ops, jitdump_lib_mapping_op_queues, None, main_thread_handle);
+            processes.push((process_id, name, jit_symbols, process_sample_data));
+        }
+        let process_sample_datas = processes.iter().map(|(.., process_sample_data)| process_sample_data);
+        let lib_symbols = self.symbolicate_libs(process_sample_datas, &mut stack_frame_scratch_buf, sample_ranges);
+
+        for (process_id, name, jit_symbols, process_sample_data) in processes {
+            let context = ExportContext {
+                process_id,
+                process_name: &name,
+                thread_names: &thread_names,
+                lib_infos: &self.lib_infos,
+                kernel_lib_mappings: &self.kernel_lib_mappings,
+                jit_symbols: jit_symbols.as_ref().map(|(lib_handle, symbols)| (*lib_handle, &symbols[..])),
+                lib_symbols: &lib_symbols,
+            };
+            f(process_sample_data, &context, &mut stack_frame_scratch_buf, &self.unresolved_stacks);
+        }
+    }
+
+    /// Returns the markers and the running and sleeping times of the threads in the Chrome trace
+    /// event format, or `None` if [ConvertOptions::chrome_trace] wasn't set.
+    pub fn finish_chrome_trace(mut self) -> Option<ChromeTrace> {
+        let mut trace = self.chrome_trace.take()?;
+        for (process_id, process) in &self.processes {
+            trace.set_process_name(*process_id, &process.name);
+        }
+        for thread in self.threads.values() {
+            if let Some(name) = &thread.merge_name {
+                trace.set_thread_name(thread.process_id, thread.thread_id, name);
+            }
+        }
+        Some(trace)
+    }
+
+    /// Adds a marker to the thread `thread_id` and to the Chrome trace if we're making one. Markers
+    /// on threads that we're not tracing are dropped.
+    fn add_marker<T: ProfilerMarker>(&mut self, thread_id: u32, category: CategoryHandle, name: &str, marker: T, timing: TraceTiming) {
+        let Some(thread) = self.threads.get(&thread_id) else { return };
+        if let Some(trace) = &mut self.chrome_trace {
+            trace.add_marker(thread.process_id, thread_id, T::MARKER_TYPE_NAME, name, marker.json_marker_data(), timing);
+        }
+        self.profile.add_marker(thread.handle, category, name, marker, timing.marker_timing());
+    }
+
+    /// Adds a library to the profile and remembers its info for [TraceConverter::export_samples]
+    fn add_lib(&mut self, info: LibraryInfo, image_size: u64) -> LibraryHandle {
+        let lib_handle = self.profile.add_lib(info.clone());
+        self.lib_infos.insert(lib_handle, (info, image_size));
+        lib_handle
+    }
+
+    fn add_kernel_lib_mapping(&mut self, lib_handle: LibraryHandle, start_avma: u64, end_avma: u64) {
+        self.profile.add_kernel_lib_mapping(lib_handle, start_avma, end_avma, 0);
+        self.kernel_lib_mappings.add_mapping(start_avma, end_avma, 0, LibMappingInfo::new_lib(lib_handle));
+    }
+
+    fn add_process_lib_mapping(&mut self, process_id: u32, timestamp: u64, lib_handle: LibraryHandle, start_avma: u64, end_avma: u64) -> Result<(), ConvertError> {
+        let is_ntdll = self.lib_infos.get(&lib_handle).is_some_and(|(info, _)| info.name.eq_ignore_ascii_case("ntdll.dll"));
+        if is_ntdll && !self.ntdll_ranges.contains(&(start_avma..end_avma)) {
+            self.ntdll_ranges.push(start_avma..end_avma);
+        }
+        let process = self.processes.get_mut(&process_id).ok_or(ConvertError::MissingProcess(process_id))?;
+        process.regular_lib_mapping_ops.push(timestamp, LibMappingOp::Add(LibMappingAdd {
+            start_avma,
+            end_avma,
+            relative_address_at_start: 0,
+            info: LibMappingInfo::new_lib(lib_handle),
+        }));
+        Ok(())
+    }
+
+    fn handle_header(&mut self, s: &TypedEvent) -> Result<(), ConvertError> {
+        let e = s.record();
+        let mut parser = Parser::create(&s);
+        self.timer_resolution = parser.property("TimerResolution")?;
+        let perf_freq: u64 = parser.property("PerfFreq")?;
+        let clock_type: u32 = parser.property("ReservedFlags")?;
+        if clock_type != 1 {
+            println!("WARNING: QPC not used as clock");
+        }
+        self.clock_type = clock_type;
+        let events_lost: u32 = parser.property("EventsLost")?;
+        if events_lost != 0 {
+            println!("WARNING: {} events lost", events_lost);
+        }
+
+        self.timestamp_converter = TimestampConverter {
+            reference_raw: e.timestamp as u64,
+            raw_to_ns_factor: 1000 * 1000 * 1000 / perf_freq,
+        };
+
+        for i in 0..s.property_count() {
+            let property = s.property(i);
+            print_property(&mut parser, &property, false);
+        }
+
+        if let Some(rundown) = self.rundown.take() {
+            // Seed everything from the DCEnd rundown as if it had been there since the start of the trace
+            let timestamp = self.timestamp_converter.convert_raw(e.timestamp as u64);
+            for process in rundown.processes {
+                let is_target = match &self.process_target_name {
+                    _ if self.options.all_processes => true,
+                    Some(process_target_name) => process.image_file_name.contains(process_target_name),
+                    None => self.process_targets.contains(&process.process_id),
+                };
+                if !is_target || self.processes.contains_key(&process.process_id) {
+                    continue;
+                }
+                println!("tracing {} from rundown", process.process_id);
+                self.process_targets.insert(process.process_id);
+                let name = process_name(process.process_id, process.image_file_name);
+                let process_handle = match self.global_process {
+                    Some(global_process) => global_process,
+                    None => self.profile.add_process(&name, process.process_id, timestamp),
+                };
+                self.processes.insert(process.process_id, ProcessState::new(process_handle, name));
+            }
+            for thread in rundown.threads {
+                if !self.processes.contains_key(&thread.process_id) || self.threads.contains_key(&thread.thread_id) {
+                    continue;
+                }
+                let handle = match self.global_thread {
+                    Some(global_thread) => global_thread,
+                    None => {
+                        let process = self.processes.get_mut(&thread.process_id).unwrap();
+                        let is_main = process.main_thread_handle.is_none();
+                        let thread_handle = self.profile.add_thread(process.process_handle, thread.thread_id, timestamp, is_main);
+                        if is_main {
+                            process.main_thread_handle = Some(thread_handle);
+                        }
+                        thread_handle
+                    }
+                };
+                let thread_state = self.threads.entry(thread.thread_id).or_insert(ThreadState::new(handle, thread.process_id, thread.thread_id));
+                if let Some(name) = thread.name {
+                    if Some(handle) != self.global_thread {
+                        self.profile.set_thread_name(handle, &name);
+                    }
+                    thread_state.merge_name = Some(name);
+                }
+            }
+            for image in rundown.images {
+                if !self.processes.contains_key(&image.process_id) && image.process_id != 0 {
+                    continue;
+                }
+                self.rundown_images.insert((image.process_id, image.image_base));
+                let lib_handle = self.add_lib(image.info, image.image_size);
+                if image.process_id == 0 {
+                    self.add_kernel_lib_mapping(lib_handle, image.image_base, image.image_base + image.image_size);
+                } else {
+                    self.add_process_lib_mapping(image.process_id, e.timestamp as u64, lib_handle, image.image_base, image.image_base + image.image_size)?;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    fn handle_collection_start(&mut self, s: &TypedEvent) -> Result<(), ConvertError> {
+        let mut parser = Parser::create(&s);
+        let interval_raw: u32 = parser.property("NewInterval")?;
+        let interval_nanos = interval_raw as u64 * 100;
+        let interval = SamplingInterval::from_nanos(interval_nanos);
+        println!("Sample rate {}ms", interval.as_secs_f64() * 1000.);
+        self.profile.set_interval(interval);
+        self.context_switch_handler = ContextSwitchHandler::new(interval_raw as u64);
+        Ok(())
+    }
+
+    fn handle_thread_set_name(&mut self, s: &TypedEvent) -> Result<(), ConvertError> {
+        let mut parser = Parser::create(&s);
+
+        let process_id: u32 = parser.property("ProcessId")?;
+        if !self.process_targets.contains(&process_id) {
+            return Ok(());
+        }
+        let thread_id: u32 = parser.property("ThreadId")?;
+        let thread_name: String = parser.property("ThreadName")?;
+        let thread = match self.threads.entry(thread_id) {
+            Entry::Occupied(e) => e.into_mut(),
+            Entry::Vacant(e) => {
+                let thread_start_instant = self.profile_start_instant;
+                let handle = match self.global_thread {
+                    Some(global_thread) => global_thread,
+                    None => {
+                        let process = self.processes.get(&process_id).ok_or(ConvertError::MissingProcess(process_id))?.process_handle;
+                        self.profile.add_thread(process, thread_id, thread_start_instant, false)
+                    }
+                };
+                let tb = e.insert(
+                    ThreadState::new(handle, process_id, thread_id)
+                );
+                tb
+             }
+        };
+        if Some(thread.handle) != self.global_thread {
+            self.profile.set_thread_name(thread.handle, &thread_name);
+        }
+        thread.merge_name = Some(thread_name);
+        Ok(())
+    }
+
+    fn handle_thread_start(&mut self, s: &TypedEvent) -> Result<(), ConvertError> {
+        let e = s.record();
+        let timestamp = e.timestamp as u64;
+        let timestamp = self.timestamp_converter.convert_raw(timestamp);
+        let mut parser = Parser::create(&s);
+
+        let thread_id: u32 = parser.property("TThreadId")?;
+        let process_id: u32 = parser.property("ProcessId")?;
+        //assert_eq!(process_id,s.process_id());
+        //println!("thread_name pid: {} tid: {} name: {:?}", process_id, thread_id, thread_name);
+        if let Some(cpu_tracks) = &mut self.cpu_tracks {
+            cpu_tracks.thread_started(thread_id, process_id);
+        }
+
+        if !self.process_targets.contains(&process_id) {
+            return Ok(());
+        }
+        if s.name() == "MSNT_SystemTrace/Thread/DCStart" && self.threads.contains_key(&thread_id) {
+            // Already seeded from the DCEnd rundown
+            return Ok(());
+        }
+
+        let thread_start_instant = self.profile_start_instant;
+        let handle = match self.global_thread {
+            Some(global_thread) => global_thread,
+            None => {
+                let process = self.processes.get_mut(&process_id).ok_or(ConvertError::MissingProcess(process_id))?;
+
+                let is_main = process.main_thread_handle.is_none();
+                let thread_handle = self.profile.add_thread(process.process_handle, thread_id, timestamp, is_main);
+                if is_main {
+                    process.main_thread_handle = Some(thread_handle);
+                }
+                thread_handle
+            }
+        };
+        let thread = ThreadState::new(handle, process_id, thread_id);
+
+        let thread = match self.threads.entry(thread_id) {
+            Entry::Occupied(e) => {
+                // Clobber the existing thread. We don't rely on thread end events to remove threads
+                // because they can be dropped and there can be subsequent events that refer to an ended thread.
+                // eg.
+                // MSNT_SystemTrace/Thread/End MSNT_SystemTrace 2-0 14 7369515373
+                //     ProcessId: InTypeUInt32 = 4532
+                //     TThreadId: InTypeUInt32 = 17524
+                // MSNT_SystemTrace/Thread/ReadyThread MSNT_SystemTrace 50-0 5 7369515411
+                //     TThreadId: InTypeUInt32 = 1644
+                // MSNT_SystemTrace/StackWalk/Stack MSNT_SystemTrace 32-0 35 7369515425
+                //     EventTimeStamp: InTypeUInt64 = 7369515411
+                //     StackProcess: InTypeUInt32 = 4532
+                //     StackThread: InTypeUInt32 = 17524
+                // MSNT_SystemTrace/Thread/CSwitch MSNT_SystemTrace 36-0 12 7369515482
+                //     NewThreadId: InTypeUInt32 = 1644
+                //     OldThreadId: InTypeUInt32 = 0
+
+                let existing = e.into_mut();
+                *existing = thread;
+                existing
+            }
+            Entry::Vacant(e) => {
+                e.insert(thread)
+            }
+        };
+
+        let thread_name: Result<String, _> = parser.try_parse("ThreadName");
+
+        match thread_name {
+            Ok(thread_name) if !thread_name.is_empty() => {
+                if Some(thread.handle) != self.global_thread {
+                    self.profile.set_thread_name(thread.handle, &thread_name);
+                }
+                thread.merge_name = Some(thread_name)
+            },
+            _ => {}
+        }
+        Ok(())
+    }
+
+    fn handle_thread_end(&mut self, s: &TypedEvent) -> Result<(), ConvertError> {
+        let e = s.record();
+        let timestamp = e.timestamp as u64;
+        let timestamp = self.timestamp_converter.convert_raw(timestamp);
+        let mut parser = Parser::create(&s);
+
+        let thread_id: u32 = parser.property("TThreadId")?;
+        if let Some(thread) = self.threads.get(&thread_id) {
+            self.profile.set_thread_end_time(thread.handle, timestamp);
+        }
+        Ok(())
+    }
+
+    fn handle_process_start(&mut self, s: &TypedEvent) -> Result<(), ConvertError> {
+        let e = s.record();
+        if let Some(cpu_tracks) = &mut self.cpu_tracks {
+            let mut parser = Parser::create(&s);
+            cpu_tracks.process_started(parser.property("ProcessId")?, parser.property("ImageFileName")?);
+        }
+        let timestamp = e.timestamp as u64;
+        let timestamp = self.timestamp_converter.convert_raw(timestamp);
+        let mut parser = Parser::create(&s);
+
+
+        let image_file_name: String = parser.property("ImageFileName")?;
+        println!("process start {}", image_file_name);
+
+        let process_id: u32 = parser.property("ProcessId")?;
+        if s.name() == "MSNT_SystemTrace/Process/DCStart" && self.processes.contains_key(&process_id) {
+            // Already seeded from the DCEnd rundown
+            return Ok(());
+        }
+        let is_target = match &self.process_target_name {
+            _ if self.options.all_processes => true,
+            Some(process_target_name) => image_file_name.contains(process_target_name),
+            None => self.process_targets.contains(&process_id),
+        };
+        if is_target {
+            self.process_targets.insert(process_id);
+            println!("tracing {}", process_id);
+            let image_file_name = process_name(process_id, image_file_name);
+            let process_handle = match self.global_process {
+                Some(global_process) => global_process,
+                None => self.profile.add_process(&image_file_name, process_id, timestamp),
+            };
+
+            self.processes.insert(process_id, ProcessState::new(process_handle, image_file_name));
+        }
+        Ok(())
+    }
+
+    fn handle_stack(&mut self, s: &TypedEvent) -> Result<(), ConvertError> {
+        let mut parser = Parser::create(&s);
+
+        let thread_id: u32 = parser.property("StackThread")?;
+        let process_id: u32 = parser.property("StackProcess")?;
+
+        let timestamp: u64 = parser.property("EventTimeStamp")?;
+        // The readying thread of a ReadyThread event can be in any process
+        if let Some(readied_thread_id) = self.ready_stacks.get(&(thread_id, timestamp)).copied() {
+            let stack = read_stack(&parser.buffer);
+            let is_user_stack = matches!(stack.first(), Some(StackFrame::InstructionPointer(_, StackMode::User)));
+            if let Some(readied_by) = self.threads.get_mut(&readied_thread_id).and_then(|thread| thread.readied_by.as_mut()) {
+                readied_by.process_id = process_id;
+                readied_by.stack.extend(stack);
+            }
+            // User stacks come last
+            if is_user_stack {
+                self.ready_stacks.remove(&(thread_id, timestamp));
+            }
+        }
+        if !self.process_targets.contains(&process_id) {
+            // eprintln!("not watching");
+            return Ok(());
+        }
+        
+        let thread = match self.threads.entry(thread_id) {
+            Entry::Occupied(e) => e.into_mut(),
+            Entry::Vacant(e) => {
+                let thread_start_instant = self.profile_start_instant;
+                let handle = match self.global_thread {
+                    Some(global_thread) => global_thread,
+                    None => {
+                        let process = self.processes.get(&process_id).ok_or(ConvertError::MissingProcess(process_id))?.process_handle;
+                        self.profile.add_thread(process, thread_id, thread_start_instant, false)
+                    }
+                };
+                let tb = e.insert(
+                    ThreadState::new(handle, process_id, thread_id)
+                );
+                tb
+            }
+        };
+        // eprint!("{} {} {}", thread_id, e.timestamp, timestamp);
+
+        let mut stack = read_stack(&parser.buffer);
+        let Some(&StackFrame::InstructionPointer(_, first_frame_stack_mode)) = stack.first() else { return Ok(()) };
+
+        // Kernel-only threads won't get a user stack, so their kernel stack takes its place
+        if first_frame_stack_mode == StackMode::Kernel && !thread.kernel_only {
+            if let Some(pending_stack ) = thread.pending_stacks.iter_mut().rev().find(|s| s.timestamp == timestamp) {
+                if let Some(kernel_stack) = pending_stack.kernel_stack.as_mut() {
+                    eprintln!("Multiple kernel stacks for timestamp {timestamp} on thread {thread_id}");
+                    kernel_stack.extend(&stack);
+                } else {
+                    pending_stack.kernel_stack = Some(stack);
+                }
+            }
+            return Ok(());
+        }
+
+        // We now know that we have a user stack. User stacks always come last. Consume
+        // the pending stack with matching timestamp.
+
+        // The outermost frame of a complete user stack is in ntdll!RtlUserThreadStart. If we
+        // don't get there, mark the stack so that the missing frames show up in the profile.
+        let truncated = !thread.kernel_only && is_truncated_user_stack(&stack, &self.ntdll_ranges);
+        if truncated {
+            stack.push(StackFrame::TruncatedStackMarker);
+        }
+
+        let mut add_sample = |thread: &ThreadState, process: &mut ProcessState, timestamp: u64, cpu_delta: u64, off_cpu: u64, weight: i32, stack: Vec<StackFrame>| {
+            let profile_timestamp = self.timestamp_converter.convert_raw(timestamp);
+            let stack_index = self.unresolved_stacks.convert(stack.into_iter().rev());
+            let extra_label_frame = if let Some(global_thread) = self.global_thread {
+                let thread_name = thread.merge_name.as_ref().map(|x| strip_thread_numbers(x).to_owned()).unwrap_or_else(|| format!("thread {}", thread.thread_id));
+                Some(FrameInfo {
+                    frame: fxprof_processed_profile::Frame::Label(self.profile.intern_string(&thread_name)),
+                    category_pair: self.user_category,
+                    flags: FrameFlags::empty(),
+                })
+            } else { None };
+            process.unresolved_samples.add_sample(thread.handle, profile_timestamp, timestamp, stack_index, cpu_delta, off_cpu, weight, extra_label_frame);
+        };
+
+        // Use this user stack for all pending stacks from this thread.
+        while thread.pending_stacks.front().is_some_and(|s| s.timestamp <= timestamp) {
+            let PendingStack {
+                timestamp,
+                kernel_stack,
+                off_cpu_sample_group,
+                ready_sample_group,
+                on_cpu_sample_cpu_delta,
+            } = thread.pending_stacks.pop_front().unwrap();
+            let process = self.processes.get_mut(&process_id).ok_or(ConvertError::MissingProcess(process_id))?;
+
+            for off_cpu_sample_group in off_cpu_sample_group.into_iter().chain(ready_sample_group) {
+                let OffCpuSampleGroup { begin_timestamp, end_timestamp, sample_count, reason, ready } = off_cpu_sample_group;
+                // The reason goes below the innermost frame so that waits and preemption are apart. The
+                // ready time after a wait is labelled as such instead of with the reason for the wait.
+                let reason_frame = match reason {
+                    Some(reason) if !ready || reason.is_ready() => Some(StackFrame::OffCpuReason(reason)),
+                    _ if ready => Some(StackFrame::ReadyMarker),
+                    _ => None,
+                };
+                let mut off_cpu_stack = Vec::with_capacity(stack.len() + 1);
+                off_cpu_stack.extend(reason_frame);
+                off_cpu_stack.extend_from_slice(&stack);
+
+                let cpu_delta_raw = self.context_switch_handler.consume_cpu_delta(&mut thread.context_switch_data);
+                let cpu_delta = cpu_delta_raw * self.timestamp_converter.raw_to_ns_factor;
+                let interval = self.context_switch_handler.interval() * self.timestamp_converter.raw_to_ns_factor;
+
+                // Add a sample at the beginning of the paused range.
+                // This "first sample" will carry any leftover accumulated running time ("cpu delta").
+                add_sample(thread, process, begin_timestamp, cpu_delta, interval, 1, off_cpu_stack.clone());
+
+                if sample_count > 1 {
+                    // Emit a "rest sample" with a CPU delta of zero covering the rest of the paused range.
+                    let weight = i32::try_from(sample_count - 1).unwrap_or(0) * 1;
+                    add_sample(thread, process, end_timestamp, 0, (sample_count - 1) * interval, weight, off_cpu_stack);
+                }
+            }
+
+            if let Some(cpu_delta) = on_cpu_sample_cpu_delta {
+                if let Some(mut combined_stack) = kernel_stack {
+                    combined_stack.extend_from_slice(&stack[..]);
+                    add_sample(thread, process, timestamp, cpu_delta, 0, 1, combined_stack);
+                } else {
+                    add_sample(thread, process, timestamp, cpu_delta, 0, 1, stack.clone());
+                }
+                self.stats.stack_samples += 1;
+                if truncated {
+                    self.stats.truncated_stacks += 1;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    fn handle_sample_prof(&mut self, s: &TypedEvent) -> Result<(), ConvertError> {
+        let e = s.record();
+        let mut parser = Parser::create(&s);
+
+        let thread_id: u32 = parser.property("ThreadId")?;
+        //println!("sample {}", thread_id);
+        self.stats.samples += 1;
+
+        let thread = match self.threads.entry(thread_id) {
+            Entry::Occupied(e) => e.into_mut(), 
+            Entry::Vacant(_) => {
+                if self.options.include_idle {
+                    if let Some(global_thread) = self.global_thread {
+                        let mut frames = Vec::new();
+                        let thread_name = match thread_id {
+                            0 => "Idle",
+                            _ => "Other"
+                        };
+                        let timestamp = e.timestamp as u64;
+                        let timestamp = self.timestamp_converter.convert_raw(timestamp);
+
+                        frames.push(FrameInfo {
+                            frame: fxprof_processed_profile::Frame::Label(self.profile.intern_string(&thread_name)),
+                            category_pair: self.user_category,
+                            flags: FrameFlags::empty()
+                        });
+                        self.profile.add_sample(global_thread, timestamp, frames.into_iter(), Duration::ZERO.into(), 1);
+                    }
+                }
+                self.stats.dropped_samples += 1;
+                // We don't know what process this will before so just drop it for now
+                return Ok(());
+            }
+        };
+
+        let timestamp = e.timestamp as u64;
+        let off_cpu_sample_group = self.context_switch_handler.handle_on_cpu_sample(timestamp, &mut thread.context_switch_data);
+        // A sample can be the first we hear of a thread running again if its switch-in was lost,
+        // so it ends the ready time like a switch-in does
+        let ready_sample_group = self.context_switch_handler.consume_ready_time(timestamp, &mut thread.context_switch_data);
+        if let Some(latency) = self.context_switch_handler.take_ready_latency(&mut thread.context_switch_data) {
+            let histogram = self.ready_latencies.entry((thread.process_id, thread.thread_id)).or_default();
+            histogram.add(latency * self.timestamp_converter.raw_to_ns_factor);
+        }
+        let delta = self.context_switch_handler.consume_cpu_delta(&mut thread.context_switch_data);
+        let cpu_delta = delta * self.timestamp_converter.raw_to_ns_factor;
+        thread.pending_stacks.push_back(PendingStack { timestamp, kernel_stack: None, off_cpu_sample_group, ready_sample_group, on_cpu_sample_cpu_delta: Some(cpu_delta) });
+        Ok(())
+    }
+
+    fn handle_demand_zero_fault(&mut self, s: &TypedEvent) -> Result<(), ConvertError> {
+        let e = s.record();
+        if !self.options.demand_zero_faults { return Ok(()) }
+
+        let thread_id: u32 = s.thread_id();
+        //println!("sample {}", thread_id);
+        self.stats.samples += 1;
+
+        let thread = match self.threads.entry(thread_id) {
+            Entry::Occupied(e) => e.into_mut(),
+            Entry::Vacant(_) => {
+                if self.options.include_idle {
+                    if let Some(global_thread) = self.global_thread {
+                        let mut frames = Vec::new();
+                        let thread_name = match thread_id {
+                            0 => "Idle",
+                            _ => "Other"
+                        };
+                        let timestamp = e.timestamp as u64;
+                        let timestamp = self.timestamp_converter.convert_raw(timestamp);
+
+                        frames.push(FrameInfo {
+                            frame: fxprof_processed_profile::Frame::Label(self.profile.intern_string(&thread_name)),
+                            category_pair: self.user_category,
+                            flags: FrameFlags::empty(),
+                        });
+
+                        self.profile.add_sample(global_thread, timestamp, frames.into_iter(), Duration::ZERO.into(), 1);
+                    }
+                }
+                self.stats.dropped_samples += 1;
+                // We don't know what process this will before so just drop it for now
+                return Ok(());
+            }
+        };
+        let timestamp = e.timestamp as u64;
+        thread.pending_stacks.push_back(PendingStack { timestamp, kernel_stack: None, off_cpu_sample_group: None, ready_sample_group: None, on_cpu_sample_cpu_delta: Some(1_000_000) });
+        Ok(())
+    }
+
+    fn handle_virtual_free(&mut self, s: &TypedEvent) -> Result<(), ConvertError> {
+        let e = s.record();
+        if !self.process_targets.contains(&e.process_id) {
+            return Ok(());
+        }
+        let mut parser = Parser::create(&s);
+        let timestamp = e.timestamp as u64;
+        let timestamp = self.timestamp_converter.convert_raw(timestamp);
+        let thread_id = e.thread_id;
+        let counter = match self.memory_usage.entry(e.process_id) {
+            Entry::Occupied(e) => e.into_mut(),
+            Entry::Vacant(entry) => {
+                let process = self.processes.get(&e.process_id).ok_or(ConvertError::MissingProcess(e.process_id))?;
+                entry.insert(MemoryUsage { counter: self.profile.add_counter(process.process_handle, "VirtualAlloc", "Memory", "Amount of VirtualAlloc allocated memory"), value: 0. })
+            }
+        };
+        if !self.threads.contains_key(&thread_id) {
+            self.stats.dropped_samples += 1;
+            // We don't know what process this will before so just drop it for now
+            return Ok(());
+        }
+        let timing = TraceTiming::Instant(self.timestamp_converter.raw_to_nanos(e.timestamp as u64));
+        let mut text = String::new();
+        let region_size: u64 = parser.property("RegionSize")?;
+        counter.value -= region_size as f64;
+
+        //println!("{} VirtualFree({}) = {}", e.process_id, region_size, counter.value);
+        
+        self.profile.add_counter_sample(counter.counter, timestamp, -(region_size as f64), 1);
+        for i in 0..s.property_count() {
+            let property = s.property(i);
+            //dbg!(&property);
+            write_property(&mut text, &mut parser, &property, false);
+            text += ", "
+        }
+
+        self.add_marker(thread_id, CategoryHandle::OTHER, "VirtualFree", TextMarker(text), timing);
+        Ok(())
+    }
+
+    fn handle_virtual_alloc(&mut self, s: &TypedEvent) -> Result<(), ConvertError> {
+        let e = s.record();
+        if !self.process_targets.contains(&e.process_id) {
+            return Ok(());
+        }
+        let mut parser = Parser::create(&s);
+        let timestamp = e.timestamp as u64;
+        let timestamp = self.timestamp_converter.convert_raw(timestamp);
+        let thread_id = e.thread_id;
+        let counter = match self.memory_usage.entry(e.process_id) {
+            Entry::Occupied(e) => e.into_mut(),
+            Entry::Vacant(entry) => {
+                let process = self.processes.get(&e.process_id).ok_or(ConvertError::MissingProcess(e.process_id))?;
+                entry.insert(MemoryUsage { counter: self.profile.add_counter(process.process_handle, "VirtualAlloc", "Memory", "Amount of VirtualAlloc allocated memory"), value: 0. })
+            }
+        };
+        if !self.threads.contains_key(&thread_id) {
+            self.stats.dropped_samples += 1;
+            // We don't know what process this will before so just drop it for now
+            return Ok(());
+        }
+        let timing = TraceTiming::Instant(self.timestamp_converter.raw_to_nanos(e.timestamp as u64));
+        let mut text = String::new();
+        let region_size: u64 = parser.property("RegionSize")?;
+        for i in 0..s.property_count() {
+            let property = s.property(i);
+            //dbg!(&property);
+            write_property(&mut text, &mut parser, &property, false);
+            text += ", "
+        }
+        counter.value += region_size as f64;
+        //println!("{}.{} VirtualAlloc({}) = {}",  e.process_id, thread_id, region_size, counter.value);
+        
+        self.profile.add_counter_sample(counter.counter, timestamp, region_size as f64, 1);
+        self.add_marker(thread_id, CategoryHandle::OTHER, "VirtualAlloc", TextMarker(text), timing);
+        Ok(())
+    }
+
+    fn handle_image_id(&mut self, s: &TypedEvent) -> Result<(), ConvertError> {
+
+        let process_id = s.process_id();
+        if !self.process_targets.contains(&process_id) && process_id != 0 {
+            return Ok(());
+        }
+        let mut parser = Parser::create(&s);
+
+        let image_base: u64 = parser.property("ImageBase")?;
+        let timestamp = parser.property("TimeDateStamp")?;
+        let image_size: u32 = parser.property("ImageSize")?;
+        let binary_path: String = parser.property("OriginalFileName")?;
+        let path = binary_path;
+        self.libs.insert(image_base, (path, image_size, timestamp));
+        Ok(())
+    }
+
+    fn handle_dbg_id(&mut self, s: &TypedEvent) -> Result<(), ConvertError> {
+        let mut parser = Parser::create(&s);
+
+        let process_id = s.process_id();
+        if !self.process_targets.contains(&process_id) && process_id != 0 {
+            return Ok(());
+        }
+        let image_base: u64 = parser.property("ImageBase")?;
+
+        let guid: GUID = parser.property("GuidSig")?;
+        let age: u32 = parser.property("Age")?;
+        let pdb_path: String = parser.property("PdbFileName")?;
+        let (ref path, image_size, timestamp) = *self.libs.get(&image_base).ok_or(ConvertError::UnknownImageBase(image_base))?;
+        let info = library_info(path, image_size, timestamp, guid, age, pdb_path);
+        if process_id == 0 {
+            self.kernel_pending_libraries.insert(image_base, info);
+        } else {
+            let process = self.processes.get_mut(&process_id).ok_or(ConvertError::MissingProcess(process_id))?;
+            process.pending_libraries.insert(image_base, info);
+        }
+
+        Ok(())
+    }
+
+    fn handle_image_load(&mut self, s: &TypedEvent) -> Result<(), ConvertError> {
+        let e = s.record();
+        // KernelTraceControl/ImageID/ and KernelTraceControl/ImageID/DbgID_RSDS are synthesized from MSNT_SystemTrace/Image/Load
+        // but don't contain the full path of the binary. We go through a bit of a dance to store the information from those events
+        // in pending_libraries and deal with it here. We assume that the KernelTraceControl events come before the Image/Load event.
+
+        let mut parser = Parser::create(&s);
+        // the ProcessId field doesn't necessarily match s.process_id();
+        let process_id = parser.property("ProcessId")?;
+        if !self.process_targets.contains(&process_id) && process_id != 0 {
+            return Ok(());
+        }
+        let image_base: u64 = parser.property("ImageBase")?;
+        let image_size: u64 = parser.property("ImageSize")?;
+        if s.name() == "MSNT_SystemTrace/Image/DCStart" && self.rundown_images.contains(&(process_id, image_base)) {
+            // Already seeded from the DCEnd rundown
+            return Ok(());
+        }
+
+        let path: String = parser.property("FileName")?;
+        // The filename is a NT kernel path (https://chrisdenton.github.io/omnipath/NT.html) which isn't direclty usable from user space.
+        // perfview goes through a dance to convert it to a regular user space path
+        // https://github.com/microsoft/perfview/blob/4fb9ec6947cb4e68ac7cb5e80f50ae3757d0ede4/src/TraceEvent/Parsers/KernelTraceEventParser.cs#L3461
+        // We'll just concatenate \\?\GLOBALROOT\
+        let path = format!("\\\\?\\GLOBALROOT{}", path);
+
+        let info = if process_id == 0 {
+            self.kernel_pending_libraries.remove(&image_base)
+        } else {
+            let process = self.processes.get_mut(&process_id).ok_or(ConvertError::MissingProcess(process_id))?;
+            process.pending_libraries.remove(&image_base)
+        };
+        // If the file doesn't exist on disk we won't have KernelTraceControl/ImageID events
+        // This happens for the ghost drivers mentioned here: https://devblogs.microsoft.com/oldnewthing/20160913-00/?p=94305
+        if let Some(mut info) = info {
+            info.path = path;
+            let lib_handle = self.add_lib(info, image_size);
+            if process_id == 0 {
+                self.add_kernel_lib_mapping(lib_handle, image_base, image_base + image_size as u64);
+            } else {
+                self.add_process_lib_mapping(process_id, e.timestamp as u64, lib_handle, image_base, image_base + image_size as u64)?;
+            }
+        }
+        Ok(())
+    }
+
+    fn handle_vsync(&mut self, s: &TypedEvent) -> Result<(), ConvertError> {
+        let e = s.record();
+        let timestamp = self.timestamp_converter.raw_to_nanos(e.timestamp as u64);
+
+        #[derive(Debug, Clone)]
+        pub struct VSyncMarker;
+
+        impl ProfilerMarker for VSyncMarker {
+            const MARKER_TYPE_NAME: &'static str = "Vsync";
+
+            fn json_marker_data(&self) -> Value {
+                json!({
+                    "type": Self::MARKER_TYPE_NAME,
+                    "name": ""
+                })
+            }
+
+            fn schema() -> MarkerSchema {
+                MarkerSchema {
+                    type_name: Self::MARKER_TYPE_NAME,
+                    locations: vec![MarkerLocation::MarkerChart, MarkerLocation::MarkerTable, MarkerLocation::TimelineOverview],
+                    chart_label: Some("{marker.data.name}"),
+                    tooltip_label: None,
+                    table_label: Some("{marker.name} - {marker.data.name}"),
+                    fields: vec![MarkerSchemaField::Dynamic(MarkerDynamicField {
+                        key: "name",
+                        label: "Details",
+                        format: MarkerFieldFormat::String,
+                        searchable: false,
+                    })],
+                }
+            }
+        }
+
+        let gpu_thread = self.gpu_thread.get_or_insert_with(|| {
+            let gpu = self.profile.add_process("GPU", 1, self.profile_start_instant);
+            self.profile.add_thread(gpu, 1, self.profile_start_instant, false)
+        });
+        self.profile.add_marker(*gpu_thread,
+            CategoryHandle::OTHER,
+            "Vsync",
+            VSyncMarker{},
+            TraceTiming::Instant(timestamp).marker_timing()
+        );
+        if let Some(trace) = &mut self.chrome_trace {
+            trace.add_global_instant(VSyncMarker::MARKER_TYPE_NAME, "Vsync", timestamp);
+        }
+        Ok(())
+    }
+
+    fn handle_cswitch(&mut self, s: &TypedEvent) -> Result<(), ConvertError> {
+        let e = s.record();
+        let mut parser = Parser::create(&s);
+        let new_thread: u32 = parser.property("NewThreadId")?;
+        let old_thread: u32 = parser.property("OldThreadId")?;
+        let thread_state: Result<i8, _> = parser.try_parse("OldThreadState");
+        let wait_reason: Result<i8, _> = parser.try_parse("OldThreadWaitReason");
+        let reason = match (thread_state, wait_reason) {
+            // A thread can't be switched out while it's still Initialized, so state 0 means that
+            // the event didn't record why
+            (Ok(thread_state), Ok(wait_reason)) if thread_state != 0 => Some(OffCpuReason { thread_state: thread_state as u8, wait_reason: wait_reason as u8 }),
+            _ => None,
+        };
+        let timestamp = e.timestamp as u64;
+        // println!("CSwitch {} -> {} @ {} on {}", old_thread, new_thread, e.timestamp, e.processor_index);
+        if let Some(cpu_tracks) = &mut self.cpu_tracks {
+            cpu_tracks.switch(&mut self.profile, e.processor_index, new_thread, self.timestamp_converter.raw_to_nanos(timestamp));
+        }
+        // The idle threads of all processors share a thread id, so we can't tell their switches apart
+        let is_tracked = |thread: &&mut ThreadState| thread.process_id != IDLE_PROCESS_ID;
+        if let Some(old_thread) = self.threads.get_mut(&old_thread).filter(is_tracked) {
+            self.context_switch_handler.handle_switch_out(timestamp, reason, &mut old_thread.context_switch_data);
+            if let Some(trace) = &mut self.chrome_trace {
+                trace.switch_out(old_thread.process_id, old_thread.thread_id, self.timestamp_converter.raw_to_nanos(timestamp));
+            }
+        };
+        if let Some(new_thread) = self.threads.get_mut(&new_thread).filter(is_tracked) {
+            if let Some(trace) = &mut self.chrome_trace {
+                trace.switch_in(new_thread.process_id, new_thread.thread_id, self.timestamp_converter.raw_to_nanos(timestamp));
+            }
+            let readied_by = new_thread.readied_by.take();
+            if let Some(readied_by) = &readied_by {
+                self.ready_stacks.remove(&(readied_by.thread_id, readied_by.timestamp));
+            }
+            let off_cpu_sample_group = self.context_switch_handler.handle_switch_in(timestamp, &mut new_thread.context_switch_data);
+            let ready_sample_group = self.context_switch_handler.consume_ready_time(timestamp, &mut new_thread.context_switch_data);
+            if let Some(latency) = self.context_switch_handler.take_ready_latency(&mut new_thread.context_switch_data) {
+                let histogram = self.ready_latencies.entry((new_thread.process_id, new_thread.thread_id)).or_default();
+                histogram.add(latency * self.timestamp_converter.raw_to_ns_factor);
+            }
+            if off_cpu_sample_group.is_some() || ready_sample_group.is_some() {
+                new_thread.pending_stacks.push_back(PendingStack { timestamp, kernel_stack: None, off_cpu_sample_group, ready_sample_group, on_cpu_sample_cpu_delta: None });
+                // Waits that end up in the profile get a marker with the stack that ended them
+                if let Some(ReadyingThread { timestamp: ready_timestamp, thread_id, process_id, mut stack }) = readied_by {
+                    let is_user_frame = |frame: &StackFrame| matches!(frame, StackFrame::InstructionPointer(_, StackMode::User) | StackFrame::ReturnAddress(_, StackMode::User));
+                    // User frames from other processes can't be resolved with this process' libraries
+                    if process_id != new_thread.process_id && stack.iter().any(is_user_frame) {
+                        stack.retain(|frame| !is_user_frame(frame));
+                        stack.push(StackFrame::TruncatedStackMarker);
+                    }
+                    let process_name = self.processes.get(&process_id).map(|process| process.name.clone());
+                    if let Some(process) = self.processes.get_mut(&new_thread.process_id) {
+                        let stack_index = self.unresolved_stacks.convert(stack.into_iter().rev());
+                        let marker = UnblockedMarker { thread_id, process_id, process_name };
+                        process.unresolved_samples.add_unblocked_marker(new_thread.handle, self.timestamp_converter.convert_raw(ready_timestamp), ready_timestamp, stack_index, marker);
+                    }
+                }
+            }
+        };
+
+        Ok(())
+    }
+
+    fn handle_ready_thread(&mut self, s: &TypedEvent) -> Result<(), ConvertError> {
+        // These events are logged by the readying thread and their stack is the unblocking stack
+        let mut parser = Parser::create(&s);
+        let thread_id: u32 = parser.property("TThreadId")?;
+        let Some(thread) = self.threads.get_mut(&thread_id) else { return Ok(()) };
+        let timestamp = s.record().timestamp as u64;
+        self.context_switch_handler.handle_ready(timestamp, &mut thread.context_switch_data);
+        let readied_by = ReadyingThread { timestamp, thread_id: s.thread_id(), process_id: s.process_id(), stack: Vec::new() };
+        if let Some(previous) = thread.readied_by.replace(readied_by) {
+            self.ready_stacks.remove(&(previous.thread_id, previous.timestamp));
+        }
+        self.ready_stacks.insert((s.thread_id(), timestamp), thread_id);
+        Ok(())
+    }
+
+    fn handle_jit_method_load(&mut self, s: &TypedEvent) -> Result<(), ConvertError> {
+        let e = s.record();
+        let mut parser = Parser::create(&s);
+        let method_name: String = parser.property("MethodName")?;
+        let method_start_address: Address = parser.property("MethodStartAddress")?;
+        let method_size: u64 = parser.property("MethodSize")?;
+        // let source_id: u64 = parser.property("SourceID")?;
+        let process_id = s.process_id();
+        let process = match self.processes.get_mut(&process_id) {
+            Some(process) => process,
+            None => {
+                // This event is probably from a process which doesn't match our name filter.
+                // Ignore it.
+                return Ok(());
+            }
+        };
+        let main_thread_handle = process.main_thread_handle;
+        if !self.jscript_symbols.contains_key(&process_id) {
+            let lib_handle = self.add_lib(LibraryInfo { name: format!("JIT-{process_id}"), debug_name: format!("JIT-{process_id}"), path: format!("JIT-{process_id}"), debug_path: format!("JIT-{process_id}"), debug_id: DebugId::nil(), code_id: None, arch: None, symbol_table: None }, 0);
+            self.jscript_symbols.insert(process_id, ProcessJitInfo { lib_handle, jit_mapping_ops: LibMappingOpQueue::default(), next_relative_address: 0, symbols: Vec::new() });
+        }
+        let process_jit_info = self.jscript_symbols.get_mut(&process_id).unwrap();
+        let start_address = method_start_address.as_u64();
+        let relative_address = process_jit_info.next_relative_address;
+        process_jit_info.next_relative_address += method_size as u32;
+
+        let timestamp = e.timestamp as u64;
+        let timestamp = self.timestamp_converter.convert_raw(timestamp);
+
+        if let Some(main_thread) = main_thread_handle {
+            self.profile.add_marker(
+                main_thread,
+                CategoryHandle::OTHER,
+                "JitFunctionAdd",
+                JitFunctionAddMarker(method_name.to_owned()),
+                MarkerTiming::Instant(timestamp),
+            );
+        }
+        
+        let (category, js_frame) = self.jit_category_manager.classify_jit_symbol(&method_name, &mut self.profile);
+        let info = LibMappingInfo::new_jit_function(process_jit_info.lib_handle, category, js_frame);
+        process_jit_info.jit_mapping_ops.push(e.timestamp as u64, LibMappingOp::Add(LibMappingAdd {
+            start_avma: start_address,
+            end_avma: start_address + method_size,
+            relative_address_at_start: relative_address,
+            info
+        }));
+        process_jit_info.symbols.push(Symbol {
+            address: relative_address,
+            size: Some(method_size as u32),
+            name: method_name,
+        });
+        Ok(())
+    }
+
+    fn handle_js_source_load(&mut self, s: &TypedEvent) -> Result<(), ConvertError> {
+        let mut parser = Parser::create(&s);
+        let source_id: u64 = parser.property("SourceID")?;
+        let url: String = parser.property("Url")?;
+        //if s.process_id() == 6736 { dbg!(s.process_id(), &method_name, method_start_address, method_size); }
+        self.jscript_sources.insert(source_id, url);
+        //dbg!(s.process_id(), jscript_symbols.keys());
+
+        Ok(())
+    }
+
+    fn handle_d3d11_decode_start(&mut self, s: &TypedEvent) -> Result<(), ConvertError> {
+        let e = s.record();
+        let mut parser = Parser::create(&s);
+
+        let timestamp = self.timestamp_converter.raw_to_nanos(e.timestamp as u64);
+        let thread_id = e.thread_id;
+        let thread = match self.threads.entry(thread_id) {
+            Entry::Occupied(e) => e.into_mut(), 
+            Entry::Vacant(_) => {
+                self.stats.dropped_samples += 1;
+                // We don't know what process this will before so just drop it for now
+                return Ok(());
+            }
+        };
+        let mut text = String::new();
+        for i in 0..s.property_count() {
+            let property = s.property(i);
+            //dbg!(&property);
+            write_property(&mut text, &mut parser, &property, false);
+            text += ", "
+        }
+        thread.pending_markers.insert(s.name().to_owned(), PendingMarker { text, start: timestamp });
+        Ok(())
+    }
+
+    fn handle_d3d11_decode_stop(&mut self, s: &TypedEvent) -> Result<(), ConvertError> {
+        let e = s.record();
+        let mut parser = Parser::create(&s);
+
+        let timestamp = self.timestamp_converter.raw_to_nanos(e.timestamp as u64);
+        let thread_id = e.thread_id;
+        let thread = match self.threads.entry(thread_id) {
+            Entry::Occupied(e) => e.into_mut(), 
+            Entry::Vacant(_) => {
+                self.stats.dropped_samples += 1;
+                // We don't know what process this will before so just drop it for now
+                return Ok(());
+            }
+        };
+        
+        let mut text = String::new();
+        let timing = if let Some(pending) = thread.pending_markers.remove("Microsoft-Windows-Direct3D11/ID3D11VideoContext_SubmitDecoderBuffers/win:Start") {
+            text = pending.text;
+            TraceTiming::Interval(pending.start, timestamp)
+        } else {
+            TraceTiming::IntervalEnd(timestamp)
+        };
+
+        for i in 0..s.property_count() {
+            let property = s.property(i);
+            //dbg!(&property);
+            write_property(&mut text, &mut parser, &property, false);
+            text += ", "
+        }
+
+        let category = match self.categories.entry(s.provider_name()) {
+            Entry::Occupied(e) => *e.get(),
+            Entry::Vacant(e) => {
+                let category = self.profile.add_category(e.key(), CategoryColor::Transparent);
+                *e.insert(category)
+            }
+        };
+
+        self.add_marker(thread_id, category, strip_provider_name(s.name()), TextMarker(text), timing);
+        Ok(())
+    }
+
+    fn handle_other(&mut self, s: &TypedEvent) -> Result<(), ConvertError> {
+        let e = s.record();
+        if let Some(marker_name) = s.name().strip_prefix("Mozilla.FirefoxTraceLogger/").and_then(|s| s.strip_suffix("/Info")) {
+            let thread_id = e.thread_id;
+            if !self.threads.contains_key(&thread_id) {
+                self.stats.dropped_samples += 1;
+                // We don't know what process this will before so just drop it for now
+                return Ok(());
+            }
+            let mut parser = Parser::create(&s);
+            let mut text = String::new();
+            for i in 0..s.property_count() {
+                let property = s.property(i);
+                match property.name.as_str() {
+                    "MarkerName" | "StartTime" | "EndTime" | "Phase" | "InnerWindowId" | "CategoryPair" => { continue; }
+                    _ => {}
+                }
+                write_property(&mut text, &mut parser, &property, false);
+                text += ", "
+            }
+
+            /// From https://searchfox.org/mozilla-central/rev/0e7394a77cdbe1df5e04a1d4171d6da67b57fa17/mozglue/baseprofiler/public/BaseProfilerMarkersPrerequisites.h#355-360
+            const PHASE_INSTANT: u8 = 0;
+            const PHASE_INTERVAL: u8 = 1;
+            const PHASE_INTERVAL_START: u8 = 2;
+            const PHASE_INTERVAL_END: u8 = 3;
+
+            // We ignore e.timestamp and instead take the timestamp from the fields.
+            let start_time_qpc: u64 = parser.property("StartTime")?;
+            let end_time_qpc: u64 = parser.property("EndTime")?;
+            // ETW traces with Firefox events should be captured with QPC timestamps (-ClockType
+            // PerfCounter) so that ETW sample timestamps are compatible with the QPC timestamps in
+            // Firefox ETW trace events. Otherwise the markers would end up in the wrong place.
+            if self.clock_type != 1 {
+                return Err(ConvertError::UnexpectedValue { property: "ReservedFlags", value: self.clock_type.to_string() });
+            }
+            let (phase, instant_time_qpc): (u8, u64) = match parser.try_parse("Phase") {
+                Ok(phase) => (phase, start_time_qpc),
+                Err(_) => {
+                    // Before the landing of https://bugzilla.mozilla.org/show_bug.cgi?id=1882640 ,
+                    // Firefox ETW trace events didn't have phase information, so we need to
+                    // guess a phase based on the timestamps.
+                    if start_time_qpc != 0 && end_time_qpc != 0 {
+                        (PHASE_INTERVAL, 0)
+                    } else if start_time_qpc != 0 {
+                        (PHASE_INSTANT, start_time_qpc)
+                    } else {
+                        (PHASE_INSTANT, end_time_qpc)
+                    }
+                }
+            };
+            let timing = match phase {
+                PHASE_INSTANT => TraceTiming::Instant(self.timestamp_converter.raw_to_nanos(instant_time_qpc)),
+                PHASE_INTERVAL => TraceTiming::Interval(self.timestamp_converter.raw_to_nanos(start_time_qpc), self.timestamp_converter.raw_to_nanos(end_time_qpc)),
+                PHASE_INTERVAL_START => TraceTiming::IntervalStart(self.timestamp_converter.raw_to_nanos(start_time_qpc)),
+                PHASE_INTERVAL_END => TraceTiming::IntervalEnd(self.timestamp_converter.raw_to_nanos(end_time_qpc)),
+                _ => return Err(ConvertError::UnexpectedValue { property: "Phase", value: phase.to_string() }),
+            };
+
+            if marker_name == "UserTiming" {
+                let name: String = parser.property("name")?;
+                self.add_marker(thread_id, CategoryHandle::OTHER, "UserTiming", UserTimingMarker(name), timing);
+            } else if marker_name == "SimpleMarker" || marker_name == "Text" || marker_name == "tracing" {
+                let marker_name: String = parser.property("MarkerName")?;
+                self.add_marker(thread_id, CategoryHandle::OTHER, &marker_name, TextMarker(text.clone()), timing);
+            } else {
+                self.add_marker(thread_id, CategoryHandle::OTHER, marker_name, TextMarker(text.clone()), timing);
+            }
+        } else if let Some(marker_name) = s.name().strip_prefix("Google.Chrome/").and_then(|s| s.strip_suffix("/Info")) {
+            // a bitfield of keywords
+            bitflags! {
+                #[derive(PartialEq, Eq)]
+                pub struct KeywordNames: u64 {
+                    const benchmark = 0x1;
+                    const blink = 0x2;
+                    const browser = 0x4;
+                    const cc = 0x8;
+                    const evdev = 0x10;
+                    const gpu = 0x20;
+                    const input = 0x40;
+                    const netlog = 0x80;
+                    const sequence_manager = 0x100;
+                    const toplevel = 0x200;
+                    const v8 = 0x400;
+                    const disabled_by_default_cc_debug = 0x800;
+                    const disabled_by_default_cc_debug_picture = 0x1000;
+                    const disabled_by_default_toplevel_flow = 0x2000;
+                    const startup = 0x4000;
+                    const latency = 0x8000;
+                    const blink_user_timing = 0x10000;
+                    const media = 0x20000;
+                    const loading = 0x40000;
+                    const base = 0x80000;
+                    const devtools_timeline = 0x100000;
+                    const unused_bit_21 = 0x200000;
+                    const unused_bit_22 = 0x400000;
+                    const unused_bit_23 = 0x800000;
+                    const unused_bit_24 = 0x1000000;
+                    const unused_bit_25 = 0x2000000;
+                    const unused_bit_26 = 0x4000000;
+                    const unused_bit_27 = 0x8000000;
+                    const unused_bit_28 = 0x10000000;
+                    const unused_bit_29 = 0x20000000;
+                    const unused_bit_30 = 0x40000000;
+                    const unused_bit_31 = 0x80000000;
+                    const unused_bit_32 = 0x100000000;
+                    const unused_bit_33 = 0x200000000;
+                    const unused_bit_34 = 0x400000000;
+                    const unused_bit_35 = 0x800000000;
+                    const unused_bit_36 = 0x1000000000;
+                    const unused_bit_37 = 0x2000000000;
+                    const unused_bit_38 = 0x4000000000;
+                    const unused_bit_39 = 0x8000000000;
+                    const unused_bit_40 = 0x10000000000;
+                    const unused_bit_41 = 0x20000000000;
+                    const navigation = 0x40000000000;
+                    const ServiceWorker = 0x80000000000;
+                    const edge_webview = 0x100000000000;
+                    const diagnostic_event = 0x200000000000;
+                    const __OTHER_EVENTS = 0x400000000000;
+                    const __DISABLED_OTHER_EVENTS = 0x800000000000;
+                }
+            }
+
+            let mut parser = Parser::create(&s);
+            let thread_id = e.thread_id;
+            let phase: String = parser.property("Phase")?;
+
+            if !self.threads.contains_key(&thread_id) {
+                self.stats.dropped_samples += 1;
+                // We don't know what process this will before so just drop it for now
+                return Ok(());
+            }
+            let mut text = String::new();
+            for i in 0..s.property_count() {
+                let property = s.property(i);
+                if property.name == "Timestamp" || property.name == "Phase" || property.name == "Duration" {
+                    continue;
+                }
+                //dbg!(&property);
+                write_property(&mut text, &mut parser, &property, false);
+                text += ", "
+            }
+
+            // We ignore e.timestamp and instead take the timestamp from the fields.
+            let timestamp_us: u64 = parser.property("Timestamp")?;
+            let timestamp = self.timestamp_converter.us_to_nanos(timestamp_us);
+
+            let timing = match phase.as_str() {
+                "Begin" => TraceTiming::IntervalStart(timestamp),
+                "End" => TraceTiming::IntervalEnd(timestamp),
+                _ => TraceTiming::Instant(timestamp),
+            };
+            let keyword = KeywordNames::from_bits_truncate(e.descriptor.keyword);
+            if keyword == KeywordNames::blink_user_timing {
+                self.add_marker(thread_id, CategoryHandle::OTHER, "UserTiming", UserTimingMarker(marker_name.to_owned()), timing);
+            } else {
+                self.add_marker(thread_id, CategoryHandle::OTHER, marker_name, TextMarker(text.clone()), timing);
+            }
+        } else {
+            let mut parser = Parser::create(&s);
+
+            let timestamp = self.timestamp_converter.raw_to_nanos(e.timestamp as u64);
+            let thread_id = e.thread_id;
+            if !self.threads.contains_key(&thread_id) {
+                self.stats.dropped_samples += 1;
+                // We don't know what process this will before so just drop it for now
+                return Ok(());
+            }
+            let mut text = String::new();
+            for i in 0..s.property_count() {
+                let property = s.property(i);
+                //dbg!(&property);
+                write_property(&mut text, &mut parser, &property, false);
+                text += ", "
+            }
+
+            let timing = TraceTiming::Instant(timestamp);
+            let category = match self.categories.entry(s.provider_name()) {
+                Entry::Occupied(e) => *e.get(),
+                Entry::Vacant(e) => {
+                    let category = self.profile.add_category(e.key(), CategoryColor::Transparent);
+                    *e.insert(category)
+                }
+            };
+
+            self.add_marker(thread_id, category, strip_provider_name(s.name()), TextMarker(text), timing)
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::{borrow::Cow, cell::Cell, rc::Rc};
+
+    use etw_reader::etw_types::{EventDescriptor, EventRecord};
+
+    use super::*;
+
+    fn dbg_id_record(process_id: u32) -> EventRecord<'static> {
+        let mut user_data = Vec::new();
+        user_data.extend_from_slice(&0x7ff0_0000_0000u64.to_le_bytes());
+        user_data.extend_from_slice(&process_id.to_le_bytes());
+        user_data.extend_from_slice(&[0x11; 16]);
+        user_data.extend_from_slice(&3u32.to_le_bytes());
+        user_data.extend_from_slice(b"xul.pdb\0");
+        EventRecord {
+            provider_id: GUID::from("b3e675d7-2554-4f18-830b-2762732560de"),
+            descriptor: EventDescriptor { version: 2, opcode: 36, ..Default::default() },
+            process_id,
+            user_data: Cow::Owned(user_data),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn registered_handler() {
+        let mut converter = TraceConverter::new(ConvertOptions::default());
+        let seen = Rc::new(Cell::new(None));
+        let handler_seen = seen.clone();
+        converter.register_handler("KernelTraceControl/ImageID/DbgID_RSDS", move |converter: &mut TraceConverter, s: &TypedEvent| {
+            let mut parser = Parser::create(s);
+            let pdb_file_name: String = parser.property("PdbFileName")?;
+            handler_seen.set(Some((s.process_id(), pdb_file_name.len())));
+            converter.stats.samples += 1;
+            Ok(())
+        });
+
+        let record = dbg_id_record(1234);
+        let s = converter.schema_locator().event_schema(&record).unwrap();
+        converter.handle_event(&s).unwrap();
+        converter.handle_event(&s).unwrap();
+        assert_eq!(seen.get(), Some((1234, "xul.pdb".len())));
+        assert_eq!(converter.stats().samples, 2);
+    }
+}
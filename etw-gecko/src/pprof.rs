@@ -0,0 +1,444 @@
+//! Writes profiles in pprof's `profile.proto` format
+//!
+//! The format is described in https://github.com/google/pprof/blob/main/proto/profile.proto.
+//! There are only a handful of messages so we encode them by hand instead of generating code.
+//! [ProcessSampleData::flush_samples_to_pprof](crate::process_sample_data::ProcessSampleData::flush_samples_to_pprof)
+//! turns the samples of a process into locations and adds them to a [PprofBuilder].
+use std::io::{self, Write};
+
+use flate2::{write::GzEncoder, Compression};
+use fxprof_processed_profile::{LibraryHandle, LibraryInfo};
+
+use super::types::FastHashMap;
+
+/// The values that are recorded for each sample, in the order of [PprofBuilder::SAMPLE_TYPES]
+#[derive(Debug, Clone, Copy, Default)]
+pub struct SampleValues {
+    pub samples: i64,
+    pub cpu_nanos: i64,
+    pub wait_nanos: i64,
+}
+
+#[derive(Debug, Clone)]
+pub enum LabelValue<'a> {
+    Str(&'a str),
+    Num(i64),
+}
+
+/// Identifies a frame in the profile. Frames with the same key share a Location.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct LocationKey {
+    pub mapping_id: u64,
+    pub address: u64,
+    pub function_name: Option<String>,
+}
+
+pub struct PprofBuilder {
+    strings: Vec<String>,
+    string_indices: FastHashMap<String, i64>,
+    mapping_ids: FastHashMap<(LibraryHandle, u64), u64>,
+    location_ids: FastHashMap<LocationKey, u64>,
+    function_ids: FastHashMap<String, u64>,
+    // The encoded messages
+    mappings: Vec<Vec<u8>>,
+    locations: Vec<Vec<u8>>,
+    functions: Vec<Vec<u8>>,
+    samples: Vec<Vec<u8>>,
+    period_nanos: i64,
+    duration_nanos: i64,
+}
+
+impl PprofBuilder {
+    /// (type, unit) of each value in a sample
+    pub const SAMPLE_TYPES: [(&'static str, &'static str); 3] = [
+        ("samples", "count"),
+        ("cpu", "nanoseconds"),
+        ("wait", "nanoseconds"),
+    ];
+
+    pub fn new(period_nanos: u64) -> Self {
+        let mut builder = PprofBuilder {
+            strings: Vec::new(),
+            string_indices: FastHashMap::default(),
+            mapping_ids: FastHashMap::default(),
+            location_ids: FastHashMap::default(),
+            function_ids: FastHashMap::default(),
+            mappings: Vec::new(),
+            locations: Vec::new(),
+            functions: Vec::new(),
+            samples: Vec::new(),
+            period_nanos: period_nanos as i64,
+            duration_nanos: 0,
+        };
+        // The first entry of the string table has to be the empty string
+        builder.string("");
+        builder
+    }
+
+    pub fn set_duration_nanos(&mut self, duration_nanos: u64) {
+        self.duration_nanos = duration_nanos as i64;
+    }
+
+    fn string(&mut self, s: &str) -> i64 {
+        if let Some(index) = self.string_indices.get(s) {
+            return *index;
+        }
+        let index = self.strings.len() as i64;
+        self.strings.push(s.to_owned());
+        self.string_indices.insert(s.to_owned(), index);
+        index
+    }
+
+    /// Returns the id of the mapping of `lib` at `memory_start`, adding it the first time it's seen
+    pub fn mapping(&mut self, lib: LibraryHandle, info: &LibraryInfo, memory_start: u64, memory_limit: u64, is_jit: bool) -> u64 {
+        if let Some(id) = self.mapping_ids.get(&(lib, memory_start)) {
+            return *id;
+        }
+        let id = self.mappings.len() as u64 + 1;
+        let filename = self.string(&info.path);
+        let build_id = self.string(&info.debug_id.breakpad().to_string());
+        let mut m = ProtoWriter::default();
+        m.uint64(1, id);
+        m.uint64(2, memory_start);
+        m.uint64(3, memory_limit);
+        m.int64(5, filename);
+        m.int64(6, build_id);
+        // JIT mappings come with function names. Everything else needs to be symbolicated by the
+        // consumer using the file name and build id.
+        m.bool(7, is_jit);
+        self.mappings.push(m.0);
+        self.mapping_ids.insert((lib, memory_start), id);
+        id
+    }
+
+    /// Returns the id of the location for `key`, adding it the first time it's seen
+    pub fn location(&mut self, key: LocationKey) -> u64 {
+        if let Some(id) = self.location_ids.get(&key) {
+            return *id;
+        }
+        let id = self.locations.len() as u64 + 1;
+        let mut m = ProtoWriter::default();
+        m.uint64(1, id);
+        m.uint64(2, key.mapping_id);
+        m.uint64(3, key.address);
+        if let Some(name) = &key.function_name {
+            let function_id = self.function(name);
+            let mut line = ProtoWriter::default();
+            line.uint64(1, function_id);
+            m.message(4, &line);
+        }
+        self.locations.push(m.0);
+        self.location_ids.insert(key, id);
+        id
+    }
+
+    fn function(&mut self, name: &str) -> u64 {
+        if let Some(id) = self.function_ids.get(name) {
+            return *id;
+        }
+        let id = self.functions.len() as u64 + 1;
+        let name_index = self.string(name);
+        let mut m = ProtoWriter::default();
+        m.uint64(1, id);
+        m.int64(2, name_index);
+        m.int64(3, name_index);
+        self.functions.push(m.0);
+        self.function_ids.insert(name.to_owned(), id);
+        id
+    }
+
+    /// Adds a sample. `location_ids` start with the innermost frame.
+    pub fn add_sample(&mut self, location_ids: &[u64], values: SampleValues, labels: &[(&str, LabelValue<'_>)]) {
+        let mut m = ProtoWriter::default();
+        m.packed(1, location_ids.iter().copied());
+        m.packed(2, [values.samples, values.cpu_nanos, values.wait_nanos].into_iter().map(|v| v as u64));
+        for (key, value) in labels {
+            let mut label = ProtoWriter::default();
+            label.int64(1, self.string(key));
+            match value {
+                LabelValue::Str(s) => label.int64(2, self.string(s)),
+                LabelValue::Num(n) => label.int64(3, *n),
+            }
+            m.message(3, &label);
+        }
+        self.samples.push(m.0);
+    }
+
+    /// Writes the gzip-compressed profile, which is what pprof expects to find in a .pb.gz file
+    pub fn write<W: Write>(mut self, writer: W) -> io::Result<()> {
+        let mut m = ProtoWriter::default();
+        for (type_, unit) in Self::SAMPLE_TYPES {
+            let value_type = self.value_type(type_, unit);
+            m.message(1, &value_type);
+        }
+        for sample in &self.samples {
+            m.bytes(2, sample);
+        }
+        for mapping in &self.mappings {
+            m.bytes(3, mapping);
+        }
+        for location in &self.locations {
+            m.bytes(4, location);
+        }
+        for function in &self.functions {
+            m.bytes(5, function);
+        }
+        m.int64(10, self.duration_nanos);
+        let period_type = self.value_type("cpu", "nanoseconds");
+        m.message(11, &period_type);
+        m.int64(12, self.period_nanos);
+        m.int64(14, self.string("cpu"));
+        // Strings have to come last because writing the other fields can add to them
+        for s in &self.strings {
+            m.bytes(6, s.as_bytes());
+        }
+
+        let mut encoder = GzEncoder::new(writer, Compression::default());
+        encoder.write_all(&m.0)?;
+        encoder.finish()?;
+        Ok(())
+    }
+
+    fn value_type(&mut self, type_: &str, unit: &str) -> ProtoWriter {
+        let mut m = ProtoWriter::default();
+        m.int64(1, self.string(type_));
+        m.int64(2, self.string(unit));
+        m
+    }
+}
+
+/// Encodes protobuf fields. Zero values are skipped like proto3 does.
+#[derive(Default)]
+struct ProtoWriter(Vec<u8>);
+
+impl ProtoWriter {
+    const VARINT: u64 = 0;
+    const LENGTH_DELIMITED: u64 = 2;
+
+    fn varint(&mut self, mut value: u64) {
+        while value >= 0x80 {
+            self.0.push(value as u8 | 0x80);
+            value >>= 7;
+        }
+        self.0.push(value as u8);
+    }
+
+    fn key(&mut self, field: u32, wire_type: u64) {
+        self.varint((field as u64) << 3 | wire_type);
+    }
+
+    fn uint64(&mut self, field: u32, value: u64) {
+        if value != 0 {
+            self.key(field, Self::VARINT);
+            self.varint(value);
+        }
+    }
+
+    fn int64(&mut self, field: u32, value: i64) {
+        self.uint64(field, value as u64);
+    }
+
+    fn bool(&mut self, field: u32, value: bool) {
+        self.uint64(field, value as u64);
+    }
+
+    fn bytes(&mut self, field: u32, data: &[u8]) {
+        self.key(field, Self::LENGTH_DELIMITED);
+        self.varint(data.len() as u64);
+        self.0.extend_from_slice(data);
+    }
+
+    fn message(&mut self, field: u32, message: &ProtoWriter) {
+        self.bytes(field, &message.0);
+    }
+
+    fn packed(&mut self, field: u32, values: impl Iterator<Item = u64>) {
+        let mut packed = ProtoWriter::default();
+        for value in values {
+            packed.varint(value);
+        }
+        if !packed.0.is_empty() {
+            self.bytes(field, &packed.0);
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use flate2::read::GzDecoder;
+    use fxprof_processed_profile::debugid::DebugId;
+    use fxprof_processed_profile::{Profile, ReferenceTimestamp, SamplingInterval};
+    use std::io::Read;
+
+    /// A field of a decoded message. Varints are the only numbers that we write.
+    #[derive(Debug, Clone, Copy, PartialEq)]
+    enum Field<'a> {
+        Varint(u64),
+        Bytes(&'a [u8]),
+    }
+
+    fn read_varint(data: &mut &[u8]) -> u64 {
+        let mut value = 0;
+        let mut shift = 0;
+        loop {
+            let (&byte, rest) = data.split_first().expect("truncated varint");
+            *data = rest;
+            value |= ((byte & 0x7f) as u64) << shift;
+            if byte & 0x80 == 0 {
+                return value;
+            }
+            shift += 7;
+        }
+    }
+
+    fn decode(mut data: &[u8]) -> Vec<(u32, Field<'_>)> {
+        let mut fields = Vec::new();
+        while !data.is_empty() {
+            let key = read_varint(&mut data);
+            let field = match key & 7 {
+                ProtoWriter::VARINT => Field::Varint(read_varint(&mut data)),
+                ProtoWriter::LENGTH_DELIMITED => {
+                    let len = read_varint(&mut data) as usize;
+                    let (bytes, rest) = data.split_at(len);
+                    data = rest;
+                    Field::Bytes(bytes)
+                }
+                wire_type => panic!("unexpected wire type {}", wire_type),
+            };
+            fields.push(((key >> 3) as u32, field));
+        }
+        fields
+    }
+
+    /// The message fields numbered `field`
+    fn messages<'a>(fields: &[(u32, Field<'a>)], field: u32) -> Vec<Vec<(u32, Field<'a>)>> {
+        fields.iter().filter(|(f, _)| *f == field).map(|(_, value)| match value {
+            Field::Bytes(bytes) => decode(bytes),
+            Field::Varint(_) => panic!("field {} isn't a message", field),
+        }).collect()
+    }
+
+    /// The value of the varint `field`, which is 0 if it's missing
+    fn varint(fields: &[(u32, Field)], field: u32) -> u64 {
+        fields.iter().rev().find_map(|(f, value)| match value {
+            Field::Varint(v) if *f == field => Some(*v),
+            _ => None,
+        }).unwrap_or(0)
+    }
+
+    fn packed(fields: &[(u32, Field)], field: u32) -> Vec<u64> {
+        let mut values = Vec::new();
+        for (_, value) in fields.iter().filter(|(f, _)| *f == field) {
+            let Field::Bytes(mut bytes) = *value else { panic!("field {} isn't packed", field) };
+            while !bytes.is_empty() {
+                values.push(read_varint(&mut bytes));
+            }
+        }
+        values
+    }
+
+    #[test]
+    fn varints() {
+        let mut m = ProtoWriter::default();
+        m.uint64(1, 150);
+        m.uint64(2, 0);
+        m.int64(3, -1);
+        m.packed(4, [1, 300].into_iter());
+        assert_eq!(m.0, [
+            0x08, 0x96, 0x01,
+            0x18, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0x01,
+            0x22, 0x03, 0x01, 0xac, 0x02,
+        ]);
+    }
+
+    #[test]
+    fn profile() {
+        let mut profile = Profile::new("test", ReferenceTimestamp::from_millis_since_unix_epoch(0.), SamplingInterval::from_millis(1));
+        let info = LibraryInfo {
+            name: "xul.dll".to_owned(),
+            debug_name: "xul.pdb".to_owned(),
+            path: "C:\\xul.dll".to_owned(),
+            debug_path: "C:\\xul.pdb".to_owned(),
+            debug_id: DebugId::nil(),
+            code_id: None,
+            arch: None,
+            symbol_table: None,
+        };
+        let lib = profile.add_lib(info.clone());
+        let jit_info = LibraryInfo { name: "JIT".to_owned(), path: "JIT".to_owned(), ..info.clone() };
+        let jit_lib = profile.add_lib(jit_info.clone());
+
+        let mut builder = PprofBuilder::new(1_000_000);
+        builder.set_duration_nanos(5_000_000);
+        assert_eq!(builder.mapping(lib, &info, 0x1000, 0x2000, false), 1);
+        assert_eq!(builder.mapping(jit_lib, &jit_info, 0x8000, 0x9000, true), 2);
+        assert_eq!(builder.mapping(lib, &info, 0x1000, 0x2000, false), 1);
+        let native = LocationKey { mapping_id: 1, address: 0x1010, function_name: None };
+        let js = LocationKey { mapping_id: 2, address: 0x8020, function_name: Some("js::foo".to_owned()) };
+        assert_eq!(builder.location(native.clone()), 1);
+        assert_eq!(builder.location(js), 2);
+        assert_eq!(builder.location(native), 1);
+        builder.add_sample(
+            &[2, 1],
+            SampleValues { samples: 1, cpu_nanos: 1000, wait_nanos: 0 },
+            &[("thread", LabelValue::Str("main")), ("tid", LabelValue::Num(42))],
+        );
+
+        let mut compressed = Vec::new();
+        builder.write(&mut compressed).unwrap();
+        let mut data = Vec::new();
+        GzDecoder::new(&compressed[..]).read_to_end(&mut data).unwrap();
+        let fields = decode(&data);
+
+        let strings: Vec<&str> = fields.iter().filter_map(|(f, value)| match value {
+            Field::Bytes(bytes) if *f == 6 => Some(std::str::from_utf8(bytes).unwrap()),
+            _ => None,
+        }).collect();
+        assert_eq!(strings[0], "");
+        let string = |index: u64| strings[index as usize];
+
+        let sample_types: Vec<(&str, &str)> = messages(&fields, 1).iter()
+            .map(|m| (string(varint(m, 1)), string(varint(m, 2))))
+            .collect();
+        assert_eq!(sample_types, PprofBuilder::SAMPLE_TYPES);
+
+        let samples = messages(&fields, 2);
+        assert_eq!(samples.len(), 1);
+        assert_eq!(packed(&samples[0], 1), [2, 1]);
+        assert_eq!(packed(&samples[0], 2), [1, 1000, 0]);
+        let labels = messages(&samples[0], 3);
+        assert_eq!(string(varint(&labels[0], 1)), "thread");
+        assert_eq!(string(varint(&labels[0], 2)), "main");
+        assert_eq!(string(varint(&labels[1], 1)), "tid");
+        assert_eq!(varint(&labels[1], 3), 42);
+
+        let mappings = messages(&fields, 3);
+        assert_eq!(mappings.len(), 2);
+        assert_eq!([varint(&mappings[0], 1), varint(&mappings[0], 2), varint(&mappings[0], 3)], [1, 0x1000, 0x2000]);
+        assert_eq!(string(varint(&mappings[0], 5)), "C:\\xul.dll");
+        assert_eq!(string(varint(&mappings[0], 6)), DebugId::nil().breakpad().to_string());
+        assert_eq!(varint(&mappings[0], 7), 0);
+        assert_eq!(varint(&mappings[1], 1), 2);
+        assert_eq!(varint(&mappings[1], 7), 1);
+
+        let locations = messages(&fields, 4);
+        assert_eq!(locations.len(), 2);
+        assert_eq!([varint(&locations[0], 1), varint(&locations[0], 2), varint(&locations[0], 3)], [1, 1, 0x1010]);
+        assert!(messages(&locations[0], 4).is_empty());
+        assert_eq!([varint(&locations[1], 1), varint(&locations[1], 2), varint(&locations[1], 3)], [2, 2, 0x8020]);
+        let lines = messages(&locations[1], 4);
+        assert_eq!(varint(&lines[0], 1), 1);
+
+        let functions = messages(&fields, 5);
+        assert_eq!(functions.len(), 1);
+        assert_eq!(varint(&functions[0], 1), 1);
+        assert_eq!(string(varint(&functions[0], 2)), "js::foo");
+
+        assert_eq!(varint(&fields, 10), 5_000_000);
+        let period_type = &messages(&fields, 11)[0];
+        assert_eq!((string(varint(period_type, 1)), string(varint(period_type, 2))), ("cpu", "nanoseconds"));
+        assert_eq!(varint(&fields, 12), 1_000_000);
+        assert_eq!(string(varint(&fields, 14)), "cpu");
+    }
+}
@@ -1,5 +1,7 @@
+use std::collections::HashMap;
+
 use fxprof_processed_profile::{
-    CategoryHandle, CategoryPairHandle, LibMappings, MarkerDynamicField, MarkerFieldFormat, MarkerLocation, MarkerSchema, MarkerSchemaField, MarkerStaticField, MarkerTiming, Profile, ProfilerMarker, ThreadHandle, Timestamp
+    CategoryHandle, CategoryPairHandle, CpuDelta, LibMappings, LibraryHandle, LibraryInfo, MarkerDynamicField, MarkerFieldFormat, MarkerLocation, MarkerSchema, MarkerSchemaField, MarkerStaticField, MarkerTiming, Profile, ProfilerMarker, Symbol, ThreadHandle, Timestamp
 };
 use rangemap::RangeSet;
 use serde_json::json;
@@ -7,15 +9,21 @@ use serde_json::json;
 use super::{
     lib_mappings::{LibMappingInfo, LibMappingOpQueue, LibMappingsHierarchy},
     marker_file::MarkerSpan,
-    stack_converter::StackConverter,
+    collapsed::{CollapsedStacks, CollapsedWeight},
+    pprof::{LabelValue, LocationKey, PprofBuilder, SampleValues},
+    stack_converter::{InlineFrames, OffCpuLabels, StackConverter, READY_FRAME_NAME},
+    symbolication::{InlineInfo, PdbSymbols},
     stack_depth_limiting_frame_iter::StackDepthLimitingFrameIter,
-    types::StackFrame,
+    types::{FastHashMap, StackFrame, StackMode},
     unresolved_samples::{
         OtherEventMarkerData, RssStatMarkerData, SampleData, SampleOrMarker,
         UnresolvedSampleOrMarker, UnresolvedSamples, UnresolvedStacks,
     },
 };
 
+/// The name of the frame at the root of stacks that are missing their outer frames
+const TRUNCATED_FRAME_NAME: &str = "truncated";
+
 #[derive(Debug, Clone)]
 pub enum RssStatMember {
     ResidentFileMappingPages,
@@ -30,7 +38,8 @@ pub struct ProcessSampleData {
     regular_lib_mapping_op_queue: LibMappingOpQueue,
     jitdump_lib_mapping_op_queues: Vec<LibMappingOpQueue>,
     perf_map_mappings: Option<LibMappings<LibMappingInfo>>,
-    main_thread_handle: ThreadHandle,
+    /// Where marker spans go. Processes without a main thread can only be written to pprof.
+    main_thread_handle: Option<ThreadHandle>,
 }
 
 impl ProcessSampleData {
@@ -39,7 +48,7 @@ impl ProcessSampleData {
         regular_lib_mapping_op_queue: LibMappingOpQueue,
         jitdump_lib_mapping_op_queues: Vec<LibMappingOpQueue>,
         perf_map_mappings: Option<LibMappings<LibMappingInfo>>,
-        main_thread_handle: ThreadHandle,
+        main_thread_handle: Option<ThreadHandle>,
     ) -> Self {
         Self {
             unresolved_samples,
@@ -54,6 +63,47 @@ impl ProcessSampleData {
         self.unresolved_samples.is_empty()
     }
 
+    /// Adds the library-relative addresses of the native frames of the samples and markers to
+    /// `hit_addresses`, so that symbolication only has to look at the code that was hit. Return
+    /// addresses are adjusted to point into the call instruction.
+    pub fn add_hit_addresses(
+        &self,
+        hit_addresses: &mut FastHashMap<LibraryHandle, Vec<u32>>,
+        stack_frame_scratch_buf: &mut Vec<StackFrame>,
+        stacks: &UnresolvedStacks,
+        kernel_lib_mappings: &LibMappings<LibMappingInfo>,
+        sample_range_set: Option<&RangeSet<Timestamp>>,
+    ) {
+        let mut lib_mappings_hierarchy = lib_mappings_hierarchy(
+            self.regular_lib_mapping_op_queue.clone(),
+            self.jitdump_lib_mapping_op_queues.clone(),
+            self.perf_map_mappings.clone(),
+        );
+        for sample in self.unresolved_samples.samples_and_markers() {
+            lib_mappings_hierarchy.process_ops(sample.timestamp_mono);
+            if sample_range_set.is_some_and(|ranges| !ranges.contains(&sample.timestamp)) {
+                continue;
+            }
+
+            stack_frame_scratch_buf.clear();
+            stacks.convert_back(sample.stack, stack_frame_scratch_buf);
+            for frame in stack_frame_scratch_buf.iter() {
+                let (lookup_address, mode) = match *frame {
+                    StackFrame::InstructionPointer(addr, mode) => (addr, mode),
+                    StackFrame::ReturnAddress(addr, mode) => (addr.saturating_sub(1), mode),
+                    _ => continue,
+                };
+                let mapping = match mode {
+                    StackMode::User => lib_mappings_hierarchy.convert_address(lookup_address),
+                    StackMode::Kernel => kernel_lib_mappings.convert_address(lookup_address),
+                };
+                if let Some((relative_lookup_address, info)) = mapping {
+                    hit_addresses.entry(info.lib_handle).or_default().push(relative_lookup_address);
+                }
+            }
+        }
+    }
+
     #[allow(clippy::too_many_arguments)]
     pub fn flush_samples_to_profile(
         self,
@@ -65,22 +115,12 @@ impl ProcessSampleData {
         event_names: &[String],
         marker_spans: &[MarkerSpan],
         sample_range_set: Option<&RangeSet<Timestamp>>,
+        inline_infos: &FastHashMap<LibraryHandle, InlineInfo>,
+        off_cpu_labels: &mut OffCpuLabels,
     ) {
-        let ProcessSampleData {
-            unresolved_samples,
-            regular_lib_mapping_op_queue,
-            jitdump_lib_mapping_op_queues,
-            perf_map_mappings,
-            main_thread_handle,
-        } = self;
-        let mut lib_mappings_hierarchy = LibMappingsHierarchy::new(regular_lib_mapping_op_queue);
-        for jitdump_lib_mapping_ops in jitdump_lib_mapping_op_queues {
-            lib_mappings_hierarchy.add_jitdump_lib_mappings_ops(jitdump_lib_mapping_ops);
-        }
-        if let Some(perf_map_mappings) = perf_map_mappings {
-            lib_mappings_hierarchy.add_perf_map_mappings(perf_map_mappings);
-        }
-        let stack_converter = StackConverter::new(user_category, kernel_category);
+        let (unresolved_samples, mut lib_mappings_hierarchy, main_thread_handle) = self.into_parts();
+        let stack_converter = StackConverter::new(user_category, kernel_category, profile.intern_string(TRUNCATED_FRAME_NAME));
+        let mut inline_frames = InlineFrames::default();
         let samples = unresolved_samples.into_inner();
         for sample in samples {
             lib_mappings_hierarchy.process_ops(sample.timestamp_mono);
@@ -101,15 +141,23 @@ impl ProcessSampleData {
 
             stack_frame_scratch_buf.clear();
             stacks.convert_back(stack, stack_frame_scratch_buf);
+            if !inline_infos.is_empty() {
+                add_inline_frames(profile, &mut inline_frames, inline_infos, stack_frame_scratch_buf, &lib_mappings_hierarchy);
+            }
+            if let Some(&StackFrame::OffCpuReason(reason)) = stack_frame_scratch_buf.first() {
+                off_cpu_labels.add_reason(profile, reason);
+            }
             let frames = stack_converter.convert_stack(
                 stack_frame_scratch_buf,
                 &lib_mappings_hierarchy,
+                &inline_frames,
+                off_cpu_labels,
                 extra_label_frame,
             );
             let frames = StackDepthLimitingFrameIter::new(profile, frames, user_category);
             match sample_or_marker {
-                SampleOrMarker::Sample(SampleData { cpu_delta, weight }) => {
-                    profile.add_sample(thread_handle, timestamp, frames, cpu_delta, weight);
+                SampleOrMarker::Sample(SampleData { cpu_delta_nanos, weight, .. }) => {
+                    profile.add_sample(thread_handle, timestamp, frames, CpuDelta::from_nanos(cpu_delta_nanos), weight);
                 }
                 SampleOrMarker::RssStatMarker(RssStatMarkerData {
                     size,
@@ -132,6 +180,17 @@ impl ProcessSampleData {
                         frames,
                     );
                 }
+                SampleOrMarker::UnblockedMarker(marker) => {
+                    let timing = MarkerTiming::Instant(timestamp);
+                    profile.add_marker_with_stack(
+                        thread_handle,
+                        CategoryHandle::OTHER,
+                        "Unblocked",
+                        marker,
+                        timing,
+                        frames,
+                    );
+                }
                 SampleOrMarker::OtherEventMarker(OtherEventMarkerData { attr_index }) => {
                     if let Some(name) = event_names.get(attr_index) {
                         let timing = MarkerTiming::Instant(timestamp);
@@ -148,6 +207,7 @@ impl ProcessSampleData {
             }
         }
 
+        let Some(main_thread_handle) = main_thread_handle else { return };
         for marker in marker_spans {
             profile.add_marker(
                 main_thread_handle,
@@ -158,6 +218,267 @@ impl ProcessSampleData {
             );
         }
     }
+
+    /// Adds the samples to `pprof`, labelled with their process id and thread name. Markers are
+    /// left out because pprof has nothing to put them in.
+    ///
+    /// JIT frames are named from the JIT symbol table and native frames from the PDBs that were
+    /// found. The other native frames are left for the consumer to symbolicate using the mappings'
+    /// file names and build ids.
+    pub fn flush_samples_to_pprof(
+        self,
+        pprof: &mut PprofBuilder,
+        context: &ExportContext,
+        stack_frame_scratch_buf: &mut Vec<StackFrame>,
+        stacks: &UnresolvedStacks,
+        sample_range_set: Option<&RangeSet<Timestamp>>,
+    ) {
+        let (unresolved_samples, mut lib_mappings_hierarchy, _) = self.into_parts();
+        let mut location_ids = Vec::new();
+        for sample in unresolved_samples.into_inner() {
+            lib_mappings_hierarchy.process_ops(sample.timestamp_mono);
+            let SampleOrMarker::Sample(SampleData { cpu_delta_nanos, off_cpu_nanos, weight }) = sample.sample_or_marker else {
+                continue;
+            };
+            if sample_range_set.is_some_and(|ranges| !ranges.contains(&sample.timestamp)) {
+                continue;
+            }
+
+            stack_frame_scratch_buf.clear();
+            stacks.convert_back(sample.stack, stack_frame_scratch_buf);
+            location_ids.clear();
+            // convert_back gives us the innermost frame first, which is also what pprof wants
+            for frame in stack_frame_scratch_buf.iter() {
+                let label = match frame {
+                    StackFrame::TruncatedStackMarker => Some(TRUNCATED_FRAME_NAME.to_owned()),
+                    StackFrame::OffCpuReason(reason) => Some(reason.label()),
+                    StackFrame::ReadyMarker => Some(READY_FRAME_NAME.to_owned()),
+                    _ => None,
+                };
+                if let Some(label) = label {
+                    location_ids.push(pprof.location(LocationKey { mapping_id: 0, address: 0, function_name: Some(label) }));
+                    continue;
+                }
+                let Some(frame) = context.resolve_frame(*frame, &lib_mappings_hierarchy) else { continue };
+                let key = match frame.lib {
+                    Some(ExportLib { lib_handle, info, image_size, relative_address, symbol, is_jit }) => {
+                        let mapping_id = if is_jit {
+                            pprof.mapping(lib_handle, info, 0, 0, true)
+                        } else {
+                            let memory_start = frame.address - relative_address as u64;
+                            pprof.mapping(lib_handle, info, memory_start, memory_start + image_size, false)
+                        };
+                        LocationKey { mapping_id, address: frame.lookup_address, function_name: symbol.map(str::to_owned) }
+                    }
+                    None => LocationKey { mapping_id: 0, address: frame.lookup_address, function_name: None },
+                };
+                location_ids.push(pprof.location(key));
+            }
+
+            let values = SampleValues {
+                samples: if off_cpu_nanos == 0 { weight as i64 } else { 0 },
+                cpu_nanos: cpu_delta_nanos as i64,
+                wait_nanos: off_cpu_nanos as i64,
+            };
+            let mut labels = vec![("pid", LabelValue::Num(context.process_id as i64))];
+            if let Some(name) = context.thread_names.get(&sample.thread_handle) {
+                labels.push(("thread", LabelValue::Str(name)));
+            }
+            pprof.add_sample(&location_ids, values, &labels);
+        }
+    }
+
+    /// Adds the samples to `collapsed`, one stack per process and thread. Frames are named
+    /// `lib.dll+0xoffset` unless we know the name of their function.
+    pub fn flush_samples_to_collapsed(
+        self,
+        collapsed: &mut CollapsedStacks,
+        context: &ExportContext,
+        stack_frame_scratch_buf: &mut Vec<StackFrame>,
+        stacks: &UnresolvedStacks,
+        sample_range_set: Option<&RangeSet<Timestamp>>,
+    ) {
+        let (unresolved_samples, mut lib_mappings_hierarchy, _) = self.into_parts();
+        let process_name = format!("{} ({})", context.process_name, context.process_id);
+        for sample in unresolved_samples.into_inner() {
+            lib_mappings_hierarchy.process_ops(sample.timestamp_mono);
+            let SampleOrMarker::Sample(SampleData { cpu_delta_nanos, weight, .. }) = sample.sample_or_marker else {
+                continue;
+            };
+            if sample_range_set.is_some_and(|ranges| !ranges.contains(&sample.timestamp)) {
+                continue;
+            }
+            let weight = match collapsed.weight() {
+                CollapsedWeight::Samples => weight as u64,
+                CollapsedWeight::CpuTime => cpu_delta_nanos / 1000,
+            };
+            if weight == 0 {
+                continue;
+            }
+
+            stack_frame_scratch_buf.clear();
+            stacks.convert_back(sample.stack, stack_frame_scratch_buf);
+            let frames = stack_frame_scratch_buf.iter().rev()
+                .filter_map(|frame| match frame {
+                    StackFrame::TruncatedStackMarker => Some(TRUNCATED_FRAME_NAME.to_owned()),
+                    StackFrame::OffCpuReason(reason) => Some(reason.label()),
+                    StackFrame::ReadyMarker => Some(READY_FRAME_NAME.to_owned()),
+                    _ => context.resolve_frame(*frame, &lib_mappings_hierarchy).map(|frame| frame.collapsed_name()),
+                })
+                .collect();
+            let thread_name = context.thread_names.get(&sample.thread_handle).map(String::as_str);
+            collapsed.add(&process_name, thread_name, frames, weight);
+        }
+    }
+
+    fn into_parts(self) -> (UnresolvedSamples, LibMappingsHierarchy, Option<ThreadHandle>) {
+        let ProcessSampleData {
+            unresolved_samples,
+            regular_lib_mapping_op_queue,
+            jitdump_lib_mapping_op_queues,
+            perf_map_mappings,
+            main_thread_handle,
+        } = self;
+        let lib_mappings_hierarchy = lib_mappings_hierarchy(regular_lib_mapping_op_queue, jitdump_lib_mapping_op_queues, perf_map_mappings);
+        (unresolved_samples, lib_mappings_hierarchy, main_thread_handle)
+    }
+}
+
+fn lib_mappings_hierarchy(
+    regular_lib_mapping_op_queue: LibMappingOpQueue,
+    jitdump_lib_mapping_op_queues: Vec<LibMappingOpQueue>,
+    perf_map_mappings: Option<LibMappings<LibMappingInfo>>,
+) -> LibMappingsHierarchy {
+    let mut lib_mappings_hierarchy = LibMappingsHierarchy::new(regular_lib_mapping_op_queue);
+    for jitdump_lib_mapping_ops in jitdump_lib_mapping_op_queues {
+        lib_mappings_hierarchy.add_jitdump_lib_mappings_ops(jitdump_lib_mapping_ops);
+    }
+    if let Some(perf_map_mappings) = perf_map_mappings {
+        lib_mappings_hierarchy.add_perf_map_mappings(perf_map_mappings);
+    }
+    lib_mappings_hierarchy
+}
+
+/// Describes the processes, threads and libraries of the samples for output formats other than
+/// the Firefox profile, which can't refer to the profile's handles.
+pub struct ExportContext<'a> {
+    pub process_id: u32,
+    pub process_name: &'a str,
+    pub thread_names: &'a FastHashMap<ThreadHandle, String>,
+    /// (info, image size) by library
+    pub lib_infos: &'a HashMap<LibraryHandle, (LibraryInfo, u64)>,
+    pub kernel_lib_mappings: &'a LibMappings<LibMappingInfo>,
+    /// The JIT library of the process and its symbols, sorted by address
+    pub jit_symbols: Option<(LibraryHandle, &'a [Symbol])>,
+    /// The symbols of the native libraries whose PDBs were found
+    pub lib_symbols: &'a FastHashMap<LibraryHandle, PdbSymbols>,
+}
+
+pub struct ExportFrame<'a> {
+    pub address: u64,
+    /// The address that was looked up: return addresses point after the call instruction so we
+    /// look up the byte before them.
+    pub lookup_address: u64,
+    pub mode: StackMode,
+    pub lib: Option<ExportLib<'a>>,
+}
+
+pub struct ExportLib<'a> {
+    pub lib_handle: LibraryHandle,
+    pub info: &'a LibraryInfo,
+    pub image_size: u64,
+    /// The address of the frame relative to the start of the library
+    pub relative_address: u32,
+    pub is_jit: bool,
+    /// The name of the function from the JIT symbols or the library's PDB
+    pub symbol: Option<&'a str>,
+}
+
+impl<'a> ExportContext<'a> {
+    /// Finds the library of `frame`. Returns `None` for truncation markers and off-cpu labels.
+    pub fn resolve_frame(&self, frame: StackFrame, lib_mappings: &LibMappingsHierarchy) -> Option<ExportFrame<'a>> {
+        let (address, lookup_address, mode) = match frame {
+            StackFrame::InstructionPointer(address, mode) => (address, address, mode),
+            StackFrame::ReturnAddress(address, mode) => (address, address.saturating_sub(1), mode),
+            StackFrame::TruncatedStackMarker | StackFrame::OffCpuReason(_) | StackFrame::ReadyMarker => return None,
+        };
+        let mapping = match mode {
+            StackMode::User => lib_mappings.convert_address(lookup_address),
+            StackMode::Kernel => self.kernel_lib_mappings.convert_address(lookup_address),
+        };
+        let lib = mapping.and_then(|(relative_lookup_address, mapping_info)| {
+            let lib_handle = mapping_info.lib_handle;
+            let (info, image_size) = self.lib_infos.get(&lib_handle)?;
+            let relative_address = relative_lookup_address + (address - lookup_address) as u32;
+            let (is_jit, symbol) = match self.jit_symbols {
+                Some((jit_lib, symbols)) if jit_lib == lib_handle => {
+                    (true, symbol_for_address(symbols, relative_lookup_address).map(|symbol| symbol.name.as_str()))
+                }
+                _ => {
+                    let symbol = self.lib_symbols.get(&lib_handle).and_then(|symbols| symbols.symbol_table.lookup(relative_lookup_address));
+                    (false, symbol.map(|symbol| symbol.name.as_str()))
+                }
+            };
+            Some(ExportLib { lib_handle, info, image_size: *image_size, relative_address, is_jit, symbol })
+        });
+        Some(ExportFrame { address, lookup_address, mode, lib })
+    }
+}
+
+impl ExportFrame<'_> {
+    /// The frame's name in collapsed stacks, with the `_[k]` and `_[j]` suffixes that flamegraph.pl
+    /// uses to color kernel and JIT frames
+    pub fn collapsed_name(&self) -> String {
+        let name = match &self.lib {
+            Some(ExportLib { symbol: Some(name), .. }) => name.to_string(),
+            Some(lib) => format!("{}+0x{:x}", lib.info.name, lib.relative_address),
+            None => format!("0x{:x}", self.address),
+        };
+        match (self.mode, &self.lib) {
+            (StackMode::Kernel, _) => format!("{}_[k]", name),
+            (StackMode::User, Some(ExportLib { is_jit: true, .. })) => format!("{}_[j]", name),
+            (StackMode::User, _) => name,
+        }
+    }
+}
+
+/// Adds the labels of the functions that were inlined at the native frames in `stack` that aren't
+/// in `inline_frames` yet
+fn add_inline_frames(
+    profile: &mut Profile,
+    inline_frames: &mut InlineFrames,
+    inline_infos: &FastHashMap<LibraryHandle, InlineInfo>,
+    stack: &[StackFrame],
+    lib_mappings: &LibMappingsHierarchy,
+) {
+    for frame in stack {
+        let lookup_address = match *frame {
+            StackFrame::InstructionPointer(addr, StackMode::User) => addr,
+            StackFrame::ReturnAddress(addr, StackMode::User) => addr.saturating_sub(1),
+            _ => continue,
+        };
+        let Some((relative_lookup_address, info)) = lib_mappings.convert_address(lookup_address) else { continue };
+        let Some(inline_info) = inline_infos.get(&info.lib_handle) else { continue };
+        inline_frames.entry((info.lib_handle, relative_lookup_address)).or_insert_with(|| {
+            inline_info.lookup(relative_lookup_address).into_iter()
+                .map(|function| profile.intern_string(function))
+                .collect()
+        });
+    }
+}
+
+/// Finds the symbol that contains `address`. `symbols` have to be sorted by address.
+fn symbol_for_address(symbols: &[Symbol], address: u32) -> Option<&Symbol> {
+    let index = match symbols.binary_search_by_key(&address, |symbol| symbol.address) {
+        Ok(index) => index,
+        Err(0) => return None,
+        Err(index) => index - 1,
+    };
+    let symbol = &symbols[index];
+    match symbol.size {
+        Some(size) if address >= symbol.address + size => None,
+        _ => Some(symbol),
+    }
 }
 
 #[derive(Debug, Clone)]
@@ -231,6 +552,59 @@ impl ProfilerMarker for OtherEventMarker {
     }
 }
 
+/// Marks when a thread that was waiting was readied, with the stack of the thread that readied it
+#[derive(Debug, Clone)]
+pub struct UnblockedMarker {
+    pub thread_id: u32,
+    pub process_id: u32,
+    /// Only known for the processes that are being profiled
+    pub process_name: Option<String>,
+}
+
+impl ProfilerMarker for UnblockedMarker {
+    const MARKER_TYPE_NAME: &'static str = "Unblocked";
+
+    fn json_marker_data(&self) -> serde_json::Value {
+        let process = match &self.process_name {
+            Some(name) => format!("{} ({})", name, self.process_id),
+            None => self.process_id.to_string(),
+        };
+        json!({
+            "type": Self::MARKER_TYPE_NAME,
+            "thread": self.thread_id,
+            "process": process,
+        })
+    }
+
+    fn schema() -> MarkerSchema {
+        MarkerSchema {
+            type_name: Self::MARKER_TYPE_NAME,
+            locations: vec![MarkerLocation::MarkerChart, MarkerLocation::MarkerTable],
+            chart_label: Some("by thread {marker.data.thread}"),
+            tooltip_label: Some("Unblocked by thread {marker.data.thread} in {marker.data.process}"),
+            table_label: Some("by thread {marker.data.thread} in {marker.data.process}"),
+            fields: vec![
+                MarkerSchemaField::Dynamic(MarkerDynamicField {
+                    key: "thread",
+                    label: "Readying thread",
+                    format: MarkerFieldFormat::Integer,
+                    searchable: true,
+                }),
+                MarkerSchemaField::Dynamic(MarkerDynamicField {
+                    key: "process",
+                    label: "Readying process",
+                    format: MarkerFieldFormat::String,
+                    searchable: true,
+                }),
+                MarkerSchemaField::Static(MarkerStaticField {
+                    label: "Description",
+                    value: "Emitted for ReadyThread events that end a wait. The stack is the readying thread's.",
+                }),
+            ],
+        }
+    }
+}
+
 #[derive(Debug, Clone)]
 pub struct UserTimingMarker(pub String);
 
@@ -0,0 +1,128 @@
+use std::collections::HashMap;
+use std::path::Path;
+
+use etw_reader::{GUID, open_trace, parser::{Parser, TryParse}, schema::SchemaLocator};
+use fxprof_processed_profile::LibraryInfo;
+
+use super::{error::{ConvertError, ParseProperty}, library_info};
+
+// EVENT_TRACE_FILE_MODE_CIRCULAR | EVENT_TRACE_BUFFERING_MODE
+const CIRCULAR_LOG_FILE_MODES: u32 = 0x2 | 0x400;
+
+/// Returns true if a trace with this `LogFileMode` was recorded into a circular buffer
+///
+/// The start of these traces has been overwritten by the time they're written out, so the
+/// DCStart rundown that tells us about the processes, threads and images that were already
+/// running is gone. The DCEnd rundown at the end of the trace has the same information.
+pub fn is_circular(log_file_mode: u32) -> bool {
+    log_file_mode & CIRCULAR_LOG_FILE_MODES != 0
+}
+
+#[derive(Debug, Clone)]
+pub struct RundownProcess {
+    pub process_id: u32,
+    pub image_file_name: String,
+}
+
+#[derive(Debug, Clone)]
+pub struct RundownThread {
+    pub thread_id: u32,
+    pub process_id: u32,
+    pub name: Option<String>,
+}
+
+#[derive(Debug, Clone)]
+pub struct RundownImage {
+    pub process_id: u32,
+    pub image_base: u64,
+    pub image_size: u64,
+    pub info: LibraryInfo,
+}
+
+/// The processes, threads and images that were still around at the end of the trace
+#[derive(Debug, Default)]
+pub struct Rundown {
+    pub processes: Vec<RundownProcess>,
+    pub threads: Vec<RundownThread>,
+    pub images: Vec<RundownImage>,
+}
+
+/// Does a pass over the trace at `path` to collect the Process, Thread and Image DCEnd rundown
+/// so that it's available before we start processing samples.
+pub fn collect_rundown(path: &Path, schema_locator: &mut SchemaLocator) -> Result<Rundown, std::io::Error> {
+    let mut rundown = Rundown::default();
+    let mut thread_names: HashMap<u32, String> = HashMap::new();
+    // See the comment in the Image/Load handler in main.rs for how the KernelTraceControl events fit in
+    let mut libs: HashMap<u64, (String, u32, u32)> = HashMap::new();
+    let mut pending_libraries: HashMap<(u32, u64), LibraryInfo> = HashMap::new();
+
+    open_trace(path, |e| {
+        let Ok(s) = schema_locator.event_schema(e) else { return };
+        // Malformed events are ignored here, the main pass reports them
+        let _ = (|| -> Result<(), ConvertError> {
+            match s.name() {
+                "MSNT_SystemTrace/Process/DCEnd" => {
+                    let mut parser = Parser::create(&s);
+                    let process_id: u32 = parser.property("ProcessId")?;
+                    let image_file_name: String = parser.property("ImageFileName")?;
+                    rundown.processes.push(RundownProcess { process_id, image_file_name });
+                }
+                "MSNT_SystemTrace/Thread/DCEnd" => {
+                    let mut parser = Parser::create(&s);
+                    let thread_id: u32 = parser.property("TThreadId")?;
+                    let process_id: u32 = parser.property("ProcessId")?;
+                    let name: Result<String, _> = parser.try_parse("ThreadName");
+                    if let Ok(name) = name {
+                        if !name.is_empty() {
+                            thread_names.insert(thread_id, name);
+                        }
+                    }
+                    rundown.threads.push(RundownThread { thread_id, process_id, name: None });
+                }
+                "MSNT_SystemTrace/Thread/SetName" => {
+                    let mut parser = Parser::create(&s);
+                    let thread_id: u32 = parser.property("ThreadId")?;
+                    let name: String = parser.property("ThreadName")?;
+                    thread_names.insert(thread_id, name);
+                }
+                "KernelTraceControl/ImageID/" => {
+                    let mut parser = Parser::create(&s);
+                    let image_base: u64 = parser.property("ImageBase")?;
+                    let timestamp: u32 = parser.property("TimeDateStamp")?;
+                    let image_size: u32 = parser.property("ImageSize")?;
+                    let path: String = parser.property("OriginalFileName")?;
+                    libs.insert(image_base, (path, image_size, timestamp));
+                }
+                "KernelTraceControl/ImageID/DbgID_RSDS" => {
+                    let mut parser = Parser::create(&s);
+                    let image_base: u64 = parser.property("ImageBase")?;
+                    let guid: GUID = parser.property("GuidSig")?;
+                    let age: u32 = parser.property("Age")?;
+                    let pdb_path: String = parser.property("PdbFileName")?;
+                    if let Some((path, image_size, timestamp)) = libs.get(&image_base) {
+                        let info = library_info(path, *image_size, *timestamp, guid, age, pdb_path);
+                        pending_libraries.insert((s.process_id(), image_base), info);
+                    }
+                }
+                "MSNT_SystemTrace/Image/DCEnd" => {
+                    let mut parser = Parser::create(&s);
+                    let process_id: u32 = parser.property("ProcessId")?;
+                    let image_base: u64 = parser.property("ImageBase")?;
+                    let image_size: u64 = parser.property("ImageSize")?;
+                    let path: String = parser.property("FileName")?;
+                    if let Some(mut info) = pending_libraries.remove(&(process_id, image_base)) {
+                        info.path = format!("\\\\?\\GLOBALROOT{}", path);
+                        rundown.images.push(RundownImage { process_id, image_base, image_size, info });
+                    }
+                }
+                _ => {}
+            }
+            Ok(())
+        })();
+    })?;
+
+    for thread in &mut rundown.threads {
+        thread.name = thread_names.remove(&thread.thread_id);
+    }
+    Ok(rundown)
+}
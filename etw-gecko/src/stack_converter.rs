@@ -1,13 +1,15 @@
-use fxprof_processed_profile::{CategoryPairHandle, Frame, FrameFlags, FrameInfo};
+use fxprof_processed_profile::{CategoryPairHandle, Frame, FrameFlags, FrameInfo, LibraryHandle, Profile, StringHandle};
 
+use super::context_switch::OffCpuReason;
 use super::jit_category_manager::{JsFrame, JsName};
 use super::lib_mappings::LibMappingsHierarchy;
-use super::types::{StackFrame, StackMode};
+use super::types::{FastHashMap, StackFrame, StackMode};
 
 #[derive(Debug, Clone, Copy)]
 pub struct StackConverter {
     user_category: CategoryPairHandle,
     kernel_category: CategoryPairHandle,
+    truncated_label: StringHandle,
 }
 
 pub struct ConvertedStackIter<'a> {
@@ -15,10 +17,56 @@ pub struct ConvertedStackIter<'a> {
     lib_mappings: &'a LibMappingsHierarchy,
     user_category: CategoryPairHandle,
     kernel_category: CategoryPairHandle,
-    pending_frame: Option<FrameInfo>,
+    truncated_label: StringHandle,
+    inline_frames: &'a InlineFrames,
+    off_cpu_labels: &'a OffCpuLabels,
+    /// Frames to return before looking at the next stack frame, last one first
+    pending_frames: Vec<FrameInfo>,
     js_name_for_baseline_interpreter: Option<JsName>,
 }
 
+/// Labels for the functions that were inlined at a (library, relative lookup address), outermost
+/// first. They follow the frame for that address, which is symbolicated as the procedure that they
+/// were inlined into.
+pub type InlineFrames = FastHashMap<(LibraryHandle, u32), Vec<StringHandle>>;
+
+/// The name of the frame of off-cpu samples for the time threads were ready to run
+pub const READY_FRAME_NAME: &str = "Ready";
+
+/// The frames that say why off-cpu samples were off the CPU. They're in the "Waiting" category,
+/// except for the time threads were ready to run, which is in the "Ready" category.
+#[derive(Debug)]
+pub struct OffCpuLabels {
+    waiting_category: CategoryPairHandle,
+    ready_category: CategoryPairHandle,
+    ready_label: StringHandle,
+    /// The label of each reason that off-cpu samples have
+    reasons: FastHashMap<OffCpuReason, StringHandle>,
+}
+
+impl OffCpuLabels {
+    pub fn new(waiting_category: CategoryPairHandle, ready_category: CategoryPairHandle, ready_label: StringHandle) -> Self {
+        Self { waiting_category, ready_category, ready_label, reasons: FastHashMap::default() }
+    }
+
+    /// Interns the label of `reason` if it doesn't have one yet
+    pub fn add_reason(&mut self, profile: &mut Profile, reason: OffCpuReason) {
+        self.reasons.entry(reason).or_insert_with(|| profile.intern_string(&reason.label()));
+    }
+
+    fn frame(&self, frame: StackFrame) -> Option<FrameInfo> {
+        let (label, category_pair) = match frame {
+            StackFrame::OffCpuReason(reason) => {
+                let category_pair = if reason.is_ready() { self.ready_category } else { self.waiting_category };
+                (*self.reasons.get(&reason)?, category_pair)
+            }
+            StackFrame::ReadyMarker => (self.ready_label, self.ready_category),
+            _ => return None,
+        };
+        Some(FrameInfo { frame: Frame::Label(label), category_pair, flags: FrameFlags::empty() })
+    }
+}
+
 impl<'a> Iterator for ConvertedStackIter<'a> {
     type Item = FrameInfo;
 
@@ -33,7 +81,7 @@ impl<'a> Iterator for ConvertedStackIter<'a> {
 
     fn next(&mut self) -> Option<Self::Item> {
         loop {
-            if let Some(pending_frame) = self.pending_frame.take() {
+            if let Some(pending_frame) = self.pending_frames.pop() {
                 return Some(pending_frame);
             }
             let frame = self.inner.next()?;
@@ -42,9 +90,19 @@ impl<'a> Iterator for ConvertedStackIter<'a> {
                 StackFrame::ReturnAddress(addr, mode) => {
                     (mode, addr, addr.saturating_sub(1), false)
                 }
-                StackFrame::TruncatedStackMarker => continue,
+                StackFrame::TruncatedStackMarker => {
+                    return Some(FrameInfo {
+                        frame: Frame::Label(self.truncated_label),
+                        category_pair: self.user_category,
+                        flags: FrameFlags::empty(),
+                    });
+                }
+                StackFrame::OffCpuReason(_) | StackFrame::ReadyMarker => {
+                    let Some(frame) = self.off_cpu_labels.frame(*frame) else { continue };
+                    return Some(frame);
+                }
             };
-            let (location, category, js_frame) = match mode {
+            let (location, category, js_frame, inline_labels) = match mode {
                 StackMode::User => match self.lib_mappings.convert_address(lookup_address) {
                     Some((relative_lookup_address, info)) => {
                         let location = if from_ip {
@@ -64,6 +122,7 @@ impl<'a> Iterator for ConvertedStackIter<'a> {
                             location,
                             info.category.unwrap_or(self.user_category),
                             info.js_frame,
+                            self.inline_frames.get(&(info.lib_handle, relative_lookup_address)),
                         )
                     }
                     None => {
@@ -71,7 +130,7 @@ impl<'a> Iterator for ConvertedStackIter<'a> {
                             true => Frame::InstructionPointer(addr),
                             false => Frame::ReturnAddress(addr),
                         };
-                        (location, self.user_category, None)
+                        (location, self.user_category, None, None)
                     }
                 },
                 StackMode::Kernel => {
@@ -79,7 +138,7 @@ impl<'a> Iterator for ConvertedStackIter<'a> {
                         true => Frame::InstructionPointer(addr),
                         false => Frame::ReturnAddress(addr),
                     };
-                    (location, self.kernel_category, None)
+                    (location, self.kernel_category, None, None)
                 }
             };
             let frame_info = FrameInfo {
@@ -87,6 +146,13 @@ impl<'a> Iterator for ConvertedStackIter<'a> {
                 category_pair: category,
                 flags: FrameFlags::empty(),
             };
+            if let Some(labels) = inline_labels {
+                self.pending_frames.extend(labels.iter().rev().map(|label| FrameInfo {
+                    frame: Frame::Label(*label),
+                    category_pair: category,
+                    flags: FrameFlags::empty(),
+                }));
+            }
 
             // Work around an imperfection in Spidermonkey's stack frames.
             // We sometimes have missing BaselineInterpreterStubs in the OSR-into-BaselineInterpreter case.
@@ -116,7 +182,7 @@ impl<'a> Iterator for ConvertedStackIter<'a> {
             let frame_info = match js_name {
                 Some(JsName::NonSelfHosted(js_name)) => {
                     // Prepend a JS frame.
-                    self.pending_frame = Some(frame_info);
+                    self.pending_frames.push(frame_info);
                     FrameInfo {
                         frame: Frame::Label(js_name),
                         category_pair: category,
@@ -132,10 +198,13 @@ impl<'a> Iterator for ConvertedStackIter<'a> {
 }
 
 impl StackConverter {
-    pub fn new(user_category: CategoryPairHandle, kernel_category: CategoryPairHandle) -> Self {
+    /// `truncated_label` is the name of the frame that stands in for the missing part of
+    /// truncated stacks
+    pub fn new(user_category: CategoryPairHandle, kernel_category: CategoryPairHandle, truncated_label: StringHandle) -> Self {
         Self {
             user_category,
             kernel_category,
+            truncated_label,
         }
     }
 
@@ -143,6 +212,8 @@ impl StackConverter {
         &self,
         stack: &'a [StackFrame],
         lib_mappings: &'a LibMappingsHierarchy,
+        inline_frames: &'a InlineFrames,
+        off_cpu_labels: &'a OffCpuLabels,
         extra_first_frame: Option<FrameInfo>,
     ) -> impl Iterator<Item = FrameInfo> + 'a {
         ConvertedStackIter {
@@ -150,7 +221,10 @@ impl StackConverter {
             lib_mappings,
             user_category: self.user_category,
             kernel_category: self.kernel_category,
-            pending_frame: extra_first_frame,
+            truncated_label: self.truncated_label,
+            inline_frames,
+            off_cpu_labels,
+            pending_frames: extra_first_frame.into_iter().collect(),
             js_name_for_baseline_interpreter: None,
         }
     }
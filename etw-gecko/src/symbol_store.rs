@@ -0,0 +1,314 @@
+//! Finds PDBs in local symbol stores
+//!
+//! Symbol paths use the `_NT_SYMBOL_PATH` syntax: entries are separated by `;` and are either a
+//! plain directory or `srv*cache*store*...`. Stores use the symstore layout, i.e.
+//! `xul.pdb/<GUID><AGE>/xul.pdb`, and files may be compressed as `xul.pd_`. Names are matched
+//! ignoring case, like they are on Windows. Only local directories are supported, so symbol
+//! servers (`http://...`) are skipped. Like symsrv, files that are found in a store are copied
+//! into the entry's cache, which is also where compressed files are expanded to.
+use std::{fs::{self, File}, io, path::{Path, PathBuf}};
+
+use fxprof_processed_profile::LibraryInfo;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum SymbolPathEntry {
+    /// A directory with files directly in it or in the symstore layout
+    Dir(PathBuf),
+    /// A `srv*` entry. The stores are searched in order after the cache.
+    Server { cache: Option<PathBuf>, stores: Vec<PathBuf> },
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct SymbolStore {
+    entries: Vec<SymbolPathEntry>,
+}
+
+fn is_url(path: &str) -> bool {
+    path.starts_with("http://") || path.starts_with("https://")
+}
+
+/// `xul.pdb` is compressed as `xul.pd_`
+fn compressed_name(name: &str) -> String {
+    let mut compressed = name.to_owned();
+    compressed.pop();
+    compressed.push('_');
+    compressed
+}
+
+impl SymbolStore {
+    pub fn parse(symbol_path: &str) -> Self {
+        let mut store = SymbolStore::default();
+        store.add_symbol_path(symbol_path);
+        store
+    }
+
+    /// Adds the entries of `symbol_path` after the existing ones
+    pub fn add_symbol_path(&mut self, symbol_path: &str) {
+        for entry in symbol_path.split(';').map(str::trim).filter(|entry| !entry.is_empty()) {
+            let mut parts = entry.split('*');
+            let kind = parts.next().unwrap().to_ascii_lowercase();
+            match kind.as_str() {
+                "srv" | "symsrv" => {
+                    let mut parts: Vec<&str> = parts.collect();
+                    // symsrv*symsrv.dll*cache*store names the DLL that implements the lookup
+                    if kind == "symsrv" && !parts.is_empty() {
+                        parts.remove(0);
+                    }
+                    // With a single directory it's the store itself and there's no cache
+                    let cache = if parts.len() > 1 { Some(parts.remove(0)) } else { None };
+                    let cache = cache.filter(|cache| !cache.is_empty() && !is_url(cache)).map(PathBuf::from);
+                    let stores: Vec<PathBuf> = parts.into_iter().filter(|store| !store.is_empty() && !is_url(store)).map(PathBuf::from).collect();
+                    if cache.is_some() || !stores.is_empty() {
+                        self.entries.push(SymbolPathEntry::Server { cache, stores });
+                    }
+                }
+                "cache" => {
+                    if let Some(dir) = parts.next().filter(|dir| !dir.is_empty()) {
+                        self.entries.push(SymbolPathEntry::Dir(PathBuf::from(dir)));
+                    }
+                }
+                _ if !is_url(entry) => self.entries.push(SymbolPathEntry::Dir(PathBuf::from(entry))),
+                _ => {}
+            }
+        }
+    }
+
+    pub fn add_dir(&mut self, dir: PathBuf) {
+        self.entries.push(SymbolPathEntry::Dir(dir));
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    /// Returns the path of the PDB of `info`, which is looked up by its debug id
+    pub fn find_pdb(&self, info: &LibraryInfo) -> io::Result<Option<PathBuf>> {
+        if info.debug_name.is_empty() || info.debug_id.is_nil() {
+            return Ok(None);
+        }
+        self.find(&info.debug_name, &info.debug_id.breakpad().to_string())
+    }
+
+    fn find(&self, name: &str, id: &str) -> io::Result<Option<PathBuf>> {
+        for entry in &self.entries {
+            match entry {
+                SymbolPathEntry::Dir(dir) => {
+                    if let Some(flat) = join_ignoring_case(dir, name).filter(|path| path.is_file()) {
+                        return Ok(Some(flat));
+                    }
+                    if let Some(found) = find_in_store(dir, name, id) {
+                        return materialize(found, None, name, id).map(Some);
+                    }
+                }
+                SymbolPathEntry::Server { cache, stores } => {
+                    for store in cache.iter().chain(stores) {
+                        if let Some(found) = find_in_store(store, name, id) {
+                            return materialize(found, cache.as_deref(), name, id).map(Some);
+                        }
+                    }
+                }
+            }
+        }
+        Ok(None)
+    }
+}
+
+/// A file that was found in a store
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum Found {
+    Plain(PathBuf),
+    Compressed(PathBuf),
+}
+
+/// The path of `name` in the store at `dir`
+pub fn store_path(dir: &Path, name: &str, id: &str) -> PathBuf {
+    dir.join(name).join(id).join(name)
+}
+
+/// Returns the entry of `dir` that's called `name` ignoring case. Stores that were copied from
+/// Windows can have names in any case, e.g. `XUL.pdb` or a lowercase id.
+fn join_ignoring_case(dir: &Path, name: &str) -> Option<PathBuf> {
+    let path = dir.join(name);
+    if path.exists() {
+        return Some(path);
+    }
+    fs::read_dir(dir).ok()?
+        .filter_map(Result::ok)
+        .find(|entry| entry.file_name().to_str().is_some_and(|entry_name| entry_name.eq_ignore_ascii_case(name)))
+        .map(|entry| entry.path())
+}
+
+fn find_in_store(dir: &Path, name: &str, id: &str) -> Option<Found> {
+    let mut dirs = Vec::new();
+    // Two-tier stores put everything in a directory named after the first two letters
+    if join_ignoring_case(dir, "index2.txt").is_some_and(|path| path.is_file()) {
+        dirs.extend(join_ignoring_case(dir, name.get(..2).unwrap_or(name)));
+    }
+    dirs.push(dir.to_owned());
+    for dir in dirs {
+        let Some(id_dir) = join_ignoring_case(&dir, name).and_then(|name_dir| join_ignoring_case(&name_dir, id)) else { continue };
+        if let Some(path) = join_ignoring_case(&id_dir, name).filter(|path| path.is_file()) {
+            return Some(Found::Plain(path));
+        }
+        if let Some(path) = join_ignoring_case(&id_dir, &compressed_name(name)).filter(|path| path.is_file()) {
+            return Some(Found::Compressed(path));
+        }
+    }
+    None
+}
+
+/// Returns the path of an uncompressed copy of `found`. Files are copied into `cache` if there is
+/// one, and compressed files that aren't cached are expanded into a temporary directory.
+fn materialize(found: Found, cache: Option<&Path>, name: &str, id: &str) -> io::Result<PathBuf> {
+    let target = match (&found, cache) {
+        (Found::Plain(path), None) => return Ok(path.clone()),
+        // Already in the cache, maybe in a different case than we'd have used
+        (Found::Plain(path), Some(cache)) if path.starts_with(cache) => return Ok(path.clone()),
+        (_, Some(cache)) => store_path(cache, name, id),
+        (Found::Compressed(_), None) => store_path(&std::env::temp_dir().join("etw-gecko-symbols"), name, id),
+    };
+    let (Found::Plain(source) | Found::Compressed(source)) = found;
+    if source == target || target.is_file() {
+        return Ok(target);
+    }
+    fs::create_dir_all(target.parent().unwrap())?;
+    // Write to a temporary file first so that an interrupted copy doesn't leave a truncated file
+    // in the cache
+    let partial = target.with_extension("partial");
+    if source.file_name() == target.file_name() {
+        fs::copy(&source, &partial)?;
+    } else {
+        expand(&source, &partial)?;
+    }
+    fs::rename(&partial, &target)?;
+    Ok(target)
+}
+
+/// Expands the single file in the cabinet at `source` to `target`
+fn expand(source: &Path, target: &Path) -> io::Result<()> {
+    let mut cabinet = cab::Cabinet::new(File::open(source)?)?;
+    let name = cabinet.folder_entries()
+        .find_map(|folder| folder.file_entries().next().map(|file| file.name().to_owned()))
+        .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidData, format!("{} is an empty cabinet", source.display())))?;
+    let mut reader = cabinet.read_file(&name)?;
+    io::copy(&mut reader, &mut File::create(target)?)?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use std::{fs, io::Write, path::{Path, PathBuf}};
+
+    use super::{compressed_name, store_path, SymbolPathEntry, SymbolStore};
+
+    const ID: &str = "0123456789ABCDEF0123456789ABCDEF1";
+
+    /// A directory for a test's stores that's removed at the end of the test
+    struct TempDir(PathBuf);
+
+    impl TempDir {
+        fn new(test: &str) -> Self {
+            let dir = std::env::temp_dir().join(format!("etw-gecko-symbol-store-{}-{}", test, std::process::id()));
+            let _ = fs::remove_dir_all(&dir);
+            fs::create_dir_all(&dir).unwrap();
+            TempDir(dir)
+        }
+
+        fn write(&self, path: &str, contents: &[u8]) -> PathBuf {
+            let path = self.0.join(path);
+            fs::create_dir_all(path.parent().unwrap()).unwrap();
+            fs::write(&path, contents).unwrap();
+            path
+        }
+    }
+
+    impl Drop for TempDir {
+        fn drop(&mut self) {
+            let _ = fs::remove_dir_all(&self.0);
+        }
+    }
+
+    /// A cabinet with `name` in it, like the ones that `symstore /compress` writes
+    fn cabinet(name: &str, contents: &[u8]) -> Vec<u8> {
+        let mut builder = cab::CabinetBuilder::new();
+        builder.add_folder(cab::CompressionType::MsZip).add_file(name);
+        let mut writer = builder.build(std::io::Cursor::new(Vec::new())).unwrap();
+        while let Some(mut file) = writer.next_file().unwrap() {
+            file.write_all(contents).unwrap();
+        }
+        writer.finish().unwrap().into_inner()
+    }
+
+    #[test]
+    fn parse() {
+        let store = SymbolStore::parse("C:\\local;srv*C:\\symbols*\\\\share\\symbols*https://msdl.microsoft.com/download/symbols; srv*/mnt/mirror ;cache*/tmp/cache;https://symbols.mozilla.org");
+        assert_eq!(store.entries, [
+            SymbolPathEntry::Dir(PathBuf::from("C:\\local")),
+            SymbolPathEntry::Server { cache: Some(PathBuf::from("C:\\symbols")), stores: vec![PathBuf::from("\\\\share\\symbols")] },
+            SymbolPathEntry::Server { cache: None, stores: vec![PathBuf::from("/mnt/mirror")] },
+            SymbolPathEntry::Dir(PathBuf::from("/tmp/cache")),
+        ]);
+        // Only symbol servers are left
+        assert!(SymbolStore::parse("srv**https://msdl.microsoft.com/download/symbols").is_empty());
+    }
+
+    #[test]
+    fn layout() {
+        assert_eq!(store_path(Path::new("/symbols"), "xul.pdb", "0123456789ABCDEF0123456789ABCDEF1"),
+            PathBuf::from("/symbols/xul.pdb/0123456789ABCDEF0123456789ABCDEF1/xul.pdb"));
+        assert_eq!(store_path(Path::new("/symbols"), "xul.dll", "5F0036A0100000"),
+            PathBuf::from("/symbols/xul.dll/5F0036A0100000/xul.dll"));
+        assert_eq!(compressed_name("xul.pdb"), "xul.pd_");
+    }
+
+    #[test]
+    fn lookup() {
+        let dir = TempDir::new("lookup");
+        let flat = dir.write("flat/xul.pdb", b"flat");
+        let stored = dir.write(&format!("store/XUL.PDB/{}/xul.pdb", ID.to_ascii_lowercase()), b"stored");
+        let store = SymbolStore::parse(&format!("{};{}", dir.0.join("flat").display(), dir.0.join("store").display()));
+        assert_eq!(store.find("xul.pdb", ID).unwrap(), Some(flat));
+        assert_eq!(store.find("XUL.pdb", ID).unwrap(), Some(dir.0.join("flat/xul.pdb")));
+        assert_eq!(store.find("nss3.pdb", ID).unwrap(), None);
+
+        let store = SymbolStore::parse(&dir.0.join("store").display().to_string());
+        assert_eq!(store.find("xul.pdb", ID).unwrap(), Some(stored));
+        assert_eq!(store.find("xul.pdb", "FEDCBA98765432100123456789ABCDEF1").unwrap(), None);
+    }
+
+    #[test]
+    fn two_tier() {
+        let dir = TempDir::new("two-tier");
+        dir.write("store/index2.txt", b"");
+        let stored = dir.write(&format!("store/xu/xul.pdb/{}/xul.pdb", ID), b"two tier");
+        let store = SymbolStore::parse(&dir.0.join("store").display().to_string());
+        assert_eq!(store.find("xul.pdb", ID).unwrap(), Some(stored));
+        // Without index2.txt the store is single-tier
+        fs::remove_file(dir.0.join("store/index2.txt")).unwrap();
+        assert_eq!(store.find("xul.pdb", ID).unwrap(), None);
+    }
+
+    #[test]
+    fn compressed() {
+        let dir = TempDir::new("compressed");
+        dir.write(&format!("store/xul.pdb/{}/xul.pd_", ID), &cabinet("xul.pdb", b"expanded"));
+        let store = SymbolStore::parse(&format!("srv*{}*{}", dir.0.join("cache").display(), dir.0.join("store").display()));
+        let found = store.find("xul.pdb", ID).unwrap().unwrap();
+        assert_eq!(found, store_path(&dir.0.join("cache"), "xul.pdb", ID));
+        assert_eq!(fs::read(&found).unwrap(), b"expanded");
+        assert!(!found.with_extension("partial").exists());
+    }
+
+    #[test]
+    fn cache_copy() {
+        let dir = TempDir::new("cache-copy");
+        let stored = dir.write(&format!("store/xul.pdb/{}/xul.pdb", ID), b"stored");
+        let store = SymbolStore::parse(&format!("srv*{}*{}", dir.0.join("cache").display(), dir.0.join("store").display()));
+        let cached = store_path(&dir.0.join("cache"), "xul.pdb", ID);
+        assert_eq!(store.find("xul.pdb", ID).unwrap(), Some(cached.clone()));
+        assert_eq!(fs::read(&cached).unwrap(), b"stored");
+        // Later lookups use the cache even if the store goes away
+        fs::remove_file(stored).unwrap();
+        assert_eq!(store.find("xul.pdb", ID).unwrap(), Some(cached));
+    }
+}
@@ -0,0 +1,327 @@
+//! Symbolicates native libraries from local PDB files while converting
+//!
+//! Without this the profile only has library-relative addresses and needs `profiler-symbol-server`
+//! to be symbolicated when it's opened. [TraceConverter::finish](crate::TraceConverter::finish)
+//! looks for the PDB of each library in a [SymbolStore] and attaches the symbols it finds to the
+//! profile. Native frames are also followed by the functions that were inlined at their address,
+//! using the [InlineInfo] from the inline sites in the PDB. The frames and symbols of the profile
+//! have nowhere to put file names and line numbers, so the line programs aren't read.
+use std::{fs::File, path::{Path, PathBuf}};
+
+use fxprof_processed_profile::{debugid::DebugId, LibraryInfo, Symbol, SymbolTable};
+use msvc_demangler::DemangleFlags;
+use pdb::FallibleIterator;
+
+use super::{symbol_store::SymbolStore, types::FastHashMap};
+
+#[derive(Debug)]
+pub enum SymbolError {
+    Io(std::io::Error),
+    Pdb(pdb::Error),
+    /// The PDB is for a different build of the library
+    DebugIdMismatch { expected: DebugId, found: DebugId },
+}
+
+impl From<std::io::Error> for SymbolError {
+    fn from(err: std::io::Error) -> Self {
+        SymbolError::Io(err)
+    }
+}
+
+impl From<pdb::Error> for SymbolError {
+    fn from(err: pdb::Error) -> Self {
+        SymbolError::Pdb(err)
+    }
+}
+
+/// Returns the path of the PDB for `info`. The path that was recorded in the trace is tried
+/// after `store`, which is useful when converting on the machine that built the library.
+pub fn find_pdb(store: &SymbolStore, info: &LibraryInfo) -> Result<Option<PathBuf>, SymbolError> {
+    if let Some(path) = store.find_pdb(info)? {
+        return Ok(Some(path));
+    }
+    let debug_path = PathBuf::from(&info.debug_path);
+    Ok(debug_path.is_file().then_some(debug_path))
+}
+
+/// A range of code that was inlined from the same function, at one inlining depth
+#[derive(Debug, Clone, Copy)]
+struct InlineRange {
+    start: u32,
+    end: u32,
+    function: u32,
+}
+
+/// The functions that were inlined into the procedures of a library, from the inline sites in its
+/// PDB
+#[derive(Debug, Default)]
+pub struct InlineInfo {
+    functions: Vec<String>,
+    function_indices: FastHashMap<String, u32>,
+    /// The ranges at each inlining depth, sorted by address. Depth 0 has the functions that were
+    /// inlined into the procedures themselves.
+    depths: Vec<Vec<InlineRange>>,
+}
+
+impl InlineInfo {
+    pub fn is_empty(&self) -> bool {
+        self.depths.is_empty()
+    }
+
+    /// Returns the functions that were inlined at the relative address `address`, starting with
+    /// the one that was inlined into the procedure
+    pub fn lookup(&self, address: u32) -> Vec<&str> {
+        let mut functions = Vec::new();
+        for ranges in &self.depths {
+            let index = ranges.partition_point(|range| range.start <= address);
+            let Some(range) = index.checked_sub(1).map(|index| &ranges[index]).filter(|range| address < range.end) else {
+                break;
+            };
+            functions.push(self.functions[range.function as usize].as_str());
+        }
+        functions
+    }
+
+    fn function(&mut self, name: &str) -> u32 {
+        if let Some(index) = self.function_indices.get(name) {
+            return *index;
+        }
+        let index = self.functions.len() as u32;
+        self.functions.push(name.to_owned());
+        self.function_indices.insert(name.to_owned(), index);
+        index
+    }
+
+    /// Adds the code of an inlined function at `depth`, given as the start and length of each of
+    /// its lines. Lines without a length end where the next one starts.
+    fn add_lines(&mut self, depth: usize, function: u32, mut lines: Vec<(u32, Option<u32>)>) {
+        if self.depths.len() <= depth {
+            self.depths.resize_with(depth + 1, Vec::new);
+        }
+        lines.sort_by_key(|(start, _)| *start);
+        for i in 0..lines.len() {
+            let (start, length) = lines[i];
+            let next_start = lines.get(i + 1).map(|(start, _)| *start);
+            let Some(end) = length.map(|length| start + length).or(next_start) else { continue };
+            self.depths[depth].push(InlineRange { start, end, function });
+        }
+    }
+
+    fn sort(&mut self) {
+        for ranges in &mut self.depths {
+            ranges.sort_by_key(|range| range.start);
+        }
+    }
+}
+
+/// Returns the start address and length of `lines`
+fn read_lines(
+    mut lines: impl FallibleIterator<Item = pdb::LineInfo, Error = pdb::Error>,
+    address_map: &pdb::AddressMap,
+) -> Result<Vec<(u32, Option<u32>)>, pdb::Error> {
+    let mut result = Vec::new();
+    while let Some(line) = lines.next()? {
+        if let Some(rva) = line.offset.to_rva(address_map) {
+            result.push((rva.0, line.length));
+        }
+    }
+    Ok(result)
+}
+
+/// What [load_pdb_symbols] reads from a PDB
+pub struct PdbSymbols {
+    pub symbol_table: SymbolTable,
+    pub inline_info: InlineInfo,
+}
+
+/// Returns whether one of the sorted `addresses` is in the `len` bytes at `start`
+fn contains_address(addresses: &[u32], start: u32, len: u32) -> bool {
+    let index = addresses.partition_point(|address| *address < start);
+    addresses.get(index).is_some_and(|address| address - start < len)
+}
+
+/// Reads the functions in the PDB at `path`, which has to match `debug_id`, and the inline sites
+/// of the procedures that contain one of the sorted `hit_addresses`. Reading the inline sites is
+/// most of the work so we skip it for the procedures that no sample is in.
+/// Procedures from the module streams have undecorated names so they're preferred over public
+/// symbols at the same address, which are demangled instead.
+pub fn load_pdb_symbols(path: &Path, debug_id: DebugId, hit_addresses: &[u32]) -> Result<PdbSymbols, SymbolError> {
+    let mut pdb = pdb::PDB::open(File::open(path)?)?;
+    let pdb_info = pdb.pdb_information()?;
+    let dbi = pdb.debug_information()?;
+    // The age in the DBI stream is the one that ends up in the image
+    let found = DebugId::from_parts(pdb_info.guid, dbi.age().unwrap_or(pdb_info.age));
+    if found != debug_id {
+        return Err(SymbolError::DebugIdMismatch { expected: debug_id, found });
+    }
+    let address_map = pdb.address_map()?;
+    let id_information = pdb.id_information()?;
+    let mut id_finder = id_information.finder();
+    let mut ids = id_information.iter();
+    while ids.next()?.is_some() {
+        id_finder.update(&ids);
+    }
+
+    let mut symbols = Vec::new();
+    let mut inline_info = InlineInfo::default();
+    let mut inlinee_functions: FastHashMap<u32, u32> = FastHashMap::default();
+    let mut modules = dbi.modules()?;
+    while let Some(module) = modules.next()? {
+        let Some(module_info) = pdb.module_info(&module)? else { continue };
+        let inlinees: FastHashMap<u32, pdb::Inlinee> = module_info.inlinees()?
+            .map(|inlinee| Ok((inlinee.index().0, inlinee)))
+            .collect()?;
+
+        // The procedure and inline sites that the current symbol is in, with the index of the
+        // symbol that ends them and whether the procedure was hit
+        let mut scopes: Vec<(pdb::SymbolIndex, pdb::PdbInternalSectionOffset, bool)> = Vec::new();
+        let mut module_symbols = module_info.symbols()?;
+        while let Some(symbol) = module_symbols.next()? {
+            while scopes.last().is_some_and(|(end, ..)| symbol.index() >= *end) {
+                scopes.pop();
+            }
+            match symbol.parse() {
+                Ok(pdb::SymbolData::Procedure(procedure)) => {
+                    let Some(rva) = procedure.offset.to_rva(&address_map) else { continue };
+                    let hit = contains_address(hit_addresses, rva.0, procedure.len);
+                    symbols.push(Symbol { address: rva.0, size: Some(procedure.len), name: procedure.name.to_string().into_owned() });
+                    scopes.push((procedure.end, procedure.offset, hit));
+                }
+                Ok(pdb::SymbolData::InlineSite(site)) => {
+                    let Some((_, procedure_offset, hit)) = scopes.first().copied() else { continue };
+                    if !hit {
+                        scopes.push((site.end, procedure_offset, hit));
+                        continue;
+                    }
+                    if let Some(inlinee) = inlinees.get(&site.inlinee.0) {
+                        let function = match inlinee_functions.get(&site.inlinee.0) {
+                            Some(function) => *function,
+                            None => {
+                                let name = match id_finder.find(site.inlinee)?.parse()? {
+                                    pdb::IdData::Function(function) => function.name.to_string().into_owned(),
+                                    pdb::IdData::MemberFunction(function) => function.name.to_string().into_owned(),
+                                    _ => "<unknown inlinee>".to_owned(),
+                                };
+                                let function = inline_info.function(&name);
+                                inlinee_functions.insert(site.inlinee.0, function);
+                                function
+                            }
+                        };
+                        // `scopes` starts with the procedure
+                        let lines = read_lines(inlinee.lines(procedure_offset, &site), &address_map)?;
+                        inline_info.add_lines(scopes.len() - 1, function, lines);
+                    }
+                    scopes.push((site.end, procedure_offset, hit));
+                }
+                _ => {}
+            }
+        }
+    }
+    inline_info.sort();
+    let mut publics = Vec::new();
+    let global_symbols = pdb.global_symbols()?;
+    let mut global_symbols = global_symbols.iter();
+    while let Some(symbol) = global_symbols.next()? {
+        if let Ok(pdb::SymbolData::Public(public)) = symbol.parse() {
+            if !public.function {
+                continue;
+            }
+            if let Some(rva) = public.offset.to_rva(&address_map) {
+                let name = public.name.to_string();
+                let name = msvc_demangler::demangle(&name, DemangleFlags::llvm()).unwrap_or_else(|_| name.into_owned());
+                publics.push(Symbol { address: rva.0, size: None, name });
+            }
+        }
+    }
+
+    symbols.sort_by_key(|symbol| symbol.address);
+    symbols.dedup_by_key(|symbol| symbol.address);
+    publics.retain(|public| symbols.binary_search_by_key(&public.address, |symbol| symbol.address).is_err());
+    symbols.extend(publics);
+    symbols.sort_by_key(|symbol| symbol.address);
+    Ok(PdbSymbols { symbol_table: SymbolTable::new(symbols), inline_info })
+}
+
+#[cfg(test)]
+mod test {
+    use std::path::Path;
+
+    use fxprof_processed_profile::debugid::DebugId;
+    use uuid::Uuid;
+
+    use super::{contains_address, load_pdb_symbols, InlineInfo, SymbolError};
+
+    /// Writes a PDB that only has the information and DBI streams, which is all that's read
+    /// before the debug id is checked
+    fn write_pdb(path: &Path, guid: Uuid, age: u32) {
+        const PAGE_SIZE: usize = 0x1000;
+        let mut info = Vec::new();
+        info.extend(20000404u32.to_le_bytes()); // version
+        info.extend(0u32.to_le_bytes()); // signature
+        info.extend(age.to_le_bytes());
+        info.extend(guid.to_bytes_le());
+        info.extend(0u32.to_le_bytes()); // size of the stream names
+        let mut dbi = vec![0; 64];
+        dbi[0..4].copy_from_slice(&u32::MAX.to_le_bytes());
+        dbi[4..8].copy_from_slice(&19990903u32.to_le_bytes()); // version
+        dbi[8..12].copy_from_slice(&age.to_le_bytes());
+        // Four streams with the information stream on page 3 and the DBI stream on page 4
+        let directory: Vec<u8> = [4, 0, info.len() as u32, 0, dbi.len() as u32, 3, 4].iter().flat_map(|n| n.to_le_bytes()).collect();
+        let mut header = b"Microsoft C/C++ MSF 7.00\r\n\x1a\x44\x53\x00\x00\x00".to_vec();
+        // Page size, free page map, page count, directory size, reserved and the page that lists
+        // the directory's pages
+        for n in [PAGE_SIZE as u32, 1, 5, directory.len() as u32, 0, 1] {
+            header.extend(n.to_le_bytes());
+        }
+        let mut file = Vec::new();
+        for mut page in [header, 2u32.to_le_bytes().to_vec(), directory, info, dbi] {
+            page.resize(PAGE_SIZE, 0);
+            file.extend(page);
+        }
+        std::fs::write(path, file).unwrap();
+    }
+
+    #[test]
+    fn debug_id_mismatch() {
+        let path = std::env::temp_dir().join(format!("etw-gecko-symbolication-test-{}.pdb", std::process::id()));
+        let guid = Uuid::from_u128(0x0123456789abcdef0123456789abcdef);
+        write_pdb(&path, guid, 2);
+        let result = load_pdb_symbols(&path, DebugId::from_parts(guid, 1), &[]);
+        std::fs::remove_file(&path).unwrap();
+        match result {
+            Err(SymbolError::DebugIdMismatch { expected, found }) => {
+                assert_eq!(expected, DebugId::from_parts(guid, 1));
+                assert_eq!(found, DebugId::from_parts(guid, 2));
+            }
+            Err(err) => panic!("unexpected error {:?}", err),
+            Ok(_) => panic!("a PDB for a different build was loaded"),
+        }
+    }
+
+    #[test]
+    fn hit_procedures() {
+        let hits = [0x100, 0x180];
+        assert!(contains_address(&hits, 0x100, 0x10));
+        assert!(contains_address(&hits, 0x170, 0x20));
+        assert!(!contains_address(&hits, 0x110, 0x70));
+        assert!(!contains_address(&hits, 0x200, 0x10));
+        assert!(!contains_address(&[], 0x100, 0x10));
+    }
+
+    #[test]
+    fn inline_lookup() {
+        let mut inline_info = InlineInfo::default();
+        let inlined = inline_info.function("Inlined");
+        let nested = inline_info.function("Nested");
+        inline_info.add_lines(0, inlined, vec![(0x110, None), (0x100, Some(0x8)), (0x120, Some(0x10))]);
+        inline_info.add_lines(1, nested, vec![(0x110, Some(0x8))]);
+        inline_info.sort();
+
+        assert!(inline_info.lookup(0xf0).is_empty());
+        assert_eq!(inline_info.lookup(0x104), ["Inlined"]);
+        assert!(inline_info.lookup(0x108).is_empty());
+        assert_eq!(inline_info.lookup(0x112), ["Inlined", "Nested"]);
+        assert_eq!(inline_info.lookup(0x11c), ["Inlined"]);
+        assert!(inline_info.lookup(0x130).is_empty());
+    }
+}
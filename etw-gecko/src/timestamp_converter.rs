@@ -10,14 +10,21 @@ pub struct TimestampConverter {
 
 impl TimestampConverter {
     pub fn convert_raw(&self, raw: u64) -> Timestamp {
-        Timestamp::from_nanos_since_reference(
-            raw.saturating_sub(self.reference_raw) * self.raw_to_ns_factor,
-        )
+        Timestamp::from_nanos_since_reference(self.raw_to_nanos(raw))
     }
 
     pub fn convert_us(&self, time_us: u64) -> Timestamp {
-        Timestamp::from_nanos_since_reference(
-            (time_us * 1000).saturating_sub(self.reference_raw * self.raw_to_ns_factor),
-        )
+        Timestamp::from_nanos_since_reference(self.us_to_nanos(time_us))
+    }
+
+    /// The nanoseconds since the reference timestamp
+    pub fn raw_to_nanos(&self, raw: u64) -> u64 {
+        raw.saturating_sub(self.reference_raw) * self.raw_to_ns_factor
+    }
+
+    /// The nanoseconds between the reference timestamp and `time_us`, which is in microseconds
+    /// on the same clock
+    pub fn us_to_nanos(&self, time_us: u64) -> u64 {
+        (time_us * 1000).saturating_sub(self.reference_raw * self.raw_to_ns_factor)
     }
 }
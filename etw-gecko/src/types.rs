@@ -3,6 +3,8 @@ use fxhash::FxHasher;
 use std::collections::HashMap;
 use std::hash::BuildHasherDefault;
 
+use super::context_switch::OffCpuReason;
+
 pub type FastHashMap<K, V> = HashMap<K, V, BuildHasherDefault<FxHasher>>;
 
 #[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
@@ -16,4 +18,9 @@ pub enum StackFrame {
     InstructionPointer(u64, StackMode),
     ReturnAddress(u64, StackMode),
     TruncatedStackMarker,
+    /// Why the thread was off the CPU, below the innermost frame of off-cpu samples
+    OffCpuReason(OffCpuReason),
+    /// Below the innermost frame of off-cpu samples for the time the thread was ready to run
+    /// after a wait
+    ReadyMarker,
 }
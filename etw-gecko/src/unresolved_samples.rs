@@ -2,7 +2,7 @@ use std::collections::hash_map::Entry;
 
 use fxprof_processed_profile::{CpuDelta, FrameInfo, ThreadHandle, Timestamp};
 
-use super::process_sample_data::RssStatMember;
+use super::process_sample_data::{RssStatMember, UnblockedMarker};
 use super::types::{FastHashMap, StackFrame, StackMode};
 
 #[derive(Debug, Clone, Default)]
@@ -26,6 +26,10 @@ impl UnresolvedSamples {
         self.samples_and_markers.is_empty()
     }
 
+    pub fn samples_and_markers(&self) -> &[UnresolvedSampleOrMarker] {
+        &self.samples_and_markers
+    }
+
     #[allow(clippy::too_many_arguments)]
     pub fn add_sample(
         &mut self,
@@ -33,7 +37,8 @@ impl UnresolvedSamples {
         timestamp: Timestamp,
         timestamp_mono: u64,
         stack: UnresolvedStackHandle,
-        cpu_delta: CpuDelta,
+        cpu_delta_nanos: u64,
+        off_cpu_nanos: u64,
         weight: i32,
         extra_label_frame: Option<FrameInfo>,
     ) {
@@ -44,13 +49,13 @@ impl UnresolvedSamples {
             timestamp_mono,
             stack,
             extra_label_frame,
-            sample_or_marker: SampleOrMarker::Sample(SampleData { weight, cpu_delta }),
+            sample_or_marker: SampleOrMarker::Sample(SampleData { weight, cpu_delta_nanos, off_cpu_nanos }),
         });
         self.prev_sample_info_per_thread.insert(
             thread_handle,
             PreviousSampleInfo {
                 stack,
-                prev_sample_index_if_zero_cpu: (cpu_delta == CpuDelta::ZERO)
+                prev_sample_index_if_zero_cpu: (CpuDelta::from_nanos(cpu_delta_nanos) == CpuDelta::ZERO)
                     .then_some(sample_index),
             },
         );
@@ -84,7 +89,8 @@ impl UnresolvedSamples {
                         extra_label_frame,
                         sample_or_marker: SampleOrMarker::Sample(SampleData {
                             weight,
-                            cpu_delta: CpuDelta::ZERO,
+                            cpu_delta_nanos: 0,
+                            off_cpu_nanos: 0,
                         }),
                     });
                     sample_info.prev_sample_index_if_zero_cpu = Some(sample_index);
@@ -101,7 +107,8 @@ impl UnresolvedSamples {
                     extra_label_frame,
                     sample_or_marker: SampleOrMarker::Sample(SampleData {
                         weight,
-                        cpu_delta: CpuDelta::ZERO,
+                        cpu_delta_nanos: 0,
+                        off_cpu_nanos: 0,
                     }),
                 });
                 entry.insert(PreviousSampleInfo {
@@ -154,6 +161,24 @@ impl UnresolvedSamples {
             sample_or_marker: SampleOrMarker::OtherEventMarker(OtherEventMarkerData { attr_index }),
         });
     }
+
+    pub fn add_unblocked_marker(
+        &mut self,
+        thread_handle: ThreadHandle,
+        timestamp: Timestamp,
+        timestamp_mono: u64,
+        stack: UnresolvedStackHandle,
+        marker: UnblockedMarker,
+    ) {
+        self.samples_and_markers.push(UnresolvedSampleOrMarker {
+            thread_handle,
+            timestamp,
+            timestamp_mono,
+            stack,
+            extra_label_frame: None,
+            sample_or_marker: SampleOrMarker::UnblockedMarker(marker),
+        });
+    }
 }
 
 #[derive(Debug, Clone)]
@@ -171,11 +196,16 @@ pub enum SampleOrMarker {
     Sample(SampleData),
     RssStatMarker(RssStatMarkerData),
     OtherEventMarker(OtherEventMarkerData),
+    /// Has the stack of the thread that readied the sample's thread
+    UnblockedMarker(UnblockedMarker),
 }
 
 #[derive(Debug, Clone)]
 pub struct SampleData {
-    pub cpu_delta: CpuDelta,
+    /// The running time since the previous sample on this thread
+    pub cpu_delta_nanos: u64,
+    /// The time that this sample stands for while the thread wasn't running. Zero for on-cpu samples.
+    pub off_cpu_nanos: u64,
     pub weight: i32,
 }
 
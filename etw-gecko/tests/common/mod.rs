@@ -0,0 +1,410 @@
+//! A harness for running synthetic traces through [TraceConverter]
+//!
+//! Events are described by name and a map of property values and are encoded using the schemas
+//! that etw-reader has built in, so no .etl files or TDH are needed. Traces can be written with
+//! [TraceBuilder] or loaded from a JSON fixture (see `tests/fixtures`). The resulting profile is
+//! turned into a text summary with [summarize] that's easy to compare against a snapshot.
+#![allow(dead_code)]
+
+use std::{borrow::Cow, collections::{HashMap, HashSet}, fmt::Write, path::Path};
+
+use etw_gecko::{error::SkippedEvents, ConversionStats, ConvertOptions, LatencyHistogram, TraceConverter};
+use etw_reader::{
+    etw_types::{EventDescriptor, EventRecord},
+    tdh_types::{Property, PropertyDesc, PropertyLength, TdhInType},
+    GUID,
+};
+use serde::Deserialize;
+use serde_json::{json, Value};
+
+/// The QPC frequency of the synthetic traces. Timestamps are in 100ns ticks like on real machines.
+pub const PERF_FREQ: u64 = 10_000_000;
+/// The sampling interval that [TraceBuilder::new] sets, in ticks (1ms)
+pub const SAMPLE_INTERVAL: u64 = 10_000;
+/// The offset of `RtlUserThreadStart` in the ntdll.dll of the synthetic traces, which is the
+/// outermost frame of complete user stacks
+pub const RTL_USER_THREAD_START: u64 = 0x5aa0;
+/// Addresses from here on are in the kernel
+const KERNEL_SPACE_START: u64 = 0xffff_8000_0000_0000;
+
+/// One event of a fixture
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct FixtureEvent {
+    /// The full name of the event, e.g. "MSNT_SystemTrace/Thread/CSwitch"
+    pub event: String,
+    pub timestamp: i64,
+    #[serde(default)]
+    pub pid: u32,
+    #[serde(default)]
+    pub tid: u32,
+    #[serde(default)]
+    pub cpu: u16,
+    /// Property values by name. Missing properties are zero or empty.
+    #[serde(default)]
+    pub properties: serde_json::Map<String, Value>,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Fixture {
+    /// A process id or a substring of the image name, like etw-gecko's second argument
+    pub target: String,
+    #[serde(default)]
+    pub merge_threads: bool,
+    #[serde(default)]
+    pub include_idle: bool,
+    pub events: Vec<FixtureEvent>,
+}
+
+impl Fixture {
+    pub fn load(path: &Path) -> Fixture {
+        let file = std::fs::File::open(path).unwrap_or_else(|err| panic!("failed to open {}: {}", path.display(), err));
+        serde_json::from_reader(file).unwrap_or_else(|err| panic!("failed to parse {}: {}", path.display(), err))
+    }
+
+    pub fn options(&self) -> ConvertOptions {
+        let mut options = ConvertOptions { merge_threads: self.merge_threads, include_idle: self.include_idle, ..Default::default() };
+        match self.target.parse() {
+            Ok(process_id) => { options.process_targets.insert(process_id); }
+            Err(_) => options.process_target_name = Some(self.target.clone()),
+        }
+        options
+    }
+
+    /// Converts the fixture and returns the [summarize]d profile
+    pub fn convert(&self) -> String {
+        let mut converter = TraceConverter::new(self.options());
+        for event in &self.events {
+            feed(&mut converter, event);
+        }
+        summarize(&serde_json::to_value(converter.finish(&[], None)).unwrap())
+    }
+}
+
+/// Encodes `event` and hands it to `converter`
+pub fn feed(converter: &mut TraceConverter, event: &FixtureEvent) {
+    let schema = converter.schema_locator().schema_by_name(&event.event)
+        .unwrap_or_else(|| panic!("no schema for {}", event.event));
+    let mut user_data = Vec::new();
+    let mut unused: HashSet<&str> = event.properties.keys().map(String::as_str).collect();
+    for i in 0..schema.property_count() {
+        let property = schema.property(i);
+        unused.remove(property.name.as_str());
+        encode_property(&mut user_data, &property, event.properties.get(&property.name));
+    }
+    assert!(unused.is_empty(), "{} has no properties called {:?}", event.event, unused);
+
+    let record = EventRecord {
+        provider_id: schema.provider_guid(),
+        descriptor: EventDescriptor {
+            id: schema.event_id(),
+            version: schema.event_version(),
+            opcode: schema.opcode(),
+            level: schema.level(),
+            ..Default::default()
+        },
+        process_id: event.pid,
+        thread_id: event.tid,
+        timestamp: event.timestamp,
+        processor_index: event.cpu,
+        user_data: Cow::Owned(user_data),
+        ..Default::default()
+    };
+    let s = converter.schema_locator().event_schema(&record).unwrap();
+    converter.handle_event(&s).unwrap();
+}
+
+fn encode_property(out: &mut Vec<u8>, property: &Property, value: Option<&Value>) {
+    if property.is_array() {
+        // Only the elements that are given are written, like the addresses of a StackWalk/Stack
+        for element in value.and_then(Value::as_array).into_iter().flatten() {
+            encode_element(out, property, Some(element));
+        }
+    } else {
+        encode_element(out, property, value);
+    }
+}
+
+fn encode_element(out: &mut Vec<u8>, property: &Property, value: Option<&Value>) {
+    use TdhInType::*;
+    let PropertyDesc::Primitive(desc) = &property.desc else {
+        panic!("{} is a struct which isn't supported", property.name)
+    };
+    let int = || match value {
+        None => 0,
+        Some(Value::String(s)) if s.starts_with("0x") => u64::from_str_radix(&s[2..], 16).unwrap(),
+        Some(value) => value.as_u64().or_else(|| value.as_i64().map(|v| v as u64))
+            .unwrap_or_else(|| panic!("{} should be an integer, not {}", property.name, value)),
+    };
+    let string = || match value {
+        None => "",
+        Some(value) => value.as_str().unwrap_or_else(|| panic!("{} should be a string, not {}", property.name, value)),
+    };
+    match desc.in_type {
+        InTypeInt8 | InTypeUInt8 => out.push(int() as u8),
+        InTypeInt16 | InTypeUInt16 => out.extend_from_slice(&(int() as u16).to_le_bytes()),
+        InTypeInt32 | InTypeUInt32 | InTypeHexInt32 | InTypeBoolean => out.extend_from_slice(&(int() as u32).to_le_bytes()),
+        InTypeInt64 | InTypeUInt64 | InTypeHexInt64 | InTypePointer | InTypeSizeT => out.extend_from_slice(&int().to_le_bytes()),
+        InTypeUnicodeString => {
+            for c in string().encode_utf16().chain([0]) {
+                out.extend_from_slice(&c.to_le_bytes());
+            }
+        }
+        InTypeAnsiString => {
+            out.extend_from_slice(string().as_bytes());
+            out.push(0);
+        }
+        InTypeGuid => {
+            let guid = if value.is_some() { GUID::from(string()) } else { GUID::zeroed() };
+            out.extend_from_slice(&guid.data1.to_le_bytes());
+            out.extend_from_slice(&guid.data2.to_le_bytes());
+            out.extend_from_slice(&guid.data3.to_le_bytes());
+            out.extend_from_slice(&guid.data4);
+        }
+        // An empty TOKEN_USER
+        InTypeWBEMSID if value.is_none() => out.extend_from_slice(&[0; 4]),
+        InTypeBinary if value.is_none() => match property.length {
+            PropertyLength::Length(length) => out.resize(out.len() + length as usize, 0),
+            PropertyLength::Index(_) => {}
+        },
+        in_type => panic!("{} has an in-type that can't be encoded: {:?}", property.name, in_type),
+    }
+}
+
+/// Builds a trace event by event. The trace starts with a header and a 1ms sampling interval.
+pub struct TraceBuilder {
+    converter: TraceConverter,
+    timestamp: i64,
+    events: Vec<FixtureEvent>,
+    /// Where ntdll.dll was loaded in each process
+    ntdll_bases: HashMap<u32, u64>,
+}
+
+impl TraceBuilder {
+    pub fn new(options: ConvertOptions) -> Self {
+        let mut builder = TraceBuilder { converter: TraceConverter::new(options), timestamp: 0, events: Vec::new(), ntdll_bases: HashMap::new() };
+        builder.event("MSNT_SystemTrace/EventTrace/Header", 0, 0, json!({
+            "PerfFreq": PERF_FREQ,
+            "TimerResolution": 156250,
+            "PointerSize": 8,
+            // QPC
+            "ReservedFlags": 1,
+        }));
+        builder.event("MSNT_SystemTrace/PerfInfo/CollectionStart", 0, 0, json!({
+            "NewInterval": SAMPLE_INTERVAL,
+        }));
+        builder
+    }
+
+    /// A builder that traces the process with id `process_id`
+    pub fn for_process(process_id: u32) -> Self {
+        TraceBuilder::new(ConvertOptions { process_targets: HashSet::from([process_id]), ..Default::default() })
+    }
+
+    /// Sets the timestamp of the following events, in ticks
+    pub fn at(&mut self, timestamp: i64) -> &mut Self {
+        self.timestamp = timestamp;
+        self
+    }
+
+    pub fn event(&mut self, name: &str, pid: u32, tid: u32, properties: Value) -> &mut Self {
+        let Value::Object(properties) = properties else { panic!("properties should be an object") };
+        let event = FixtureEvent { event: name.to_owned(), timestamp: self.timestamp, pid, tid, cpu: 0, properties };
+        feed(&mut self.converter, &event);
+        self.events.push(event);
+        self
+    }
+
+    pub fn process_start(&mut self, pid: u32, image_file_name: &str) -> &mut Self {
+        self.event("MSNT_SystemTrace/Process/Start", pid, 0, json!({
+            "ProcessId": pid,
+            "ImageFileName": image_file_name,
+        }))
+    }
+
+    pub fn thread_start(&mut self, pid: u32, tid: u32, name: &str) -> &mut Self {
+        self.event("MSNT_SystemTrace/Thread/Start", pid, tid, json!({
+            "ProcessId": pid,
+            "TThreadId": tid,
+            "ThreadName": name,
+        }))
+    }
+
+    /// Loads an image along with the KernelTraceControl events that describe it. Use a `pid`
+    /// of 0 for kernel images.
+    pub fn image_load(&mut self, pid: u32, base: u64, size: u64, name: &str) -> &mut Self {
+        if name.eq_ignore_ascii_case("ntdll.dll") {
+            self.ntdll_bases.insert(pid, base);
+        }
+        let pdb_name = Path::new(name).with_extension("pdb");
+        self.event("KernelTraceControl/ImageID/", pid, 0, json!({
+            "ImageBase": base,
+            "ImageSize": size,
+            "TimeDateStamp": 0x5f000000,
+            "OriginalFileName": name,
+        }));
+        self.event("KernelTraceControl/ImageID/DbgID_RSDS", pid, 0, json!({
+            "ImageBase": base,
+            "ProcessId": pid,
+            "GuidSig": "01234567-89ab-cdef-0123-456789abcdef",
+            "Age": 1,
+            "PdbFileName": pdb_name.to_str().unwrap(),
+        }));
+        self.event("MSNT_SystemTrace/Image/Load", pid, 0, json!({
+            "ImageBase": base,
+            "ImageSize": size,
+            "ProcessId": pid,
+            "FileName": format!("\\Device\\HarddiskVolume1\\{}", name),
+        }))
+    }
+
+    /// A SampleProf event. Its stacks need to follow with [TraceBuilder::stack].
+    pub fn sample(&mut self, tid: u32, instruction_pointer: u64) -> &mut Self {
+        self.event("MSNT_SystemTrace/PerfInfo/SampleProf", 0, tid, json!({
+            "InstructionPointer": instruction_pointer,
+            "ThreadId": tid,
+            "Count": 1,
+        }))
+    }
+
+    /// A StackWalk/Stack event for the event at `event_timestamp`, with the innermost frame first.
+    /// Like in real traces, user stacks end in `RtlUserThreadStart` if ntdll.dll was loaded into
+    /// the process. Use [TraceBuilder::truncated_stack] for stacks where stack walking gave up.
+    pub fn stack(&mut self, pid: u32, tid: u32, event_timestamp: i64, frames: &[u64]) -> &mut Self {
+        let mut frames = frames.to_vec();
+        let is_user_stack = frames.first().is_some_and(|address| *address < KERNEL_SPACE_START);
+        if let Some(ntdll_base) = self.ntdll_bases.get(&pid).filter(|_| is_user_stack) {
+            frames.push(ntdll_base + RTL_USER_THREAD_START);
+        }
+        self.truncated_stack(pid, tid, event_timestamp, &frames)
+    }
+
+    /// A StackWalk/Stack event with exactly the given frames, innermost first
+    pub fn truncated_stack(&mut self, pid: u32, tid: u32, event_timestamp: i64, frames: &[u64]) -> &mut Self {
+        self.event("MSNT_SystemTrace/StackWalk/Stack", pid, tid, json!({
+            "EventTimeStamp": event_timestamp,
+            "StackProcess": pid,
+            "StackThread": tid,
+            "Stack": frames,
+        }))
+    }
+
+    /// A context switch where `old_tid` starts waiting for a user request
+    pub fn cswitch(&mut self, old_tid: u32, new_tid: u32) -> &mut Self {
+        self.event("MSNT_SystemTrace/Thread/CSwitch", 0, new_tid, json!({
+            "NewThreadId": new_tid,
+            "OldThreadId": old_tid,
+            // Waiting
+            "OldThreadState": 5,
+            // UserRequest
+            "OldThreadWaitReason": 6,
+        }))
+    }
+
+    /// A ReadyThread event for `tid`, logged by the readying thread. Its stack needs to follow
+    /// with [TraceBuilder::stack].
+    pub fn ready_thread(&mut self, readying_pid: u32, readying_tid: u32, tid: u32) -> &mut Self {
+        self.event("MSNT_SystemTrace/Thread/ReadyThread", readying_pid, readying_tid, json!({
+            "TThreadId": tid,
+        }))
+    }
+
+    pub fn timestamp(&self) -> i64 {
+        self.timestamp
+    }
+
+    pub fn stats(&self) -> ConversionStats {
+        self.converter.stats()
+    }
+
+    pub fn skipped_events(&self) -> &SkippedEvents {
+        self.converter.skipped_events()
+    }
+
+    pub fn ready_latencies(&self) -> Vec<(String, LatencyHistogram)> {
+        self.converter.ready_latencies().map(|(thread, histogram)| (thread, histogram.clone())).collect()
+    }
+
+    /// The events so far, in the format of [Fixture::events]
+    pub fn events(&self) -> &[FixtureEvent] {
+        &self.events
+    }
+
+    /// Finishes the conversion and returns the [summarize]d profile
+    pub fn convert(self) -> String {
+        summarize(&serde_json::to_value(self.converter.finish(&[], None)).unwrap())
+    }
+}
+
+/// Describes the threads, samples and libraries of a processed profile as text. Sample stacks are
+/// written root first as `lib!address` frames with addresses relative to the library.
+pub fn summarize(profile: &Value) -> String {
+    let mut out = String::new();
+    let interval = profile["meta"]["interval"].as_f64().unwrap();
+    writeln!(out, "interval: {}ms", interval).unwrap();
+    for lib in profile["libs"].as_array().unwrap() {
+        writeln!(out, "lib {} {} {}", lib["name"].as_str().unwrap(), lib["debugName"].as_str().unwrap(), lib["breakpadId"].as_str().unwrap()).unwrap();
+    }
+
+    let mut threads: Vec<&Value> = profile["threads"].as_array().unwrap().iter().collect();
+    threads.sort_by_key(|thread| (thread["pid"].to_string(), thread["tid"].to_string()));
+    for thread in threads {
+        writeln!(out).unwrap();
+        writeln!(out, "thread {:?} pid={} tid={}{}", thread["name"].as_str().unwrap(), json_id(&thread["pid"]), json_id(&thread["tid"]),
+            if thread["isMainThread"].as_bool() == Some(true) { " main" } else { "" }).unwrap();
+        write_samples(&mut out, thread);
+        let markers = &thread["markers"];
+        for i in 0..markers["length"].as_u64().unwrap() as usize {
+            let name = &thread["stringArray"][markers["name"][i].as_u64().unwrap() as usize];
+            writeln!(out, "  marker {} {}", name.as_str().unwrap(), markers["data"][i]["name"].as_str().unwrap_or("")).unwrap();
+        }
+    }
+    out
+}
+
+fn json_id(value: &Value) -> String {
+    match value {
+        Value::String(s) => s.clone(),
+        value => value.to_string(),
+    }
+}
+
+fn write_samples(out: &mut String, thread: &Value) {
+    let samples = &thread["samples"];
+    let stack_table = &thread["stackTable"];
+    let frame_table = &thread["frameTable"];
+    let func_table = &thread["funcTable"];
+    let resource_table = &thread["resourceTable"];
+    let strings = &thread["stringArray"];
+    let string = |index: &Value| strings[index.as_u64().unwrap() as usize].as_str().unwrap().to_owned();
+
+    let mut time = 0.;
+    for i in 0..samples["length"].as_u64().unwrap() as usize {
+        // Newer versions of the format store the deltas between sample times
+        match samples.get("timeDeltas") {
+            Some(deltas) => time += deltas[i].as_f64().unwrap(),
+            None => time = samples["time"][i].as_f64().unwrap(),
+        }
+        let weight = samples["weight"].get(i).and_then(Value::as_i64).unwrap_or(1);
+        let cpu_delta = samples["threadCPUDelta"].get(i).and_then(Value::as_u64).unwrap_or(0);
+
+        let mut frames = Vec::new();
+        let mut stack = samples["stack"][i].as_u64();
+        while let Some(stack_index) = stack {
+            let frame = stack_table["frame"][stack_index as usize].as_u64().unwrap() as usize;
+            let func = frame_table["func"][frame].as_u64().unwrap() as usize;
+            let name = string(&func_table["name"][func]);
+            let resource = func_table["resource"][func].as_i64().unwrap();
+            frames.push(if resource < 0 {
+                name
+            } else {
+                let address = frame_table["address"][frame].as_i64().unwrap();
+                format!("{}!0x{:x}", string(&resource_table["name"][resource as usize]), address)
+            });
+            stack = stack_table["prefix"][stack_index as usize].as_u64();
+        }
+        frames.reverse();
+        writeln!(out, "  {:.3}ms cpu={}us weight={} {}", time, cpu_delta, weight, frames.join(" > ")).unwrap();
+    }
+}
@@ -0,0 +1,259 @@
+//! End-to-end tests that run synthetic traces through the converter and compare a summary of the
+//! resulting profile against the snapshots in `tests/snapshots`. Run with `INSTA_UPDATE=always`
+//! or use `cargo insta review` to accept changes.
+mod common;
+
+use std::path::Path;
+
+use common::{Fixture, TraceBuilder, SAMPLE_INTERVAL};
+use etw_gecko::ConvertOptions;
+use serde_json::json;
+
+const KERNEL_BASE: u64 = 0xfffff800_00000000;
+const XUL_BASE: u64 = 0x7ff0_0000_0000;
+const NTDLL_BASE: u64 = 0x7ff1_0000_0000;
+
+/// A trace for firefox.exe (pid 100) with a main thread (101), a kernel and two user images
+fn firefox() -> TraceBuilder {
+    let mut trace = TraceBuilder::for_process(100);
+    trace.at(1000)
+        .image_load(0, KERNEL_BASE, 0x100000, "ntoskrnl.exe")
+        .process_start(100, "firefox.exe")
+        .thread_start(100, 101, "Main")
+        .image_load(100, XUL_BASE, 0x100000, "xul.dll")
+        .image_load(100, NTDLL_BASE, 0x10000, "ntdll.dll");
+    trace
+}
+
+#[test]
+fn fixtures() {
+    let dir = Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/fixtures");
+    let mut paths: Vec<_> = std::fs::read_dir(&dir).unwrap().map(|entry| entry.unwrap().path()).collect();
+    paths.sort();
+    for path in paths {
+        let name = path.file_stem().unwrap().to_str().unwrap().to_owned();
+        insta::assert_snapshot!(name, Fixture::load(&path).convert());
+    }
+}
+
+#[test]
+fn stack_pairing() {
+    let mut trace = firefox();
+    // A sample with a kernel and a user stack
+    trace.at(10_000)
+        .sample(101, KERNEL_BASE + 0x10)
+        .stack(100, 101, 10_000, &[KERNEL_BASE + 0x10, KERNEL_BASE + 0x20])
+        .stack(100, 101, 10_000, &[NTDLL_BASE + 0x30, XUL_BASE + 0x40]);
+    // Two samples in user mode that share the user stack that's logged once the thread leaves
+    // the kernel
+    trace.at(20_000).sample(101, XUL_BASE + 0x50);
+    trace.at(30_000)
+        .sample(101, KERNEL_BASE + 0x60)
+        .stack(100, 101, 30_000, &[KERNEL_BASE + 0x60]);
+    trace.at(31_000).stack(100, 101, 30_000, &[XUL_BASE + 0x70, XUL_BASE + 0x80]);
+    // A sample whose user stack never arrives
+    trace.at(40_000).sample(101, XUL_BASE + 0x90);
+    insta::assert_snapshot!(trace.convert());
+}
+
+#[test]
+fn off_cpu_samples() {
+    let mut trace = firefox();
+    trace.at(10_000).cswitch(0, 101);
+    trace.at(15_000)
+        .sample(101, XUL_BASE + 0x10)
+        .stack(100, 101, 15_000, &[XUL_BASE + 0x10, XUL_BASE + 0x20]);
+    // Blocked for 5.5 sampling intervals, then woken up and sampled again
+    trace.at(18_000).cswitch(101, 0);
+    trace.at(18_000 + 11 * SAMPLE_INTERVAL as i64 / 2).cswitch(0, 101);
+    let woken = trace.timestamp();
+    trace.stack(100, 101, woken, &[XUL_BASE + 0x30, XUL_BASE + 0x20]);
+    trace.at(woken + 4_000)
+        .sample(101, XUL_BASE + 0x40)
+        .stack(100, 101, woken + 4_000, &[XUL_BASE + 0x40, XUL_BASE + 0x20]);
+    insta::assert_snapshot!(trace.convert());
+}
+
+#[test]
+fn unblocking_stacks() {
+    let mut trace = firefox();
+    trace.at(2000).thread_start(100, 102, "Worker");
+    trace.at(10_000).cswitch(0, 101);
+    trace.at(18_000).cswitch(101, 0);
+    // The worker releases what the main thread is waiting on
+    let ready = 18_000 + 11 * SAMPLE_INTERVAL as i64 / 2;
+    trace.at(ready)
+        .ready_thread(100, 102, 101)
+        .stack(100, 102, ready, &[XUL_BASE + 0x50, XUL_BASE + 0x60]);
+    trace.at(ready + 100).cswitch(0, 101);
+    let woken = trace.timestamp();
+    trace.stack(100, 101, woken, &[XUL_BASE + 0x30, XUL_BASE + 0x20]);
+    let summary = trace.convert();
+    assert!(summary.contains("marker Unblocked"), "{}", summary);
+}
+
+#[test]
+fn ready_time() {
+    let mut trace = firefox();
+    trace.at(2000).thread_start(100, 102, "Worker");
+    trace.at(10_000).cswitch(0, 101);
+    trace.at(18_000).cswitch(101, 0);
+    // Readied after waiting for 3 intervals but only switched in 2.5 intervals later
+    let ready = 18_000 + 3 * SAMPLE_INTERVAL as i64;
+    trace.at(ready)
+        .ready_thread(100, 102, 101)
+        .stack(100, 102, ready, &[XUL_BASE + 0x50]);
+    trace.at(ready + 5 * SAMPLE_INTERVAL as i64 / 2).cswitch(0, 101);
+    let woken = trace.timestamp();
+    trace.stack(100, 101, woken, &[XUL_BASE + 0x30, XUL_BASE + 0x20]);
+    let latencies = trace.ready_latencies();
+    assert_eq!(latencies.len(), 1);
+    assert_eq!(latencies[0].0, "firefox.exe (100) Main (101)");
+    assert_eq!((latencies[0].1.count, latencies[0].1.total), (1, 2_500_000));
+    let summary = trace.convert();
+    assert!(summary.contains("> Waiting: UserRequest\n"), "{}", summary);
+    assert!(summary.contains("> Ready\n"), "{}", summary);
+}
+
+#[test]
+fn ready_time_without_switch_in() {
+    let mut trace = firefox();
+    trace.at(2000).thread_start(100, 102, "Worker");
+    trace.at(10_000).cswitch(0, 101);
+    trace.at(18_000).cswitch(101, 0);
+    let ready = 18_000 + 3 * SAMPLE_INTERVAL as i64;
+    trace.at(ready)
+        .ready_thread(100, 102, 101)
+        .stack(100, 102, ready, &[XUL_BASE + 0x50]);
+    // The switch-in is missing, so the sample is the first sign of the thread running again
+    trace.at(ready + 5 * SAMPLE_INTERVAL as i64 / 2).sample(101, XUL_BASE + 0x30);
+    let sampled = trace.timestamp();
+    trace.stack(100, 101, sampled, &[XUL_BASE + 0x30, XUL_BASE + 0x20]);
+    let latencies = trace.ready_latencies();
+    assert_eq!((latencies.len(), latencies[0].1.total), (1, 2_500_000));
+    let summary = trace.convert();
+    assert!(summary.contains("> Waiting: UserRequest\n"), "{}", summary);
+    assert!(summary.contains("> Ready\n"), "{}", summary);
+}
+
+#[test]
+fn lib_mapping() {
+    // A library that's loaded later only applies to later samples
+    let late_base = 0x7ff2_0000_0000;
+    let mut trace = firefox();
+    trace.at(10_000)
+        .sample(101, 0x1234)
+        .stack(100, 101, 10_000, &[0x1234, XUL_BASE + 0xfffff, XUL_BASE + 0x100000, NTDLL_BASE, late_base + 0x10]);
+    trace.at(20_000).image_load(100, late_base, 0x1000, "late.dll");
+    trace.at(30_000)
+        .sample(101, late_base + 0x10)
+        .stack(100, 101, 30_000, &[late_base + 0x10]);
+    insta::assert_snapshot!(trace.convert());
+}
+
+#[test]
+fn merge_threads() {
+    let mut trace = TraceBuilder::new(ConvertOptions { merge_threads: true, process_target_name: Some("firefox".to_owned()), ..Default::default() });
+    trace.at(1000)
+        .process_start(100, "firefox.exe")
+        .thread_start(100, 101, "Main")
+        .thread_start(100, 102, "DOM Worker#12")
+        .image_load(100, XUL_BASE, 0x100000, "xul.dll");
+    trace.at(10_000)
+        .sample(101, XUL_BASE + 0x10)
+        .stack(100, 101, 10_000, &[XUL_BASE + 0x10]);
+    trace.at(20_000)
+        .sample(102, XUL_BASE + 0x20)
+        .stack(100, 102, 20_000, &[XUL_BASE + 0x20]);
+    // Not a thread of the target
+    trace.at(30_000).sample(200, XUL_BASE + 0x30);
+    insta::assert_snapshot!(trace.convert());
+}
+
+#[test]
+fn cpu_tracks() {
+    let mut trace = TraceBuilder::new(ConvertOptions { cpu_tracks: true, process_target_name: Some("firefox".to_owned()), ..Default::default() });
+    trace.at(1000)
+        .process_start(100, "firefox.exe")
+        .thread_start(100, 101, "Main")
+        .process_start(200, "other.exe")
+        .thread_start(200, 201, "Other");
+    trace.at(10_000).cswitch(0, 101);
+    trace.at(20_000).cswitch(101, 201);
+    trace.at(30_000).cswitch(201, 0);
+    let summary = trace.convert();
+    assert!(summary.contains("thread \"CPU 0\""), "{}", summary);
+    assert!(summary.contains("marker firefox.exe (100)"), "{}", summary);
+    assert!(summary.contains("marker other.exe (200)"), "{}", summary);
+}
+
+#[test]
+fn all_processes() {
+    let mut trace = TraceBuilder::new(ConvertOptions { all_processes: true, ..Default::default() });
+    trace.at(1000)
+        .image_load(0, KERNEL_BASE, 0x100000, "ntoskrnl.exe")
+        .process_start(0, "")
+        .thread_start(0, 0, "")
+        .process_start(4, "System")
+        .thread_start(4, 8, "")
+        .process_start(100, "firefox.exe")
+        .thread_start(100, 101, "Main")
+        .process_start(200, "other.exe")
+        .thread_start(200, 201, "Other")
+        .image_load(200, XUL_BASE, 0x100000, "other.dll");
+    // The idle and System threads only get kernel stacks
+    trace.at(10_000)
+        .sample(0, KERNEL_BASE + 0x10)
+        .stack(0, 0, 10_000, &[KERNEL_BASE + 0x10, KERNEL_BASE + 0x20]);
+    trace.at(20_000)
+        .sample(8, KERNEL_BASE + 0x30)
+        .stack(4, 8, 20_000, &[KERNEL_BASE + 0x30, KERNEL_BASE + 0x40]);
+    trace.at(30_000)
+        .sample(201, XUL_BASE + 0x50)
+        .stack(200, 201, 30_000, &[XUL_BASE + 0x50]);
+    assert_eq!(trace.stats().dropped_samples, 0);
+    assert_eq!(trace.stats().truncated_stacks, 0);
+    let summary = trace.convert();
+    for thread in ["pid=0 tid=0", "pid=4 tid=8", "pid=100 tid=101", "pid=200 tid=201"] {
+        assert!(summary.contains(thread), "{}", summary);
+    }
+    assert!(summary.contains("ntoskrnl.exe!0x30\n"), "{}", summary);
+    assert!(summary.contains("other.dll!0x50\n"), "{}", summary);
+}
+
+#[test]
+fn truncated_stacks() {
+    let mut trace = firefox();
+    // A complete stack that starts in ntdll
+    trace.at(10_000)
+        .sample(101, XUL_BASE + 0x10)
+        .stack(100, 101, 10_000, &[XUL_BASE + 0x10, XUL_BASE + 0x20]);
+    // Stack walking stopped in xul.dll and at an address outside of any library
+    trace.at(20_000)
+        .sample(101, XUL_BASE + 0x10)
+        .truncated_stack(100, 101, 20_000, &[XUL_BASE + 0x10, XUL_BASE + 0x20]);
+    trace.at(30_000)
+        .sample(101, XUL_BASE + 0x10)
+        .truncated_stack(100, 101, 30_000, &[XUL_BASE + 0x10, 0x1234]);
+    assert_eq!(trace.stats().truncated_stacks, 2);
+    insta::assert_snapshot!(trace.convert());
+}
+
+#[test]
+fn skipped_events() {
+    let mut trace = firefox();
+    // Debug info for an image base without an ImageID event is skipped and conversion carries on
+    trace.at(10_000)
+        .event("KernelTraceControl/ImageID/DbgID_RSDS", 100, 0, json!({
+            "ImageBase": 0x1000_0000u64,
+            "ProcessId": 100,
+            "GuidSig": "01234567-89ab-cdef-0123-456789abcdef",
+            "Age": 1,
+            "PdbFileName": "unknown.pdb",
+        }))
+        .sample(101, XUL_BASE + 0x10)
+        .stack(100, 101, 10_000, &[XUL_BASE + 0x10]);
+    let skipped: Vec<_> = trace.skipped_events().iter().collect();
+    assert_eq!(skipped, [("KernelTraceControl/ImageID/DbgID_RSDS: unknown image base", 1)]);
+    assert_eq!(trace.stats().samples, 1);
+}
@@ -8,7 +8,7 @@ fn main() {
     open_trace(Path::new(&std::env::args().nth(1).unwrap()), |e| {
         let s = schema_locator.event_schema(e);
         if let Ok(s) = s {
-            let name = format!("{} {:?}/{}/{}", s.name(), e.EventHeader.ProviderId, e.EventHeader.EventDescriptor.Id, e.EventHeader.EventDescriptor.Opcode);
+            let name = format!("{} {:?}/{}/{}", s.name(), e.provider_id, e.descriptor.id, e.descriptor.opcode);
             if let Some(count) = event_counts.get_mut(&name) {
                 *count += 1;
             } else {
@@ -16,7 +16,7 @@ fn main() {
             }
         } else {
             let provider_name = 
-            match e.EventHeader.ProviderId {
+            match e.provider_id {
                 GUID{ data1: 0x9B79EE91, data2: 0xB5FD, data3: 0x41C0, data4: [0xA2, 0x43, 0x42, 0x48, 0xE2, 0x66, 0xE9, 0xD0]} => "SysConfig ",
                 GUID{ data1: 0xB3E675D7, data2: 0x2554, data3: 0x4F18, data4: [0x83, 0x0B, 0x27, 0x62, 0x73, 0x25, 0x60, 0xDE]} => "KernelTraceControl ",
                 GUID{ data1: 0xED54DFF8, data2: 0xC409, data3: 0x4CF6, data4: [0xBF, 0x83, 0x05, 0xE1, 0xE6, 0x1A, 0x09, 0xC4]} => "WinSat ",
@@ -26,7 +26,7 @@ fn main() {
                 _ => ""
             };
 
-            let provider = format!("{}{:?}/{}-{}/{}", provider_name,  e.EventHeader.ProviderId, e.EventHeader.EventDescriptor.Id, e.EventHeader.EventDescriptor.Version, e.EventHeader.EventDescriptor.Task);
+            let provider = format!("{}{:?}/{}-{}/{}", provider_name,  e.provider_id, e.descriptor.id, e.descriptor.version, e.descriptor.task);
             *event_counts.entry(provider).or_insert(0) += 1;
         }
     });
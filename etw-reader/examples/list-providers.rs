@@ -1,6 +1,13 @@
+#[cfg(windows)]
 use etw_reader::{enumerate_trace_guids_ex, tdh};
 
+#[cfg(windows)]
 pub fn main() {
     tdh::list_etw_providers();
     enumerate_trace_guids_ex(false);
-}
\ No newline at end of file
+}
+
+#[cfg(not(windows))]
+pub fn main() {
+    eprintln!("Listing providers is only supported on Windows");
+}
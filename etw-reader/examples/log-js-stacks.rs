@@ -55,10 +55,10 @@ fn main() {
     let mut jscript_symbols: HashMap<u32, BTreeMap<u64, (u64, String)>> = HashMap::new();
     let mut jscript_sources: HashMap<u64, String> = HashMap::new();
     open_trace(Path::new(&std::env::args().nth(1).unwrap()), |e| {
-        //dbg!(e.EventHeader.TimeStamp);
+        //dbg!(e.timestamp);
 
         let s = schema_locator.event_schema(e);
-        let mut thread_id = e.EventHeader.ThreadId;
+        let mut thread_id = e.thread_id;
         if let Ok(s) = s {
             match s.name() {
                 "MSNT_SystemTrace/StackWalk/Stack" => {
@@ -170,7 +170,7 @@ fn main() {
             if pattern.is_none() {
                 /*println!(
                     "unknown event {:x?}:{}",
-                    e.EventHeader.ProviderId, e.EventHeader.EventDescriptor.Opcode
+                    e.provider_id, e.descriptor.opcode
                 );*/
             }
         }
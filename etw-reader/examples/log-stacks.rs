@@ -49,10 +49,10 @@ fn main() {
     let mut events: Vec<Event> = Vec::new();
     let mut threads = HashMap::new();
     open_trace(Path::new(&std::env::args().nth(1).unwrap()), |e| {
-        //dbg!(e.EventHeader.TimeStamp);
+        //dbg!(e.timestamp);
 
         let s = schema_locator.event_schema(e);
-        let mut thread_id = e.EventHeader.ThreadId;
+        let mut thread_id = e.thread_id;
         if let Ok(s) = s {
             match s.name() {
                 "MSNT_SystemTrace/StackWalk/Stack" => {
@@ -77,7 +77,7 @@ fn main() {
                     let ends_in_kernel = is_kernel_address(*stack.last().unwrap(), 8);
                     let mut i = events.len() - 1;
                     let mut found_event: Option<usize> = None;
-                    let cpu = unsafe { e.BufferContext.Anonymous.ProcessorIndex };
+                    let cpu = e.processor_index;
                     while i > 0 {
                         if events[i].timestamp < timestamp as i64 {
                             break;
@@ -188,9 +188,9 @@ fn main() {
 
             events.push(Event {
                 name: s.name().to_owned(),
-                timestamp: e.EventHeader.TimeStamp,
+                timestamp: e.timestamp,
                 thread_id,
-                cpu: unsafe { e.BufferContext.Anonymous.ProcessorIndex },
+                cpu: e.processor_index,
                 stack: None,
                 bad_stack: false,
             });
@@ -198,7 +198,7 @@ fn main() {
             if pattern.is_none() {
                 /*println!(
                     "unknown event {:x?}:{}",
-                    e.EventHeader.ProviderId, e.EventHeader.EventDescriptor.Opcode
+                    e.provider_id, e.descriptor.opcode
                 );*/
             }
         }
@@ -1,6 +1,8 @@
+#[cfg(windows)]
 use etw_reader::{start_trace, parser::{Parser}, print_property, schema::SchemaLocator};
 
 
+#[cfg(windows)]
 fn main() {
 
     let mut schema_locator = SchemaLocator::new();
@@ -15,8 +17,8 @@ fn main() {
             if !s.name().contains("VideoProcessorBltParameters") {
                 return;
             }
-        println!("pid {} time {}", e.EventHeader.ProcessId, e.EventHeader.TimeStamp);
-        println!("{:?} {} {}-{} {} {}", e.EventHeader.ProviderId, s.name(),  e.EventHeader.EventDescriptor.Opcode, e.EventHeader.EventDescriptor.Id, s.property_count(), e.EventHeader.TimeStamp);
+        println!("pid {} time {}", e.process_id, e.timestamp);
+        println!("{:?} {} {}-{} {} {}", e.provider_id, s.name(),  e.descriptor.opcode, e.descriptor.id, s.property_count(), e.timestamp);
 
         let mut parser = Parser::create(&s);
         for i in 0..s.property_count() {
@@ -26,11 +28,16 @@ fn main() {
         }
     } else {
         if pattern.is_none() {
-            println!("unknown event {:x?}:{}", e.EventHeader.ProviderId, e.EventHeader.EventDescriptor.Opcode);
+            println!("unknown event {:x?}:{}", e.provider_id, e.descriptor.opcode);
         }
     }
 
 
 });
 
-}
\ No newline at end of file
+}
+
+#[cfg(not(windows))]
+fn main() {
+    eprintln!("Recording a trace is only supported on Windows");
+}
@@ -0,0 +1,443 @@
+//! Writes every event in an .etl file as a line of JSON
+//!
+//! ```text
+//! etw-dump [--provider <guid|name>]... [--event <glob>]... [--pid <pid>]... [--tid <tid>]...
+//!          [--start-ms <ms>] [--end-ms <ms>] [--keywords <mask>]
+//!          [--manifest <file>]... [--load-schemas <file>] <trace.etl>
+//! ```
+//!
+//! Filters of the same kind are ORed together and different kinds are ANDed. Times are in
+//! milliseconds since the start of the trace.
+use std::fs::File;
+use std::io::{BufWriter, Write};
+use std::path::Path;
+
+use etw_reader::etw_types::{EventRecord, ExtendedDataItem};
+use etw_reader::parser::{Parser, TryParse};
+use etw_reader::schema::{SchemaLocator, TypedEvent};
+use etw_reader::tracelogging::ProviderTraits;
+use etw_reader::{open_trace, utils, GUID};
+use etw_reader::value::Value;
+use serde::Serialize;
+use serde_json::json;
+
+enum ProviderFilter {
+    Guid(GUID),
+    Name(String),
+}
+
+struct Filters {
+    providers: Vec<ProviderFilter>,
+    events: Vec<String>,
+    pids: Vec<u32>,
+    tids: Vec<u32>,
+    start_ms: Option<f64>,
+    end_ms: Option<f64>,
+    keywords: Option<u64>,
+}
+
+impl Filters {
+    fn matches(&self, e: &EventRecord, s: Option<&TypedEvent>, time_ms: Option<f64>) -> bool {
+        if !self.providers.is_empty() {
+            let provider_name = s.map(|s| s.provider_name());
+            let matches = self.providers.iter().any(|provider| match provider {
+                ProviderFilter::Guid(guid) => *guid == e.provider_id,
+                ProviderFilter::Name(name) => provider_name.as_deref().is_some_and(|n| n.eq_ignore_ascii_case(name)),
+            });
+            if !matches {
+                return false;
+            }
+        }
+        if !self.events.is_empty() {
+            match s {
+                Some(s) if self.events.iter().any(|pattern| glob_match(pattern, s.name())) => {}
+                _ => return false,
+            }
+        }
+        if !self.pids.is_empty() && !self.pids.contains(&e.process_id) {
+            return false;
+        }
+        if !self.tids.is_empty() && !self.tids.contains(&e.thread_id) {
+            return false;
+        }
+        if let Some(time_ms) = time_ms {
+            if self.start_ms.is_some_and(|start| time_ms < start) || self.end_ms.is_some_and(|end| time_ms > end) {
+                return false;
+            }
+        }
+        if let Some(keywords) = self.keywords {
+            if e.descriptor.keyword & keywords == 0 {
+                return false;
+            }
+        }
+        true
+    }
+}
+
+/// Matches `name` against a pattern where `*` matches any run of characters and `?` matches one
+fn glob_match(pattern: &str, name: &str) -> bool {
+    let pattern: Vec<char> = pattern.chars().collect();
+    let name: Vec<char> = name.chars().collect();
+    let (mut p, mut n) = (0, 0);
+    // Where to resume if the last `*` needs to match more characters
+    let mut backtrack = None;
+    while n < name.len() {
+        if p < pattern.len() && (pattern[p] == '?' || pattern[p] == name[n]) {
+            p += 1;
+            n += 1;
+        } else if p < pattern.len() && pattern[p] == '*' {
+            backtrack = Some((p, n));
+            p += 1;
+        } else if let Some((star, matched)) = backtrack {
+            p = star + 1;
+            n = matched + 1;
+            backtrack = Some((star, matched + 1));
+        } else {
+            return false;
+        }
+    }
+    pattern[p..].iter().all(|&c| c == '*')
+}
+
+/// The number of raw timestamp ticks per millisecond of a trace whose header has the clock type
+/// `reserved_flags`, or `None` for clock types that we don't know
+fn ticks_per_ms(reserved_flags: u32, perf_freq: u64, cpu_speed_mhz: u32) -> Option<f64> {
+    match reserved_flags {
+        // QPC
+        1 => Some(perf_freq as f64 / 1000.),
+        // System time, in 100ns units
+        2 => Some(10_000.),
+        // CPU cycle counter
+        3 => Some(cpu_speed_mhz as f64 * 1000.),
+        _ => None,
+    }
+}
+
+fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+/// A line of output
+#[derive(Serialize)]
+struct EventLine<'a> {
+    name: Option<&'a str>,
+    provider: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    provider_name: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    task_name: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    opcode_name: Option<String>,
+    id: u16,
+    version: u8,
+    task: u16,
+    opcode: u8,
+    level: u8,
+    keywords: String,
+    pid: u32,
+    tid: u32,
+    cpu: u16,
+    timestamp: i64,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    time_ms: Option<f64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    activity_id: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    properties: Option<Value>,
+    /// Why the properties couldn't be decoded
+    #[serde(skip_serializing_if = "Option::is_none")]
+    error: Option<String>,
+    /// The raw payload of events that we couldn't decode
+    #[serde(skip_serializing_if = "Option::is_none")]
+    user_data: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    stack: Option<Vec<String>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    stack_match_id: Option<u64>,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    extended_data: Vec<serde_json::Value>,
+}
+
+fn extended_data(e: &EventRecord, line: &mut EventLine) {
+    for item in &e.extended_data {
+        let item = match item {
+            ExtendedDataItem::StackTrace32 { match_id, addresses } => {
+                line.stack_match_id = Some(*match_id);
+                line.stack = Some(addresses.iter().map(|a| format!("0x{:x}", a)).collect());
+                continue;
+            }
+            ExtendedDataItem::StackTrace64 { match_id, addresses } => {
+                line.stack_match_id = Some(*match_id);
+                line.stack = Some(addresses.iter().map(|a| format!("0x{:x}", a)).collect());
+                continue;
+            }
+            // The metadata has already been used to decode the event
+            ExtendedDataItem::EventSchemaTl(_) => continue,
+            ExtendedDataItem::RelatedActivityId(guid) => json!({ "type": "RelatedActivityId", "value": format!("{:?}", guid) }),
+            ExtendedDataItem::Sid(sid) => json!({ "type": "Sid", "value": hex(sid) }),
+            ExtendedDataItem::TerminalSessionId(id) => json!({ "type": "TerminalSessionId", "value": id }),
+            ExtendedDataItem::InstanceInfo { instance_id, parent_instance_id, parent_guid } => json!({
+                "type": "InstanceInfo",
+                "instance_id": instance_id,
+                "parent_instance_id": parent_instance_id,
+                "parent_guid": format!("{:?}", parent_guid),
+            }),
+            ExtendedDataItem::PebsIndex(index) => json!({ "type": "PebsIndex", "value": index }),
+            ExtendedDataItem::PmcCounters(counters) => json!({ "type": "PmcCounters", "value": counters }),
+            ExtendedDataItem::EventKey(key) => json!({ "type": "EventKey", "value": key }),
+            ExtendedDataItem::ProvTraits(data) => match ProviderTraits::parse(data) {
+                Some(traits) => json!({
+                    "type": "ProvTraits",
+                    "name": traits.name,
+                    "group": traits.group.map(|group| format!("{:?}", group)),
+                }),
+                None => json!({ "type": "ProvTraits", "data": hex(data) }),
+            },
+            ExtendedDataItem::ProcessStartKey(key) => json!({ "type": "ProcessStartKey", "value": key }),
+            ExtendedDataItem::ControlGuid(guid) => json!({ "type": "ControlGuid", "value": format!("{:?}", guid) }),
+            ExtendedDataItem::QpcDelta(delta) => json!({ "type": "QpcDelta", "value": delta }),
+            ExtendedDataItem::ContainerId(guid) => json!({ "type": "ContainerId", "value": format!("{:?}", guid) }),
+            ExtendedDataItem::StackKey32 { match_id, stack_key } => {
+                json!({ "type": "StackKey", "match_id": match_id, "stack_key": stack_key })
+            }
+            ExtendedDataItem::StackKey64 { match_id, stack_key } => {
+                json!({ "type": "StackKey", "match_id": match_id, "stack_key": stack_key })
+            }
+            ExtendedDataItem::Other { ext_type, data } => json!({ "type": ext_type, "data": hex(data) }),
+        };
+        line.extended_data.push(item);
+    }
+}
+
+const USAGE: &str = "usage: etw-dump [--provider <guid|name>]... [--event <glob>]... [--pid <pid>]... [--tid <tid>]...
+                [--start-ms <ms>] [--end-ms <ms>] [--keywords <mask>]
+                [--manifest <file>]... [--load-schemas <file>] <trace.etl>";
+
+struct Args {
+    filters: Filters,
+    manifests: Vec<String>,
+    schema_file: Option<String>,
+    trace_file: String,
+}
+
+fn parse_args(mut pargs: pico_args::Arguments) -> Result<Args, pico_args::Error> {
+    let providers: Vec<String> = pargs.values_from_str("--provider")?;
+    let filters = Filters {
+        providers: providers.into_iter().map(|p| match utils::guid_from_str(&p) {
+            Some(guid) => ProviderFilter::Guid(guid),
+            None => ProviderFilter::Name(p),
+        }).collect(),
+        events: pargs.values_from_str("--event")?,
+        pids: pargs.values_from_str("--pid")?,
+        tids: pargs.values_from_str("--tid")?,
+        start_ms: pargs.opt_value_from_str("--start-ms")?,
+        end_ms: pargs.opt_value_from_str("--end-ms")?,
+        keywords: pargs.opt_value_from_fn("--keywords", |s| utils::parse_number(s).ok_or("not a number"))?,
+    };
+    Ok(Args {
+        filters,
+        manifests: pargs.values_from_str("--manifest")?,
+        schema_file: pargs.opt_value_from_str("--load-schemas")?,
+        trace_file: pargs.free_from_str()?,
+    })
+}
+
+fn main() {
+    let mut pargs = pico_args::Arguments::from_env();
+    if pargs.contains(["-h", "--help"]) {
+        println!("{}", USAGE);
+        return;
+    }
+    let Args { filters, manifests, schema_file, trace_file } = match parse_args(pargs) {
+        Ok(args) => args,
+        Err(err) => {
+            eprintln!("error: {}\n{}", err, USAGE);
+            std::process::exit(1);
+        }
+    };
+
+    let mut schema_locator = SchemaLocator::new();
+    etw_reader::add_custom_schemas(&mut schema_locator);
+    for manifest in &manifests {
+        if let Err(err) = schema_locator.add_manifest(Path::new(manifest)) {
+            eprintln!("failed to load manifest {}: {:?}", manifest, err);
+            std::process::exit(1);
+        }
+    }
+    if let Some(schema_file) = schema_file {
+        let loaded = File::open(&schema_file).map_err(Into::into)
+            .and_then(|f| schema_locator.load_schemas(std::io::BufReader::new(f)));
+        if let Err(err) = loaded {
+            eprintln!("failed to load schemas from {}: {:?}", schema_file, err);
+            std::process::exit(1);
+        }
+    }
+    let mut out = BufWriter::new(std::io::stdout().lock());
+    // The raw timestamp of the trace header and the number of raw ticks per millisecond
+    let mut trace_start: Option<(i64, f64)> = None;
+    let result = open_trace(Path::new(&trace_file), |e| {
+        let s = schema_locator.event_schema(e).ok();
+        if let Some(s) = &s {
+            if s.name() == "MSNT_SystemTrace/EventTrace/Header" {
+                let mut parser = Parser::create(s);
+                let perf_freq: Result<u64, _> = parser.try_parse("PerfFreq");
+                let cpu_speed: Result<u32, _> = parser.try_parse("CPUSpeed");
+                let clock_type: Result<u32, _> = parser.try_parse("ReservedFlags");
+                if let (Ok(perf_freq), Ok(cpu_speed), Ok(clock_type)) = (perf_freq, cpu_speed, clock_type) {
+                    match ticks_per_ms(clock_type, perf_freq, cpu_speed) {
+                        Some(ticks_per_ms) => trace_start = Some((e.timestamp, ticks_per_ms)),
+                        None if filters.start_ms.is_some() || filters.end_ms.is_some() => {
+                            eprintln!("can't filter by time, the trace has an unknown clock type {}", clock_type);
+                            std::process::exit(1);
+                        }
+                        None => {}
+                    }
+                }
+            }
+        }
+        let time_ms = trace_start.map(|(start, ticks_per_ms)| (e.timestamp - start) as f64 / ticks_per_ms);
+        if !filters.matches(e, s.as_ref(), time_ms) {
+            return;
+        }
+
+        let mut line = EventLine {
+            name: s.as_ref().map(|s| s.name()),
+            provider: format!("{:?}", e.provider_id),
+            provider_name: s.as_ref().map(|s| s.provider_name()),
+            task_name: s.as_ref().map(|s| s.task_name()),
+            opcode_name: s.as_ref().map(|s| s.opcode_name()),
+            id: e.descriptor.id,
+            version: e.descriptor.version,
+            task: e.descriptor.task,
+            opcode: e.descriptor.opcode,
+            level: e.descriptor.level,
+            keywords: format!("0x{:x}", e.descriptor.keyword),
+            pid: e.process_id,
+            tid: e.thread_id,
+            cpu: e.processor_index,
+            timestamp: e.timestamp,
+            time_ms,
+            activity_id: (e.activity_id != GUID::zeroed()).then(|| format!("{:?}", e.activity_id)),
+            properties: None,
+            error: None,
+            user_data: None,
+            stack: None,
+            stack_match_id: None,
+            extended_data: Vec::new(),
+        };
+        match s.as_ref().map(|s| s.to_value()) {
+            Some(Ok(properties)) => line.properties = Some(properties),
+            Some(Err(err)) => {
+                line.error = Some(format!("{:?}", err));
+                line.user_data = Some(hex(&e.user_data));
+            }
+            None => line.user_data = Some(hex(&e.user_data)),
+        }
+        extended_data(e, &mut line);
+
+        // Stop quietly if whatever we're writing to goes away, e.g. `etw-dump trace.etl | head`
+        if serde_json::to_writer(&mut out, &line).is_err() || writeln!(out).is_err() {
+            std::process::exit(0);
+        }
+    });
+    if let Err(err) = result {
+        eprintln!("failed to read {}: {}", trace_file, err);
+        std::process::exit(1);
+    }
+    let _ = out.flush();
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use etw_reader::etw_types::EventDescriptor;
+
+    fn filters() -> Filters {
+        Filters {
+            providers: Vec::new(),
+            events: Vec::new(),
+            pids: Vec::new(),
+            tids: Vec::new(),
+            start_ms: None,
+            end_ms: None,
+            keywords: None,
+        }
+    }
+
+    fn record() -> EventRecord<'static> {
+        EventRecord {
+            provider_id: GUID::from_u128(0x9e814aad_3204_11d2_9a82_006008a86939),
+            descriptor: EventDescriptor { keyword: 0x10, ..Default::default() },
+            process_id: 1234,
+            thread_id: 5678,
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn globs() {
+        assert!(glob_match("MSNT_SystemTrace/Thread/*", "MSNT_SystemTrace/Thread/CSwitch"));
+        assert!(glob_match("*/CSwitch", "MSNT_SystemTrace/Thread/CSwitch"));
+        assert!(glob_match("*Thread*Start", "MSNT_SystemTrace/Thread/DCStart"));
+        assert!(glob_match("MSNT_SystemTrace/Thread/?Switch", "MSNT_SystemTrace/Thread/CSwitch"));
+        assert!(!glob_match("MSNT_SystemTrace/Thread/?Switch", "MSNT_SystemTrace/Thread/Switch"));
+        assert!(!glob_match("*/Process/*", "MSNT_SystemTrace/Thread/CSwitch"));
+        assert!(glob_match("*", ""));
+        assert!(!glob_match("a", ""));
+    }
+
+    #[test]
+    fn providers() {
+        let e = record();
+        let mut f = filters();
+        f.providers = vec![ProviderFilter::Guid(utils::guid_from_str("{9E814AAD-3204-11D2-9A82-006008A86939}").unwrap())];
+        assert!(f.matches(&e, None, None));
+        f.providers = vec![ProviderFilter::Guid(GUID::zeroed())];
+        assert!(!f.matches(&e, None, None));
+        // Names are only known once the event has been decoded
+        f.providers = vec![ProviderFilter::Name("msnt_systemtrace".to_owned())];
+        assert!(!f.matches(&e, None, None));
+        assert!(utils::guid_from_str("MSNT_SystemTrace").is_none());
+    }
+
+    #[test]
+    fn pids_and_tids() {
+        let e = record();
+        let mut f = filters();
+        f.pids = vec![1, 1234];
+        assert!(f.matches(&e, None, None));
+        f.tids = vec![1];
+        assert!(!f.matches(&e, None, None));
+        f.tids = vec![5678];
+        assert!(f.matches(&e, None, None));
+        f.pids = vec![1];
+        assert!(!f.matches(&e, None, None));
+    }
+
+    #[test]
+    fn times_and_keywords() {
+        let e = record();
+        let mut f = filters();
+        f.start_ms = Some(10.);
+        f.end_ms = Some(20.);
+        assert!(f.matches(&e, None, Some(10.)));
+        assert!(f.matches(&e, None, Some(20.)));
+        assert!(!f.matches(&e, None, Some(9.5)));
+        assert!(!f.matches(&e, None, Some(20.5)));
+        // Events before the trace header don't have a time
+        assert!(f.matches(&e, None, None));
+
+        f.keywords = Some(0x30);
+        assert!(f.matches(&e, None, Some(15.)));
+        f.keywords = Some(0x20);
+        assert!(!f.matches(&e, None, Some(15.)));
+    }
+
+    #[test]
+    fn clock_types() {
+        assert_eq!(ticks_per_ms(1, 10_000_000, 3000), Some(10_000.));
+        assert_eq!(ticks_per_ms(2, 10_000_000, 3000), Some(10_000.));
+        assert_eq!(ticks_per_ms(3, 10_000_000, 3000), Some(3_000_000.));
+        assert_eq!(ticks_per_ms(0, 10_000_000, 3000), None);
+    }
+}
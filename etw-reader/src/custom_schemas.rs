@@ -38,7 +38,7 @@ impl EventSchema for ImageID {
     fn level(&self) -> u8 { 0 }
 
     fn decoding_source(&self) -> DecodingSource {
-        panic!()
+        DecodingSource::DecodingSourceWbem
     }
 
     fn provider_name(&self) -> String {
@@ -100,7 +100,7 @@ impl EventSchema for DbgID {
     fn level(&self) -> u8 { 0 }
 
     fn decoding_source(&self) -> DecodingSource {
-        panic!()
+        DecodingSource::DecodingSourceWbem
     }
 
     fn provider_name(&self) -> String {
@@ -166,7 +166,7 @@ impl EventSchema for EventInfo {
     fn level(&self) -> u8 { 0 }
 
     fn decoding_source(&self) -> DecodingSource {
-        panic!()
+        DecodingSource::DecodingSourceWbem
     }
 
     fn provider_name(&self) -> String {
@@ -200,81 +200,6 @@ impl EventSchema for EventInfo {
         flags: PropertyFlags::empty()}
     }
 }
-// We could override ThreadStop using the same properties to get a ThreadName there too.
-pub struct ThreadStart {}
-
-const Thread_PROPS: [PropDesc; 15] = [
-    PropDesc{ name: "ProcessId", in_type: TdhInType::InTypeUInt32, out_type: TdhOutType::OutTypeHexInt32},
-    PropDesc{ name: "TThreadId", in_type: TdhInType::InTypeUInt32, out_type: TdhOutType::OutTypeHexInt32},
-    PropDesc{ name: "StackBase", in_type: TdhInType::InTypePointer, out_type: TdhOutType::OutTypeNull},
-    PropDesc{ name: "StackLimit", in_type: TdhInType::InTypePointer, out_type: TdhOutType::OutTypeNull},
-    PropDesc{ name: "UserStackBase", in_type: TdhInType::InTypePointer, out_type: TdhOutType::OutTypeNull},
-    PropDesc{ name: "UserStackLimit", in_type: TdhInType::InTypePointer, out_type: TdhOutType::OutTypeNull},
-    PropDesc{ name: "Affinity", in_type: TdhInType::InTypePointer, out_type: TdhOutType::OutTypeNull},
-    PropDesc{ name: "Win32StartAddr", in_type: TdhInType::InTypePointer, out_type: TdhOutType::OutTypeNull},
-    PropDesc{ name: "TebBase", in_type: TdhInType::InTypePointer, out_type: TdhOutType::OutTypeNull},
-    PropDesc{ name: "SubProcessTag", in_type: TdhInType::InTypeUInt32, out_type: TdhOutType::OutTypeHexInt32},
-    PropDesc{ name: "BasePriority", in_type: TdhInType::InTypeUInt8, out_type: TdhOutType::OutTypeNull},
-    PropDesc{ name: "PagePriority", in_type: TdhInType::InTypeUInt8, out_type: TdhOutType::OutTypeNull},
-    PropDesc{ name: "IoPriority", in_type: TdhInType::InTypeUInt8, out_type: TdhOutType::OutTypeNull},
-    PropDesc{ name: "ThreadFlags", in_type: TdhInType::InTypeUInt8, out_type: TdhOutType::OutTypeNull},
-    PropDesc{ name: "ThreadName", in_type: TdhInType::InTypeUnicodeString, out_type: TdhOutType::OutTypeString},
-    ];
-
-
-
-impl EventSchema for ThreadStart {
-    fn provider_guid(&self) -> GUID {
-        GUID::from("3D6FA8D1-FE05-11D0-9DDA-00C04FD7BA7C")
-    }
-
-    fn event_id(&self) -> u16 {
-        0
-    }
-
-    fn opcode(&self) -> u8 {
-        3
-    }
-
-    fn event_version(&self) -> u8 {
-        3
-    }
-
-    fn level(&self) -> u8 { 0 }
-
-
-    fn decoding_source(&self) -> DecodingSource {
-        panic!()
-    }
-
-    fn provider_name(&self) -> String {
-        "MSNT_SystemTrace".to_owned()
-    }
-
-    fn task_name(&self) -> String {
-        "Thread".to_owned()
-    }
-
-    fn opcode_name(&self) -> String {
-        "DCStart".to_string()
-    }
-    
-    fn property_count(&self) -> u32 {
-        Thread_PROPS.len() as u32
-    }
-    
-    fn property(&self, index: u32) -> Property {
-        let prop = &Thread_PROPS[index as usize];
-        Property { name: prop.name.to_owned(),
-            desc: PropertyDesc::Primitive(PrimitiveDesc{ in_type: prop.in_type,
-                out_type: prop.out_type,}),
-        length: PropertyLength::Length(0),
-        count:1,
-        map_info: None,
-        flags: PropertyFlags::empty()}
-    }
-}
-
 // from umdprovider.h
 pub struct D3DUmdLogging_MapAllocation {}
 
@@ -302,7 +227,7 @@ impl EventSchema for D3DUmdLogging_MapAllocation {
     fn level(&self) -> u8 { 0 }
 
     fn decoding_source(&self) -> DecodingSource {
-        panic!()
+        DecodingSource::DecodingSourceXMLFile
     }
 
     fn provider_name(&self) -> String {
@@ -349,7 +274,7 @@ impl EventSchema for D3DUmdLogging_RundownAllocation {
     fn level(&self) -> u8 { 0 }
 
     fn decoding_source(&self) -> DecodingSource {
-        panic!()
+        DecodingSource::DecodingSourceXMLFile
     }
 
     fn provider_name(&self) -> String {
@@ -397,7 +322,7 @@ impl EventSchema for D3DUmdLogging_UnmapAllocation {
     fn level(&self) -> u8 { 0 }
 
     fn decoding_source(&self) -> DecodingSource {
-        panic!()
+        DecodingSource::DecodingSourceXMLFile
     }
 
     fn provider_name(&self) -> String {
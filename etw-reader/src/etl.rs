@@ -0,0 +1,664 @@
+//! A native reader for `.etl` files
+//!
+//! `OpenTraceW`/`ProcessTrace` are only available on Windows. This module decodes the on-disk
+//! format directly so that traces can be processed on any platform.
+//!
+//! An ETL file is a sequence of buffers. Every buffer starts with a `WMI_BUFFER_HEADER` and is
+//! followed by 8-byte aligned event records. Each record starts with a marker that identifies
+//! which of the header layouts (`SYSTEM_TRACE_HEADER`, compact, perfinfo, `EVENT_TRACE_HEADER` or
+//! `EVENT_HEADER`) it uses. Buffers are filled per processor, so like `ProcessTrace` we merge the
+//! per-processor streams by timestamp before handing the events to the callback.
+//!
+//! Timestamps are passed through unchanged, which matches `PROCESS_TRACE_MODE_RAW_TIMESTAMP`.
+use crate::etw_types::{EventDescriptor, EventRecord, ExtendedDataItem};
+use crate::utils;
+use std::borrow::Cow;
+use std::cmp::Reverse;
+use std::collections::BinaryHeap;
+use std::fs::File;
+use std::io::{self, Read, Seek, SeekFrom};
+use std::ops::{ControlFlow, Range};
+use std::path::Path;
+use windows::core::GUID;
+use windows::Win32::System::Diagnostics::Etw;
+
+const BUFFER_HEADER_SIZE: usize = 0x48;
+const RECORD_ALIGNMENT: usize = 8;
+
+// WMI_BUFFER_HEADER field offsets
+const BUFFER_SIZE_OFFSET: usize = 0x00;
+const BUFFER_SEQUENCE_NUMBER_OFFSET: usize = 0x18;
+const BUFFER_CLIENT_CONTEXT_OFFSET: usize = 0x28;
+const BUFFER_OFFSET_OFFSET: usize = 0x30;
+const BUFFER_TYPE_OFFSET: usize = 0x36;
+
+// ETW_BUFFER_TYPE
+const BUFFER_TYPE_CTX_SWAP: u16 = 2;
+const BUFFER_TYPE_EMPTY_MARKER: u16 = 6;
+
+// The marker flags in the top byte of the first dword of every record
+const TRACE_HEADER_FLAG: u32 = 0x8000_0000;
+
+// TRACE_HEADER_TYPE_*
+const TRACE_HEADER_TYPE_SYSTEM32: u8 = 1;
+const TRACE_HEADER_TYPE_SYSTEM64: u8 = 2;
+const TRACE_HEADER_TYPE_COMPACT32: u8 = 3;
+const TRACE_HEADER_TYPE_COMPACT64: u8 = 4;
+const TRACE_HEADER_TYPE_FULL_HEADER32: u8 = 10;
+const TRACE_HEADER_TYPE_INSTANCE32: u8 = 11;
+const TRACE_HEADER_TYPE_TIMED: u8 = 12;
+const TRACE_HEADER_TYPE_ERROR: u8 = 13;
+const TRACE_HEADER_TYPE_WNODE_HEADER: u8 = 14;
+const TRACE_HEADER_TYPE_MESSAGE: u8 = 15;
+const TRACE_HEADER_TYPE_PERFINFO32: u8 = 16;
+const TRACE_HEADER_TYPE_PERFINFO64: u8 = 17;
+const TRACE_HEADER_TYPE_EVENT_HEADER32: u8 = 18;
+const TRACE_HEADER_TYPE_EVENT_HEADER64: u8 = 19;
+const TRACE_HEADER_TYPE_FULL_HEADER64: u8 = 20;
+const TRACE_HEADER_TYPE_INSTANCE64: u8 = 21;
+
+const SYSTEM_TRACE_HEADER_SIZE: usize = 32;
+const COMPACT_TRACE_HEADER_SIZE: usize = 24;
+const PERFINFO_TRACE_HEADER_SIZE: usize = 16;
+const EVENT_TRACE_HEADER_SIZE: usize = 48;
+const EVENT_HEADER_SIZE: usize = 80;
+const EXTENDED_ITEM_HEADER_SIZE: usize = 8;
+
+/// The provider guids of the kernel event groups. `SYSTEM_TRACE_HEADER` based events only carry
+/// the group in the high byte of their hook id.
+const KERNEL_GROUPS: [(u8, GUID); 22] = [
+    // EventTrace
+    (0x00, GUID::from_u128(0x68fdd900_4a3e_11d1_84f4_0000f80464e3)),
+    // DiskIo
+    (0x01, GUID::from_u128(0x3d6fa8d4_fe05_11d0_9dda_00c04fd7ba7c)),
+    // PageFault
+    (0x02, GUID::from_u128(0x3d6fa8d3_fe05_11d0_9dda_00c04fd7ba7c)),
+    // Process
+    (0x03, GUID::from_u128(0x3d6fa8d0_fe05_11d0_9dda_00c04fd7ba7c)),
+    // FileIo
+    (0x04, GUID::from_u128(0x90cbdc39_4a3e_11d1_84f4_0000f80464e3)),
+    // Thread
+    (0x05, GUID::from_u128(0x3d6fa8d1_fe05_11d0_9dda_00c04fd7ba7c)),
+    // TcpIp
+    (0x06, GUID::from_u128(0x9a280ac0_c8e0_11d1_84e2_00c04fb998a2)),
+    // Job
+    (0x07, GUID::from_u128(0x3282fc76_feed_498e_8aa7_e70f459d430e)),
+    // UdpIp
+    (0x08, GUID::from_u128(0xbf3a50c5_a9c9_4988_a005_2df0b7c80f80)),
+    // Registry
+    (0x09, GUID::from_u128(0xae53722e_c863_11d2_8659_00c04fa321a1)),
+    // DebugPrint
+    (0x0a, GUID::from_u128(0x13976d09_a327_438c_950b_7f03192815c7)),
+    // EventTraceConfig
+    (0x0b, GUID::from_u128(0x01853a65_418f_4f36_aefc_dc0f1d2fd235)),
+    // Pool
+    (0x0e, GUID::from_u128(0x0268a8b6_74fd_4302_9dd0_6e8f1795c0cf)),
+    // PerfInfo
+    (0x0f, GUID::from_u128(0xce1dbfb4_137e_4da6_87b0_3f59aa102cbc)),
+    // Heap
+    (0x10, GUID::from_u128(0x222962ab_6180_4b88_a825_346b75f2a24a)),
+    // ObTrace
+    (0x11, GUID::from_u128(0x89497f50_effe_4440_8cf2_ce6b1cdcaca7)),
+    // Power
+    (0x12, GUID::from_u128(0xe43445e0_0903_48c3_b878_ff0fccebdd04)),
+    // Image
+    (0x14, GUID::from_u128(0x2cb15d1d_5fc1_11d2_abe1_00a0c911f518)),
+    // CritSec
+    (0x17, GUID::from_u128(0x3ac66736_cc59_4cff_8115_8df50e39816b)),
+    // StackWalk
+    (0x18, GUID::from_u128(0xdef2fe46_7bd6_4b80_bd94_f57fe20d0ce3)),
+    // ALPC
+    (0x1a, GUID::from_u128(0x45d8cccd_539f_4b72_a8b7_5c683142609a)),
+    // SplitIo
+    (0x1b, GUID::from_u128(0xd837ca92_12b9_44a5_ad6a_3a65b3578aa8)),
+];
+
+fn kernel_group_guid(group: u8) -> Option<GUID> {
+    KERNEL_GROUPS.iter().find(|(g, _)| *g == group).map(|(_, guid)| *guid)
+}
+
+fn u16_at(data: &[u8], offset: usize) -> u16 {
+    u16::from_le_bytes(data[offset..offset + 2].try_into().unwrap())
+}
+
+fn u32_at(data: &[u8], offset: usize) -> u32 {
+    u32::from_le_bytes(data[offset..offset + 4].try_into().unwrap())
+}
+
+fn u64_at(data: &[u8], offset: usize) -> u64 {
+    u64::from_le_bytes(data[offset..offset + 8].try_into().unwrap())
+}
+
+fn align_up(value: usize) -> usize {
+    (value + RECORD_ALIGNMENT - 1) & !(RECORD_ALIGNMENT - 1)
+}
+
+fn invalid_data(message: String) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message)
+}
+
+/// A decoded record. The user data and extended data are still ranges in the buffer it came from.
+struct RawEvent {
+    header: EventRecord<'static>,
+    /// (ExtType, data range) of each extended data item
+    extended_data: Vec<(u16, Range<usize>)>,
+    user_data: Range<usize>,
+}
+
+enum Record {
+    Event(RawEvent),
+    /// A record that we know how to skip over but don't deliver
+    Skip,
+}
+
+/// Decodes the record at `pos`. Returns the record and the offset of the end of the record, or
+/// `None` if there are no more records in the buffer.
+fn parse_record(data: &[u8], pos: usize) -> Option<(Record, usize)> {
+    if pos + 8 > data.len() {
+        return None;
+    }
+    let marker = u32_at(data, pos);
+    if marker & TRACE_HEADER_FLAG == 0 || marker == u32::MAX {
+        // Padding or the end of the valid data.
+        return None;
+    }
+    let header_type = (marker >> 16) as u8;
+    let mut header = EventRecord::default();
+
+    let (size, record) = match header_type {
+        TRACE_HEADER_TYPE_SYSTEM32
+        | TRACE_HEADER_TYPE_SYSTEM64
+        | TRACE_HEADER_TYPE_COMPACT32
+        | TRACE_HEADER_TYPE_COMPACT64
+        | TRACE_HEADER_TYPE_PERFINFO32
+        | TRACE_HEADER_TYPE_PERFINFO64 => {
+            // SYSTEM_TRACE_HEADER and friends:
+            // USHORT Version; UCHAR HeaderType; UCHAR Flags; USHORT Size; USHORT HookId; ...
+            let size = u16_at(data, pos + 4) as usize;
+            let hook_id = u16_at(data, pos + 6);
+            let header_size = match header_type {
+                TRACE_HEADER_TYPE_SYSTEM32 | TRACE_HEADER_TYPE_SYSTEM64 => SYSTEM_TRACE_HEADER_SIZE,
+                TRACE_HEADER_TYPE_COMPACT32 | TRACE_HEADER_TYPE_COMPACT64 => COMPACT_TRACE_HEADER_SIZE,
+                _ => PERFINFO_TRACE_HEADER_SIZE,
+            };
+            if size < header_size || pos + size > data.len() {
+                return None;
+            }
+            let Some(provider) = kernel_group_guid((hook_id >> 8) as u8) else {
+                return Some((Record::Skip, pos + size));
+            };
+            let is_32bit = matches!(
+                header_type,
+                TRACE_HEADER_TYPE_SYSTEM32 | TRACE_HEADER_TYPE_COMPACT32 | TRACE_HEADER_TYPE_PERFINFO32
+            );
+            header.flags = (Etw::EVENT_HEADER_FLAG_CLASSIC_HEADER
+                | if is_32bit {
+                    Etw::EVENT_HEADER_FLAG_32_BIT_HEADER
+                } else {
+                    Etw::EVENT_HEADER_FLAG_64_BIT_HEADER
+                }) as u16;
+            header.provider_id = provider;
+            header.descriptor.version = u16_at(data, pos) as u8;
+            header.descriptor.opcode = hook_id as u8;
+            if header_size == PERFINFO_TRACE_HEADER_SIZE {
+                // Perfinfo events don't record the current thread.
+                header.thread_id = u32::MAX;
+                header.process_id = u32::MAX;
+                header.timestamp = u64_at(data, pos + 8) as i64;
+            } else {
+                header.thread_id = u32_at(data, pos + 8);
+                header.process_id = u32_at(data, pos + 12);
+                header.timestamp = u64_at(data, pos + 16) as i64;
+            }
+            if header_size == SYSTEM_TRACE_HEADER_SIZE {
+                // KernelTime and UserTime
+                header.processor_time = u64_at(data, pos + 24);
+            }
+            let record = RawEvent {
+                header,
+                extended_data: Vec::new(),
+                user_data: pos + header_size..pos + size,
+            };
+            (size, Record::Event(record))
+        }
+        TRACE_HEADER_TYPE_FULL_HEADER32 | TRACE_HEADER_TYPE_FULL_HEADER64 => {
+            // EVENT_TRACE_HEADER, used by classic (MOF) providers
+            let size = u16_at(data, pos) as usize;
+            if size < EVENT_TRACE_HEADER_SIZE || pos + size > data.len() {
+                return None;
+            }
+            header.flags = (Etw::EVENT_HEADER_FLAG_CLASSIC_HEADER
+                | if header_type == TRACE_HEADER_TYPE_FULL_HEADER32 {
+                    Etw::EVENT_HEADER_FLAG_32_BIT_HEADER
+                } else {
+                    Etw::EVENT_HEADER_FLAG_64_BIT_HEADER
+                }) as u16;
+            header.descriptor.opcode = data[pos + 4];
+            header.descriptor.level = data[pos + 5];
+            header.descriptor.version = u16_at(data, pos + 6) as u8;
+            header.thread_id = u32_at(data, pos + 8);
+            header.process_id = u32_at(data, pos + 12);
+            header.timestamp = u64_at(data, pos + 16) as i64;
+            header.provider_id = utils::parse_guid(&data[pos + 24..]);
+            header.processor_time = u64_at(data, pos + 40);
+            let record = RawEvent {
+                header,
+                extended_data: Vec::new(),
+                user_data: pos + EVENT_TRACE_HEADER_SIZE..pos + size,
+            };
+            (size, Record::Event(record))
+        }
+        TRACE_HEADER_TYPE_EVENT_HEADER32 | TRACE_HEADER_TYPE_EVENT_HEADER64 => {
+            let size = u16_at(data, pos) as usize;
+            if size < EVENT_HEADER_SIZE || pos + size > data.len() {
+                return None;
+            }
+            header.flags = u16_at(data, pos + 4);
+            header.event_property = u16_at(data, pos + 6);
+            header.thread_id = u32_at(data, pos + 8);
+            header.process_id = u32_at(data, pos + 12);
+            header.timestamp = u64_at(data, pos + 16) as i64;
+            header.provider_id = utils::parse_guid(&data[pos + 24..]);
+            header.descriptor = EventDescriptor {
+                id: u16_at(data, pos + 40),
+                version: data[pos + 42],
+                channel: data[pos + 43],
+                level: data[pos + 44],
+                opcode: data[pos + 45],
+                task: u16_at(data, pos + 46),
+                keyword: u64_at(data, pos + 48),
+            };
+            header.processor_time = u64_at(data, pos + 56);
+            header.activity_id = utils::parse_guid(&data[pos + 64..]);
+
+            let end = pos + size;
+            let mut offset = pos + EVENT_HEADER_SIZE;
+            let mut extended_data = Vec::new();
+            if header.flags as u32 & Etw::EVENT_HEADER_FLAG_EXTENDED_INFO != 0 {
+                loop {
+                    if offset + EXTENDED_ITEM_HEADER_SIZE > end {
+                        return None;
+                    }
+                    // USHORT Reserved1; USHORT ExtType; USHORT Linkage:1; USHORT DataSize
+                    let ext_type = u16_at(data, offset + 2);
+                    let linkage = u16_at(data, offset + 4) & 1;
+                    let data_size = u16_at(data, offset + 6) as usize;
+                    let item_start = offset + EXTENDED_ITEM_HEADER_SIZE;
+                    if item_start + data_size > end {
+                        return None;
+                    }
+                    extended_data.push((ext_type, item_start..item_start + data_size));
+                    offset = align_up(item_start + data_size).min(end);
+                    if linkage == 0 {
+                        break;
+                    }
+                }
+            }
+            let record = RawEvent {
+                header,
+                extended_data,
+                user_data: offset..end,
+            };
+            (size, Record::Event(record))
+        }
+        TRACE_HEADER_TYPE_INSTANCE32
+        | TRACE_HEADER_TYPE_INSTANCE64
+        | TRACE_HEADER_TYPE_TIMED
+        | TRACE_HEADER_TYPE_ERROR
+        | TRACE_HEADER_TYPE_WNODE_HEADER
+        | TRACE_HEADER_TYPE_MESSAGE => {
+            // We don't decode these but they all start with their size.
+            let size = u16_at(data, pos) as usize;
+            if size == 0 || pos + size > data.len() {
+                return None;
+            }
+            (size, Record::Skip)
+        }
+        _ => return None,
+    };
+    Some((record, align_up(pos + size)))
+}
+
+struct BufferRef {
+    file_offset: u64,
+    size: usize,
+    sequence_number: u64,
+}
+
+/// The buffers written by a single processor and the position of the next event in them.
+struct ProcessorStream {
+    buffers: Vec<BufferRef>,
+    next_buffer: usize,
+    data: Vec<u8>,
+    /// The ETW_BUFFER_CONTEXT of the current buffer
+    context: u32,
+    pos: usize,
+    end: usize,
+    current: Option<RawEvent>,
+}
+
+impl ProcessorStream {
+    /// Advances to the next deliverable event, loading more buffers as needed.
+    fn advance(&mut self, file: &mut File) -> io::Result<()> {
+        self.current = None;
+        loop {
+            while let Some((record, next)) = parse_record(&self.data[..self.end], self.pos) {
+                self.pos = next;
+                if let Record::Event(event) = record {
+                    self.current = Some(event);
+                    return Ok(());
+                }
+            }
+            let Some(buffer) = self.buffers.get(self.next_buffer) else {
+                return Ok(());
+            };
+            self.next_buffer += 1;
+            self.data.resize(buffer.size, 0);
+            file.seek(SeekFrom::Start(buffer.file_offset))?;
+            file.read_exact(&mut self.data)?;
+            self.context = u32_at(&self.data, BUFFER_CLIENT_CONTEXT_OFFSET);
+            self.end = (u32_at(&self.data, BUFFER_OFFSET_OFFSET) as usize).clamp(BUFFER_HEADER_SIZE, buffer.size);
+            self.pos = BUFFER_HEADER_SIZE;
+        }
+    }
+
+    fn timestamp(&self) -> Option<i64> {
+        self.current.as_ref().map(|e| e.header.timestamp)
+    }
+
+    fn deliver<F: FnMut(&EventRecord) -> ControlFlow<()>>(&self, callback: &mut F) -> ControlFlow<()> {
+        let Some(event) = &self.current else { return ControlFlow::Continue(()) };
+        let mut record = event.header.clone();
+        record.processor_index = self.context as u8 as u16;
+        record.logger_id = (self.context >> 16) as u16;
+        record.user_data = Cow::Borrowed(&self.data[event.user_data.clone()]);
+        record.extended_data = event
+            .extended_data
+            .iter()
+            .map(|(ext_type, range)| ExtendedDataItem::parse(*ext_type, &self.data[range.clone()]))
+            .collect();
+        callback(&record)
+    }
+}
+
+/// Reads the buffer headers of `file` and groups the buffers by processor. Also returns the number
+/// of compressed context switch buffers, which are skipped.
+fn scan_buffers(file: &mut File) -> io::Result<(Vec<ProcessorStream>, usize)> {
+    let file_len = file.metadata()?.len();
+    let mut streams: Vec<ProcessorStream> = Vec::new();
+    let mut context_switch_buffers = 0;
+    let mut header = [0u8; BUFFER_HEADER_SIZE];
+    let mut offset = 0u64;
+    while offset + BUFFER_HEADER_SIZE as u64 <= file_len {
+        file.seek(SeekFrom::Start(offset))?;
+        file.read_exact(&mut header)?;
+        let size = u32_at(&header, BUFFER_SIZE_OFFSET) as usize;
+        if size < BUFFER_HEADER_SIZE || offset + size as u64 > file_len {
+            return Err(invalid_data(format!(
+                "bad buffer size {} at offset {:#x}",
+                size, offset
+            )));
+        }
+        let buffer_type = u16_at(&header, BUFFER_TYPE_OFFSET);
+        // Compressed context switch buffers use a different record format that we don't support
+        // yet. Empty markers don't contain any events.
+        if buffer_type == BUFFER_TYPE_CTX_SWAP {
+            context_switch_buffers += 1;
+        } else if buffer_type != BUFFER_TYPE_EMPTY_MARKER {
+            let processor = header[BUFFER_CLIENT_CONTEXT_OFFSET] as usize;
+            if streams.len() <= processor {
+                streams.resize_with(processor + 1, || ProcessorStream {
+                    buffers: Vec::new(),
+                    next_buffer: 0,
+                    data: Vec::new(),
+                    context: 0,
+                    pos: 0,
+                    end: 0,
+                    current: None,
+                });
+            }
+            streams[processor].buffers.push(BufferRef {
+                file_offset: offset,
+                size,
+                sequence_number: u64_at(&header, BUFFER_SEQUENCE_NUMBER_OFFSET),
+            });
+        }
+        offset += size as u64;
+    }
+    for stream in &mut streams {
+        stream.buffers.sort_by_key(|b| b.sequence_number);
+    }
+    Ok((streams, context_switch_buffers))
+}
+
+/// Reads the events in the .etl file at `path` and calls `callback` with each of them in timestamp
+/// order, until `callback` returns [ControlFlow::Break].
+pub fn process_trace<F: FnMut(&EventRecord) -> ControlFlow<()>>(path: &Path, mut callback: F) -> Result<(), io::Error> {
+    let mut file = File::open(path)?;
+    let (mut streams, context_switch_buffers) = scan_buffers(&mut file)?;
+    if context_switch_buffers > 0 {
+        // Without them the trace looks like it has no context switches at all
+        eprintln!(
+            "WARNING: skipped {} compressed context switch buffers in {}. Decoding them isn't supported outside of Windows yet, so the trace is missing their CSwitch events.",
+            context_switch_buffers,
+            path.display()
+        );
+    }
+
+    let mut heap = BinaryHeap::new();
+    for (i, stream) in streams.iter_mut().enumerate() {
+        stream.advance(&mut file)?;
+        if let Some(timestamp) = stream.timestamp() {
+            heap.push(Reverse((timestamp, i)));
+        }
+    }
+    while let Some(Reverse((_, i))) = heap.pop() {
+        let stream = &mut streams[i];
+        if stream.deliver(&mut callback).is_break() {
+            break;
+        }
+        stream.advance(&mut file)?;
+        if let Some(timestamp) = stream.timestamp() {
+            heap.push(Reverse((timestamp, i)));
+        }
+    }
+    Ok(())
+}
+
+// The offset of LogFileMode in TRACE_LOGFILE_HEADER
+const LOGFILE_HEADER_LOG_FILE_MODE_OFFSET: usize = 32;
+
+/// Returns the `LogFileMode` that the trace in the .etl file at `path` was recorded with.
+///
+/// This only reads the logfile header event at the start of the first buffer so it's cheap
+/// compared to processing the whole trace.
+pub fn log_file_mode(path: &Path) -> Result<u32, io::Error> {
+    let mut file = File::open(path)?;
+    let mut header = [0u8; BUFFER_HEADER_SIZE];
+    file.read_exact(&mut header)?;
+    let size = u32_at(&header, BUFFER_SIZE_OFFSET) as usize;
+    if size < BUFFER_HEADER_SIZE {
+        return Err(invalid_data(format!("bad buffer size {} at offset 0", size)));
+    }
+    let mut data = vec![0u8; size];
+    file.seek(SeekFrom::Start(0))?;
+    file.read_exact(&mut data)?;
+    let end = (u32_at(&data, BUFFER_OFFSET_OFFSET) as usize).clamp(BUFFER_HEADER_SIZE, size);
+    match parse_record(&data[..end], BUFFER_HEADER_SIZE) {
+        Some((Record::Event(event), _))
+            if event.header.provider_id == KERNEL_GROUPS[0].1
+                && event.user_data.len() >= LOGFILE_HEADER_LOG_FILE_MODE_OFFSET + 4 =>
+        {
+            Ok(u32_at(&data, event.user_data.start + LOGFILE_HEADER_LOG_FILE_MODE_OFFSET))
+        }
+        _ => Err(invalid_data("the first event isn't a logfile header".to_string())),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::io::Write;
+
+    fn buffer(processor: u8, sequence_number: u64, records: &[Vec<u8>]) -> Vec<u8> {
+        typed_buffer(0, processor, sequence_number, records)
+    }
+
+    fn typed_buffer(buffer_type: u16, processor: u8, sequence_number: u64, records: &[Vec<u8>]) -> Vec<u8> {
+        let mut data = vec![0u8; BUFFER_HEADER_SIZE];
+        for record in records {
+            data.extend_from_slice(record);
+            data.resize(align_up(data.len()), 0);
+        }
+        let offset = data.len() as u32;
+        data.resize(0x200, 0xff);
+        let size = data.len() as u32;
+        data[BUFFER_SIZE_OFFSET..][..4].copy_from_slice(&size.to_le_bytes());
+        data[BUFFER_SEQUENCE_NUMBER_OFFSET..][..8].copy_from_slice(&sequence_number.to_le_bytes());
+        data[BUFFER_CLIENT_CONTEXT_OFFSET] = processor;
+        data[BUFFER_OFFSET_OFFSET..][..4].copy_from_slice(&offset.to_le_bytes());
+        data[BUFFER_TYPE_OFFSET..][..2].copy_from_slice(&buffer_type.to_le_bytes());
+        data
+    }
+
+    fn system_record(group: u8, opcode: u8, tid: u32, timestamp: u64, payload: &[u8]) -> Vec<u8> {
+        let mut r = Vec::new();
+        r.extend_from_slice(&2u16.to_le_bytes());
+        r.push(TRACE_HEADER_TYPE_SYSTEM64);
+        r.push(0xc0);
+        r.extend_from_slice(&((SYSTEM_TRACE_HEADER_SIZE + payload.len()) as u16).to_le_bytes());
+        r.push(opcode);
+        r.push(group);
+        r.extend_from_slice(&tid.to_le_bytes());
+        r.extend_from_slice(&1234u32.to_le_bytes());
+        r.extend_from_slice(&timestamp.to_le_bytes());
+        r.extend_from_slice(&[0; 8]);
+        r.extend_from_slice(payload);
+        r
+    }
+
+    fn event_header_record(timestamp: u64, stack: &[u64], payload: &[u8]) -> Vec<u8> {
+        let mut ext = Vec::new();
+        ext.extend_from_slice(&0u16.to_le_bytes());
+        ext.extend_from_slice(&(Etw::EVENT_HEADER_EXT_TYPE_STACK_TRACE64 as u16).to_le_bytes());
+        ext.extend_from_slice(&0u16.to_le_bytes());
+        ext.extend_from_slice(&((8 + stack.len() * 8) as u16).to_le_bytes());
+        ext.extend_from_slice(&7u64.to_le_bytes());
+        for address in stack {
+            ext.extend_from_slice(&address.to_le_bytes());
+        }
+        let size = EVENT_HEADER_SIZE + ext.len() + payload.len();
+        let mut r = Vec::new();
+        r.extend_from_slice(&(size as u16).to_le_bytes());
+        r.push(TRACE_HEADER_TYPE_EVENT_HEADER64);
+        r.push(0xc0);
+        r.extend_from_slice(&((Etw::EVENT_HEADER_FLAG_EXTENDED_INFO | Etw::EVENT_HEADER_FLAG_64_BIT_HEADER) as u16).to_le_bytes());
+        r.extend_from_slice(&0u16.to_le_bytes());
+        r.extend_from_slice(&5u32.to_le_bytes());
+        r.extend_from_slice(&6u32.to_le_bytes());
+        r.extend_from_slice(&timestamp.to_le_bytes());
+        r.extend_from_slice(&0x11223344u32.to_le_bytes());
+        r.extend_from_slice(&0x5566u16.to_le_bytes());
+        r.extend_from_slice(&0x7788u16.to_le_bytes());
+        r.extend_from_slice(&[0x99, 0xaa, 0xbb, 0xcc, 0xdd, 0xee, 0xff, 0x00]);
+        // Id, Version, Channel, Level, Opcode, Task, Keyword
+        r.extend_from_slice(&42u16.to_le_bytes());
+        r.extend_from_slice(&[1, 0, 4, 0]);
+        r.extend_from_slice(&0u16.to_le_bytes());
+        r.extend_from_slice(&0x10u64.to_le_bytes());
+        r.extend_from_slice(&[0; 8]);
+        r.extend_from_slice(&[0; 16]);
+        r.extend_from_slice(&ext);
+        r.extend_from_slice(payload);
+        r
+    }
+
+    #[test]
+    fn reads_log_file_mode() {
+        let mut logfile_header = vec![0u8; 48];
+        logfile_header[32..36].copy_from_slice(&0x400u32.to_le_bytes());
+        let mut file = Vec::new();
+        file.extend(buffer(0, 1, &[system_record(0x00, 0, 1, 100, &logfile_header)]));
+        file.extend(buffer(0, 2, &[system_record(0x05, 36, 1, 200, &[])]));
+
+        let path = std::env::temp_dir().join(format!("etw-reader-etl-mode-test-{}.etl", std::process::id()));
+        File::create(&path).unwrap().write_all(&file).unwrap();
+        let mode = log_file_mode(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(mode, 0x400);
+    }
+
+    #[test]
+    fn merges_processors_by_timestamp() {
+        let mut file = Vec::new();
+        file.extend(buffer(0, 1, &[system_record(0x05, 36, 1, 100, &[1, 2, 3, 4]), system_record(0x05, 36, 1, 300, &[])]));
+        file.extend(buffer(1, 2, &[event_header_record(200, &[0x1000, 0x2000], b"hi")]));
+        file.extend(buffer(0, 3, &[system_record(0x14, 10, 1, 400, &[])]));
+
+        let path = std::env::temp_dir().join(format!("etw-reader-etl-test-{}.etl", std::process::id()));
+        File::create(&path).unwrap().write_all(&file).unwrap();
+
+        let mut events = Vec::new();
+        process_trace(&path, |e| {
+            events.push((
+                e.timestamp,
+                e.provider_id,
+                e.descriptor.opcode,
+                e.processor_index,
+                e.user_data.to_vec(),
+                e.stack_trace().unwrap_or_default(),
+            ));
+            ControlFlow::Continue(())
+        })
+        .unwrap();
+        std::fs::remove_file(&path).unwrap();
+
+        let thread = kernel_group_guid(0x05).unwrap();
+        let image = kernel_group_guid(0x14).unwrap();
+        let provider = GUID::from("11223344-5566-7788-99aa-bbccddeeff00");
+        assert_eq!(
+            events,
+            vec![
+                (100, thread, 36, 0, vec![1, 2, 3, 4], vec![]),
+                (200, provider, 0, 1, b"hi".to_vec(), vec![0x1000, 0x2000]),
+                (300, thread, 36, 0, vec![], vec![]),
+                (400, image, 10, 0, vec![], vec![]),
+            ]
+        );
+    }
+
+    #[test]
+    fn stops_when_asked() {
+        let mut file = Vec::new();
+        file.extend(buffer(0, 1, &[system_record(0x05, 36, 1, 100, &[]), system_record(0x05, 36, 1, 300, &[])]));
+        file.extend(buffer(1, 2, &[system_record(0x05, 36, 1, 200, &[])]));
+
+        let path = std::env::temp_dir().join(format!("etw-reader-etl-stop-test-{}.etl", std::process::id()));
+        File::create(&path).unwrap().write_all(&file).unwrap();
+
+        let mut timestamps = Vec::new();
+        process_trace(&path, |e| {
+            timestamps.push(e.timestamp);
+            if e.timestamp == 200 { ControlFlow::Break(()) } else { ControlFlow::Continue(()) }
+        })
+        .unwrap();
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(timestamps, [100, 200]);
+    }
+
+    #[test]
+    fn counts_context_switch_buffers() {
+        let mut file = Vec::new();
+        file.extend(buffer(0, 1, &[system_record(0x05, 36, 1, 100, &[])]));
+        file.extend(typed_buffer(BUFFER_TYPE_CTX_SWAP, 0, 2, &[]));
+        file.extend(typed_buffer(BUFFER_TYPE_CTX_SWAP, 1, 3, &[]));
+        file.extend(typed_buffer(BUFFER_TYPE_EMPTY_MARKER, 1, 4, &[]));
+
+        let path = std::env::temp_dir().join(format!("etw-reader-etl-ctx-swap-test-{}.etl", std::process::id()));
+        File::create(&path).unwrap().write_all(&file).unwrap();
+        let (streams, context_switch_buffers) = scan_buffers(&mut File::open(&path).unwrap()).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(context_switch_buffers, 2);
+        assert_eq!(streams.iter().map(|stream| stream.buffers.len()).sum::<usize>(), 1);
+    }
+}
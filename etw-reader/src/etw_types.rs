@@ -1,4 +1,4 @@
-use std::ops::Deref;
+use std::borrow::Cow;
 use std::rc::Rc;
 
 use once_cell::unsync::OnceCell;
@@ -7,26 +7,201 @@ use crate::{tdh_types::PropertyMapInfo};
 use crate::schema::EventSchema;
 use crate::utils;
 use crate::tdh_types::Property;
-use windows::core::{GUID, PCWSTR};
+use windows::core::GUID;
+#[cfg(windows)]
+use windows::core::PCWSTR;
 
-#[repr(transparent)]
-pub struct EventRecord(Etw::EVENT_RECORD);
+/// The fields of an [EVENT_DESCRIPTOR]
+///
+/// [EVENT_DESCRIPTOR]: https://docs.microsoft.com/en-us/windows/win32/api/evntprov/ns-evntprov-event_descriptor
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct EventDescriptor {
+    pub id: u16,
+    pub version: u8,
+    pub channel: u8,
+    pub level: u8,
+    pub opcode: u8,
+    pub task: u16,
+    pub keyword: u64,
+}
 
-impl Deref for EventRecord {
-    type Target = Etw::EVENT_RECORD;
+/// An ETW event
+///
+/// This mirrors [EVENT_RECORD] but doesn't depend on it, so events can be produced by `ProcessTrace`,
+/// by the native .etl reader in [crate::etl] or be built directly (e.g. in tests) on any platform.
+/// The user data and extended data are either borrowed from the buffer the event was read from or owned.
+///
+/// [EVENT_RECORD]: https://docs.microsoft.com/en-us/windows/win32/api/evntcons/ns-evntcons-event_record
+#[derive(Debug, Clone, Default)]
+pub struct EventRecord<'a> {
+    pub provider_id: GUID,
+    pub descriptor: EventDescriptor,
+    /// EVENT_HEADER_FLAG_* values
+    pub flags: u16,
+    pub event_property: u16,
+    pub thread_id: u32,
+    pub process_id: u32,
+    pub timestamp: i64,
+    /// The kernel and user time (in the low and high dword) or the processor time, depending on `flags`
+    pub processor_time: u64,
+    pub activity_id: GUID,
+    /// The index of the processor that logged the event
+    pub processor_index: u16,
+    pub logger_id: u16,
+    pub user_data: Cow<'a, [u8]>,
+    pub extended_data: Vec<ExtendedDataItem<'a>>,
+}
 
-    fn deref(&self) -> &Self::Target {
-        &self.0
+impl<'a> EventRecord<'a> {
+    /// Creates an [EventRecord] that borrows from an `EVENT_RECORD` delivered by `ProcessTrace`
+    ///
+    /// # Safety
+    /// The `UserData` and `ExtendedData` pointers of `record` must be valid for `'a`.
+    pub unsafe fn from_etw(record: &'a Etw::EVENT_RECORD) -> Self {
+        let header = &record.EventHeader;
+        let user_data: &[u8] = if record.UserDataLength == 0 {
+            &[]
+        } else {
+            std::slice::from_raw_parts(record.UserData as *const u8, record.UserDataLength.into())
+        };
+        let extended_data = if record.ExtendedDataCount == 0 {
+            Vec::new()
+        } else {
+            std::slice::from_raw_parts(record.ExtendedData, record.ExtendedDataCount as usize)
+                .iter()
+                .map(|item| {
+                    let data = std::slice::from_raw_parts(item.DataPtr as *const u8, item.DataSize as usize);
+                    ExtendedDataItem::parse(item.ExtType, data)
+                })
+                .collect()
+        };
+        let processor_index = if header.Flags as u32 & Etw::EVENT_HEADER_FLAG_PROCESSOR_INDEX != 0 {
+            record.BufferContext.Anonymous.ProcessorIndex
+        } else {
+            record.BufferContext.Anonymous.Anonymous.ProcessorNumber as u16
+        };
+        EventRecord {
+            provider_id: header.ProviderId,
+            descriptor: EventDescriptor {
+                id: header.EventDescriptor.Id,
+                version: header.EventDescriptor.Version,
+                channel: header.EventDescriptor.Channel,
+                level: header.EventDescriptor.Level,
+                opcode: header.EventDescriptor.Opcode,
+                task: header.EventDescriptor.Task,
+                keyword: header.EventDescriptor.Keyword,
+            },
+            flags: header.Flags,
+            event_property: header.EventProperty,
+            thread_id: header.ThreadId,
+            process_id: header.ProcessId,
+            timestamp: header.TimeStamp,
+            processor_time: header.Anonymous.ProcessorTime,
+            activity_id: header.ActivityId,
+            processor_index,
+            logger_id: record.BufferContext.LoggerId,
+            user_data: Cow::Borrowed(user_data),
+            extended_data,
+        }
     }
-}
 
-impl EventRecord {
     pub(crate) fn user_buffer(&self) -> &[u8] {
-        unsafe {
-            std::slice::from_raw_parts(
-                self.UserData as *mut _,
-                self.UserDataLength.into(),
-            )
+        &self.user_data
+    }
+
+    /// Returns the stack attached to the event with `EVENT_ENABLE_PROPERTY_STACK_TRACE`, if any
+    pub fn stack_trace(&self) -> Option<Vec<u64>> {
+        self.extended_data.iter().find_map(|item| match item {
+            ExtendedDataItem::StackTrace32 { addresses, .. } => Some(addresses.iter().map(|a| *a as u64).collect()),
+            ExtendedDataItem::StackTrace64 { addresses, .. } => Some(addresses.clone()),
+            _ => None,
+        })
+    }
+}
+
+/// A decoded [EVENT_HEADER_EXTENDED_DATA_ITEM]
+///
+/// [EVENT_HEADER_EXTENDED_DATA_ITEM]: https://docs.microsoft.com/en-us/windows/win32/api/evntcons/ns-evntcons-event_header_extended_data_item
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ExtendedDataItem<'a> {
+    RelatedActivityId(GUID),
+    /// The SID of the user that logged the event
+    Sid(Cow<'a, [u8]>),
+    TerminalSessionId(u32),
+    InstanceInfo { instance_id: u32, parent_instance_id: u32, parent_guid: GUID },
+    StackTrace32 { match_id: u64, addresses: Vec<u32> },
+    StackTrace64 { match_id: u64, addresses: Vec<u64> },
+    PebsIndex(u64),
+    PmcCounters(Vec<u64>),
+    EventKey(u64),
+    /// TraceLogging event metadata
+    EventSchemaTl(Cow<'a, [u8]>),
+    /// TraceLogging provider traits
+    ProvTraits(Cow<'a, [u8]>),
+    ProcessStartKey(u64),
+    ControlGuid(GUID),
+    QpcDelta(u64),
+    ContainerId(GUID),
+    StackKey32 { match_id: u64, stack_key: u32 },
+    StackKey64 { match_id: u64, stack_key: u64 },
+    /// An item we don't know how to decode or that was truncated
+    Other { ext_type: u16, data: Cow<'a, [u8]> },
+}
+
+impl<'a> ExtendedDataItem<'a> {
+    pub fn parse(ext_type: u16, data: &'a [u8]) -> Self {
+        let u32_at = |offset: usize| u32::from_le_bytes(data[offset..offset + 4].try_into().unwrap());
+        let u64_at = |offset: usize| u64::from_le_bytes(data[offset..offset + 8].try_into().unwrap());
+        let min_size = match ext_type as u32 {
+            Etw::EVENT_HEADER_EXT_TYPE_RELATED_ACTIVITYID
+            | Etw::EVENT_HEADER_EXT_TYPE_CONTROL_GUID
+            | Etw::EVENT_HEADER_EXT_TYPE_CONTAINER_ID => 16,
+            Etw::EVENT_HEADER_EXT_TYPE_TS_ID => 4,
+            Etw::EVENT_HEADER_EXT_TYPE_INSTANCE_INFO => 24,
+            Etw::EVENT_HEADER_EXT_TYPE_STACK_TRACE32
+            | Etw::EVENT_HEADER_EXT_TYPE_STACK_TRACE64
+            | Etw::EVENT_HEADER_EXT_TYPE_PEBS_INDEX
+            | Etw::EVENT_HEADER_EXT_TYPE_EVENT_KEY
+            | Etw::EVENT_HEADER_EXT_TYPE_PROCESS_START_KEY
+            | Etw::EVENT_HEADER_EXT_TYPE_QPC_DELTA => 8,
+            Etw::EVENT_HEADER_EXT_TYPE_STACK_KEY32 => 12,
+            Etw::EVENT_HEADER_EXT_TYPE_STACK_KEY64 => 16,
+            _ => 0,
+        };
+        if data.len() < min_size {
+            return ExtendedDataItem::Other { ext_type, data: Cow::Borrowed(data) };
+        }
+        match ext_type as u32 {
+            Etw::EVENT_HEADER_EXT_TYPE_RELATED_ACTIVITYID => ExtendedDataItem::RelatedActivityId(utils::parse_guid(data)),
+            Etw::EVENT_HEADER_EXT_TYPE_SID => ExtendedDataItem::Sid(Cow::Borrowed(data)),
+            Etw::EVENT_HEADER_EXT_TYPE_TS_ID => ExtendedDataItem::TerminalSessionId(u32_at(0)),
+            Etw::EVENT_HEADER_EXT_TYPE_INSTANCE_INFO => ExtendedDataItem::InstanceInfo {
+                instance_id: u32_at(0),
+                parent_instance_id: u32_at(4),
+                parent_guid: utils::parse_guid(&data[8..]),
+            },
+            Etw::EVENT_HEADER_EXT_TYPE_STACK_TRACE32 => ExtendedDataItem::StackTrace32 {
+                match_id: u64_at(0),
+                addresses: data[8..].chunks_exact(4).map(|a| u32::from_le_bytes(a.try_into().unwrap())).collect(),
+            },
+            Etw::EVENT_HEADER_EXT_TYPE_STACK_TRACE64 => ExtendedDataItem::StackTrace64 {
+                match_id: u64_at(0),
+                addresses: data[8..].chunks_exact(8).map(|a| u64::from_le_bytes(a.try_into().unwrap())).collect(),
+            },
+            Etw::EVENT_HEADER_EXT_TYPE_PEBS_INDEX => ExtendedDataItem::PebsIndex(u64_at(0)),
+            Etw::EVENT_HEADER_EXT_TYPE_PMC_COUNTERS => ExtendedDataItem::PmcCounters(
+                data.chunks_exact(8).map(|a| u64::from_le_bytes(a.try_into().unwrap())).collect(),
+            ),
+            Etw::EVENT_HEADER_EXT_TYPE_EVENT_KEY => ExtendedDataItem::EventKey(u64_at(0)),
+            Etw::EVENT_HEADER_EXT_TYPE_EVENT_SCHEMA_TL => ExtendedDataItem::EventSchemaTl(Cow::Borrowed(data)),
+            Etw::EVENT_HEADER_EXT_TYPE_PROV_TRAITS => ExtendedDataItem::ProvTraits(Cow::Borrowed(data)),
+            Etw::EVENT_HEADER_EXT_TYPE_PROCESS_START_KEY => ExtendedDataItem::ProcessStartKey(u64_at(0)),
+            Etw::EVENT_HEADER_EXT_TYPE_CONTROL_GUID => ExtendedDataItem::ControlGuid(utils::parse_guid(data)),
+            Etw::EVENT_HEADER_EXT_TYPE_QPC_DELTA => ExtendedDataItem::QpcDelta(u64_at(0)),
+            Etw::EVENT_HEADER_EXT_TYPE_CONTAINER_ID => ExtendedDataItem::ContainerId(utils::parse_guid(data)),
+            Etw::EVENT_HEADER_EXT_TYPE_STACK_KEY32 => ExtendedDataItem::StackKey32 { match_id: u64_at(0), stack_key: u32_at(8) },
+            Etw::EVENT_HEADER_EXT_TYPE_STACK_KEY64 => ExtendedDataItem::StackKey64 { match_id: u64_at(0), stack_key: u64_at(8) },
+            _ => ExtendedDataItem::Other { ext_type, data: Cow::Borrowed(data) },
         }
     }
 }
@@ -54,7 +229,9 @@ impl std::ops::DerefMut for EventPropertyInfo {
 
 impl From<&[u8]> for EventPropertyInfo {
     fn from(val: &[u8]) -> Self {
-        unsafe { *(val.as_ptr() as *mut EventPropertyInfo) }
+        assert!(val.len() >= std::mem::size_of::<EventPropertyInfo>());
+        // The buffer only has the alignment of a u8
+        unsafe { std::ptr::read_unaligned(val.as_ptr() as *const EventPropertyInfo) }
     }
 }
 
@@ -71,7 +248,7 @@ pub const EVENT_HEADER_FLAG_32_BIT_HEADER: u16 = Etw::EVENT_HEADER_FLAG_32_BIT_H
 /// Wrapper over the [DECODING_SOURCE] type
 ///
 /// [DECODING_SOURCE]: https://microsoft.github.io/windows-docs-rs/doc/bindings/Windows/Win32/Etw/struct.DECODING_SOURCE.html
-#[derive(Debug)]
+#[derive(Debug, Clone, Copy, PartialEq)]
 pub enum DecodingSource {
     DecodingSourceXMLFile,
     DecodingSourceWbem,
@@ -93,6 +270,27 @@ impl From<Etw::DECODING_SOURCE> for DecodingSource {
 }
 
 
+/// The standard opcodes from winmeta.xml as (value, manifest name, display name)
+pub(crate) const STANDARD_OPCODES: [(u8, &str, &str); 11] = [
+    (0, "win:Info", "Info"),
+    (1, "win:Start", "Start"),
+    (2, "win:Stop", "Stop"),
+    (3, "win:DC_Start", "DCStart"),
+    (4, "win:DC_Stop", "DCStop"),
+    (5, "win:Extension", "Extension"),
+    (6, "win:Reply", "Reply"),
+    (7, "win:Resume", "Resume"),
+    (8, "win:Suspend", "Suspend"),
+    (9, "win:Send", "Send"),
+    (240, "win:Receive", "Receive"),
+];
+
+/// The display name of `opcode` if it's one of the [STANDARD_OPCODES]
+pub(crate) fn standard_opcode_name(opcode: u8) -> Option<&'static str> {
+    STANDARD_OPCODES.iter().find(|(value, _, _)| *value == opcode).map(|(_, _, name)| *name)
+}
+
+
 /// Newtype wrapper over an [TRACE_EVENT_INFO]
 ///
 /// [TRACE_EVENT_INFO]: https://microsoft.github.io/windows-docs-rs/doc/bindings/Windows/Win32/Etw/struct.TRACE_EVENT_INFO.html
@@ -124,10 +322,22 @@ impl std::ops::DerefMut for TraceEventInfo {
 
 impl From<&TraceEventInfoRaw> for TraceEventInfo {
     fn from(val: &TraceEventInfoRaw) -> Self {
-        unsafe { *(val.info.as_ptr() as *mut TraceEventInfo) }
+        assert!(val.info.len() >= std::mem::size_of::<TraceEventInfo>());
+        // The buffer only has the alignment of a u8
+        unsafe { std::ptr::read_unaligned(val.info.as_ptr() as *const TraceEventInfo) }
     }
 }
 
+/// The offset of the `index`th [EventPropertyInfo] in a `TRACE_EVENT_INFO`
+fn property_offset(index: usize) -> usize {
+    // We need to subtract the sizeof(EVENT_PROPERTY_INFO) due to how TRACE_EVENT_INFO is declared
+    // in the bindings, the last field `EventPropertyInfoArray[ANYSIZE_ARRAY]` is declared as
+    // [EVENT_PROPERTY_INFO; 1]
+    // https://microsoft.github.io/windows-docs-rs/doc/bindings/Windows/Win32/Etw/struct.TRACE_EVENT_INFO.html#structfield.EventPropertyInfoArray
+    index * std::mem::size_of::<EventPropertyInfo>()
+        + (std::mem::size_of::<TraceEventInfo>() - std::mem::size_of::<EventPropertyInfo>())
+}
+
 
 impl TraceEventInfoRaw {
     pub(crate) fn new(info: Vec<u8>) -> Self {
@@ -147,6 +357,62 @@ impl TraceEventInfoRaw {
         self.info.as_mut_ptr()
     }
 
+    /// Builds a [TraceEventInfoRaw] whose property maps have already been looked up
+    ///
+    /// `info` doesn't come from TDH so we check that its property array, struct members and
+    /// strings are inside of it before anything reads them. Returns what's wrong with it otherwise.
+    pub(crate) fn with_property_maps(info: Vec<u8>, maps: Vec<Option<Rc<PropertyMapInfo>>>) -> Result<Self, String> {
+        if info.len() < std::mem::size_of::<TraceEventInfo>() {
+            return Err("truncated TRACE_EVENT_INFO".to_owned());
+        }
+        let raw = TraceEventInfoRaw::new(info);
+        let event_info = TraceEventInfo::from(&raw);
+        let property_count = event_info.PropertyCount as usize;
+        if raw.info.len() < property_offset(property_count) {
+            return Err(format!("truncated array of {} properties", property_count));
+        }
+        if maps.len() != property_count {
+            return Err(format!("{} property maps for {} properties", maps.len(), property_count));
+        }
+        raw.check_string_offset("ProviderNameOffset", event_info.ProviderNameOffset)?;
+        raw.check_string_offset("TaskNameOffset", event_info.TaskNameOffset)?;
+        raw.check_string_offset("OpcodeNameOffset", event_info.OpcodeNameOffset)?;
+        raw.check_string_offset("EventMessageOffset", event_info.EventMessageOffset)?;
+        for index in 0..property_count {
+            let property = EventPropertyInfo::from(&raw.info[property_offset(index)..]);
+            raw.check_string_offset("NameOffset", property.NameOffset)?;
+            if property.Flags.0 & PropertyStruct.0 != 0 {
+                let members = unsafe { property.Anonymous1.structType };
+                if members.StructStartIndex as usize + members.NumOfStructMembers as usize > property_count {
+                    return Err(format!("struct members of property {} are out of bounds", index));
+                }
+            } else {
+                raw.check_string_offset("MapNameOffset", unsafe { property.Anonymous1.nonStructType.MapNameOffset })?;
+            }
+        }
+        let property_maps = maps.into_iter().map(|map| {
+            let cell = OnceCell::new();
+            let _ = cell.set(map);
+            cell
+        }).collect();
+        let _ = raw.property_maps.set(property_maps);
+        Ok(raw)
+    }
+
+    /// Checks that `offset` points to a UTF-16 string in `info`. An offset of 0 means that there's
+    /// no string.
+    fn check_string_offset(&self, field: &str, offset: u32) -> Result<(), String> {
+        if offset != 0 && (offset as usize >= self.info.len() || offset & 1 != 0) {
+            return Err(format!("{} {} doesn't point to a string in the {} bytes of TRACE_EVENT_INFO", field, offset, self.info.len()));
+        }
+        Ok(())
+    }
+
+    /// The number of properties including struct members, unlike [EventSchema::property_count]
+    pub(crate) fn total_property_count(&self) -> u32 {
+        TraceEventInfo::from(self).PropertyCount
+    }
+
     fn property_map_info(&self, index: u32) -> Option<Rc<PropertyMapInfo>> {
 
         // let's make sure index is not bigger thant the PropertyCount
@@ -155,18 +421,13 @@ impl TraceEventInfoRaw {
              vec![OnceCell::new(); TraceEventInfo::from(self).PropertyCount as usize]
         });
         let map = property_maps[index as usize].get_or_init(|| {
-            // We need to subtract the sizeof(EVENT_PROPERTY_INFO) due to how TRACE_EVENT_INFO is declared
-            // in the bindings, the last field `EventPropertyInfoArray[ANYSIZE_ARRAY]` is declared as
-            // [EVENT_PROPERTY_INFO; 1]
-            // https://microsoft.github.io/windows-docs-rs/doc/bindings/Windows/Win32/Etw/struct.TRACE_EVENT_INFO.html#structfield.EventPropertyInfoArray
-            let curr_prop_offset = index as usize * std::mem::size_of::<EventPropertyInfo>()
-                + (std::mem::size_of::<TraceEventInfo>() - std::mem::size_of::<EventPropertyInfo>());
-
-            let curr_prop = EventPropertyInfo::from(&self.info[curr_prop_offset..]);
-            if curr_prop.Flags.0 & PropertyStruct.0 == 0 {
+            let curr_prop = EventPropertyInfo::from(&self.info[property_offset(index as usize)..]);
+            if curr_prop.Flags.0 & PropertyStruct.0 != 0 {
                 // This property is a struct so it has no map info
                 return None;
             } else {
+                // TDH is needed to look up the map, so there's no map info on other platforms.
+                #[cfg(windows)]
                 unsafe {
                     if curr_prop.Anonymous1.nonStructType.MapNameOffset != 0 {
                         // build an empty event record that we can use to get the map info
@@ -252,14 +513,7 @@ impl EventSchema for TraceEventInfoRaw {
     fn opcode_name(&self) -> String {
         let opcode_name_offset = TraceEventInfo::from(self).OpcodeNameOffset as usize;
         if opcode_name_offset == 0 {
-            return String::from(match self.opcode() {
-                0 => "Info",
-                1 => "Start",
-                2 => "Stop",
-                3 => "DCStart",
-                4 => "DCStop",
-                _ => ""
-            });
+            return standard_opcode_name(self.opcode()).unwrap_or_default().to_owned();
         }
         utils::parse_unk_size_null_utf16_string(&self.info[opcode_name_offset..])
     }
@@ -272,19 +526,16 @@ impl EventSchema for TraceEventInfoRaw {
         // let's make sure index is not bigger thant the PropertyCount
         assert!(index <= TraceEventInfo::from(self).PropertyCount);
 
-        // We need to subtract the sizeof(EVENT_PROPERTY_INFO) due to how TRACE_EVENT_INFO is declared
-        // in the bindings, the last field `EventPropertyInfoArray[ANYSIZE_ARRAY]` is declared as
-        // [EVENT_PROPERTY_INFO; 1]
-        // https://microsoft.github.io/windows-docs-rs/doc/bindings/Windows/Win32/Etw/struct.TRACE_EVENT_INFO.html#structfield.EventPropertyInfoArray
-        let curr_prop_offset = index as usize * std::mem::size_of::<EventPropertyInfo>()
-            + (std::mem::size_of::<TraceEventInfo>() - std::mem::size_of::<EventPropertyInfo>());
-
-        let curr_prop = EventPropertyInfo::from(&self.info[curr_prop_offset..]);
+        let curr_prop = EventPropertyInfo::from(&self.info[property_offset(index as usize)..]);
         let name =
             utils::parse_unk_size_null_utf16_string(&self.info[curr_prop.NameOffset as usize..]);
         Property::new(name, &curr_prop, self.property_map_info(index))
     }
 
+    fn trace_event_info(&self) -> Option<&TraceEventInfoRaw> {
+        Some(self)
+    }
+
     fn event_message(&self) -> Option<String> {        
         let offset = TraceEventInfo::from(self).EventMessageOffset;
         if offset != 0 {
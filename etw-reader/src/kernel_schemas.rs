@@ -0,0 +1,730 @@
+//! Built-in schemas for the classic kernel (MSNT_SystemTrace) MOF event classes
+//!
+//! These layouts come from the MOF classes in wmicore.mof and the documentation for the NT Kernel
+//! Logger event classes. Having them built in means that the kernel events that we care about are
+//! decoded the same way regardless of the OS version that we're running on and without needing TDH.
+use windows::core::GUID;
+
+use crate::{etw_types::DecodingSource, schema::{EventSchema, SchemaLocator}, tdh_types::{Property, PropertyDesc, PrimitiveDesc, PropertyFlags, TdhInType, TdhOutType, PropertyLength}};
+use TdhInType::*;
+use TdhOutType::*;
+
+struct MofProp {
+    name: &'static str,
+    in_type: TdhInType,
+    out_type: TdhOutType,
+    length: u16,
+    count: u16,
+}
+
+const fn prop(name: &'static str, in_type: TdhInType, out_type: TdhOutType) -> MofProp {
+    MofProp { name, in_type, out_type, length: 0, count: 1 }
+}
+
+/// A property with a fixed size in bytes, e.g. a binary blob or an IP address
+const fn fixed(name: &'static str, in_type: TdhInType, out_type: TdhOutType, length: u16) -> MofProp {
+    MofProp { name, in_type, out_type, length, count: 1 }
+}
+
+/// A fixed size array of `count` elements
+const fn array(name: &'static str, in_type: TdhInType, out_type: TdhOutType, count: u16) -> MofProp {
+    MofProp { name, in_type, out_type, length: 0, count }
+}
+
+/// An event type group from the MOF: a set of opcodes that share the same layout for some versions
+struct MofEventType {
+    task: &'static str,
+    guid: GUID,
+    opcodes: &'static [(u8, &'static str)],
+    versions: &'static [u8],
+    props: &'static [MofProp],
+}
+
+const EVENT_TRACE_GUID: GUID = GUID::from_u128(0x68fdd900_4a3e_11d1_84f4_0000f80464e3);
+const PROCESS_GUID: GUID = GUID::from_u128(0x3d6fa8d0_fe05_11d0_9dda_00c04fd7ba7c);
+const THREAD_GUID: GUID = GUID::from_u128(0x3d6fa8d1_fe05_11d0_9dda_00c04fd7ba7c);
+const IMAGE_GUID: GUID = GUID::from_u128(0x2cb15d1d_5fc1_11d2_abe1_00a0c911f518);
+const STACK_WALK_GUID: GUID = GUID::from_u128(0xdef2fe46_7bd6_4b80_bd94_f57fe20d0ce3);
+const PERF_INFO_GUID: GUID = GUID::from_u128(0xce1dbfb4_137e_4da6_87b0_3f59aa102cbc);
+const PAGE_FAULT_GUID: GUID = GUID::from_u128(0x3d6fa8d3_fe05_11d0_9dda_00c04fd7ba7c);
+const DISK_IO_GUID: GUID = GUID::from_u128(0x3d6fa8d4_fe05_11d0_9dda_00c04fd7ba7c);
+const FILE_IO_GUID: GUID = GUID::from_u128(0x90cbdc39_4a3e_11d1_84f4_0000f80464e3);
+const TCP_IP_GUID: GUID = GUID::from_u128(0x9a280ac0_c8e0_11d1_84e2_00c04fb998a2);
+
+const EVENT_TRACE_HEADER_PROPS: [MofProp; 23] = [
+    prop("BufferSize", InTypeUInt32, OutTypeUInt32),
+    prop("Version", InTypeUInt32, OutTypeUInt32),
+    prop("ProviderVersion", InTypeUInt32, OutTypeUInt32),
+    prop("NumberOfProcessors", InTypeUInt32, OutTypeUInt32),
+    prop("EndTime", InTypeUInt64, OutTypeUInt64),
+    prop("TimerResolution", InTypeUInt32, OutTypeUInt32),
+    prop("MaxFileSize", InTypeUInt32, OutTypeUInt32),
+    prop("LogFileMode", InTypeUInt32, OutTypeHexInt32),
+    prop("BuffersWritten", InTypeUInt32, OutTypeUInt32),
+    prop("StartBuffers", InTypeUInt32, OutTypeUInt32),
+    prop("PointerSize", InTypeUInt32, OutTypeUInt32),
+    prop("EventsLost", InTypeUInt32, OutTypeUInt32),
+    prop("CPUSpeed", InTypeUInt32, OutTypeUInt32),
+    prop("LoggerName", InTypePointer, OutTypeHexInt64),
+    prop("LogFileName", InTypePointer, OutTypeHexInt64),
+    // a TIME_ZONE_INFORMATION
+    fixed("TimeZoneInformation", InTypeBinary, OutTypeHexBinary, 176),
+    prop("BootTime", InTypeUInt64, OutTypeUInt64),
+    prop("PerfFreq", InTypeUInt64, OutTypeUInt64),
+    prop("StartTime", InTypeUInt64, OutTypeUInt64),
+    prop("ReservedFlags", InTypeUInt32, OutTypeUInt32),
+    prop("BuffersLost", InTypeUInt32, OutTypeUInt32),
+    prop("SessionNameString", InTypeUnicodeString, OutTypeString),
+    prop("LogFileNameString", InTypeUnicodeString, OutTypeString),
+];
+
+const PROCESS_OPCODES: [(u8, &str); 5] = [(1, "Start"), (2, "End"), (3, "DCStart"), (4, "DCEnd"), (39, "Defunct")];
+
+const PROCESS_V2_PROPS: [MofProp; 8] = [
+    prop("UniqueProcessKey", InTypePointer, OutTypeHexInt64),
+    prop("ProcessId", InTypeUInt32, OutTypeHexInt32),
+    prop("ParentId", InTypeUInt32, OutTypeHexInt32),
+    prop("SessionId", InTypeUInt32, OutTypeUInt32),
+    prop("ExitStatus", InTypeInt32, OutTypeInt32),
+    prop("UserSID", InTypeWBEMSID, OutTypeNull),
+    prop("ImageFileName", InTypeAnsiString, OutTypeString),
+    prop("CommandLine", InTypeUnicodeString, OutTypeString),
+];
+
+const PROCESS_V3_PROPS: [MofProp; 9] = [
+    prop("UniqueProcessKey", InTypePointer, OutTypeHexInt64),
+    prop("ProcessId", InTypeUInt32, OutTypeHexInt32),
+    prop("ParentId", InTypeUInt32, OutTypeHexInt32),
+    prop("SessionId", InTypeUInt32, OutTypeUInt32),
+    prop("ExitStatus", InTypeInt32, OutTypeInt32),
+    prop("DirectoryTableBase", InTypePointer, OutTypeHexInt64),
+    prop("UserSID", InTypeWBEMSID, OutTypeNull),
+    prop("ImageFileName", InTypeAnsiString, OutTypeString),
+    prop("CommandLine", InTypeUnicodeString, OutTypeString),
+];
+
+const PROCESS_V4_PROPS: [MofProp; 12] = [
+    prop("UniqueProcessKey", InTypePointer, OutTypeHexInt64),
+    prop("ProcessId", InTypeUInt32, OutTypeHexInt32),
+    prop("ParentId", InTypeUInt32, OutTypeHexInt32),
+    prop("SessionId", InTypeUInt32, OutTypeUInt32),
+    prop("ExitStatus", InTypeInt32, OutTypeInt32),
+    prop("DirectoryTableBase", InTypePointer, OutTypeHexInt64),
+    prop("Flags", InTypeUInt32, OutTypeHexInt32),
+    prop("UserSID", InTypeWBEMSID, OutTypeNull),
+    prop("ImageFileName", InTypeAnsiString, OutTypeString),
+    prop("CommandLine", InTypeUnicodeString, OutTypeString),
+    prop("PackageFullName", InTypeUnicodeString, OutTypeString),
+    prop("ApplicationId", InTypeUnicodeString, OutTypeString),
+];
+
+const THREAD_OPCODES: [(u8, &str); 4] = [(1, "Start"), (2, "End"), (3, "DCStart"), (4, "DCEnd")];
+
+const THREAD_V2_PROPS: [MofProp; 10] = [
+    prop("ProcessId", InTypeUInt32, OutTypeHexInt32),
+    prop("TThreadId", InTypeUInt32, OutTypeHexInt32),
+    prop("StackBase", InTypePointer, OutTypeHexInt64),
+    prop("StackLimit", InTypePointer, OutTypeHexInt64),
+    prop("UserStackBase", InTypePointer, OutTypeHexInt64),
+    prop("UserStackLimit", InTypePointer, OutTypeHexInt64),
+    prop("StartAddr", InTypePointer, OutTypeHexInt64),
+    prop("Win32StartAddr", InTypePointer, OutTypeHexInt64),
+    prop("TebBase", InTypePointer, OutTypeHexInt64),
+    prop("SubProcessTag", InTypeUInt32, OutTypeHexInt32),
+];
+
+// Version 3 events don't declare a ThreadName in the MOF but newer versions of Windows append one
+// anyway so we include it for both version 3 and 4.
+const THREAD_V3_PROPS: [MofProp; 15] = [
+    prop("ProcessId", InTypeUInt32, OutTypeHexInt32),
+    prop("TThreadId", InTypeUInt32, OutTypeHexInt32),
+    prop("StackBase", InTypePointer, OutTypeHexInt64),
+    prop("StackLimit", InTypePointer, OutTypeHexInt64),
+    prop("UserStackBase", InTypePointer, OutTypeHexInt64),
+    prop("UserStackLimit", InTypePointer, OutTypeHexInt64),
+    prop("Affinity", InTypePointer, OutTypeHexInt64),
+    prop("Win32StartAddr", InTypePointer, OutTypeHexInt64),
+    prop("TebBase", InTypePointer, OutTypeHexInt64),
+    prop("SubProcessTag", InTypeUInt32, OutTypeHexInt32),
+    prop("BasePriority", InTypeUInt8, OutTypeUInt8),
+    prop("PagePriority", InTypeUInt8, OutTypeUInt8),
+    prop("IoPriority", InTypeUInt8, OutTypeUInt8),
+    prop("ThreadFlags", InTypeUInt8, OutTypeHexInt8),
+    prop("ThreadName", InTypeUnicodeString, OutTypeString),
+];
+
+const CSWITCH_PROPS: [MofProp; 12] = [
+    prop("NewThreadId", InTypeUInt32, OutTypeHexInt32),
+    prop("OldThreadId", InTypeUInt32, OutTypeHexInt32),
+    prop("NewThreadPriority", InTypeInt8, OutTypeInt8),
+    prop("OldThreadPriority", InTypeInt8, OutTypeInt8),
+    prop("PreviousCState", InTypeUInt8, OutTypeUInt8),
+    prop("SpareByte", InTypeInt8, OutTypeInt8),
+    prop("OldThreadWaitReason", InTypeInt8, OutTypeInt8),
+    prop("OldThreadWaitMode", InTypeInt8, OutTypeInt8),
+    prop("OldThreadState", InTypeInt8, OutTypeInt8),
+    prop("OldThreadWaitIdealProcessor", InTypeInt8, OutTypeInt8),
+    prop("NewThreadWaitTime", InTypeUInt32, OutTypeUInt32),
+    prop("Reserved", InTypeUInt32, OutTypeUInt32),
+];
+
+const READY_THREAD_PROPS: [MofProp; 5] = [
+    prop("TThreadId", InTypeUInt32, OutTypeHexInt32),
+    prop("AdjustReason", InTypeInt8, OutTypeInt8),
+    prop("AdjustIncrement", InTypeInt8, OutTypeInt8),
+    prop("Flag", InTypeInt8, OutTypeInt8),
+    prop("Reserved", InTypeInt8, OutTypeInt8),
+];
+
+const THREAD_SET_NAME_PROPS: [MofProp; 3] = [
+    prop("ProcessId", InTypeUInt32, OutTypeHexInt32),
+    prop("ThreadId", InTypeUInt32, OutTypeHexInt32),
+    prop("ThreadName", InTypeUnicodeString, OutTypeString),
+];
+
+const IMAGE_OPCODES: [(u8, &str); 4] = [(10, "Load"), (2, "UnLoad"), (3, "DCStart"), (4, "DCEnd")];
+
+const IMAGE_V2_PROPS: [MofProp; 12] = [
+    prop("ImageBase", InTypePointer, OutTypeHexInt64),
+    prop("ImageSize", InTypePointer, OutTypeHexInt64),
+    prop("ProcessId", InTypeUInt32, OutTypeUInt32),
+    prop("ImageChecksum", InTypeUInt32, OutTypeUInt32),
+    prop("TimeDateStamp", InTypeUInt32, OutTypeUInt32),
+    prop("Reserved0", InTypeUInt32, OutTypeUInt32),
+    prop("DefaultBase", InTypePointer, OutTypeHexInt64),
+    prop("Reserved1", InTypeUInt32, OutTypeUInt32),
+    prop("Reserved2", InTypeUInt32, OutTypeUInt32),
+    prop("Reserved3", InTypeUInt32, OutTypeUInt32),
+    prop("Reserved4", InTypeUInt32, OutTypeUInt32),
+    prop("FileName", InTypeUnicodeString, OutTypeString),
+];
+
+const IMAGE_V3_PROPS: [MofProp; 14] = [
+    prop("ImageBase", InTypePointer, OutTypeHexInt64),
+    prop("ImageSize", InTypePointer, OutTypeHexInt64),
+    prop("ProcessId", InTypeUInt32, OutTypeUInt32),
+    prop("ImageChecksum", InTypeUInt32, OutTypeUInt32),
+    prop("TimeDateStamp", InTypeUInt32, OutTypeUInt32),
+    prop("SignatureLevel", InTypeUInt8, OutTypeUInt8),
+    prop("SignatureType", InTypeUInt8, OutTypeUInt8),
+    prop("Reserved0", InTypeUInt16, OutTypeUInt16),
+    prop("DefaultBase", InTypePointer, OutTypeHexInt64),
+    prop("Reserved1", InTypeUInt32, OutTypeUInt32),
+    prop("Reserved2", InTypeUInt32, OutTypeUInt32),
+    prop("Reserved3", InTypeUInt32, OutTypeUInt32),
+    prop("Reserved4", InTypeUInt32, OutTypeUInt32),
+    prop("FileName", InTypeUnicodeString, OutTypeString),
+];
+
+const IMAGE_KERNEL_BASE_PROPS: [MofProp; 1] = [
+    prop("ImageBase", InTypePointer, OutTypeHexInt64),
+];
+
+// The MOF declares Stack1 to Stack192 but only as many addresses as were captured are logged.
+const STACK_WALK_PROPS: [MofProp; 4] = [
+    prop("EventTimeStamp", InTypeUInt64, OutTypeUInt64),
+    prop("StackProcess", InTypeUInt32, OutTypeHexInt32),
+    prop("StackThread", InTypeUInt32, OutTypeHexInt32),
+    array("Stack", InTypePointer, OutTypeHexInt64, 192),
+];
+
+const SAMPLE_PROF_PROPS: [MofProp; 4] = [
+    prop("InstructionPointer", InTypePointer, OutTypeHexInt64),
+    prop("ThreadId", InTypeUInt32, OutTypeHexInt32),
+    prop("Count", InTypeUInt16, OutTypeUInt16),
+    prop("Reserved", InTypeUInt16, OutTypeUInt16),
+];
+
+const SAMPLED_PROFILE_INTERVAL_OPCODES: [(u8, &str); 3] = [(72, "SetInterval"), (73, "CollectionStart"), (74, "CollectionEnd")];
+
+const SAMPLED_PROFILE_INTERVAL_V2_PROPS: [MofProp; 3] = [
+    prop("Source", InTypeUInt32, OutTypeUInt32),
+    prop("NewInterval", InTypeUInt32, OutTypeUInt32),
+    prop("OldInterval", InTypeUInt32, OutTypeUInt32),
+];
+
+const SAMPLED_PROFILE_INTERVAL_V3_PROPS: [MofProp; 4] = [
+    prop("Source", InTypeUInt32, OutTypeUInt32),
+    prop("NewInterval", InTypeUInt32, OutTypeUInt32),
+    prop("OldInterval", InTypeUInt32, OutTypeUInt32),
+    prop("SourceName", InTypeUnicodeString, OutTypeString),
+];
+
+const PAGE_FAULT_OPCODES: [(u8, &str); 6] = [
+    (10, "TransitionFault"),
+    (11, "DemandZeroFault"),
+    (12, "CopyOnWrite"),
+    (13, "GuardPageFault"),
+    (14, "HardPageFault"),
+    (15, "AccessViolation"),
+];
+
+const PAGE_FAULT_PROPS: [MofProp; 2] = [
+    prop("VirtualAddress", InTypePointer, OutTypeHexInt64),
+    prop("ProgramCounter", InTypePointer, OutTypeHexInt64),
+];
+
+const HARD_FAULT_PROPS: [MofProp; 6] = [
+    prop("InitialTime", InTypeInt64, OutTypeInt64),
+    prop("ReadOffset", InTypeUInt64, OutTypeHexInt64),
+    prop("VirtualAddress", InTypePointer, OutTypeHexInt64),
+    prop("FileObject", InTypePointer, OutTypeHexInt64),
+    prop("TThreadId", InTypeUInt32, OutTypeHexInt32),
+    prop("ByteCount", InTypeUInt32, OutTypeUInt32),
+];
+
+const VIRTUAL_ALLOC_PROPS: [MofProp; 4] = [
+    prop("BaseAddress", InTypePointer, OutTypeHexInt64),
+    prop("RegionSize", InTypePointer, OutTypeHexInt64),
+    prop("ProcessId", InTypeUInt32, OutTypeUInt32),
+    prop("Flags", InTypeUInt32, OutTypeHexInt32),
+];
+
+const DISK_IO_V2_PROPS: [MofProp; 8] = [
+    prop("DiskNumber", InTypeUInt32, OutTypeUInt32),
+    prop("IrpFlags", InTypeUInt32, OutTypeHexInt32),
+    prop("TransferSize", InTypeUInt32, OutTypeUInt32),
+    prop("Reserved", InTypeUInt32, OutTypeUInt32),
+    prop("ByteOffset", InTypeInt64, OutTypeInt64),
+    prop("FileObject", InTypePointer, OutTypeHexInt64),
+    prop("Irp", InTypePointer, OutTypeHexInt64),
+    prop("HighResResponseTime", InTypeUInt64, OutTypeUInt64),
+];
+
+const DISK_IO_V3_PROPS: [MofProp; 9] = [
+    prop("DiskNumber", InTypeUInt32, OutTypeUInt32),
+    prop("IrpFlags", InTypeUInt32, OutTypeHexInt32),
+    prop("TransferSize", InTypeUInt32, OutTypeUInt32),
+    prop("Reserved", InTypeUInt32, OutTypeUInt32),
+    prop("ByteOffset", InTypeInt64, OutTypeInt64),
+    prop("FileObject", InTypePointer, OutTypeHexInt64),
+    prop("Irp", InTypePointer, OutTypeHexInt64),
+    prop("HighResResponseTime", InTypeUInt64, OutTypeUInt64),
+    prop("IssuingThreadId", InTypeUInt32, OutTypeUInt32),
+];
+
+const DISK_IO_INIT_OPCODES: [(u8, &str); 3] = [(12, "ReadInit"), (13, "WriteInit"), (15, "FlushInit")];
+
+const DISK_IO_INIT_V2_PROPS: [MofProp; 1] = [
+    prop("Irp", InTypePointer, OutTypeHexInt64),
+];
+
+const DISK_IO_INIT_V3_PROPS: [MofProp; 2] = [
+    prop("Irp", InTypePointer, OutTypeHexInt64),
+    prop("IssuingThreadId", InTypeUInt32, OutTypeUInt32),
+];
+
+const DISK_IO_FLUSH_V2_PROPS: [MofProp; 4] = [
+    prop("DiskNumber", InTypeUInt32, OutTypeUInt32),
+    prop("IrpFlags", InTypeUInt32, OutTypeHexInt32),
+    prop("HighResResponseTime", InTypeUInt64, OutTypeUInt64),
+    prop("Irp", InTypePointer, OutTypeHexInt64),
+];
+
+const DISK_IO_FLUSH_V3_PROPS: [MofProp; 5] = [
+    prop("DiskNumber", InTypeUInt32, OutTypeUInt32),
+    prop("IrpFlags", InTypeUInt32, OutTypeHexInt32),
+    prop("HighResResponseTime", InTypeUInt64, OutTypeUInt64),
+    prop("Irp", InTypePointer, OutTypeHexInt64),
+    prop("IssuingThreadId", InTypeUInt32, OutTypeUInt32),
+];
+
+const FILE_IO_NAME_OPCODES: [(u8, &str); 4] = [(0, "Name"), (32, "FileCreate"), (35, "FileDelete"), (36, "FileRundown")];
+
+const FILE_IO_NAME_PROPS: [MofProp; 2] = [
+    prop("FileObject", InTypePointer, OutTypeHexInt64),
+    prop("FileName", InTypeUnicodeString, OutTypeString),
+];
+
+const FILE_IO_CREATE_V2_PROPS: [MofProp; 7] = [
+    prop("IrpPtr", InTypePointer, OutTypeHexInt64),
+    prop("TTID", InTypePointer, OutTypeHexInt64),
+    prop("FileObject", InTypePointer, OutTypeHexInt64),
+    prop("CreateOptions", InTypeUInt32, OutTypeHexInt32),
+    prop("FileAttributes", InTypeUInt32, OutTypeHexInt32),
+    prop("ShareAccess", InTypeUInt32, OutTypeHexInt32),
+    prop("OpenPath", InTypeUnicodeString, OutTypeString),
+];
+
+const FILE_IO_CREATE_V3_PROPS: [MofProp; 7] = [
+    prop("IrpPtr", InTypePointer, OutTypeHexInt64),
+    prop("FileObject", InTypePointer, OutTypeHexInt64),
+    prop("IssuingThreadId", InTypeUInt32, OutTypeUInt32),
+    prop("CreateOptions", InTypeUInt32, OutTypeHexInt32),
+    prop("FileAttributes", InTypeUInt32, OutTypeHexInt32),
+    prop("ShareAccess", InTypeUInt32, OutTypeHexInt32),
+    prop("OpenPath", InTypeUnicodeString, OutTypeString),
+];
+
+const FILE_IO_SIMPLE_OP_OPCODES: [(u8, &str); 3] = [(65, "Cleanup"), (66, "Close"), (73, "Flush")];
+
+const FILE_IO_SIMPLE_OP_V2_PROPS: [MofProp; 4] = [
+    prop("IrpPtr", InTypePointer, OutTypeHexInt64),
+    prop("TTID", InTypePointer, OutTypeHexInt64),
+    prop("FileObject", InTypePointer, OutTypeHexInt64),
+    prop("FileKey", InTypePointer, OutTypeHexInt64),
+];
+
+const FILE_IO_SIMPLE_OP_V3_PROPS: [MofProp; 4] = [
+    prop("IrpPtr", InTypePointer, OutTypeHexInt64),
+    prop("FileObject", InTypePointer, OutTypeHexInt64),
+    prop("FileKey", InTypePointer, OutTypeHexInt64),
+    prop("IssuingThreadId", InTypeUInt32, OutTypeUInt32),
+];
+
+const FILE_IO_READ_WRITE_OPCODES: [(u8, &str); 2] = [(67, "Read"), (68, "Write")];
+
+const FILE_IO_READ_WRITE_V2_PROPS: [MofProp; 7] = [
+    prop("Offset", InTypeUInt64, OutTypeUInt64),
+    prop("IrpPtr", InTypePointer, OutTypeHexInt64),
+    prop("TTID", InTypePointer, OutTypeHexInt64),
+    prop("FileObject", InTypePointer, OutTypeHexInt64),
+    prop("FileKey", InTypePointer, OutTypeHexInt64),
+    prop("IoSize", InTypeUInt32, OutTypeUInt32),
+    prop("IoFlags", InTypeUInt32, OutTypeHexInt32),
+];
+
+const FILE_IO_READ_WRITE_V3_PROPS: [MofProp; 7] = [
+    prop("Offset", InTypeUInt64, OutTypeUInt64),
+    prop("IrpPtr", InTypePointer, OutTypeHexInt64),
+    prop("FileObject", InTypePointer, OutTypeHexInt64),
+    prop("FileKey", InTypePointer, OutTypeHexInt64),
+    prop("IssuingThreadId", InTypeUInt32, OutTypeUInt32),
+    prop("IoSize", InTypeUInt32, OutTypeUInt32),
+    prop("IoFlags", InTypeUInt32, OutTypeHexInt32),
+];
+
+const FILE_IO_INFO_OPCODES: [(u8, &str); 5] = [(69, "SetInfo"), (70, "Delete"), (71, "Rename"), (74, "QueryInfo"), (75, "FSControl")];
+
+const FILE_IO_INFO_V2_PROPS: [MofProp; 6] = [
+    prop("IrpPtr", InTypePointer, OutTypeHexInt64),
+    prop("TTID", InTypePointer, OutTypeHexInt64),
+    prop("FileObject", InTypePointer, OutTypeHexInt64),
+    prop("FileKey", InTypePointer, OutTypeHexInt64),
+    prop("ExtraInfo", InTypePointer, OutTypeHexInt64),
+    prop("InfoClass", InTypeUInt32, OutTypeUInt32),
+];
+
+const FILE_IO_INFO_V3_PROPS: [MofProp; 6] = [
+    prop("IrpPtr", InTypePointer, OutTypeHexInt64),
+    prop("FileObject", InTypePointer, OutTypeHexInt64),
+    prop("FileKey", InTypePointer, OutTypeHexInt64),
+    prop("ExtraInfo", InTypePointer, OutTypeHexInt64),
+    prop("IssuingThreadId", InTypeUInt32, OutTypeUInt32),
+    prop("InfoClass", InTypeUInt32, OutTypeUInt32),
+];
+
+const FILE_IO_DIR_ENUM_OPCODES: [(u8, &str); 2] = [(72, "DirEnum"), (77, "DirNotify")];
+
+const FILE_IO_DIR_ENUM_V2_PROPS: [MofProp; 8] = [
+    prop("IrpPtr", InTypePointer, OutTypeHexInt64),
+    prop("TTID", InTypePointer, OutTypeHexInt64),
+    prop("FileObject", InTypePointer, OutTypeHexInt64),
+    prop("FileKey", InTypePointer, OutTypeHexInt64),
+    prop("Length", InTypeUInt32, OutTypeUInt32),
+    prop("InfoClass", InTypeUInt32, OutTypeUInt32),
+    prop("FileIndex", InTypeUInt32, OutTypeUInt32),
+    prop("FileName", InTypeUnicodeString, OutTypeString),
+];
+
+const FILE_IO_DIR_ENUM_V3_PROPS: [MofProp; 8] = [
+    prop("IrpPtr", InTypePointer, OutTypeHexInt64),
+    prop("FileObject", InTypePointer, OutTypeHexInt64),
+    prop("FileKey", InTypePointer, OutTypeHexInt64),
+    prop("IssuingThreadId", InTypeUInt32, OutTypeUInt32),
+    prop("Length", InTypeUInt32, OutTypeUInt32),
+    prop("InfoClass", InTypeUInt32, OutTypeUInt32),
+    prop("FileIndex", InTypeUInt32, OutTypeUInt32),
+    prop("FileName", InTypeUnicodeString, OutTypeString),
+];
+
+const FILE_IO_OP_END_PROPS: [MofProp; 3] = [
+    prop("IrpPtr", InTypePointer, OutTypeHexInt64),
+    prop("ExtraInfo", InTypePointer, OutTypeHexInt64),
+    prop("NtStatus", InTypeUInt32, OutTypeNtStatus),
+];
+
+const TCP_IP_SEND_IPV4_PROPS: [MofProp; 10] = [
+    prop("PID", InTypeUInt32, OutTypeUInt32),
+    prop("size", InTypeUInt32, OutTypeUInt32),
+    fixed("daddr", InTypeUInt32, OutTypeIpv4, 4),
+    fixed("saddr", InTypeUInt32, OutTypeIpv4, 4),
+    prop("dport", InTypeUInt16, OutTypePort),
+    prop("sport", InTypeUInt16, OutTypePort),
+    prop("startime", InTypeUInt32, OutTypeUInt32),
+    prop("endtime", InTypeUInt32, OutTypeUInt32),
+    prop("seqnum", InTypeUInt32, OutTypeUInt32),
+    prop("connid", InTypePointer, OutTypeHexInt64),
+];
+
+const TCP_IP_IPV4_OPCODES: [(u8, &str); 5] = [
+    (11, "RecvIPV4"),
+    (13, "DisconnectIPV4"),
+    (14, "RetransmitIPV4"),
+    (16, "ReconnectIPV4"),
+    (18, "TCPCopyIPV4"),
+];
+
+const TCP_IP_IPV4_PROPS: [MofProp; 8] = [
+    prop("PID", InTypeUInt32, OutTypeUInt32),
+    prop("size", InTypeUInt32, OutTypeUInt32),
+    fixed("daddr", InTypeUInt32, OutTypeIpv4, 4),
+    fixed("saddr", InTypeUInt32, OutTypeIpv4, 4),
+    prop("dport", InTypeUInt16, OutTypePort),
+    prop("sport", InTypeUInt16, OutTypePort),
+    prop("seqnum", InTypeUInt32, OutTypeUInt32),
+    prop("connid", InTypePointer, OutTypeHexInt64),
+];
+
+const TCP_IP_CONNECT_IPV4_OPCODES: [(u8, &str); 2] = [(12, "ConnectIPV4"), (15, "AcceptIPV4")];
+
+const TCP_IP_CONNECT_IPV4_PROPS: [MofProp; 15] = [
+    prop("PID", InTypeUInt32, OutTypeUInt32),
+    prop("size", InTypeUInt32, OutTypeUInt32),
+    fixed("daddr", InTypeUInt32, OutTypeIpv4, 4),
+    fixed("saddr", InTypeUInt32, OutTypeIpv4, 4),
+    prop("dport", InTypeUInt16, OutTypePort),
+    prop("sport", InTypeUInt16, OutTypePort),
+    prop("mss", InTypeUInt16, OutTypeUInt16),
+    prop("sackopt", InTypeUInt16, OutTypeUInt16),
+    prop("tsopt", InTypeUInt16, OutTypeUInt16),
+    prop("wsopt", InTypeUInt16, OutTypeUInt16),
+    prop("rcvwin", InTypeUInt32, OutTypeUInt32),
+    prop("rcvwinscale", InTypeInt16, OutTypeInt16),
+    prop("sndwinscale", InTypeInt16, OutTypeInt16),
+    prop("seqnum", InTypeUInt32, OutTypeUInt32),
+    prop("connid", InTypePointer, OutTypeHexInt64),
+];
+
+const TCP_IP_FAIL_PROPS: [MofProp; 2] = [
+    prop("Proto", InTypeUInt16, OutTypeUInt16),
+    prop("FailureCode", InTypeUInt16, OutTypeUInt16),
+];
+
+const TCP_IP_SEND_IPV6_PROPS: [MofProp; 10] = [
+    prop("PID", InTypeUInt32, OutTypeUInt32),
+    prop("size", InTypeUInt32, OutTypeUInt32),
+    fixed("daddr", InTypeBinary, OutTypeIpv6, 16),
+    fixed("saddr", InTypeBinary, OutTypeIpv6, 16),
+    prop("dport", InTypeUInt16, OutTypePort),
+    prop("sport", InTypeUInt16, OutTypePort),
+    prop("startime", InTypeUInt32, OutTypeUInt32),
+    prop("endtime", InTypeUInt32, OutTypeUInt32),
+    prop("seqnum", InTypeUInt32, OutTypeUInt32),
+    prop("connid", InTypePointer, OutTypeHexInt64),
+];
+
+const TCP_IP_IPV6_OPCODES: [(u8, &str); 5] = [
+    (27, "RecvIPV6"),
+    (29, "DisconnectIPV6"),
+    (30, "RetransmitIPV6"),
+    (32, "ReconnectIPV6"),
+    (34, "TCPCopyIPV6"),
+];
+
+const TCP_IP_IPV6_PROPS: [MofProp; 8] = [
+    prop("PID", InTypeUInt32, OutTypeUInt32),
+    prop("size", InTypeUInt32, OutTypeUInt32),
+    fixed("daddr", InTypeBinary, OutTypeIpv6, 16),
+    fixed("saddr", InTypeBinary, OutTypeIpv6, 16),
+    prop("dport", InTypeUInt16, OutTypePort),
+    prop("sport", InTypeUInt16, OutTypePort),
+    prop("seqnum", InTypeUInt32, OutTypeUInt32),
+    prop("connid", InTypePointer, OutTypeHexInt64),
+];
+
+const TCP_IP_CONNECT_IPV6_OPCODES: [(u8, &str); 2] = [(28, "ConnectIPV6"), (31, "AcceptIPV6")];
+
+const TCP_IP_CONNECT_IPV6_PROPS: [MofProp; 15] = [
+    prop("PID", InTypeUInt32, OutTypeUInt32),
+    prop("size", InTypeUInt32, OutTypeUInt32),
+    fixed("daddr", InTypeBinary, OutTypeIpv6, 16),
+    fixed("saddr", InTypeBinary, OutTypeIpv6, 16),
+    prop("dport", InTypeUInt16, OutTypePort),
+    prop("sport", InTypeUInt16, OutTypePort),
+    prop("mss", InTypeUInt16, OutTypeUInt16),
+    prop("sackopt", InTypeUInt16, OutTypeUInt16),
+    prop("tsopt", InTypeUInt16, OutTypeUInt16),
+    prop("wsopt", InTypeUInt16, OutTypeUInt16),
+    prop("rcvwin", InTypeUInt32, OutTypeUInt32),
+    prop("rcvwinscale", InTypeInt16, OutTypeInt16),
+    prop("sndwinscale", InTypeInt16, OutTypeInt16),
+    prop("seqnum", InTypeUInt32, OutTypeUInt32),
+    prop("connid", InTypePointer, OutTypeHexInt64),
+];
+
+static KERNEL_EVENT_TYPES: [MofEventType; 44] = [
+    MofEventType { task: "EventTrace", guid: EVENT_TRACE_GUID, opcodes: &[(0, "Header")], versions: &[2], props: &EVENT_TRACE_HEADER_PROPS },
+
+    MofEventType { task: "Process", guid: PROCESS_GUID, opcodes: &PROCESS_OPCODES, versions: &[2], props: &PROCESS_V2_PROPS },
+    MofEventType { task: "Process", guid: PROCESS_GUID, opcodes: &PROCESS_OPCODES, versions: &[3], props: &PROCESS_V3_PROPS },
+    MofEventType { task: "Process", guid: PROCESS_GUID, opcodes: &PROCESS_OPCODES, versions: &[4], props: &PROCESS_V4_PROPS },
+
+    MofEventType { task: "Thread", guid: THREAD_GUID, opcodes: &THREAD_OPCODES, versions: &[2], props: &THREAD_V2_PROPS },
+    MofEventType { task: "Thread", guid: THREAD_GUID, opcodes: &THREAD_OPCODES, versions: &[3, 4], props: &THREAD_V3_PROPS },
+    MofEventType { task: "Thread", guid: THREAD_GUID, opcodes: &[(36, "CSwitch")], versions: &[2, 3, 4], props: &CSWITCH_PROPS },
+    MofEventType { task: "Thread", guid: THREAD_GUID, opcodes: &[(50, "ReadyThread")], versions: &[2], props: &READY_THREAD_PROPS },
+    MofEventType { task: "Thread", guid: THREAD_GUID, opcodes: &[(72, "SetName")], versions: &[2], props: &THREAD_SET_NAME_PROPS },
+
+    MofEventType { task: "Image", guid: IMAGE_GUID, opcodes: &IMAGE_OPCODES, versions: &[2], props: &IMAGE_V2_PROPS },
+    MofEventType { task: "Image", guid: IMAGE_GUID, opcodes: &IMAGE_OPCODES, versions: &[3], props: &IMAGE_V3_PROPS },
+    MofEventType { task: "Image", guid: IMAGE_GUID, opcodes: &[(33, "KernelBase")], versions: &[2], props: &IMAGE_KERNEL_BASE_PROPS },
+
+    MofEventType { task: "StackWalk", guid: STACK_WALK_GUID, opcodes: &[(32, "Stack")], versions: &[2], props: &STACK_WALK_PROPS },
+
+    MofEventType { task: "PerfInfo", guid: PERF_INFO_GUID, opcodes: &[(46, "SampleProf")], versions: &[2], props: &SAMPLE_PROF_PROPS },
+    MofEventType { task: "PerfInfo", guid: PERF_INFO_GUID, opcodes: &SAMPLED_PROFILE_INTERVAL_OPCODES, versions: &[2], props: &SAMPLED_PROFILE_INTERVAL_V2_PROPS },
+    MofEventType { task: "PerfInfo", guid: PERF_INFO_GUID, opcodes: &SAMPLED_PROFILE_INTERVAL_OPCODES, versions: &[3], props: &SAMPLED_PROFILE_INTERVAL_V3_PROPS },
+
+    MofEventType { task: "PageFault", guid: PAGE_FAULT_GUID, opcodes: &PAGE_FAULT_OPCODES, versions: &[2], props: &PAGE_FAULT_PROPS },
+    MofEventType { task: "PageFault", guid: PAGE_FAULT_GUID, opcodes: &[(32, "HardFault")], versions: &[2], props: &HARD_FAULT_PROPS },
+    MofEventType { task: "PageFault", guid: PAGE_FAULT_GUID, opcodes: &[(98, "VirtualAlloc"), (99, "VirtualFree")], versions: &[2], props: &VIRTUAL_ALLOC_PROPS },
+
+    MofEventType { task: "DiskIo", guid: DISK_IO_GUID, opcodes: &[(10, "Read"), (11, "Write")], versions: &[2], props: &DISK_IO_V2_PROPS },
+    MofEventType { task: "DiskIo", guid: DISK_IO_GUID, opcodes: &[(10, "Read"), (11, "Write")], versions: &[3], props: &DISK_IO_V3_PROPS },
+    MofEventType { task: "DiskIo", guid: DISK_IO_GUID, opcodes: &DISK_IO_INIT_OPCODES, versions: &[2], props: &DISK_IO_INIT_V2_PROPS },
+    MofEventType { task: "DiskIo", guid: DISK_IO_GUID, opcodes: &DISK_IO_INIT_OPCODES, versions: &[3], props: &DISK_IO_INIT_V3_PROPS },
+    MofEventType { task: "DiskIo", guid: DISK_IO_GUID, opcodes: &[(14, "FlushBuffers")], versions: &[2], props: &DISK_IO_FLUSH_V2_PROPS },
+    MofEventType { task: "DiskIo", guid: DISK_IO_GUID, opcodes: &[(14, "FlushBuffers")], versions: &[3], props: &DISK_IO_FLUSH_V3_PROPS },
+
+    MofEventType { task: "FileIo", guid: FILE_IO_GUID, opcodes: &FILE_IO_NAME_OPCODES, versions: &[2, 3], props: &FILE_IO_NAME_PROPS },
+    MofEventType { task: "FileIo", guid: FILE_IO_GUID, opcodes: &[(64, "Create")], versions: &[2], props: &FILE_IO_CREATE_V2_PROPS },
+    MofEventType { task: "FileIo", guid: FILE_IO_GUID, opcodes: &[(64, "Create")], versions: &[3], props: &FILE_IO_CREATE_V3_PROPS },
+    MofEventType { task: "FileIo", guid: FILE_IO_GUID, opcodes: &FILE_IO_SIMPLE_OP_OPCODES, versions: &[2], props: &FILE_IO_SIMPLE_OP_V2_PROPS },
+    MofEventType { task: "FileIo", guid: FILE_IO_GUID, opcodes: &FILE_IO_SIMPLE_OP_OPCODES, versions: &[3], props: &FILE_IO_SIMPLE_OP_V3_PROPS },
+    MofEventType { task: "FileIo", guid: FILE_IO_GUID, opcodes: &FILE_IO_READ_WRITE_OPCODES, versions: &[2], props: &FILE_IO_READ_WRITE_V2_PROPS },
+    MofEventType { task: "FileIo", guid: FILE_IO_GUID, opcodes: &FILE_IO_READ_WRITE_OPCODES, versions: &[3], props: &FILE_IO_READ_WRITE_V3_PROPS },
+    MofEventType { task: "FileIo", guid: FILE_IO_GUID, opcodes: &FILE_IO_INFO_OPCODES, versions: &[2], props: &FILE_IO_INFO_V2_PROPS },
+    MofEventType { task: "FileIo", guid: FILE_IO_GUID, opcodes: &FILE_IO_INFO_OPCODES, versions: &[3], props: &FILE_IO_INFO_V3_PROPS },
+    MofEventType { task: "FileIo", guid: FILE_IO_GUID, opcodes: &FILE_IO_DIR_ENUM_OPCODES, versions: &[2], props: &FILE_IO_DIR_ENUM_V2_PROPS },
+    MofEventType { task: "FileIo", guid: FILE_IO_GUID, opcodes: &FILE_IO_DIR_ENUM_OPCODES, versions: &[3], props: &FILE_IO_DIR_ENUM_V3_PROPS },
+    MofEventType { task: "FileIo", guid: FILE_IO_GUID, opcodes: &[(76, "OperationEnd")], versions: &[2, 3], props: &FILE_IO_OP_END_PROPS },
+
+    MofEventType { task: "TcpIp", guid: TCP_IP_GUID, opcodes: &[(10, "SendIPV4")], versions: &[2], props: &TCP_IP_SEND_IPV4_PROPS },
+    MofEventType { task: "TcpIp", guid: TCP_IP_GUID, opcodes: &TCP_IP_IPV4_OPCODES, versions: &[2], props: &TCP_IP_IPV4_PROPS },
+    MofEventType { task: "TcpIp", guid: TCP_IP_GUID, opcodes: &TCP_IP_CONNECT_IPV4_OPCODES, versions: &[2], props: &TCP_IP_CONNECT_IPV4_PROPS },
+    MofEventType { task: "TcpIp", guid: TCP_IP_GUID, opcodes: &[(17, "Fail")], versions: &[2], props: &TCP_IP_FAIL_PROPS },
+    MofEventType { task: "TcpIp", guid: TCP_IP_GUID, opcodes: &[(26, "SendIPV6")], versions: &[2], props: &TCP_IP_SEND_IPV6_PROPS },
+    MofEventType { task: "TcpIp", guid: TCP_IP_GUID, opcodes: &TCP_IP_IPV6_OPCODES, versions: &[2], props: &TCP_IP_IPV6_PROPS },
+    MofEventType { task: "TcpIp", guid: TCP_IP_GUID, opcodes: &TCP_IP_CONNECT_IPV6_OPCODES, versions: &[2], props: &TCP_IP_CONNECT_IPV6_PROPS },
+];
+
+/// A single opcode and version of one of the [KERNEL_EVENT_TYPES]
+struct KernelSchema {
+    event_type: &'static MofEventType,
+    opcode: u8,
+    opcode_name: &'static str,
+    version: u8,
+}
+
+impl EventSchema for KernelSchema {
+    fn provider_guid(&self) -> GUID {
+        self.event_type.guid
+    }
+
+    fn event_id(&self) -> u16 {
+        0
+    }
+
+    fn opcode(&self) -> u8 {
+        self.opcode
+    }
+
+    fn event_version(&self) -> u8 {
+        self.version
+    }
+
+    fn level(&self) -> u8 { 0 }
+
+    fn decoding_source(&self) -> DecodingSource {
+        DecodingSource::DecodingSourceWbem
+    }
+
+    fn provider_name(&self) -> String {
+        "MSNT_SystemTrace".to_owned()
+    }
+
+    fn task_name(&self) -> String {
+        self.event_type.task.to_owned()
+    }
+
+    fn opcode_name(&self) -> String {
+        self.opcode_name.to_owned()
+    }
+
+    fn property_count(&self) -> u32 {
+        self.event_type.props.len() as u32
+    }
+
+    fn property(&self, index: u32) -> Property {
+        let prop = &self.event_type.props[index as usize];
+        Property { name: prop.name.to_owned(),
+            desc: PropertyDesc::Primitive(PrimitiveDesc{ in_type: prop.in_type,
+                out_type: prop.out_type,}),
+        count: prop.count,
+        length: PropertyLength::Length(prop.length),
+        map_info: None,
+        flags: PropertyFlags::empty()}
+    }
+}
+
+/// Registers a schema for every opcode and version of the kernel event classes that we know about
+pub fn add_kernel_schemas(locator: &mut SchemaLocator) {
+    for event_type in &KERNEL_EVENT_TYPES {
+        for &(opcode, opcode_name) in event_type.opcodes {
+            for &version in event_type.versions {
+                locator.add_custom_schema(Box::new(KernelSchema { event_type, opcode, opcode_name, version }));
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::etw_types::{EventDescriptor, EventRecord};
+    use crate::parser::{Parser, TryParse};
+    use std::borrow::Cow;
+
+    #[test]
+    fn process_dcstart_v4() {
+        let mut locator = SchemaLocator::new();
+        add_kernel_schemas(&mut locator);
+
+        let mut user_data = Vec::new();
+        user_data.extend_from_slice(&0xffff_a000_0000_1000u64.to_le_bytes()); // UniqueProcessKey
+        user_data.extend_from_slice(&1234u32.to_le_bytes()); // ProcessId
+        user_data.extend_from_slice(&4u32.to_le_bytes()); // ParentId
+        user_data.extend_from_slice(&1u32.to_le_bytes()); // SessionId
+        user_data.extend_from_slice(&259i32.to_le_bytes()); // ExitStatus
+        user_data.extend_from_slice(&0x1aa000u64.to_le_bytes()); // DirectoryTableBase
+        user_data.extend_from_slice(&0u32.to_le_bytes()); // Flags
+        // UserSID: a TOKEN_USER followed by S-1-5-18
+        user_data.extend_from_slice(&0xffff_b000_0000_2000u64.to_le_bytes());
+        user_data.extend_from_slice(&0u64.to_le_bytes());
+        user_data.extend_from_slice(&[1, 1, 0, 0, 0, 0, 0, 5]);
+        user_data.extend_from_slice(&18u32.to_le_bytes());
+        user_data.extend_from_slice(b"firefox.exe\0");
+        for c in "firefox.exe -contentproc\0".encode_utf16() {
+            user_data.extend_from_slice(&c.to_le_bytes());
+        }
+        user_data.extend_from_slice(&[0, 0, 0, 0]);
+
+        let record = EventRecord {
+            provider_id: PROCESS_GUID,
+            descriptor: EventDescriptor { version: 4, opcode: 3, ..Default::default() },
+            user_data: Cow::Owned(user_data),
+            ..Default::default()
+        };
+
+        let event = locator.event_schema(&record).unwrap();
+        assert_eq!(event.name(), "MSNT_SystemTrace/Process/DCStart");
+        let mut parser = Parser::create(&event);
+        let process_id: u32 = parser.parse("ProcessId");
+        let image_file_name: String = parser.parse("ImageFileName");
+        let command_line: String = parser.parse("CommandLine");
+        assert_eq!(process_id, 1234);
+        assert_eq!(image_file_name, "firefox.exe");
+        assert_eq!(command_line, "firefox.exe -contentproc");
+    }
+}
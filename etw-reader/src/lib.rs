@@ -6,15 +6,21 @@ extern crate bitflags;
 #[macro_use]
 extern crate num_derive;
 
-use windows::{core::{h, Error, HRESULT, HSTRING, PWSTR}, Win32::{Foundation::{GetLastError, ERROR_INSUFFICIENT_BUFFER, ERROR_MORE_DATA, ERROR_SUCCESS, MAX_PATH}, System::Diagnostics::Etw::{EnumerateTraceGuids, EnumerateTraceGuidsEx, TraceGuidQueryInfo, TraceGuidQueryList, CONTROLTRACE_HANDLE, EVENT_TRACE_FLAG, TRACE_GUID_INFO, TRACE_GUID_PROPERTIES, TRACE_PROVIDER_INSTANCE_INFO}}};
-use crate::{parser::{Parser, ParserError, TryParse}, schema::SchemaLocator, tdh_types::{PropertyDesc, PrimitiveDesc, TdhInType}, traits::EncodeUtf16};
+#[cfg(windows)]
+use windows::{core::{h, HSTRING, PWSTR}, Win32::{Foundation::{GetLastError, ERROR_INSUFFICIENT_BUFFER, ERROR_MORE_DATA, MAX_PATH}, System::Diagnostics::Etw::{EnumerateTraceGuids, EnumerateTraceGuidsEx, TraceGuidQueryInfo, TraceGuidQueryList, CONTROLTRACE_HANDLE, EVENT_TRACE_FLAG, TRACE_GUID_INFO, TRACE_GUID_PROPERTIES, TRACE_PROVIDER_INSTANCE_INFO}}};
+#[cfg(windows)]
+use crate::traits::EncodeUtf16;
+use crate::{parser::{Parser, ParserError, TryParse}, schema::SchemaLocator, tdh_types::{PropertyDesc, TdhInType}};
 
 #[macro_use]
 extern crate memoffset;
 
 use etw_types::EventRecord;
 use tdh_types::{Property, TdhOutType};
-use std::{borrow::Cow, collections::HashMap, hash::BuildHasherDefault, mem, path::Path};
+use std::{borrow::Cow, collections::HashMap, hash::BuildHasherDefault, net::IpAddr, ops::ControlFlow, path::Path};
+#[cfg(windows)]
+use std::mem;
+#[cfg(windows)]
 use windows::Win32::System::Diagnostics::Etw;
 use fxhash::FxHasher;
 
@@ -24,31 +30,42 @@ pub const INVALID_TRACE_HANDLE: TraceHandle = u64::MAX;
 //, WindowsProgramming};
 
 pub mod etw_types;
+pub mod etl;
+#[cfg(windows)]
 pub mod tdh;
 pub mod tdh_types;
 pub mod utils;
 pub mod parser;
 pub mod property;
 pub mod schema;
+#[cfg(windows)]
 pub mod sddl;
 pub mod traits;
 pub mod custom_schemas;
+pub mod kernel_schemas;
+pub mod tracelogging;
+pub mod manifest;
+pub mod schema_store;
+pub mod value;
 //pub mod trace;
 //pub mod provider;
 
 pub use windows::core::GUID;
 
 pub type FastHashMap<K, V> = HashMap<K, V, BuildHasherDefault<FxHasher>>;
+#[cfg(windows)]
 #[repr(C)]
 #[derive(Clone)]
 pub struct EventTraceLogfile(Etw::EVENT_TRACE_LOGFILEW);
 
+#[cfg(windows)]
 impl Default for EventTraceLogfile {
     fn default() -> Self {
         unsafe { std::mem::zeroed::<EventTraceLogfile>() }
     }
 }
 
+#[cfg(windows)]
 impl std::ops::Deref for EventTraceLogfile {
     type Target = Etw::EVENT_TRACE_LOGFILEW;
 
@@ -56,33 +73,103 @@ impl std::ops::Deref for EventTraceLogfile {
         &self.0
     }
 }
+#[cfg(windows)]
 impl std::ops::DerefMut for EventTraceLogfile {
     fn deref_mut(&mut self) -> &mut Self::Target {
         &mut self.0
     }
 }
 
+#[cfg(windows)]
 unsafe extern "system" fn trace_callback_thunk(event_record: *mut Etw::EVENT_RECORD) {
     let f: &mut &mut dyn FnMut(&EventRecord) = std::mem::transmute((*event_record).UserContext);
-    f(std::mem::transmute(event_record))
+    f(&EventRecord::from_etw(&*event_record))
 }
 
+/// The `Context` of a trace opened by [open_trace_until]
+#[cfg(windows)]
+struct TraceContext<'a> {
+    callback: &'a mut dyn FnMut(&EventRecord) -> ControlFlow<()>,
+    stopped: bool,
+}
+
+#[cfg(windows)]
+unsafe extern "system" fn trace_until_callback_thunk(event_record: *mut Etw::EVENT_RECORD) {
+    let context = &mut *((*event_record).UserContext as *mut TraceContext);
+    // The rest of the current buffer is still delivered after the callback asked to stop
+    if !context.stopped {
+        context.stopped = (context.callback)(&EventRecord::from_etw(&*event_record)).is_break();
+    }
+}
+
+/// Called after each buffer, `ProcessTrace` returns when this returns `FALSE`
+#[cfg(windows)]
+unsafe extern "system" fn trace_buffer_callback_thunk(log_file: *mut Etw::EVENT_TRACE_LOGFILEW) -> u32 {
+    let context = &*((*log_file).Context as *const TraceContext);
+    (!context.stopped) as u32
+}
+
+/// Calls `callback` for each event of the .etl file at `path`, in timestamp order
 pub fn open_trace<F: FnMut(&EventRecord)>(path: &Path, mut callback: F) -> Result<(), std::io::Error> {
+    open_trace_until(path, |e| {
+        callback(e);
+        ControlFlow::Continue(())
+    })
+}
+
+/// Like [open_trace], but stops processing the trace once `callback` returns [ControlFlow::Break]
+#[cfg(windows)]
+pub fn open_trace_until<F: FnMut(&EventRecord) -> ControlFlow<()>>(path: &Path, mut callback: F) -> Result<(), std::io::Error> {
     let mut log_file = EventTraceLogfile::default();
 
-    #[cfg(windows)]
     let path = HSTRING::from(path.as_os_str());
-    #[cfg(not(windows))]
-    let path: HSTRING = panic!();
     log_file.0.LogFileName = PWSTR(path.as_wide().as_ptr() as *mut _);
     log_file.0.Anonymous1.ProcessTraceMode = Etw::PROCESS_TRACE_MODE_EVENT_RECORD | Etw::PROCESS_TRACE_MODE_RAW_TIMESTAMP;
-    let mut cb: &mut dyn FnMut(&EventRecord) = &mut callback;
-    log_file.0.Context = unsafe { std::mem::transmute(&mut cb) };
-    log_file.0.Anonymous2.EventRecordCallback = Some(trace_callback_thunk);
+    let mut context = TraceContext { callback: &mut callback, stopped: false };
+    log_file.0.Context = &mut context as *mut TraceContext as *mut _;
+    log_file.0.Anonymous2.EventRecordCallback = Some(trace_until_callback_thunk);
+    log_file.0.BufferCallback = Some(trace_buffer_callback_thunk);
 
     let session_handle = unsafe { Etw::OpenTraceW(&mut *log_file) };
     let result = unsafe { Etw::ProcessTrace(&[session_handle], None, None) };
-    result.map_err(|e| std::io::Error::from_raw_os_error(e.code().0))
+    match result {
+        // ProcessTrace reports being stopped by the buffer callback as ERROR_CANCELLED
+        Err(_) if context.stopped => Ok(()),
+        result => result.map_err(|e| std::io::Error::from_raw_os_error(e.code().0)),
+    }
+}
+
+/// There is no `ProcessTrace` outside of Windows so we decode the .etl file ourselves.
+#[cfg(not(windows))]
+pub fn open_trace_until<F: FnMut(&EventRecord) -> ControlFlow<()>>(path: &Path, callback: F) -> Result<(), std::io::Error> {
+    etl::process_trace(path, callback)
+}
+
+/// Returns the `LogFileMode` that the .etl file at `path` was recorded with, without processing
+/// any of its events.
+///
+/// This is useful for noticing traces that were recorded into a circular buffer.
+#[cfg(windows)]
+pub fn trace_log_file_mode(path: &Path) -> Result<u32, std::io::Error> {
+    let mut log_file = EventTraceLogfile::default();
+
+    let path = HSTRING::from(path.as_os_str());
+    log_file.0.LogFileName = PWSTR(path.as_wide().as_ptr() as *mut _);
+    log_file.0.Anonymous1.ProcessTraceMode = Etw::PROCESS_TRACE_MODE_EVENT_RECORD | Etw::PROCESS_TRACE_MODE_RAW_TIMESTAMP;
+
+    // OpenTrace fills in the logfile header
+    let session_handle = unsafe { Etw::OpenTraceW(&mut *log_file) };
+    if session_handle.Value == INVALID_TRACE_HANDLE {
+        return Err(std::io::Error::last_os_error());
+    }
+    let log_file_mode = log_file.0.LogfileHeader.LogFileMode;
+    unsafe { Etw::CloseTrace(session_handle) }.map_err(|e| std::io::Error::from_raw_os_error(e.code().0))?;
+    Ok(log_file_mode)
+}
+
+#[cfg(not(windows))]
+pub fn trace_log_file_mode(path: &Path) -> Result<u32, std::io::Error> {
+    etl::log_file_mode(path)
 }
 
 /// Complete Trace Properties struct
@@ -92,6 +179,7 @@ pub fn open_trace<F: FnMut(&EventRecord)>(path: &Path, mut callback: F) -> Resul
 /// provides the full definition of the properties plus the the allocation for both names
 ///
 /// See: [EVENT_TRACE_PROPERTIES](https://docs.microsoft.com/en-us/windows/win32/api/evntrace/ns-evntrace-event_trace_properties)
+#[cfg(windows)]
 #[repr(C)]
 #[derive(Clone, Copy)]
 pub struct TraceInfo {
@@ -101,6 +189,7 @@ pub struct TraceInfo {
     log_file_name: [u16; MAX_PATH as usize],
 }
 
+#[cfg(windows)]
 impl Default for TraceInfo {
     fn default() -> Self {
         let properties = Etw::EVENT_TRACE_PROPERTIES::default();
@@ -112,6 +201,7 @@ impl Default for TraceInfo {
     }
 }
 
+#[cfg(windows)]
 impl TraceInfo {
     pub(crate) fn fill(
         &mut self,
@@ -142,10 +232,12 @@ impl TraceInfo {
 }
 
 
+#[cfg(windows)]
 #[repr(C)]
 #[derive(Clone, Copy, Default)]
 pub struct EnableTraceParameters(Etw::ENABLE_TRACE_PARAMETERS);
 
+#[cfg(windows)]
 impl EnableTraceParameters {
     pub fn create(guid: GUID, trace_flags: u32) -> Self {
         let mut params = EnableTraceParameters::default();
@@ -162,6 +254,7 @@ impl EnableTraceParameters {
     }
 }
 
+#[cfg(windows)]
 impl std::ops::Deref for EnableTraceParameters {
     type Target = Etw::ENABLE_TRACE_PARAMETERS;
 
@@ -170,6 +263,7 @@ impl std::ops::Deref for EnableTraceParameters {
     }
 }
 
+#[cfg(windows)]
 impl std::ops::DerefMut for EnableTraceParameters {
     fn deref_mut(&mut self) -> &mut Self::Target {
         &mut self.0
@@ -221,6 +315,7 @@ pub struct Provider {
     // filters: RwLock<Vec<F>>,
 }
 
+#[cfg(windows)]
 pub fn start_trace<F: FnMut(&EventRecord)>(mut callback: F)  {
     let guid_str = "22fb2cd6-0e7b-422b-a0c7-2fad1fd0e716";
     let guid_str = "DB6F6DDB-AC77-4E88-8253-819DF9BBF140";
@@ -294,12 +389,45 @@ pub fn write_property(output: &mut dyn std::fmt::Write, parser: &mut Parser, pro
     } else {
         write!(output, "  {}= ", property.name).unwrap();
     }
+    write_value(output, parser, &property.name, property);
+}
+
+/// Writes the value at `path`, which is described by `property`, including all of its elements
+/// if it's an array
+fn write_value(output: &mut dyn std::fmt::Write, parser: &mut Parser, path: &str, property: &Property) {
+    if property.is_array() {
+        let len = match parser.array_len(path) {
+            Ok(len) => len,
+            Err(e) => {
+                write!(output, "Err({:?}) type: {:?}", e, property.desc).unwrap();
+                return;
+            }
+        };
+        write!(output, "[").unwrap();
+        for i in 0..len {
+            if i > 0 {
+                write!(output, ", ").unwrap();
+            }
+            write_element(output, parser, &format!("{}[{}]", path, i), property);
+        }
+        write!(output, "]").unwrap();
+    } else {
+        write_element(output, parser, path, property);
+    }
+}
+
+fn write_element(output: &mut dyn std::fmt::Write, parser: &mut Parser, path: &str, property: &Property) {
     if let Some(map_info) = &property.map_info {
-        let value = match property.desc {
-            PropertyDesc::Primitive(PrimitiveDesc{ in_type: TdhInType::InTypeUInt32, ..}) => TryParse::<u32>::parse(parser, &property.name),
-            PropertyDesc::Primitive(PrimitiveDesc{ in_type: TdhInType::InTypeUInt16, ..}) => TryParse::<u16>::parse(parser, &property.name) as u32,
-            PropertyDesc::Primitive(PrimitiveDesc{ in_type: TdhInType::InTypeUInt8, ..}) => TryParse::<u8>::parse(parser, &property.name) as u32,
-            _ => panic!("{:?}", property.desc)
+        let value = match &property.desc {
+            PropertyDesc::Primitive(desc) => value::map_key(parser, path, desc.in_type),
+            PropertyDesc::Struct(_) => Err(ParserError::InvalidType),
+        };
+        let value = match value {
+            Ok(value) => value,
+            Err(e) => {
+                write!(output, "Err({:?}) type: {:?}", e, property.desc).unwrap();
+                return;
+            }
         };
         if map_info.is_bitmap {
             let mut remaining_bits_str = String::new();
@@ -325,17 +453,21 @@ pub fn write_property(output: &mut dyn std::fmt::Write, parser: &mut Parser, pro
             PropertyDesc::Primitive(desc) => {
                 // XXX: we should be using the out_type here instead of in_type
                 match desc.in_type {
-                    TdhInType::InTypeUnicodeString => TryParse::<String>::try_parse(parser, &property.name),
-                    TdhInType::InTypeAnsiString => TryParse::<String>::try_parse(parser, &property.name),
-                    TdhInType::InTypeBoolean => TryParse::<bool>::try_parse(parser, &property.name).map(|x| x.to_string()),
-                    TdhInType::InTypeHexInt32 => TryParse::<i32>::try_parse(parser, &property.name).map(|x| x.to_string()),
-                    TdhInType::InTypeUInt32 => TryParse::<u32>::try_parse(parser, &property.name).map(|x| x.to_string()),
-                    TdhInType::InTypeUInt16 => TryParse::<u16>::try_parse(parser, &property.name).map(|x| x.to_string()),
-                    TdhInType::InTypeUInt8 => TryParse::<u8>::try_parse(parser, &property.name).map(|x| x.to_string()),
-                    TdhInType::InTypeInt8 => TryParse::<i8>::try_parse(parser, &property.name).map(|x| x.to_string()),
-                    TdhInType::InTypeInt64 => TryParse::<i64>::try_parse(parser, &property.name).map(|x| x.to_string()),
+                    TdhInType::InTypeUnicodeString | TdhInType::InTypeAnsiString |
+                    TdhInType::InTypeCountedString | TdhInType::InTypeCountedAnsiString |
+                    TdhInType::InTypeReverseCountedString | TdhInType::InTypeReverseCountedAnsiString |
+                    TdhInType::InTypeNonNullTerminatedString | TdhInType::InTypeNonNullTerminatedAnsiString |
+                    TdhInType::InTypeSid | TdhInType::InTypeWBEMSID => TryParse::<String>::try_parse(parser, path),
+                    TdhInType::InTypeBoolean => TryParse::<bool>::try_parse(parser, path).map(|x| x.to_string()),
+                    TdhInType::InTypeHexInt32 => TryParse::<i32>::try_parse(parser, path).map(|x| x.to_string()),
+                    TdhInType::InTypeUInt32 => TryParse::<u32>::try_parse(parser, path).map(|x| x.to_string()),
+                    TdhInType::InTypeUInt16 => TryParse::<u16>::try_parse(parser, path).map(|x| x.to_string()),
+                    TdhInType::InTypeUInt8 => TryParse::<u8>::try_parse(parser, path).map(|x| x.to_string()),
+                    TdhInType::InTypeInt8 => TryParse::<i8>::try_parse(parser, path).map(|x| x.to_string()),
+                    TdhInType::InTypeInt16 => TryParse::<i16>::try_parse(parser, path).map(|x| x.to_string()),
+                    TdhInType::InTypeInt64 => TryParse::<i64>::try_parse(parser, path).map(|x| x.to_string()),
                     TdhInType::InTypeUInt64 => {
-                        let i = TryParse::<u64>::try_parse(parser, &property.name);
+                        let i = TryParse::<u64>::try_parse(parser, path);
                         if desc.out_type == TdhOutType::OutTypeHexInt64 {
                             i.map(|x| format!("0x{:x}", x))
                         } else {
@@ -343,21 +475,41 @@ pub fn write_property(output: &mut dyn std::fmt::Write, parser: &mut Parser, pro
                         }
                     },
                     TdhInType::InTypeHexInt64 => {
-                        let i = TryParse::<i64>::try_parse(parser, &property.name);
+                        let i = TryParse::<i64>::try_parse(parser, path);
                         i.map(|x| format!("0x{:x}", x))
                     },
-                    TdhInType::InTypePointer | TdhInType::InTypeSizeT => TryParse::<u64>::try_parse(parser, &property.name).map(|x| format!("0x{:x}", x)),
-                    TdhInType::InTypeGuid => TryParse::<GUID>::try_parse(parser, &property.name).map(|x| format!("{:?}", x)),
+                    TdhInType::InTypePointer | TdhInType::InTypeSizeT => TryParse::<u64>::try_parse(parser, path).map(|x| format!("0x{:x}", x)),
+                    TdhInType::InTypeGuid => TryParse::<GUID>::try_parse(parser, path).map(|x| format!("{:?}", x)),
                     TdhInType::InTypeInt32 => {
-                        TryParse::<i32>::try_parse(parser, &property.name).map(|x| x.to_string())
+                        TryParse::<i32>::try_parse(parser, path).map(|x| x.to_string())
                     }
                     TdhInType::InTypeFloat => {
-                        TryParse::<f32>::try_parse(parser, &property.name).map(|x| x.to_string())
+                        TryParse::<f32>::try_parse(parser, path).map(|x| x.to_string())
+                    }
+                    TdhInType::InTypeDouble => {
+                        TryParse::<f64>::try_parse(parser, path).map(|x| x.to_string())
+                    }
+                    TdhInType::InTypeBinary if desc.out_type == TdhOutType::OutTypeIpv6 => {
+                        TryParse::<IpAddr>::try_parse(parser, path).map(|x| x.to_string())
+                    }
+                    TdhInType::InTypeBinary | TdhInType::InTypeHexdump => {
+                        TryParse::<Vec<u8>>::try_parse(parser, path).map(|x| x.iter().map(|b| format!("{:02x}", b)).collect())
                     }
                     _ => Ok(format!("Unknown {:?} -> {:?}", desc.in_type, desc.out_type))
                 }
             }
-            PropertyDesc::Struct(desc) => Ok(format!("unhandled struct {} {}", desc.start_index, desc.num_members)),
+            PropertyDesc::Struct(_) => {
+                write!(output, "{{ ").unwrap();
+                for (i, member) in parser.struct_members(property).iter().enumerate() {
+                    if i > 0 {
+                        write!(output, ", ").unwrap();
+                    }
+                    write!(output, "{}: ", member.name).unwrap();
+                    write_value(output, parser, &format!("{}.{}", path, member.name), member);
+                }
+                write!(output, " }}").unwrap();
+                return;
+            }
         };
         let value = match value {
             Ok(value) => value,
@@ -377,15 +529,16 @@ pub fn print_property(parser: &mut Parser, property: &Property, write_types: boo
 
 
 pub fn add_custom_schemas(locator: &mut SchemaLocator) {
+    kernel_schemas::add_kernel_schemas(locator);
     locator.add_custom_schema(Box::new(custom_schemas::ImageID{}));
     locator.add_custom_schema(Box::new(custom_schemas::DbgID{}));
     locator.add_custom_schema(Box::new(custom_schemas::EventInfo{}));
-    locator.add_custom_schema(Box::new(custom_schemas::ThreadStart{}));
     locator.add_custom_schema(Box::new(custom_schemas::D3DUmdLogging_MapAllocation{}));
     locator.add_custom_schema(Box::new(custom_schemas::D3DUmdLogging_RundownAllocation{}));
     locator.add_custom_schema(Box::new(custom_schemas::D3DUmdLogging_UnmapAllocation{}));
 }
 
+#[cfg(windows)]
 pub fn enumerate_trace_guids() {
     let mut count = 1;
     loop {
@@ -412,6 +565,7 @@ pub fn enumerate_trace_guids() {
     }
 }
 
+#[cfg(windows)]
 pub fn enumerate_trace_guids_ex(print_instances: bool) {
     let mut required_size: u32 = 0;
 
@@ -452,6 +606,7 @@ pub fn enumerate_trace_guids_ex(print_instances: bool) {
 }
 
 
+#[cfg(windows)]
 pub fn get_provider_info(guid: &GUID) -> Vec<u8> {
     let mut required_size: u32 = 0;
 
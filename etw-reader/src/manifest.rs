@@ -0,0 +1,615 @@
+//! Instrumentation manifest (.man) loading
+//!
+//! Manifest based providers that aren't registered on the machine that we're running on can't be
+//! decoded with TDH. This module parses the manifest XML directly and produces an [EventSchema]
+//! for every event in it.
+//!
+//! See: [Instrumentation manifest schema](https://learn.microsoft.com/en-us/windows/win32/wes/eventmanifestschema-schema)
+use std::collections::HashMap;
+use std::rc::Rc;
+
+use roxmltree::{Document, Node};
+use windows::core::GUID;
+
+use crate::etw_types::{DecodingSource, STANDARD_OPCODES};
+use crate::schema::EventSchema;
+use crate::tdh_types::{PrimitiveDesc, Property, PropertyDesc, PropertyFlags, PropertyLength, PropertyMapInfo, StructDesc, TdhInType, TdhOutType};
+use crate::tracelogging::default_out_type;
+use crate::utils;
+use crate::FastHashMap;
+
+#[derive(Debug)]
+pub enum ManifestError {
+    IoError(std::io::Error),
+    XmlError(roxmltree::Error),
+    /// The manifest is well formed XML but not a valid manifest
+    InvalidManifest(String),
+}
+
+impl From<std::io::Error> for ManifestError {
+    fn from(err: std::io::Error) -> Self {
+        ManifestError::IoError(err)
+    }
+}
+
+impl From<roxmltree::Error> for ManifestError {
+    fn from(err: roxmltree::Error) -> Self {
+        ManifestError::XmlError(err)
+    }
+}
+
+type ManifestResult<T> = Result<T, ManifestError>;
+
+fn invalid<T>(message: String) -> ManifestResult<T> {
+    Err(ManifestError::InvalidManifest(message))
+}
+
+/// An event from an instrumentation manifest
+#[derive(Debug, Clone)]
+pub struct ManifestEvent {
+    provider_guid: GUID,
+    provider_name: String,
+    id: u16,
+    version: u8,
+    level: u8,
+    opcode: u8,
+    opcode_name: String,
+    task: u16,
+    task_name: String,
+    keywords: u64,
+    message: Option<String>,
+    top_level_property_count: u32,
+    properties: Vec<Property>,
+}
+
+impl ManifestEvent {
+    /// The task value of the event
+    pub fn task(&self) -> u16 {
+        self.task
+    }
+
+    /// The combined mask of all of the keywords of the event
+    pub fn keywords(&self) -> u64 {
+        self.keywords
+    }
+}
+
+impl EventSchema for ManifestEvent {
+    fn decoding_source(&self) -> DecodingSource {
+        DecodingSource::DecodingSourceXMLFile
+    }
+
+    fn provider_guid(&self) -> GUID {
+        self.provider_guid
+    }
+
+    fn event_id(&self) -> u16 {
+        self.id
+    }
+
+    fn opcode(&self) -> u8 {
+        self.opcode
+    }
+
+    fn event_version(&self) -> u8 {
+        self.version
+    }
+
+    fn provider_name(&self) -> String {
+        self.provider_name.clone()
+    }
+
+    fn task_name(&self) -> String {
+        self.task_name.clone()
+    }
+
+    fn opcode_name(&self) -> String {
+        self.opcode_name.clone()
+    }
+
+    fn level(&self) -> u8 {
+        self.level
+    }
+
+    fn property_count(&self) -> u32 {
+        self.top_level_property_count
+    }
+
+    fn property(&self, index: u32) -> Property {
+        self.properties[index as usize].clone()
+    }
+
+    fn event_message(&self) -> Option<String> {
+        self.message.clone()
+    }
+}
+
+const STANDARD_LEVELS: [(&str, u8); 6] = [
+    ("win:LogAlways", 0),
+    ("win:Critical", 1),
+    ("win:Error", 2),
+    ("win:Warning", 3),
+    ("win:Informational", 4),
+    ("win:Verbose", 5),
+];
+
+fn in_type(name: &str) -> Option<TdhInType> {
+    use TdhInType::*;
+    Some(match name {
+        "win:UnicodeString" => InTypeUnicodeString,
+        "win:AnsiString" => InTypeAnsiString,
+        "win:Int8" => InTypeInt8,
+        "win:UInt8" => InTypeUInt8,
+        "win:Int16" => InTypeInt16,
+        "win:UInt16" => InTypeUInt16,
+        "win:Int32" => InTypeInt32,
+        "win:UInt32" => InTypeUInt32,
+        "win:Int64" => InTypeInt64,
+        "win:UInt64" => InTypeUInt64,
+        "win:Float" => InTypeFloat,
+        "win:Double" => InTypeDouble,
+        "win:Boolean" => InTypeBoolean,
+        "win:Binary" => InTypeBinary,
+        "win:GUID" => InTypeGuid,
+        "win:Pointer" => InTypePointer,
+        "win:FILETIME" => InTypeFileTime,
+        "win:SYSTEMTIME" => InTypeSystemTime,
+        "win:SID" => InTypeSid,
+        "win:HexInt32" => InTypeHexInt32,
+        "win:HexInt64" => InTypeHexInt64,
+        "win:CountedString" | "win:CountedUnicodeString" => InTypeCountedString,
+        "win:CountedAnsiString" => InTypeCountedAnsiString,
+        "win:UnicodeChar" => InTypeUnicodeChar,
+        "win:AnsiChar" => InTypeAnsiChar,
+        "win:SizeT" => InTypeSizeT,
+        "win:HexDump" => InTypeHexdump,
+        _ => return None,
+    })
+}
+
+fn out_type(name: &str) -> Option<TdhOutType> {
+    use TdhOutType::*;
+    Some(match name {
+        "xs:string" => OutTypeString,
+        "xs:dateTime" | "win:CIMDateTime" | "win:ETWTIME" | "win:DateTimeCultureInsensitive" => OutTypeDateTime,
+        "xs:byte" => OutTypeInt8,
+        "xs:unsignedByte" => OutTypeUInt8,
+        "xs:short" => OutTypeInt16,
+        "xs:unsignedShort" => OutTypeUInt16,
+        "xs:int" => OutTypeInt32,
+        "xs:unsignedInt" => OutTypeUInt32,
+        "xs:long" => OutTypeInt64,
+        "xs:unsignedLong" => OutTypeUInt64,
+        "xs:float" => OutTypeFloat,
+        "xs:double" => OutTypeDouble,
+        "xs:boolean" => OutTypeBoolean,
+        "xs:GUID" => OutTypeGuid,
+        "xs:hexBinary" | "win:SocketAddress" => OutTypeHexBinary,
+        "win:HexInt8" => OutTypeHexInt8,
+        "win:HexInt16" => OutTypeHexInt16,
+        "win:HexInt32" => OutTypeHexInt32,
+        "win:HexInt64" => OutTypeHexInt64,
+        "win:PID" => OutTypePid,
+        "win:TID" => OutTypeTid,
+        "win:Port" => OutTypePort,
+        "win:IPv4" => OutTypeIpv4,
+        "win:IPv6" => OutTypeIpv6,
+        "win:Xml" => OutTypeString,
+        "win:ErrorCode" | "win:Win32Error" => OutTypeWin32Error,
+        "win:NTSTATUS" => OutTypeNtStatus,
+        "win:HResult" => OutTypeHResult,
+        "win:Json" => OutTypeJson,
+        "win:Utf8" => OutTypeUtf8,
+        "win:Pkcs7WithTypeInfo" => OutTypePkcs7,
+        "win:CodePointer" => OutTypeCodePointer,
+        "win:DateTimeUtc" => OutTypeDatetimeUtc,
+        _ => return None,
+    })
+}
+
+/// The things declared by a provider that events refer to by name
+struct ProviderContext<'a> {
+    strings: &'a HashMap<String, String>,
+    levels: HashMap<String, u8>,
+    tasks: HashMap<String, (u16, String)>,
+    /// Opcodes keyed on (task name, opcode name). Opcodes that aren't scoped to a task use an empty task name.
+    opcodes: HashMap<(String, String), (u8, String)>,
+    keywords: HashMap<String, u64>,
+    maps: HashMap<String, Rc<PropertyMapInfo>>,
+}
+
+impl<'a> ProviderContext<'a> {
+    /// Resolves a `$(string.id)` reference
+    fn message(&self, value: &str) -> String {
+        match value.strip_prefix("$(string.").and_then(|v| v.strip_suffix(')')) {
+            Some(id) => self.strings.get(id).cloned().unwrap_or_else(|| value.to_owned()),
+            None => value.to_owned(),
+        }
+    }
+
+    /// The display name of an element: its message if it has one and its name otherwise
+    fn display_name(&self, node: Node, name: &str) -> String {
+        match node.attribute("message") {
+            Some(message) => self.message(message),
+            None => name.to_owned(),
+        }
+    }
+}
+
+fn children<'a, 'input>(node: Node<'a, 'input>, name: &'static str) -> impl Iterator<Item = Node<'a, 'input>> {
+    node.children().filter(move |n| n.has_tag_name(name))
+}
+
+fn descendants<'a, 'input>(node: Node<'a, 'input>, name: &'static str) -> impl Iterator<Item = Node<'a, 'input>> {
+    node.descendants().filter(move |n| n.has_tag_name(name))
+}
+
+fn required_attribute<'a>(node: Node<'a, '_>, name: &str) -> ManifestResult<&'a str> {
+    match node.attribute(name) {
+        Some(value) => Ok(value),
+        None => invalid(format!("<{}> is missing the {} attribute", node.tag_name().name(), name)),
+    }
+}
+
+fn number_attribute(node: Node, name: &str) -> ManifestResult<u64> {
+    let value = required_attribute(node, name)?;
+    match utils::parse_number(value) {
+        Some(value) => Ok(value),
+        None => invalid(format!("<{}> has an invalid {}: {}", node.tag_name().name(), name, value)),
+    }
+}
+
+fn parse_provider_context<'a>(provider: Node, strings: &'a HashMap<String, String>) -> ManifestResult<ProviderContext<'a>> {
+    let mut context = ProviderContext {
+        strings,
+        levels: STANDARD_LEVELS.iter().map(|&(name, value)| (name.to_owned(), value)).collect(),
+        tasks: HashMap::new(),
+        // Like TDH, the standard opcodes are named e.g. win:Start because the manifest doesn't give them a message
+        opcodes: STANDARD_OPCODES.iter().map(|&(value, name, _)| ((String::new(), name.to_owned()), (value, name.to_owned()))).collect(),
+        keywords: HashMap::new(),
+        maps: HashMap::new(),
+    };
+
+    for level in descendants(provider, "level") {
+        let name = required_attribute(level, "name")?;
+        context.levels.insert(name.to_owned(), number_attribute(level, "value")? as u8);
+    }
+
+    for opcode in children(provider, "opcodes").flat_map(|n| children(n, "opcode")) {
+        let name = required_attribute(opcode, "name")?;
+        let display_name = context.display_name(opcode, name);
+        context.opcodes.insert((String::new(), name.to_owned()), (number_attribute(opcode, "value")? as u8, display_name));
+    }
+
+    for task in descendants(provider, "task") {
+        let name = required_attribute(task, "name")?;
+        let display_name = context.display_name(task, name);
+        context.tasks.insert(name.to_owned(), (number_attribute(task, "value")? as u16, display_name));
+        for opcode in descendants(task, "opcode") {
+            let opcode_name = required_attribute(opcode, "name")?;
+            let display_name = context.display_name(opcode, opcode_name);
+            context.opcodes.insert((name.to_owned(), opcode_name.to_owned()), (number_attribute(opcode, "value")? as u8, display_name));
+        }
+    }
+
+    for keyword in descendants(provider, "keyword") {
+        let name = required_attribute(keyword, "name")?;
+        context.keywords.insert(name.to_owned(), number_attribute(keyword, "mask")?);
+    }
+
+    for map in descendants(provider, "valueMap").chain(descendants(provider, "bitMap")) {
+        let name = required_attribute(map, "name")?;
+        let mut map_info = PropertyMapInfo { is_bitmap: map.has_tag_name("bitMap"), map: FastHashMap::default() };
+        for entry in children(map, "map") {
+            let message = context.message(required_attribute(entry, "message")?);
+            map_info.map.insert(number_attribute(entry, "value")? as u32, message);
+        }
+        context.maps.insert(name.to_owned(), Rc::new(map_info));
+    }
+
+    Ok(context)
+}
+
+/// Appends the properties for the `<data>` and `<struct>` children of `node`
+///
+/// Struct members are appended after all of the properties at the current level, the same way
+/// that TDH lays them out. Returns the number of properties at the current level.
+fn push_properties(properties: &mut Vec<Property>, node: Node, context: &ProviderContext) -> ManifestResult<u32> {
+    let start = properties.len();
+    let items: Vec<Node> = node.children().filter(|n| n.has_tag_name("data") || n.has_tag_name("struct")).collect();
+    let index_of = |properties: &Vec<Property>, name: &str| -> ManifestResult<u16> {
+        match properties[start..].iter().position(|p| p.name == name) {
+            Some(index) => Ok((start + index) as u16),
+            None => invalid(format!("unknown length or count property {}", name)),
+        }
+    };
+
+    for item in &items {
+        let name = required_attribute(*item, "name")?.to_owned();
+        let mut flags = PropertyFlags::empty();
+
+        let mut length = PropertyLength::Length(0);
+        if let Some(value) = item.attribute("length") {
+            length = match utils::parse_number(value) {
+                Some(value) => {
+                    flags |= PropertyFlags::PROPERTY_PARAM_FIXED_LENGTH;
+                    PropertyLength::Length(value as u16)
+                }
+                None => {
+                    flags |= PropertyFlags::PROPERTY_PARAM_LENGTH;
+                    PropertyLength::Index(index_of(properties, value)?)
+                }
+            };
+        }
+
+        let mut count = 1;
+        if let Some(value) = item.attribute("count") {
+            count = match utils::parse_number(value) {
+                Some(value) => {
+                    flags |= PropertyFlags::PROPERTY_PARAM_FIXED_COUNT;
+                    value as u16
+                }
+                None => {
+                    flags |= PropertyFlags::PROPERTY_PARAM_COUNT;
+                    index_of(properties, value)?
+                }
+            };
+        }
+
+        let mut map_info = None;
+        let desc = if item.has_tag_name("struct") {
+            flags |= PropertyFlags::PROPERTY_STRUCT;
+            let num_members = item.children().filter(|n| n.has_tag_name("data") || n.has_tag_name("struct")).count() as u16;
+            PropertyDesc::Struct(StructDesc { start_index: 0, num_members })
+        } else {
+            let in_type_name = required_attribute(*item, "inType")?;
+            let in_type = match in_type(in_type_name) {
+                Some(in_type) => in_type,
+                None => return invalid(format!("unknown inType {} for {}", in_type_name, name)),
+            };
+            let out_type = item.attribute("outType").and_then(out_type).unwrap_or_else(|| default_out_type(in_type));
+            if let Some(map) = item.attribute("map") {
+                match context.maps.get(map) {
+                    Some(map) => map_info = Some(map.clone()),
+                    None => return invalid(format!("unknown map {}", map)),
+                }
+            }
+            PropertyDesc::Primitive(PrimitiveDesc { in_type, out_type })
+        };
+
+        properties.push(Property { name, flags, length, desc, map_info, count });
+    }
+
+    for (i, item) in items.iter().enumerate() {
+        if item.has_tag_name("struct") {
+            let start_index = properties.len() as u16;
+            if let PropertyDesc::Struct(desc) = &mut properties[start + i].desc {
+                desc.start_index = start_index;
+            }
+            push_properties(properties, *item, context)?;
+        }
+    }
+    Ok(items.len() as u32)
+}
+
+fn parse_event(event: Node, provider_guid: GUID, provider_name: &str, context: &ProviderContext, templates: &HashMap<&str, Node>) -> ManifestResult<ManifestEvent> {
+    let id = number_attribute(event, "value")? as u16;
+
+    let level = match event.attribute("level") {
+        Some(level) => match context.levels.get(level) {
+            Some(&level) => level,
+            None => return invalid(format!("event {} has an unknown level {}", id, level)),
+        },
+        None => 0,
+    };
+
+    let task_name = event.attribute("task").unwrap_or_default();
+    let (task, task_display_name) = match context.tasks.get(task_name) {
+        Some((task, display_name)) => (*task, display_name.clone()),
+        None if task_name.is_empty() => (0, String::new()),
+        None => return invalid(format!("event {} has an unknown task {}", id, task_name)),
+    };
+
+    let (opcode, opcode_name) = match event.attribute("opcode") {
+        Some(opcode) => {
+            let scoped = context.opcodes.get(&(task_name.to_owned(), opcode.to_owned()));
+            match scoped.or_else(|| context.opcodes.get(&(String::new(), opcode.to_owned()))) {
+                Some((opcode, name)) => (*opcode, name.clone()),
+                None => return invalid(format!("event {} has an unknown opcode {}", id, opcode)),
+            }
+        }
+        None => (0, String::new()),
+    };
+
+    let mut keywords = 0;
+    for keyword in event.attribute("keywords").unwrap_or_default().split_whitespace() {
+        match context.keywords.get(keyword) {
+            Some(mask) => keywords |= mask,
+            None => return invalid(format!("event {} has an unknown keyword {}", id, keyword)),
+        }
+    }
+
+    let mut properties = Vec::new();
+    let mut top_level_property_count = 0;
+    if let Some(template) = event.attribute("template") {
+        match templates.get(template) {
+            Some(template) => top_level_property_count = push_properties(&mut properties, *template, context)?,
+            None => return invalid(format!("event {} has an unknown template {}", id, template)),
+        }
+    }
+
+    Ok(ManifestEvent {
+        provider_guid,
+        provider_name: provider_name.to_owned(),
+        id,
+        version: event.attribute("version").and_then(utils::parse_number).unwrap_or(0) as u8,
+        level,
+        opcode,
+        opcode_name,
+        task,
+        task_name: task_display_name,
+        keywords,
+        message: event.attribute("message").map(|message| context.message(message)),
+        top_level_property_count,
+        properties,
+    })
+}
+
+/// Parses the events of all of the providers in the manifest
+pub fn parse_manifest(xml: &str) -> ManifestResult<Vec<ManifestEvent>> {
+    let document = Document::parse(xml)?;
+    let root = document.root_element();
+
+    // We use the first culture in the manifest for the message strings
+    let mut strings = HashMap::new();
+    if let Some(string_table) = descendants(root, "stringTable").next() {
+        for string in children(string_table, "string") {
+            strings.insert(required_attribute(string, "id")?.to_owned(), required_attribute(string, "value")?.to_owned());
+        }
+    }
+
+    let mut events = Vec::new();
+    for provider in descendants(root, "provider") {
+        let provider_name = required_attribute(provider, "name")?;
+        let guid = required_attribute(provider, "guid")?;
+        let provider_guid = match utils::guid_from_str(guid) {
+            Some(guid) => guid,
+            None => return invalid(format!("provider {} has an invalid guid {}", provider_name, guid)),
+        };
+        let context = parse_provider_context(provider, &strings)?;
+        let mut templates = HashMap::new();
+        for template in descendants(provider, "template") {
+            templates.insert(required_attribute(template, "tid")?, template);
+        }
+        for event in children(provider, "events").flat_map(|n| children(n, "event")) {
+            events.push(parse_event(event, provider_guid, provider_name, &context, &templates)?);
+        }
+    }
+    Ok(events)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::etw_types::{EventDescriptor, EventRecord};
+    use crate::schema::SchemaLocator;
+
+    const MANIFEST: &str = r#"<?xml version="1.0" encoding="UTF-8"?>
+<instrumentationManifest xmlns="http://schemas.microsoft.com/win/2004/08/events" xmlns:win="http://manifests.microsoft.com/win/2004/08/windows/events" xmlns:xs="http://www.w3.org/2001/XMLSchema">
+  <instrumentation>
+    <events>
+      <provider name="Test-Provider" guid="{12345678-9abc-def0-1122-334455667788}" symbol="TEST_PROVIDER">
+        <events>
+          <event value="1" version="2" level="win:Informational" task="Paint" opcode="win:Start" keywords="Graphics Layout" template="PaintArgs" message="$(string.Event.Paint)"/>
+          <event value="2" level="Custom" task="Paint" opcode="Composite"/>
+        </events>
+        <levels>
+          <level name="Custom" value="16"/>
+        </levels>
+        <tasks>
+          <task name="Paint" value="7" message="$(string.Task.Paint)">
+            <opcodes>
+              <opcode name="Composite" value="10"/>
+            </opcodes>
+          </task>
+        </tasks>
+        <keywords>
+          <keyword name="Graphics" mask="0x1"/>
+          <keyword name="Layout" mask="0x4"/>
+        </keywords>
+        <maps>
+          <valueMap name="PaintKind">
+            <map value="0" message="$(string.Map.Full)"/>
+            <map value="1" message="$(string.Map.Partial)"/>
+          </valueMap>
+        </maps>
+        <templates>
+          <template tid="PaintArgs">
+            <data name="Kind" inType="win:UInt32" map="PaintKind"/>
+            <data name="Count" inType="win:UInt16"/>
+            <struct name="Rects" count="Count">
+              <data name="X" inType="win:Int32"/>
+              <data name="Y" inType="win:Int32"/>
+            </struct>
+            <data name="Url" inType="win:UnicodeString" outType="xs:string"/>
+          </template>
+        </templates>
+      </provider>
+    </events>
+  </instrumentation>
+  <localization>
+    <resources culture="en-US">
+      <stringTable>
+        <string id="Event.Paint" value="Painted %4"/>
+        <string id="Task.Paint" value="Painting"/>
+        <string id="Map.Full" value="Full"/>
+        <string id="Map.Partial" value="Partial"/>
+      </stringTable>
+    </resources>
+  </localization>
+</instrumentationManifest>
+"#;
+
+    #[test]
+    fn events() {
+        let events = parse_manifest(MANIFEST).unwrap();
+        assert_eq!(events.len(), 2);
+
+        let paint = &events[0];
+        assert_eq!(paint.provider_guid(), GUID::from_u128(0x12345678_9abc_def0_1122_334455667788));
+        assert_eq!(paint.provider_name(), "Test-Provider");
+        assert_eq!(paint.event_id(), 1);
+        assert_eq!(paint.event_version(), 2);
+        assert_eq!(paint.level(), 4);
+        assert_eq!(paint.task(), 7);
+        assert_eq!(paint.task_name(), "Painting");
+        assert_eq!(paint.opcode(), 1);
+        assert_eq!(paint.opcode_name(), "win:Start");
+        assert_eq!(paint.keywords(), 0x5);
+        assert_eq!(paint.event_message().as_deref(), Some("Painted %4"));
+        assert_eq!(paint.property_count(), 4);
+
+        let kind = paint.property(0);
+        let map_info = kind.map_info.unwrap();
+        assert!(!map_info.is_bitmap);
+        assert_eq!(map_info.map[&1], "Partial");
+
+        let rects = paint.property(2);
+        assert!(rects.flags.contains(PropertyFlags::PROPERTY_PARAM_COUNT));
+        assert_eq!(rects.count, 1);
+        match rects.desc {
+            PropertyDesc::Struct(desc) => {
+                assert_eq!(desc.num_members, 2);
+                assert_eq!(paint.property(desc.start_index as u32).name, "X");
+            }
+            _ => panic!("expected a struct"),
+        }
+
+        let composite = &events[1];
+        assert_eq!(composite.level(), 16);
+        assert_eq!(composite.opcode(), 10);
+        assert_eq!(composite.opcode_name(), "Composite");
+        assert_eq!(composite.property_count(), 0);
+    }
+
+    #[test]
+    fn standard_opcode_name() {
+        let mut locator = SchemaLocator::new();
+        for event in parse_manifest(MANIFEST).unwrap() {
+            locator.add_custom_schema(Box::new(event));
+        }
+        let record = EventRecord {
+            provider_id: GUID::from_u128(0x12345678_9abc_def0_1122_334455667788),
+            descriptor: EventDescriptor { id: 1, version: 2, level: 4, opcode: 1, ..Default::default() },
+            ..Default::default()
+        };
+        let event = locator.event_schema(&record).unwrap();
+        assert_eq!(event.name(), "Test-Provider/Painting/win:Start");
+    }
+
+    #[test]
+    fn unknown_template() {
+        let manifest = MANIFEST.replace("template=\"PaintArgs\"", "template=\"Missing\"");
+        assert!(matches!(parse_manifest(&manifest), Err(ManifestError::InvalidManifest(_))));
+    }
+}
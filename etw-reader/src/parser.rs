@@ -2,11 +2,12 @@
 //!
 //! This module act as a helper to parse the Buffer from an ETW Event
 use crate::etw_types::EVENT_HEADER_FLAG_32_BIT_HEADER;
-use crate::sddl;
+#[cfg(windows)]
 use crate::tdh;
 use crate::tdh_types::PrimitiveDesc;
 use crate::tdh_types::PropertyDesc;
 use crate::tdh_types::PropertyLength;
+use crate::tdh_types::StructDesc;
 use crate::tdh_types::{Property, PropertyFlags, TdhInType, TdhOutType};
 use crate::property::{PropertyInfo, PropertyIter};
 use crate::schema::TypedEvent;
@@ -52,9 +53,11 @@ pub enum ParserError {
     /// Represents an internal [TdhNativeError]
     ///
     /// [TdhNativeError]: tdh::TdhNativeError
+    #[cfg(windows)]
     TdhNativeError(tdh::TdhNativeError),
 }
 
+#[cfg(windows)]
 impl From<tdh::TdhNativeError> for ParserError {
     fn from(err: tdh::TdhNativeError) -> Self {
         ParserError::TdhNativeError(err)
@@ -173,48 +176,190 @@ impl<'a> Parser<'a> {
         )?)
     }*/
 
-    // TODO: Find a cleaner way to do this, not very happy with it rn
-    fn find_property_size(&self, property: &Property) -> ParserResult<usize> {
-        match property.length {
-            PropertyLength::Index(_) => {
-                // e.g. Microsoft-Windows-Kernel-Power/SystemTimerResolutionStackRundown uses the AppNameLength property
-                // as the size of AppName
+    fn pointer_size(&self) -> usize {
+        if (self.event.event_flags() & EVENT_HEADER_FLAG_32_BIT_HEADER) != 0 {
+            4
+        } else {
+            8
+        }
+    }
 
-                // Fallback to Tdh
-                return Ok(tdh::property_size(self.event.record(), &property.name).unwrap() as usize);
+    /// Reads the integer property at `index` that holds the length or count of another property
+    fn index_value(&self, index: u16, scope: Option<&StructInstance<'a>>) -> ParserResult<usize> {
+        let index = index as usize;
+        let info = match scope {
+            Some(instance) if index >= instance.base && index - instance.base < instance.members.len() => {
+                &instance.members[index - instance.base]
             }
-            PropertyLength::Length(length) => {
-                // TODO: Study heuristic method used in krabsetw :)
-                if property.flags.is_empty() && length > 0 && property.count == 1 {
-                    return Ok(length as usize);
-                }
-                if property.count == 1 {
-                    if let PropertyDesc::Primitive(desc) = &property.desc {
-                        match desc.in_type {
-                            TdhInType::InTypeBoolean => return Ok(4),
-                            TdhInType::InTypeInt32 | TdhInType::InTypeUInt32 | TdhInType::InTypeHexInt32 => return Ok(4),
-                            TdhInType::InTypeInt64 | TdhInType::InTypeUInt64 | TdhInType::InTypeHexInt64 => return Ok(8),
-                            TdhInType::InTypeInt8 | TdhInType::InTypeUInt8 => return Ok(1),
-                            TdhInType::InTypeInt16 | TdhInType::InTypeUInt16 => return Ok(2),
-                            TdhInType::InTypePointer => return Ok(if (self.event.event_flags() & EVENT_HEADER_FLAG_32_BIT_HEADER) != 0 {
-                                4
-                            } else {
-                                8
-                            }),
-                            TdhInType::InTypeGuid => return Ok(std::mem::size_of::<GUID>()),
-                            TdhInType::InTypeUnicodeString => { 
-                                return Ok(utils::parse_unk_size_null_unicode_size(&self.buffer))
-                            }
-                            TdhInType::InTypeAnsiString => {
-                                return Ok(utils::parse_unk_size_null_ansi_size(&self.buffer));
-                            }
-                            _ => {}
-                        }
-                    }
+            _ => self.cache.get(index).ok_or_else(|| {
+                ParserError::PropertyError(format!("property {} isn't available", index))
+            })?,
+        };
+        let buffer = info.buffer;
+        Ok(match buffer.len() {
+            1 => buffer[0] as usize,
+            2 => u16::from_ne_bytes(buffer.try_into()?) as usize,
+            4 => u32::from_ne_bytes(buffer.try_into()?) as usize,
+            8 => u64::from_ne_bytes(buffer.try_into()?) as usize,
+            _ => return Err(ParserError::LengthMismatch),
+        })
+    }
+
+    /// Returns the number of elements of `property`
+    fn element_count(&self, property: &Property, scope: Option<&StructInstance<'a>>) -> ParserResult<usize> {
+        if property.flags.contains(PropertyFlags::PROPERTY_PARAM_COUNT) {
+            return self.index_value(property.count, scope);
+        }
+        if property.flags.contains(PropertyFlags::PROPERTY_PARAM_FIXED_COUNT) {
+            return Ok(property.count as usize);
+        }
+        Ok(property.count.max(1) as usize)
+    }
+
+    /// The size of each element of `property` if it doesn't depend on the event data
+    fn fixed_element_size(&self, property: &Property) -> Option<usize> {
+        use TdhInType::*;
+        let PropertyDesc::Primitive(desc) = &property.desc else { return None };
+        Some(match desc.in_type {
+            InTypeInt8 | InTypeUInt8 | InTypeAnsiChar => 1,
+            InTypeInt16 | InTypeUInt16 | InTypeUnicodeChar => 2,
+            InTypeInt32 | InTypeUInt32 | InTypeHexInt32 | InTypeBoolean | InTypeFloat => 4,
+            InTypeInt64 | InTypeUInt64 | InTypeHexInt64 | InTypeDouble | InTypeFileTime => 8,
+            InTypePointer | InTypeSizeT => self.pointer_size(),
+            InTypeSystemTime => 16,
+            InTypeGuid => std::mem::size_of::<GUID>(),
+            InTypeBinary => match property.length {
+                PropertyLength::Length(length) if length > 0 => length as usize,
+                PropertyLength::Length(_) if desc.out_type == TdhOutType::OutTypeIpv6 => 16,
+                PropertyLength::Length(_) | PropertyLength::Index(_) => return None,
+            },
+            _ => return None,
+        })
+    }
+
+    /// Returns the size of the single element of `property` at the start of `buffer`
+    fn element_size(&self, property: &'a Property, buffer: &'a [u8], scope: Option<&StructInstance<'a>>) -> ParserResult<usize> {
+        use TdhInType::*;
+        if let Some(size) = self.fixed_element_size(property) {
+            return Ok(size);
+        }
+        let desc = match &property.desc {
+            PropertyDesc::Struct(desc) => return Ok(self.decode_struct(desc, buffer)?.size),
+            PropertyDesc::Primitive(desc) => desc,
+        };
+        // e.g. Microsoft-Windows-Kernel-Power/SystemTimerResolutionStackRundown uses the AppNameLength property
+        // as the size of AppName
+        let length = match property.length {
+            PropertyLength::Index(index) => Some(self.index_value(index, scope)?),
+            PropertyLength::Length(length) if length > 0 => Some(length as usize),
+            PropertyLength::Length(_) => None,
+        };
+        Ok(match desc.in_type {
+            // String lengths are in characters
+            InTypeUnicodeString => match length {
+                Some(length) => length * 2,
+                None => utils::parse_unk_size_null_unicode_size(buffer).min(buffer.len()),
+            },
+            InTypeAnsiString => match length {
+                Some(length) => length,
+                None => utils::parse_unk_size_null_ansi_size(buffer).min(buffer.len()),
+            },
+            InTypeNonNullTerminatedString => length.map_or(buffer.len(), |length| length * 2),
+            InTypeNonNullTerminatedAnsiString => length.unwrap_or(buffer.len()),
+            InTypeCountedString | InTypeCountedAnsiString => utils::counted_size(buffer),
+            InTypeReverseCountedString | InTypeReverseCountedAnsiString => match buffer.get(..2) {
+                Some(count) => (2 + u16::from_be_bytes(count.try_into()?) as usize).min(buffer.len()),
+                None => buffer.len(),
+            },
+            InTypeBinary => match length {
+                Some(length) => length,
+                None => return self.property_size_from_tdh(property),
+            },
+            InTypeHexdump => match buffer.get(..4) {
+                Some(count) => (4 + u32::from_le_bytes(count.try_into()?) as usize).min(buffer.len()),
+                None => buffer.len(),
+            },
+            InTypeSid => utils::sid_size(buffer),
+            InTypeWBEMSID => {
+                // A TOKEN_USER (a pointer and a u32 padded to a pointer) followed by the SID
+                if buffer.len() < 4 || buffer[..4] == [0; 4] {
+                    return Ok(4.min(buffer.len()));
                 }
-                return Ok(tdh::property_size(self.event.record(), &property.name).unwrap() as usize)
+                let token_size = 2 * self.pointer_size();
+                token_size + utils::sid_size(buffer.get(token_size..).unwrap_or_default())
+            }
+            _ => return self.property_size_from_tdh(property),
+        })
+    }
+
+    /// Returns the size of `property` at the start of `buffer`, including all of its elements if
+    /// it's an array
+    fn property_size(&self, property: &'a Property, buffer: &'a [u8], scope: Option<&StructInstance<'a>>) -> ParserResult<usize> {
+        let count = self.element_count(property, scope)?;
+        let mut size = 0;
+        if let Some(element_size) = self.fixed_element_size(property) {
+            return Ok(size + count * element_size);
+        }
+        for _ in 0..count {
+            size += self.element_size(property, buffer.get(size..).unwrap_or_default(), scope)?;
+        }
+        Ok(size)
+    }
+
+    #[cfg(windows)]
+    fn property_size_from_tdh(&self, property: &Property) -> ParserResult<usize> {
+        Ok(tdh::property_size(self.event.etw_record(), &property.name)? as usize)
+    }
+
+    #[cfg(not(windows))]
+    fn property_size_from_tdh(&self, property: &Property) -> ParserResult<usize> {
+        Err(ParserError::PropertyError(format!("can't determine the size of {} without TDH", property.name)))
+    }
+
+    /// Splits `buffer`, which starts with an instance of the struct described by `desc`, into its members
+    fn decode_struct(&self, desc: &StructDesc, buffer: &'a [u8]) -> ParserResult<StructInstance<'a>> {
+        let base = desc.start_index as usize;
+        let mut instance = StructInstance { base, members: Vec::new(), size: 0 };
+        for i in base..base + desc.num_members as usize {
+            let member = self.properties.property(i).ok_or_else(
+                || ParserError::PropertyError(format!("struct member {} is missing", i)))?;
+            let remaining = &buffer[instance.size..];
+            let size = self.property_size(member, remaining, Some(&instance))?;
+            if remaining.len() < size {
+                return Err(ParserError::PropertyError(
+                    format!("Property of {} bytes out of buffer bounds ({})", size, remaining.len()),
+                ));
             }
+            instance.members.push(PropertyInfo::create(member, instance.size, &remaining[..size]));
+            instance.size += size;
         }
+        Ok(instance)
+    }
+
+    /// Returns element `element` of the array property described by `info`
+    fn element(&self, info: &PropertyInfo<'a>, element: usize, scope: Option<&StructInstance<'a>>) -> ParserResult<PropertyInfo<'a>> {
+        let property = info.property;
+        let count = self.element_count(property, scope)?;
+        let mut offset = 0;
+        if element >= count {
+            return Err(ParserError::PropertyError(
+                format!("{}[{}] is out of bounds of {} elements", property.name, element, count),
+            ));
+        }
+        let size = match self.fixed_element_size(property) {
+            Some(size) => {
+                offset += element * size;
+                size
+            }
+            None => {
+                for _ in 0..element {
+                    offset += self.element_size(property, info.buffer.get(offset..).unwrap_or_default(), scope)?;
+                }
+                self.element_size(property, info.buffer.get(offset..).unwrap_or_default(), scope)?
+            }
+        };
+        let buffer = info.buffer.get(offset..offset + size).ok_or(ParserError::LengthMismatch)?;
+        Ok(PropertyInfo::create(property, info.offset + offset, buffer))
     }
 
     pub fn find_property(&mut self, name: &str) -> ParserResult<usize> {
@@ -229,7 +374,7 @@ impl<'a> Parser<'a> {
         for i in self.cache.len()..=indx {
             let curr_prop = self.properties.property(i).unwrap();
 
-            let prop_size = self.find_property_size(&curr_prop)?;
+            let prop_size = self.property_size(curr_prop, self.buffer, None)?;
 
             if self.buffer.len() < prop_size {
                 return Err(ParserError::PropertyError(
@@ -246,6 +391,130 @@ impl<'a> Parser<'a> {
         }
         Ok(indx)
     }
+
+    /// Finds the property, struct member or array element named by `path`, e.g. `Foo.Bar[3]`
+    fn resolve(&mut self, path: &str) -> ParserResult<Resolved<'a>> {
+        let mut segments = path.split('.');
+        let (name, mut element) = split_element(segments.next().unwrap_or_default())?;
+        let index = self.find_property(name)?;
+        let mut resolved = Resolved { info: self.cache[index].clone(), scope: None };
+        loop {
+            if let Some(element) = element {
+                resolved.info = self.element(&resolved.info, element, resolved.scope.as_ref())?;
+            }
+            let Some(segment) = segments.next() else { return Ok(resolved) };
+
+            let property = resolved.info.property;
+            let PropertyDesc::Struct(desc) = &property.desc else {
+                return Err(ParserError::PropertyError(format!("{} isn't a struct", property.name)));
+            };
+            if property.is_array() && element.is_none() {
+                return Err(ParserError::PropertyError(format!("{} is an array of structs and needs an index", property.name)));
+            }
+            let (member, member_element) = split_element(segment)?;
+            let instance = self.decode_struct(desc, resolved.info.buffer)?;
+            let position = instance.members.iter().position(|info| info.property.name == member).ok_or_else(
+                || ParserError::PropertyError(format!("{} has no member {}", property.name, member)))?;
+            let mut info = instance.members[position].clone();
+            info.offset += resolved.info.offset;
+            resolved = Resolved { info, scope: Some(instance) };
+            element = member_element;
+        }
+    }
+
+    /// Returns the location of the value named by `path` in the event, e.g. `Foo.Bar[3]`
+    pub fn property_info(&mut self, path: &str) -> ParserResult<PropertyInfo<'a>> {
+        if !path.contains(['.', '[']) {
+            let indx = self.find_property(path)?;
+            return Ok(self.cache[indx].clone());
+        }
+        Ok(self.resolve(path)?.info)
+    }
+
+    /// Returns the number of elements in the array named by `path`
+    pub fn array_len(&mut self, path: &str) -> ParserResult<usize> {
+        let resolved = self.resolve(path)?;
+        if !resolved.info.property.is_array() {
+            return Err(ParserError::InvalidType);
+        }
+        self.element_count(resolved.info.property, resolved.scope.as_ref())
+    }
+
+    /// Returns the members of a struct property
+    pub fn struct_members(&self, property: &Property) -> &'a [Property] {
+        match &property.desc {
+            PropertyDesc::Struct(desc) => self.properties.members(desc),
+            PropertyDesc::Primitive(_) => &[],
+        }
+    }
+
+    /// Returns the path of every primitive value in the event along with the property that
+    /// describes it. Struct members and array elements get paths like `Foo.Bar[3]`, which can be
+    /// passed to [TryParse::try_parse].
+    pub fn property_paths(&mut self) -> ParserResult<Vec<(String, &'a Property)>> {
+        let mut paths = Vec::new();
+        for property in self.properties.properties_iter() {
+            self.push_property_paths(property.name.clone(), property, &mut paths)?;
+        }
+        Ok(paths)
+    }
+
+    fn push_property_paths(&mut self, path: String, property: &'a Property, paths: &mut Vec<(String, &'a Property)>) -> ParserResult<()> {
+        let elements = if property.is_array() {
+            (0..self.array_len(&path)?).map(|i| format!("{}[{}]", path, i)).collect()
+        } else {
+            vec![path]
+        };
+        for element in elements {
+            if let PropertyDesc::Struct(_) = &property.desc {
+                for member in self.struct_members(property) {
+                    self.push_property_paths(format!("{}.{}", element, member.name), member, paths)?;
+                }
+            } else {
+                paths.push((element, property));
+            }
+        }
+        Ok(())
+    }
+}
+
+/// The members of a struct instance. Lengths and counts of later members can refer to earlier ones.
+struct StructInstance<'a> {
+    /// The property index of the first member
+    base: usize,
+    members: Vec<PropertyInfo<'a>>,
+    size: usize,
+}
+
+struct Resolved<'a> {
+    info: PropertyInfo<'a>,
+    /// The struct that contains the property, if it isn't at the top level
+    scope: Option<StructInstance<'a>>,
+}
+
+/// Splits a path segment like `Bar[3]` into its name and element index
+fn split_element(segment: &str) -> ParserResult<(&str, Option<usize>)> {
+    let Some((name, rest)) = segment.split_once('[') else { return Ok((segment, None)) };
+    let element = rest.strip_suffix(']').and_then(|element| element.parse().ok()).ok_or_else(
+        || ParserError::PropertyError(format!("invalid property path {}", segment)))?;
+    Ok((name, Some(element)))
+}
+
+/// Formats a SID the same way as ConvertSidToStringSid, e.g. S-1-5-18
+fn sid_to_string(sid: &[u8]) -> ParserResult<String> {
+    if sid.len() < 8 || sid.len() < 8 + 4 * sid[1] as usize {
+        return Err(ParserError::LengthMismatch);
+    }
+    let authority = sid[2..8].iter().fold(0u64, |authority, &b| authority << 8 | b as u64);
+    let mut res = if authority >> 32 == 0 {
+        format!("S-{}-{}", sid[0], authority)
+    } else {
+        format!("S-{}-0x{:012X}", sid[0], authority)
+    };
+    for sub_authority in sid[8..8 + 4 * sid[1] as usize].chunks_exact(4) {
+        res += &format!("-{}", u32::from_le_bytes(sub_authority.try_into()?));
+    }
+    Ok(res)
 }
 
 /*
@@ -277,8 +546,7 @@ macro_rules! impl_try_parse_primitive {
         impl TryParse<$T> for Parser<'_> {
             fn try_parse(&mut self, name: &str) -> ParserResult<$T> {
                 use TdhInType::*;
-                let indx = self.find_property(name)?;
-                let prop_info = &self.cache[indx];
+                let prop_info = &self.property_info(name)?;
                 let prop_info: &PropertyInfo = prop_info.borrow();
                 if let PropertyDesc::Primitive(desc) = &prop_info.property.desc {
                     if desc.in_type != $ty {
@@ -306,8 +574,7 @@ impl_try_parse_primitive!(u32, InTypeUInt32);
 impl TryParse<u64> for Parser<'_> {
     fn try_parse(&mut self, name: &str) -> ParserResult<u64> {
         use TdhInType::*;
-        let indx = self.find_property(name)?;
-        let prop_info = &self.cache[indx];
+        let prop_info = &self.property_info(name)?;
         if let PropertyDesc::Primitive(desc) = &prop_info.property.desc {
             if desc.in_type == InTypeUInt64 {
                 if std::mem::size_of::<u64>() != prop_info.buffer.len() {
@@ -335,8 +602,7 @@ impl TryParse<u64> for Parser<'_> {
 impl TryParse<i64> for Parser<'_> {
     fn try_parse(&mut self, name: &str) -> ParserResult<i64> {
         use TdhInType::*;
-        let indx = self.find_property(name)?;
-        let prop_info = &self.cache[indx];
+        let prop_info = &self.property_info(name)?;
         if let PropertyDesc::Primitive(desc) = &prop_info.property.desc {
             if desc.in_type == InTypeInt64 || desc.in_type == InTypeHexInt64 {
                 if std::mem::size_of::<i64>() != prop_info.buffer.len() {
@@ -352,8 +618,7 @@ impl TryParse<i64> for Parser<'_> {
 impl TryParse<i32> for Parser<'_> {
     fn try_parse(&mut self, name: &str) -> ParserResult<i32> {
         use TdhInType::*;
-        let indx = self.find_property(name)?;
-        let prop_info = &self.cache[indx];
+        let prop_info = &self.property_info(name)?;
         if let PropertyDesc::Primitive(desc) = &prop_info.property.desc {
             if desc.in_type == InTypeInt32 || desc.in_type == InTypeHexInt32 {
                 if std::mem::size_of::<i32>() != prop_info.buffer.len() {
@@ -369,8 +634,7 @@ impl TryParse<i32> for Parser<'_> {
 impl TryParse<Address> for Parser<'_> {
     fn try_parse(&mut self, name: &str) -> ParserResult<Address> {
         use TdhInType::*;
-        let indx = self.find_property(name)?;
-        let prop_info = &self.cache[indx];
+        let prop_info = &self.property_info(name)?;
 
         if let PropertyDesc::Primitive(desc) = &prop_info.property.desc {
             if self.event.is_64bit() {
@@ -396,8 +660,7 @@ impl TryParse<Address> for Parser<'_> {
 impl TryParse<bool> for Parser<'_> {
     fn try_parse(&mut self, name: &str) -> ParserResult<bool> {
         use TdhInType::*;
-        let indx = self.find_property(name)?;
-        let prop_info = &self.cache[indx];
+        let prop_info = &self.property_info(name)?;
         if let PropertyDesc::Primitive(desc) = &prop_info.property.desc {
             if desc.in_type != InTypeBoolean {
                 return Err(ParserError::InvalidType)
@@ -418,8 +681,7 @@ impl TryParse<bool> for Parser<'_> {
 impl TryParse<f32> for Parser<'_> {
     fn try_parse(&mut self, name: &str) -> ParserResult<f32> {
         use TdhInType::*;
-        let indx = self.find_property(name)?;
-        let prop_info = &self.cache[indx];
+        let prop_info = &self.property_info(name)?;
         if let PropertyDesc::Primitive(desc) = &prop_info.property.desc {
             if desc.in_type == InTypeFloat {
                 if std::mem::size_of::<f32>() != prop_info.buffer.len() {
@@ -432,12 +694,34 @@ impl TryParse<f32> for Parser<'_> {
     }
 }
 
+impl TryParse<f64> for Parser<'_> {
+    fn try_parse(&mut self, name: &str) -> ParserResult<f64> {
+        use TdhInType::*;
+        let prop_info = &self.property_info(name)?;
+        if let PropertyDesc::Primitive(desc) = &prop_info.property.desc {
+            if desc.in_type == InTypeDouble {
+                if std::mem::size_of::<f64>() != prop_info.buffer.len() {
+                    return Err(ParserError::LengthMismatch);
+                }
+                return Ok(f64::from_ne_bytes(prop_info.buffer.try_into()?));
+            }
+        }
+        return Err(ParserError::InvalidType);
+    }
+}
+
 /// The `String` impl of the `TryParse` trait should be used to retrieve the following [TdhInTypes]:
 ///
 /// * InTypeUnicodeString
 /// * InTypeAnsiString
 /// * InTypeCountedString
-/// * InTypeGuid
+/// * InTypeCountedAnsiString
+/// * InTypeReverseCountedString
+/// * InTypeReverseCountedAnsiString
+/// * InTypeNonNullTerminatedString
+/// * InTypeNonNullTerminatedAnsiString
+/// * InTypeSid
+/// * InTypeWBEMSID
 ///
 /// On success a `String` with the with the data from the `name` property will be returned
 ///
@@ -456,23 +740,35 @@ impl TryParse<f32> for Parser<'_> {
 /// [TdhInTypes]: TdhInType
 impl TryParse<String> for Parser<'_> {
     fn try_parse(&mut self, name: &str) -> ParserResult<String> {
-        let indx = self.find_property(name)?;
-        let prop_info = &self.cache[indx];
+        let prop_info = &self.property_info(name)?;
 
         // TODO: Handle errors and type checking better
         if let PropertyDesc::Primitive(desc) = &prop_info.property.desc {
             let res = match desc.in_type {
-                TdhInType::InTypeUnicodeString => {
+                TdhInType::InTypeUnicodeString | TdhInType::InTypeNonNullTerminatedString => {
                     utils::parse_null_utf16_string(prop_info.buffer)
                 }
-                TdhInType::InTypeAnsiString => String::from_utf8(prop_info.buffer.to_vec())?
+                TdhInType::InTypeAnsiString | TdhInType::InTypeNonNullTerminatedAnsiString => String::from_utf8(prop_info.buffer.to_vec())?
                     .trim_matches(char::default())
                     .to_string(),
-                TdhInType::InTypeSid => {
-                    panic!()
-                    //sddl::convert_sid_to_string(prop_info.buffer.as_ptr() as isize)?
+                // Counted strings are prefixed by their length in bytes
+                TdhInType::InTypeCountedString | TdhInType::InTypeReverseCountedString => {
+                    utils::parse_null_utf16_string(prop_info.buffer.get(2..).unwrap_or_default())
+                }
+                TdhInType::InTypeCountedAnsiString | TdhInType::InTypeReverseCountedAnsiString => {
+                    String::from_utf8(prop_info.buffer.get(2..).unwrap_or_default().to_vec())?
+                        .trim_matches(char::default())
+                        .to_string()
+                }
+                TdhInType::InTypeSid => sid_to_string(prop_info.buffer)?,
+                TdhInType::InTypeWBEMSID => {
+                    // Skip the TOKEN_USER that precedes the SID
+                    if prop_info.buffer.len() <= 4 {
+                        return Ok(String::new());
+                    }
+                    let token_size = 2 * self.pointer_size();
+                    sid_to_string(prop_info.buffer.get(token_size..).unwrap_or_default())?
                 }
-                TdhInType::InTypeCountedString => unimplemented!(),
                 _ => return Err(ParserError::InvalidType),
             };
             return Ok(res)
@@ -483,8 +779,7 @@ impl TryParse<String> for Parser<'_> {
 
 impl TryParse<GUID> for Parser<'_> {
     fn try_parse(&mut self, name: &str) -> Result<GUID, ParserError> {
-        let indx = self.find_property(name)?;
-        let prop_info = &self.cache[indx];
+        let prop_info = &self.property_info(name)?;
         if let PropertyDesc::Primitive(desc) = &prop_info.property.desc {
             match desc.in_type {
                 TdhInType::InTypeUnicodeString => {
@@ -521,8 +816,7 @@ impl TryParse<GUID> for Parser<'_> {
 
 impl TryParse<IpAddr> for Parser<'_> {
     fn try_parse(&mut self, name: &str) -> ParserResult<IpAddr> {
-        let indx = self.find_property(name)?;
-        let prop_info = &self.cache[indx];
+        let prop_info = &self.property_info(name)?;
         if let PropertyDesc::Primitive(desc) = &prop_info.property.desc {
 
             if desc.out_type != TdhOutType::OutTypeIpv4
@@ -531,13 +825,12 @@ impl TryParse<IpAddr> for Parser<'_> {
                 return Err(ParserError::InvalidType);
             }
 
-            // Hardcoded values for now
-            let res = match prop_info.property.length {
-                PropertyLength::Length(16) => {
+            let res = match prop_info.buffer.len() {
+                16 => {
                     let tmp: [u8; 16] = prop_info.buffer.try_into()?;
                     IpAddr::V6(Ipv6Addr::from(tmp))
                 }
-                PropertyLength::Length(4) => {
+                4 => {
                     let tmp: [u8; 4] = prop_info.buffer.try_into()?;
                     IpAddr::V4(Ipv4Addr::from(tmp))
                 }
@@ -593,8 +886,7 @@ impl std::fmt::Display for Pointer {
 
 impl TryParse<Pointer> for Parser<'_> {
     fn try_parse(&mut self, name: &str) -> ParserResult<Pointer> {
-        let indx = self.find_property(name)?;
-        let prop_info = &self.cache[indx];
+        let prop_info = &self.property_info(name)?;
 
         let mut res = Pointer::default();
         if prop_info.buffer.len() == std::mem::size_of::<u32>() {
@@ -607,14 +899,150 @@ impl TryParse<Pointer> for Parser<'_> {
     }
 }
 
+/// Returns the raw bytes of a property. The size prefix of hex dump properties is left out.
 impl TryParse<Vec<u8>> for Parser<'_> {
     fn try_parse(&mut self, name: &str) -> Result<Vec<u8>, ParserError> {
-        let indx = self.find_property(name)?;
-        let prop_info = &self.cache[indx];
+        let prop_info = &self.property_info(name)?;
 
+        if let PropertyDesc::Primitive(PrimitiveDesc { in_type: TdhInType::InTypeHexdump, .. }) = &prop_info.property.desc {
+            return Ok(prop_info.buffer.get(4..).unwrap_or_default().to_vec());
+        }
         Ok(prop_info.buffer.to_vec())
     }
 }
 
+macro_rules! impl_try_parse_array {
+    ($T:ty) => {
+        impl TryParse<Vec<$T>> for Parser<'_> {
+            fn try_parse(&mut self, name: &str) -> ParserResult<Vec<$T>> {
+                let len = self.array_len(name)?;
+                (0..len).map(|i| TryParse::<$T>::try_parse(self, &format!("{}[{}]", name, i))).collect()
+            }
+        }
+    };
+}
+
+impl_try_parse_array!(i8);
+impl_try_parse_array!(u16);
+impl_try_parse_array!(i16);
+impl_try_parse_array!(u32);
+impl_try_parse_array!(i32);
+impl_try_parse_array!(u64);
+impl_try_parse_array!(i64);
+impl_try_parse_array!(f32);
+impl_try_parse_array!(f64);
+impl_try_parse_array!(bool);
+impl_try_parse_array!(String);
+impl_try_parse_array!(GUID);
+impl_try_parse_array!(Address);
+impl_try_parse_array!(IpAddr);
+
 // TODO: Implement SocketAddress
 // TODO: Study if we can use primitive types for HexInt64, HexInt32 and Pointer
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::etw_types::{EventDescriptor, EventRecord};
+    use crate::manifest::parse_manifest;
+    use crate::schema::SchemaLocator;
+    use std::borrow::Cow;
+    use windows::Win32::System::Diagnostics::Etw::EVENT_HEADER_FLAG_64_BIT_HEADER;
+
+    const MANIFEST: &str = r#"<?xml version="1.0" encoding="UTF-8"?>
+<instrumentationManifest xmlns="http://schemas.microsoft.com/win/2004/08/events" xmlns:win="http://manifests.microsoft.com/win/2004/08/windows/events">
+  <instrumentation>
+    <events>
+      <provider name="Test-Provider" guid="{12345678-9abc-def0-1122-334455667788}" symbol="TEST_PROVIDER">
+        <events>
+          <event value="1" template="Args"/>
+        </events>
+        <templates>
+          <template tid="Args">
+            <data name="Count" inType="win:UInt16"/>
+            <struct name="Items" count="Count">
+              <data name="X" inType="win:Int32"/>
+              <data name="NameLength" inType="win:UInt16"/>
+              <data name="Name" inType="win:UnicodeString" length="NameLength"/>
+            </struct>
+            <data name="Title" inType="win:CountedUnicodeString"/>
+            <data name="Size" inType="win:UInt32"/>
+            <data name="Blob" inType="win:Binary" length="Size"/>
+            <data name="User" inType="win:SID"/>
+            <data name="Address" inType="win:Binary" outType="win:IPv6"/>
+            <data name="Values" inType="win:UInt32" count="2"/>
+          </template>
+        </templates>
+      </provider>
+    </events>
+  </instrumentation>
+</instrumentationManifest>
+"#;
+
+    fn utf16(s: &str) -> Vec<u8> {
+        s.encode_utf16().flat_map(|c| c.to_le_bytes()).collect()
+    }
+
+    fn record() -> EventRecord<'static> {
+        let mut user_data = Vec::new();
+        user_data.extend_from_slice(&2u16.to_le_bytes());
+        for (x, name) in [(10i32, "ab"), (-5, "xyz")] {
+            user_data.extend_from_slice(&x.to_le_bytes());
+            user_data.extend_from_slice(&(name.len() as u16).to_le_bytes());
+            user_data.extend_from_slice(&utf16(name));
+        }
+        user_data.extend_from_slice(&8u16.to_le_bytes());
+        user_data.extend_from_slice(&utf16("Test"));
+        user_data.extend_from_slice(&3u32.to_le_bytes());
+        user_data.extend_from_slice(&[1, 2, 3]);
+        // S-1-5-18
+        user_data.extend_from_slice(&[1, 1, 0, 0, 0, 0, 0, 5, 18, 0, 0, 0]);
+        user_data.extend_from_slice(&Ipv6Addr::LOCALHOST.octets());
+        user_data.extend_from_slice(&7u32.to_le_bytes());
+        user_data.extend_from_slice(&9u32.to_le_bytes());
+        EventRecord {
+            provider_id: GUID::from_u128(0x12345678_9abc_def0_1122_334455667788),
+            descriptor: EventDescriptor { id: 1, ..Default::default() },
+            flags: EVENT_HEADER_FLAG_64_BIT_HEADER as u16,
+            user_data: Cow::Owned(user_data),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn structs_and_arrays() {
+        let mut locator = SchemaLocator::new();
+        for event in parse_manifest(MANIFEST).unwrap() {
+            locator.add_custom_schema(Box::new(event));
+        }
+        let record = record();
+        let event = locator.event_schema(&record).unwrap();
+        let mut parser = Parser::create(&event);
+
+        assert_eq!(parser.array_len("Items").unwrap(), 2);
+        assert_eq!(TryParse::<i32>::parse(&mut parser, "Items[1].X"), -5);
+        assert_eq!(TryParse::<String>::parse(&mut parser, "Items[0].Name"), "ab");
+        assert_eq!(TryParse::<String>::parse(&mut parser, "Items[1].Name"), "xyz");
+        assert!(TryParse::<i32>::try_parse(&mut parser, "Items[2].X").is_err());
+        assert!(TryParse::<i32>::try_parse(&mut parser, "Items.X").is_err());
+        assert_eq!(TryParse::<String>::parse(&mut parser, "Title"), "Test");
+        assert_eq!(TryParse::<Vec<u8>>::parse(&mut parser, "Blob"), [1, 2, 3]);
+        assert_eq!(TryParse::<String>::parse(&mut parser, "User"), "S-1-5-18");
+        assert_eq!(TryParse::<IpAddr>::parse(&mut parser, "Address"), IpAddr::V6(Ipv6Addr::LOCALHOST));
+        assert_eq!(TryParse::<Vec<u32>>::parse(&mut parser, "Values"), [7, 9]);
+        assert!(parser.buffer.is_empty());
+
+        let paths: Vec<String> = parser.property_paths().unwrap().into_iter().map(|(path, _)| path).collect();
+        assert_eq!(paths, [
+            "Count",
+            "Items[0].X", "Items[0].NameLength", "Items[0].Name",
+            "Items[1].X", "Items[1].NameLength", "Items[1].Name",
+            "Title", "Size", "Blob", "User", "Address", "Values[0]", "Values[1]",
+        ]);
+
+        let mut output = String::new();
+        let items = event.property(1);
+        crate::write_property(&mut output, &mut parser, &items, false);
+        assert_eq!(output, "  Items= [{ X: 10, NameLength: 2, Name: ab }, { X: -5, NameLength: 3, Name: xyz }]");
+    }
+}
@@ -3,7 +3,7 @@
 //! The `property` module expose the basic structures that represent the Properties an Event contains
 //! based on it's Schema. This Properties can then be used to parse accordingly their values.
 use crate::FastHashMap;
-use crate::tdh_types::Property;
+use crate::tdh_types::{Property, PropertyDesc, StructDesc};
 use crate::schema::Schema;
 
 /// Event Property information
@@ -23,7 +23,9 @@ impl<'a> PropertyInfo<'a> {
 }
 
 pub(crate) struct PropertyIter {
+    /// The top level properties followed by the members of any structs
     properties: Vec<Property>,
+    top_level_count: usize,
     pub (crate) name_to_indx: FastHashMap<String, usize>,
 }
 
@@ -38,14 +40,35 @@ impl PropertyIter {
             properties.push(prop);
         }
 
-        PropertyIter { properties, name_to_indx }
+        // Struct members aren't included in the property count so keep going until we've seen all of them
+        let mut count = properties.len();
+        let mut i = 0;
+        while i < count {
+            if i == properties.len() {
+                properties.push(schema.event_schema.property(i as u32));
+            }
+            if let PropertyDesc::Struct(desc) = &properties[i].desc {
+                count = count.max(desc.start_index as usize + desc.num_members as usize);
+            }
+            i += 1;
+        }
+
+        PropertyIter { properties, top_level_count: prop_count as usize, name_to_indx }
     }
 
     pub fn property(&self, index: usize) -> Option<&Property> {
         self.properties.get(index)
     }
 
+    /// The top level properties
     pub fn properties_iter(&self) -> &[Property] {
-        &self.properties
+        &self.properties[..self.top_level_count]
+    }
+
+    /// The members of the struct described by `desc`
+    pub fn members(&self, desc: &StructDesc) -> &[Property] {
+        let start = (desc.start_index as usize).min(self.properties.len());
+        let end = (start + desc.num_members as usize).min(self.properties.len());
+        &self.properties[start..end]
     }
 }
@@ -2,16 +2,24 @@
 //!
 //! This module contains the means needed to locate and interact with the Schema of an ETW event
 use crate::custom_schemas::EventInfo;
-use crate::etw_types::{DecodingSource, EventRecord, TraceEventInfoRaw};
+use crate::etw_types::{DecodingSource, EventRecord, ExtendedDataItem, TraceEventInfoRaw};
 use crate::property::PropertyIter;
+#[cfg(windows)]
 use crate::tdh;
+use crate::manifest::{self, ManifestError};
+use crate::schema_store::{SchemaStore, SchemaStoreError, StoredSchema, SCHEMA_STORE_VERSION, guid_from_string};
 use crate::tdh_types::Property;
+use crate::tracelogging::TraceLoggingSchema;
+use crate::value::Value;
+use crate::parser::ParserError;
 use crate::FastHashMap;
 use std::any::{Any, TypeId};
 use std::collections::hash_map::Entry;
+use std::io::{Read, Write};
+use std::path::Path;
 use std::sync::Arc;
 use once_cell::unsync::OnceCell;
-use windows::Win32::System::Diagnostics::Etw::{self, EVENT_HEADER_FLAG_64_BIT_HEADER};
+use windows::Win32::System::Diagnostics::Etw::EVENT_HEADER_FLAG_64_BIT_HEADER;
 use windows::core::GUID;
 
 /// Schema module errors
@@ -22,9 +30,14 @@ pub enum SchemaError {
     /// Represents an internal [TdhNativeError]
     ///
     /// [TdhNativeError]: tdh::TdhNativeError
+    #[cfg(windows)]
     TdhNativeError(tdh::TdhNativeError),
+    /// There's no known schema for the event and TDH isn't available to look one up
+    #[cfg(not(windows))]
+    UnknownSchema,
 }
 
+#[cfg(windows)]
 impl From<tdh::TdhNativeError> for SchemaError {
     fn from(err: tdh::TdhNativeError) -> Self {
         SchemaError::TdhNativeError(err)
@@ -63,31 +76,28 @@ impl SchemaKey {
         // TraceLogging events all use the same id and are distinguished from each other using the metadata.
         // Instead of storing the metadata in the SchemaKey we follow the approach of PerfView and have a side table of synthetic ids keyed on metadata.
         // It might be better to store the metadata in the SchemaKey but then we may want to be careful not to allocate a fresh metadata for every event.
-        let mut id = event.EventHeader.EventDescriptor.Id;
-        if event.ExtendedDataCount > 0 {
-            let extended = unsafe { std::slice::from_raw_parts(event.ExtendedData, event.ExtendedDataCount as usize) };
-            for e in extended {
-                if e.ExtType as u32 == Etw::EVENT_HEADER_EXT_TYPE_EVENT_SCHEMA_TL {
-                    let mut provider = locator.tracelogging_providers.entry(event.EventHeader.ProviderId).or_insert(TraceLoggingProviderIds::new());
-                    let data = unsafe { std::slice::from_raw_parts(e.DataPtr as *const u8, e.DataSize as usize) } ;
-                    if let Some(metadata_id) = provider.ids.get(data) {
-                        // we want to ensure that our synthetic ids don't overlap with any ids used in the events
-                        assert_ne!(id, *metadata_id);
-                        id = *metadata_id;
-                    } else {
-                        provider.ids.insert(data.to_vec(), provider.next_id);
-                        id = provider.next_id;
-                        provider.next_id += 1;
-                    }
+        let mut id = event.descriptor.id;
+        for e in &event.extended_data {
+            if let ExtendedDataItem::EventSchemaTl(data) = e {
+                let data: &[u8] = data;
+                let mut provider = locator.tracelogging_providers.entry(event.provider_id).or_insert(TraceLoggingProviderIds::new());
+                if let Some(metadata_id) = provider.ids.get(data) {
+                    // we want to ensure that our synthetic ids don't overlap with any ids used in the events
+                    assert_ne!(id, *metadata_id);
+                    id = *metadata_id;
+                } else {
+                    provider.ids.insert(data.to_vec(), provider.next_id);
+                    id = provider.next_id;
+                    provider.next_id += 1;
                 }
             }
         }
         SchemaKey {
-            provider: event.EventHeader.ProviderId,
+            provider: event.provider_id,
             id,
-            version: event.EventHeader.EventDescriptor.Version,
-            level: event.EventHeader.EventDescriptor.Level,
-            opcode: event.EventHeader.EventDescriptor.Opcode,
+            version: event.descriptor.version,
+            level: event.descriptor.level,
+            opcode: event.descriptor.opcode,
         }
     }
 }
@@ -96,11 +106,11 @@ impl SchemaKey {
 ///
 /// This cache is implemented as a [HashMap] where the key is a combination of the following elements
 /// of an [Event Record](https://docs.microsoft.com/en-us/windows/win32/api/evntcons/ns-evntcons-event_record)
-/// * EventHeader.ProviderId
-/// * EventHeader.EventDescriptor.Id
-/// * EventHeader.EventDescriptor.Opcode
-/// * EventHeader.EventDescriptor.Version
-/// * EventHeader.EventDescriptor.Level
+/// * provider_id
+/// * descriptor.id
+/// * descriptor.opcode
+/// * descriptor.version
+/// * descriptor.level
 ///
 /// Credits: [KrabsETW::schema_locator](https://github.com/microsoft/krabsetw/blob/master/krabs/krabs/schema_locator.hpp)
 #[derive(Default)]
@@ -128,6 +138,8 @@ pub trait EventSchema {
 
     fn event_message(&self) -> Option<String> { return None }
     fn is_event_metadata(&self) -> bool { return false }
+    /// The raw TRACE_EVENT_INFO that this schema reads from, if it has one
+    fn trace_event_info(&self) -> Option<&TraceEventInfoRaw> { None }
 }
 
 
@@ -156,6 +168,74 @@ impl SchemaLocator {
         self.schemas.insert(key, Arc::new(Schema::new(schema)));
     }
 
+    /// Returns the schema for the event called `name` (e.g. "MSNT_SystemTrace/Thread/CSwitch"),
+    /// preferring the highest version if there are several. Only schemas that have been added or
+    /// already looked up are considered.
+    pub fn schema_by_name(&self, name: &str) -> Option<&dyn EventSchema> {
+        self.schemas.values()
+            .filter(|schema| schema.name() == name)
+            .max_by_key(|schema| schema.event_schema.event_version())
+            .map(|schema| schema.event_schema.as_ref())
+    }
+
+    /// Adds schemas for all of the events in the instrumentation manifest at `path`
+    ///
+    /// This lets us decode events from providers that aren't registered on this machine.
+    pub fn add_manifest(&mut self, path: &Path) -> Result<(), ManifestError> {
+        let xml = std::fs::read_to_string(path)?;
+        for event in manifest::parse_manifest(&xml)? {
+            self.add_custom_schema(Box::new(event));
+        }
+        Ok(())
+    }
+
+    /// Writes every schema that has been resolved so far so that it can be loaded with
+    /// [SchemaLocator::load_schemas], possibly on another machine
+    pub fn save_schemas<W: Write>(&self, writer: W) -> Result<(), SchemaStoreError> {
+        // TraceLogging schemas are stored under synthetic ids so we need their metadata to find them again
+        let mut metadata = FastHashMap::default();
+        for (provider, ids) in &self.tracelogging_providers {
+            for (data, id) in &ids.ids {
+                metadata.insert((provider.to_u128(), *id), data);
+            }
+        }
+        let mut keys: Vec<_> = self.schemas.keys().collect();
+        keys.sort_by_key(|key| (key.provider.to_u128(), key.id, key.version, key.level, key.opcode));
+        let schemas = keys.into_iter().map(|key| {
+            let data = metadata.get(&(key.provider.to_u128(), key.id)).map(|data| data.to_vec());
+            StoredSchema::new(key.provider, key.id, key.version, key.level, key.opcode, data, self.schemas[key].event_schema.as_ref())
+        }).collect();
+        serde_json::to_writer(writer, &SchemaStore { version: SCHEMA_STORE_VERSION, schemas })?;
+        Ok(())
+    }
+
+    /// Adds the schemas written by [SchemaLocator::save_schemas]
+    ///
+    /// These take precedence over any schemas that have already been added for the same events.
+    pub fn load_schemas<R: Read>(&mut self, reader: R) -> Result<(), SchemaStoreError> {
+        let store: SchemaStore = serde_json::from_reader(reader)?;
+        if store.version != SCHEMA_STORE_VERSION {
+            return Err(SchemaStoreError::UnsupportedVersion(store.version));
+        }
+        for stored in store.schemas {
+            let provider = guid_from_string(&stored.provider)?;
+            let schema = stored.to_event_schema()?;
+            let id = match stored.tracelogging_metadata {
+                Some(data) => {
+                    let provider = self.tracelogging_providers.entry(provider).or_insert(TraceLoggingProviderIds::new());
+                    *provider.ids.entry(data).or_insert_with(|| {
+                        provider.next_id += 1;
+                        provider.next_id - 1
+                    })
+                }
+                None => stored.id,
+            };
+            let key = SchemaKey { provider, id, version: stored.version, level: stored.level, opcode: stored.opcode };
+            self.schemas.insert(key, Arc::new(Schema::new(schema)));
+        }
+        Ok(())
+    }
+
     /// Use the `event_schema` function to retrieve the Schema of an ETW Event
     ///
     /// # Arguments
@@ -174,12 +254,20 @@ impl SchemaLocator {
     ///     let schema = schema_locator.event_schema(record)?;
     /// };
     /// ```
-    pub fn event_schema<'a>(&mut self, event: &'a EventRecord) -> SchemaResult<TypedEvent<'a>> {
+    pub fn event_schema<'a>(&mut self, event: &'a EventRecord<'a>) -> SchemaResult<TypedEvent<'a>> {
         let key = SchemaKey::new(&event, self);
+        #[cfg(windows)]
+        let etw_record = OnceCell::new();
         let info = match self.schemas.entry(key) {
             Entry::Occupied(entry) => entry.into_mut(),
             Entry::Vacant(entry) => {
-                let info = Box::new(tdh::schema_from_tdh(event.clone())?);
+                let info = match TraceLoggingSchema::new(event) {
+                    Some(info) => Box::new(info),
+                    #[cfg(windows)]
+                    None => schema_from_system(etw_record.get_or_init(|| tdh::EtwRecord::new(event)))?,
+                    #[cfg(not(windows))]
+                    None => schema_from_system(event)?,
+                };
                 // dbg!(info.provider_guid(), info.provider_name(), info.decoding_source());
                 // TODO: Cloning for now, should be a reference at some point...
                 entry.insert(Arc::new(Schema::new(info)))
@@ -193,10 +281,26 @@ impl SchemaLocator {
             self.add_custom_schema(Box::new(event_info));
         }
             
-        Ok(TypedEvent::new(event, info))
+        Ok(TypedEvent {
+            record: event,
+            schema: info,
+            #[cfg(windows)]
+            etw_record,
+        })
     }
 }
 
+/// Looks up the schema of an event that we don't have a custom schema for
+#[cfg(windows)]
+fn schema_from_system(event: &tdh::EtwRecord) -> SchemaResult<Box<dyn EventSchema>> {
+    Ok(Box::new(tdh::schema_from_tdh(event)?))
+}
+
+#[cfg(not(windows))]
+fn schema_from_system(_event: &EventRecord) -> SchemaResult<Box<dyn EventSchema>> {
+    Err(SchemaError::UnknownSchema)
+}
+
 pub struct Schema {
     pub event_schema: Box<dyn EventSchema>,
     properties: OnceCell<PropertyIter>,
@@ -219,13 +323,26 @@ impl Schema {
 }
 
 pub struct TypedEvent<'a> {
-    record: &'a EventRecord,
+    record: &'a EventRecord<'a>,
     pub (crate) schema: Arc<Schema>,
+    /// The `EVENT_RECORD` that's passed to TDH, built on first use
+    #[cfg(windows)]
+    etw_record: OnceCell<tdh::EtwRecord<'a>>,
 }
 
 impl<'a> TypedEvent<'a> {
-    pub fn new(record: &'a EventRecord, schema: Arc<Schema>) -> Self {
-        TypedEvent { record, schema }
+    pub fn new(record: &'a EventRecord<'a>, schema: Arc<Schema>) -> Self {
+        TypedEvent {
+            record,
+            schema,
+            #[cfg(windows)]
+            etw_record: OnceCell::new(),
+        }
+    }
+
+    #[cfg(windows)]
+    pub(crate) fn etw_record(&self) -> &tdh::EtwRecord<'a> {
+        self.etw_record.get_or_init(|| tdh::EtwRecord::new(self.record))
     }
 
     pub(crate) fn user_buffer(&self) -> &[u8] {
@@ -234,7 +351,7 @@ impl<'a> TypedEvent<'a> {
 
     // Horrible getters FTW!! :D
     // TODO: Not a big fan of this, think a better way..
-    pub(crate) fn record(&self) -> &EventRecord {
+    pub fn record(&self) -> &EventRecord<'a> {
         self.record
     }
 
@@ -250,7 +367,7 @@ impl<'a> TypedEvent<'a> {
     /// };
     /// ```
     pub fn event_id(&self) -> u16 {
-        self.record.EventHeader.EventDescriptor.Id
+        self.record.descriptor.id
     }
 
     /// Use the `opcode` function to obtain the Opcode of the Event Record
@@ -265,7 +382,7 @@ impl<'a> TypedEvent<'a> {
     /// };
     /// ```
     pub fn opcode(&self) -> u8 {
-        self.record.EventHeader.EventDescriptor.Opcode
+        self.record.descriptor.opcode
     }
 
     /// Use the `event_flags` function to obtain the Event Flags of the [EventRecord]
@@ -280,11 +397,11 @@ impl<'a> TypedEvent<'a> {
     /// };
     /// ```
     pub fn event_flags(&self) -> u16 {
-        self.record.EventHeader.Flags
+        self.record.flags
     }
 
     pub fn is_64bit(&self) -> bool {
-        (self.record.EventHeader.Flags & EVENT_HEADER_FLAG_64_BIT_HEADER as u16) != 0
+        (self.record.flags & EVENT_HEADER_FLAG_64_BIT_HEADER as u16) != 0
     }
 
     /// Use the `event_version` function to obtain the Version of the [EventRecord]
@@ -299,7 +416,7 @@ impl<'a> TypedEvent<'a> {
     /// };
     /// ```  
     pub fn event_version(&self) -> u8 {
-        self.record.EventHeader.EventDescriptor.Version
+        self.record.descriptor.version
     }
 
     /// Use the `process_id` function to obtain the ProcessId of the [EventRecord]
@@ -314,7 +431,7 @@ impl<'a> TypedEvent<'a> {
     /// };
     /// ```  
     pub fn process_id(&self) -> u32 {
-        self.record.EventHeader.ProcessId
+        self.record.process_id
     }
 
     /// Use the `thread_id` function to obtain the ThreadId of the [EventRecord]
@@ -329,7 +446,7 @@ impl<'a> TypedEvent<'a> {
     /// };
     /// ```  
     pub fn thread_id(&self) -> u32 {
-        self.record.EventHeader.ThreadId
+        self.record.thread_id
     }
 
     /// Use the `timestamp` function to obtain the TimeStamp of the [EventRecord]
@@ -344,7 +461,7 @@ impl<'a> TypedEvent<'a> {
     /// };
     /// ```  
     pub fn timestamp(&self) -> i64 {
-        self.record.EventHeader.TimeStamp
+        self.record.timestamp
     }
 
     /// Use the `activity_id` function to obtain the ActivityId of the [EventRecord]
@@ -360,7 +477,7 @@ impl<'a> TypedEvent<'a> {
     /// ```
     /// [TraceEventInfo]: crate::native::etw_types::TraceEventInfo
     pub fn activity_id(&self) -> GUID {
-        self.record.EventHeader.ActivityId
+        self.record.activity_id
     }
 
     /// Use the `decoding_source` function to obtain the [DecodingSource] from the [TraceEventInfo]
@@ -439,6 +556,12 @@ impl<'a> TypedEvent<'a> {
     pub fn event_message(&self) -> Option<String> {
         self.schema.event_schema.event_message()
     }
+
+    /// Decodes all of the event's properties into a [Value::Struct], which can be inspected
+    /// without knowing the event's schema or serialized with serde
+    pub fn to_value(&self) -> Result<Value, ParserError> {
+        crate::value::event_value(self)
+    }
 }
 
 impl<'a> PartialEq for TypedEvent<'a> {
@@ -450,3 +573,109 @@ impl<'a> PartialEq for TypedEvent<'a> {
 }
 
 impl<'a> Eq for TypedEvent<'a> {}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::etw_types::EventDescriptor;
+    use crate::parser::{Parser, TryParse};
+    use std::borrow::Cow;
+
+    fn dbg_id_record() -> EventRecord<'static> {
+        let mut user_data = Vec::new();
+        user_data.extend_from_slice(&0x7ff0_0000_0000u64.to_le_bytes());
+        user_data.extend_from_slice(&1234u32.to_le_bytes());
+        user_data.extend_from_slice(&[0x11; 16]);
+        user_data.extend_from_slice(&3u32.to_le_bytes());
+        user_data.extend_from_slice(b"xul.pdb\0");
+        EventRecord {
+            provider_id: GUID::from("b3e675d7-2554-4f18-830b-2762732560de"),
+            descriptor: EventDescriptor { version: 2, opcode: 36, ..Default::default() },
+            flags: EVENT_HEADER_FLAG_64_BIT_HEADER as u16,
+            timestamp: 42,
+            user_data: Cow::Owned(user_data),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn owned_record() {
+        let mut locator = SchemaLocator::new();
+        crate::add_custom_schemas(&mut locator);
+        let record = dbg_id_record();
+
+        let event = locator.event_schema(&record).unwrap();
+        assert_eq!(event.name(), "KernelTraceControl/ImageID/DbgID_RSDS");
+        assert_eq!(event.timestamp(), 42);
+        let mut parser = Parser::create(&event);
+        let image_base: u64 = parser.parse("ImageBase");
+        let process_id: u32 = parser.parse("ProcessId");
+        let age: u32 = parser.parse("Age");
+        let pdb_file_name: String = parser.parse("PdbFileName");
+        assert_eq!(image_base, 0x7ff0_0000_0000);
+        assert_eq!(process_id, 1234);
+        assert_eq!(age, 3);
+        assert_eq!(pdb_file_name, "xul.pdb");
+    }
+
+    #[test]
+    fn to_value() {
+        let mut locator = SchemaLocator::new();
+        crate::add_custom_schemas(&mut locator);
+        let record = dbg_id_record();
+        let event = locator.event_schema(&record).unwrap();
+
+        let value = event.to_value().unwrap();
+        assert_eq!(value.get("ImageBase"), Some(&Value::UInt(0x7ff0_0000_0000)));
+        assert_eq!(value.get("PdbFileName").and_then(Value::as_str), Some("xul.pdb"));
+        assert_eq!(
+            serde_json::to_string(&value).unwrap(),
+            r#"{"ImageBase":140668768878592,"ProcessId":1234,"GuidSig":"11111111-1111-1111-1111-111111111111","Age":3,"PdbFileName":"xul.pdb"}"#
+        );
+    }
+
+    #[test]
+    fn saved_schemas() {
+        let mut locator = SchemaLocator::new();
+        crate::add_custom_schemas(&mut locator);
+        let mut saved = Vec::new();
+        locator.save_schemas(&mut saved).unwrap();
+
+        // A locator without any built in schemas should be able to decode the event from the saved ones
+        let mut locator = SchemaLocator::new();
+        locator.load_schemas(&saved[..]).unwrap();
+        let record = dbg_id_record();
+        let event = locator.event_schema(&record).unwrap();
+        assert_eq!(event.name(), "KernelTraceControl/ImageID/DbgID_RSDS");
+        let mut parser = Parser::create(&event);
+        let pdb_file_name: String = parser.parse("PdbFileName");
+        assert_eq!(pdb_file_name, "xul.pdb");
+
+        let mut resaved = Vec::new();
+        locator.save_schemas(&mut resaved).unwrap();
+        assert_eq!(saved, resaved);
+    }
+
+    #[test]
+    fn saved_schemas_version() {
+        let mut locator = SchemaLocator::new();
+        let result = locator.load_schemas(&br#"{"version": 1000, "schemas": []}"#[..]);
+        assert!(matches!(result, Err(SchemaStoreError::UnsupportedVersion(1000))));
+    }
+
+    #[test]
+    fn saved_schemas_bad_offset() {
+        let mut info: windows::Win32::System::Diagnostics::Etw::TRACE_EVENT_INFO = unsafe { std::mem::zeroed() };
+        info.TaskNameOffset = 0x1000;
+        let info = unsafe { std::slice::from_raw_parts(&info as *const _ as *const u8, std::mem::size_of_val(&info)) };
+        let hex: String = info.iter().map(|b| format!("{:02x}", b)).collect();
+        let saved = format!(
+            r#"{{"version": 1, "schemas": [{{"provider": "b3e675d7-2554-4f18-830b-2762732560de", "id": 1, "version": 0, "level": 0, "opcode": 0,
+                "info": {{"kind": "TraceEventInfo", "info": "{}", "property_maps": []}}}}]}}"#,
+            hex
+        );
+        let mut locator = SchemaLocator::new();
+        let result = locator.load_schemas(saved.as_bytes());
+        assert!(matches!(result, Err(SchemaStoreError::InvalidSchema(message)) if message.starts_with("TaskNameOffset 4096")));
+    }
+}
@@ -0,0 +1,429 @@
+//! Serializable schema store
+//!
+//! Most schemas are looked up with TDH, so they're only available on the machine that recorded
+//! the trace. The store lets a [SchemaLocator] save every schema it has resolved to a file that
+//! can be shipped next to the trace and loaded into a [SchemaLocator] somewhere else, e.g. to
+//! analyze a trace recorded on Windows on Linux.
+//!
+//! The file is JSON. Schemas that came from TDH are stored as the raw `TRACE_EVENT_INFO` blob
+//! along with any property maps, everything else is stored as a list of [Property]s.
+//!
+//! [SchemaLocator]: crate::schema::SchemaLocator
+use std::rc::Rc;
+
+use num_traits::{FromPrimitive, ToPrimitive};
+use serde::{Deserialize, Serialize};
+use windows::core::GUID;
+
+use crate::etw_types::{DecodingSource, TraceEventInfoRaw};
+use crate::schema::EventSchema;
+use crate::tdh_types::{PrimitiveDesc, Property, PropertyDesc, PropertyFlags, PropertyLength, PropertyMapInfo, StructDesc};
+use crate::utils;
+
+/// The version of the file format written by [SchemaLocator::save_schemas]
+///
+/// This needs to be bumped whenever the format changes in a way that older readers can't handle.
+///
+/// [SchemaLocator::save_schemas]: crate::schema::SchemaLocator::save_schemas
+pub const SCHEMA_STORE_VERSION: u32 = 1;
+
+#[derive(Debug)]
+pub enum SchemaStoreError {
+    IoError(std::io::Error),
+    JsonError(serde_json::Error),
+    /// The file was written by a version of the store that we don't understand
+    UnsupportedVersion(u32),
+    /// The file is valid JSON but contains a schema that we can't use
+    InvalidSchema(String),
+}
+
+impl From<std::io::Error> for SchemaStoreError {
+    fn from(err: std::io::Error) -> Self {
+        SchemaStoreError::IoError(err)
+    }
+}
+
+impl From<serde_json::Error> for SchemaStoreError {
+    fn from(err: serde_json::Error) -> Self {
+        SchemaStoreError::JsonError(err)
+    }
+}
+
+pub(crate) type SchemaStoreResult<T> = Result<T, SchemaStoreError>;
+
+fn invalid<T>(message: String) -> SchemaStoreResult<T> {
+    Err(SchemaStoreError::InvalidSchema(message))
+}
+
+#[derive(Serialize, Deserialize)]
+pub(crate) struct SchemaStore {
+    pub(crate) version: u32,
+    pub(crate) schemas: Vec<StoredSchema>,
+}
+
+/// A schema along with the key that it was stored under in the [SchemaLocator]
+///
+/// [SchemaLocator]: crate::schema::SchemaLocator
+#[derive(Serialize, Deserialize)]
+pub(crate) struct StoredSchema {
+    pub(crate) provider: String,
+    pub(crate) id: u16,
+    pub(crate) version: u8,
+    pub(crate) level: u8,
+    pub(crate) opcode: u8,
+    /// TraceLogging events are keyed on their metadata instead of their id
+    #[serde(default, skip_serializing_if = "Option::is_none", with = "hex_bytes_opt")]
+    pub(crate) tracelogging_metadata: Option<Vec<u8>>,
+    info: StoredInfo,
+}
+
+#[derive(Serialize, Deserialize)]
+#[serde(tag = "kind")]
+enum StoredInfo {
+    TraceEventInfo {
+        #[serde(with = "hex_bytes")]
+        info: Vec<u8>,
+        /// The map of each property, indexed like the properties
+        property_maps: Vec<Option<StoredMap>>,
+    },
+    Generic(GenericSchema),
+}
+
+#[derive(Serialize, Deserialize)]
+struct GenericSchema {
+    decoding_source: String,
+    provider_guid: String,
+    event_id: u16,
+    opcode: u8,
+    version: u8,
+    level: u8,
+    provider_name: String,
+    task_name: String,
+    opcode_name: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    message: Option<String>,
+    #[serde(default)]
+    is_event_metadata: bool,
+    top_level_property_count: u32,
+    properties: Vec<StoredProperty>,
+}
+
+#[derive(Serialize, Deserialize)]
+struct StoredProperty {
+    name: String,
+    flags: u32,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    length: Option<u16>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    length_index: Option<u16>,
+    count: u16,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    in_type: Option<u16>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    out_type: Option<u16>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    struct_start_index: Option<u16>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    struct_member_count: Option<u16>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    map: Option<StoredMap>,
+}
+
+#[derive(Serialize, Deserialize)]
+struct StoredMap {
+    is_bitmap: bool,
+    entries: Vec<(u32, String)>,
+}
+
+impl StoredMap {
+    fn new(map: &PropertyMapInfo) -> Self {
+        let mut entries: Vec<_> = map.map.iter().map(|(value, name)| (*value, name.clone())).collect();
+        entries.sort();
+        StoredMap { is_bitmap: map.is_bitmap, entries }
+    }
+
+    fn to_map_info(&self) -> Rc<PropertyMapInfo> {
+        Rc::new(PropertyMapInfo { is_bitmap: self.is_bitmap, map: self.entries.iter().cloned().collect() })
+    }
+}
+
+impl StoredProperty {
+    fn new(property: &Property) -> Self {
+        let (length, length_index) = match property.length {
+            PropertyLength::Length(length) => (Some(length), None),
+            PropertyLength::Index(index) => (None, Some(index)),
+        };
+        let mut stored = StoredProperty {
+            name: property.name.clone(),
+            flags: property.flags.bits(),
+            length,
+            length_index,
+            count: property.count,
+            in_type: None,
+            out_type: None,
+            struct_start_index: None,
+            struct_member_count: None,
+            map: property.map_info.as_deref().map(StoredMap::new),
+        };
+        match &property.desc {
+            PropertyDesc::Primitive(desc) => {
+                stored.in_type = desc.in_type.to_u16();
+                stored.out_type = desc.out_type.to_u16();
+            }
+            PropertyDesc::Struct(desc) => {
+                stored.struct_start_index = Some(desc.start_index);
+                stored.struct_member_count = Some(desc.num_members);
+            }
+        }
+        stored
+    }
+
+    fn to_property(&self) -> SchemaStoreResult<Property> {
+        let length = match (self.length, self.length_index) {
+            (_, Some(index)) => PropertyLength::Index(index),
+            (length, None) => PropertyLength::Length(length.unwrap_or(0)),
+        };
+        let desc = match (self.in_type, self.struct_start_index) {
+            (Some(in_type), _) => {
+                let in_type = match FromPrimitive::from_u16(in_type) {
+                    Some(in_type) => in_type,
+                    None => return invalid(format!("property {} has unknown in type {}", self.name, in_type)),
+                };
+                let out_type = self.out_type.and_then(FromPrimitive::from_u16).unwrap_or_default();
+                PropertyDesc::Primitive(PrimitiveDesc { in_type, out_type })
+            }
+            (None, Some(start_index)) => PropertyDesc::Struct(StructDesc {
+                start_index,
+                num_members: self.struct_member_count.unwrap_or(0),
+            }),
+            (None, None) => return invalid(format!("property {} has no type", self.name)),
+        };
+        Ok(Property {
+            name: self.name.clone(),
+            flags: PropertyFlags::from_bits_truncate(self.flags),
+            length,
+            desc,
+            map_info: self.map.as_ref().map(StoredMap::to_map_info),
+            count: self.count,
+        })
+    }
+}
+
+fn decoding_source_name(source: DecodingSource) -> &'static str {
+    match source {
+        DecodingSource::DecodingSourceXMLFile => "XMLFile",
+        DecodingSource::DecodingSourceWbem => "Wbem",
+        DecodingSource::DecodingSourceWPP => "WPP",
+        DecodingSource::DecodingSourceTlg => "Tlg",
+        DecodingSource::DecodingSourceMax => "Max",
+    }
+}
+
+fn decoding_source_from_name(name: &str) -> SchemaStoreResult<DecodingSource> {
+    Ok(match name {
+        "XMLFile" => DecodingSource::DecodingSourceXMLFile,
+        "Wbem" => DecodingSource::DecodingSourceWbem,
+        "WPP" => DecodingSource::DecodingSourceWPP,
+        "Tlg" => DecodingSource::DecodingSourceTlg,
+        "Max" => DecodingSource::DecodingSourceMax,
+        _ => return invalid(format!("unknown decoding source {}", name)),
+    })
+}
+
+pub(crate) fn guid_to_string(guid: GUID) -> String {
+    format!("{:?}", guid)
+}
+
+pub(crate) fn guid_from_string(value: &str) -> SchemaStoreResult<GUID> {
+    match utils::guid_from_str(value) {
+        Some(guid) => Ok(guid),
+        None => invalid(format!("invalid guid {}", value)),
+    }
+}
+
+/// Returns all of the properties of `schema`, including the members of structs which aren't
+/// counted by [EventSchema::property_count]
+fn all_properties(schema: &dyn EventSchema) -> Vec<Property> {
+    let mut properties = Vec::new();
+    let mut count = schema.property_count();
+    let mut index = 0;
+    while index < count {
+        let property = schema.property(index);
+        if let PropertyDesc::Struct(desc) = &property.desc {
+            count = count.max(desc.start_index as u32 + desc.num_members as u32);
+        }
+        properties.push(property);
+        index += 1;
+    }
+    properties
+}
+
+impl StoredSchema {
+    pub(crate) fn new(provider: GUID, id: u16, version: u8, level: u8, opcode: u8, tracelogging_metadata: Option<Vec<u8>>, schema: &dyn EventSchema) -> Self {
+        let info = match schema.trace_event_info() {
+            Some(raw) => StoredInfo::TraceEventInfo {
+                info: raw.info.clone(),
+                property_maps: (0..raw.total_property_count())
+                    .map(|i| schema.property(i).map_info.as_deref().map(StoredMap::new))
+                    .collect(),
+            },
+            None => StoredInfo::Generic(GenericSchema {
+                decoding_source: decoding_source_name(schema.decoding_source()).to_owned(),
+                provider_guid: guid_to_string(schema.provider_guid()),
+                event_id: schema.event_id(),
+                opcode: schema.opcode(),
+                version: schema.event_version(),
+                level: schema.level(),
+                provider_name: schema.provider_name(),
+                task_name: schema.task_name(),
+                opcode_name: schema.opcode_name(),
+                message: schema.event_message(),
+                is_event_metadata: schema.is_event_metadata(),
+                top_level_property_count: schema.property_count(),
+                properties: all_properties(schema).iter().map(StoredProperty::new).collect(),
+            }),
+        };
+        StoredSchema { provider: guid_to_string(provider), id, version, level, opcode, tracelogging_metadata, info }
+    }
+
+    pub(crate) fn to_event_schema(&self) -> SchemaStoreResult<Box<dyn EventSchema>> {
+        Ok(match &self.info {
+            StoredInfo::TraceEventInfo { info, property_maps } => {
+                let maps = property_maps.iter().map(|map| map.as_ref().map(StoredMap::to_map_info)).collect();
+                match TraceEventInfoRaw::with_property_maps(info.clone(), maps) {
+                    Ok(raw) => Box::new(raw),
+                    Err(message) => return invalid(format!("{} for event {} of {}", message, self.id, self.provider)),
+                }
+            }
+            StoredInfo::Generic(schema) => {
+                let properties = schema.properties.iter().map(StoredProperty::to_property).collect::<SchemaStoreResult<Vec<_>>>()?;
+                if schema.top_level_property_count as usize > properties.len() {
+                    return invalid(format!("{}/{} has fewer properties than its property count", schema.provider_name, schema.task_name));
+                }
+                Box::new(StoredEventSchema {
+                    decoding_source: decoding_source_from_name(&schema.decoding_source)?,
+                    provider_guid: guid_from_string(&schema.provider_guid)?,
+                    event_id: schema.event_id,
+                    opcode: schema.opcode,
+                    version: schema.version,
+                    level: schema.level,
+                    provider_name: schema.provider_name.clone(),
+                    task_name: schema.task_name.clone(),
+                    opcode_name: schema.opcode_name.clone(),
+                    message: schema.message.clone(),
+                    is_event_metadata: schema.is_event_metadata,
+                    top_level_property_count: schema.top_level_property_count,
+                    properties,
+                })
+            }
+        })
+    }
+}
+
+/// An [EventSchema] loaded from a schema store
+struct StoredEventSchema {
+    decoding_source: DecodingSource,
+    provider_guid: GUID,
+    event_id: u16,
+    opcode: u8,
+    version: u8,
+    level: u8,
+    provider_name: String,
+    task_name: String,
+    opcode_name: String,
+    message: Option<String>,
+    is_event_metadata: bool,
+    top_level_property_count: u32,
+    properties: Vec<Property>,
+}
+
+impl EventSchema for StoredEventSchema {
+    fn decoding_source(&self) -> DecodingSource {
+        self.decoding_source
+    }
+
+    fn provider_guid(&self) -> GUID {
+        self.provider_guid
+    }
+
+    fn event_id(&self) -> u16 {
+        self.event_id
+    }
+
+    fn opcode(&self) -> u8 {
+        self.opcode
+    }
+
+    fn event_version(&self) -> u8 {
+        self.version
+    }
+
+    fn provider_name(&self) -> String {
+        self.provider_name.clone()
+    }
+
+    fn task_name(&self) -> String {
+        self.task_name.clone()
+    }
+
+    fn opcode_name(&self) -> String {
+        self.opcode_name.clone()
+    }
+
+    fn level(&self) -> u8 {
+        self.level
+    }
+
+    fn property_count(&self) -> u32 {
+        self.top_level_property_count
+    }
+
+    fn property(&self, index: u32) -> Property {
+        self.properties[index as usize].clone()
+    }
+
+    fn event_message(&self) -> Option<String> {
+        self.message.clone()
+    }
+
+    fn is_event_metadata(&self) -> bool {
+        self.is_event_metadata
+    }
+}
+
+/// Stores byte blobs as hex strings instead of arrays of numbers
+mod hex_bytes {
+    use serde::{de::Error, Deserialize, Deserializer, Serializer};
+
+    pub fn serialize<S: Serializer>(bytes: &[u8], serializer: S) -> Result<S::Ok, S::Error> {
+        let hex: String = bytes.iter().map(|b| format!("{:02x}", b)).collect();
+        serializer.serialize_str(&hex)
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Vec<u8>, D::Error> {
+        let hex = String::deserialize(deserializer)?;
+        if hex.len() % 2 != 0 || !hex.is_ascii() {
+            return Err(D::Error::custom("invalid hex string"));
+        }
+        (0..hex.len()).step_by(2)
+            .map(|i| u8::from_str_radix(&hex[i..i + 2], 16).map_err(D::Error::custom))
+            .collect()
+    }
+}
+
+mod hex_bytes_opt {
+    use serde::{Deserialize, Deserializer, Serializer};
+
+    pub fn serialize<S: Serializer>(bytes: &Option<Vec<u8>>, serializer: S) -> Result<S::Ok, S::Error> {
+        match bytes {
+            Some(bytes) => super::hex_bytes::serialize(bytes, serializer),
+            None => serializer.serialize_none(),
+        }
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Option<Vec<u8>>, D::Error> {
+        #[derive(Deserialize)]
+        struct Wrapper(#[serde(with = "super::hex_bytes")] Vec<u8>);
+        Ok(Option::<Wrapper>::deserialize(deserializer)?.map(|Wrapper(bytes)| bytes))
+    }
+}
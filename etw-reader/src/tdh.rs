@@ -1,5 +1,5 @@
 use std::ffi::OsString;
-use std::ops::Deref;
+use std::marker::PhantomData;
 use std::os::windows::ffi::OsStringExt;
 use std::ptr;
 
@@ -29,7 +29,76 @@ impl From<std::io::Error> for TdhNativeError {
 
 pub(crate) type TdhNativeResult<T> = Result<T, TdhNativeError>;
 
-pub fn schema_from_tdh(event: &Etw::EVENT_RECORD) -> TdhNativeResult<TraceEventInfoRaw> {
+/// An `EVENT_RECORD` built from an [EventRecord] so that it can be passed to TDH
+///
+/// TDH only looks at the header, the user data and the TraceLogging metadata, so those are the
+/// only parts that we fill in. Building one copies the extended data items, so it's built once per
+/// event and shared by all the TDH calls for that event.
+pub(crate) struct EtwRecord<'a> {
+    record: Etw::EVENT_RECORD,
+    // `record.ExtendedData` points into this
+    _extended_data: Vec<Etw::EVENT_HEADER_EXTENDED_DATA_ITEM>,
+    // `record` points into the user data and the extended data of the event
+    _event: PhantomData<&'a EventRecord<'a>>,
+}
+
+impl<'a> EtwRecord<'a> {
+    pub(crate) fn new(event: &'a EventRecord<'a>) -> Self {
+        let mut extended_data: Vec<Etw::EVENT_HEADER_EXTENDED_DATA_ITEM> = event
+            .extended_data
+            .iter()
+            .filter_map(|item| {
+                let (ext_type, data) = match item {
+                    ExtendedDataItem::EventSchemaTl(data) => (Etw::EVENT_HEADER_EXT_TYPE_EVENT_SCHEMA_TL, data),
+                    ExtendedDataItem::ProvTraits(data) => (Etw::EVENT_HEADER_EXT_TYPE_PROV_TRAITS, data),
+                    _ => return None,
+                };
+                Some(Etw::EVENT_HEADER_EXTENDED_DATA_ITEM {
+                    ExtType: ext_type as u16,
+                    DataSize: data.len() as u16,
+                    DataPtr: data.as_ptr() as u64,
+                    ..Default::default()
+                })
+            })
+            .collect();
+
+        let mut record = Etw::EVENT_RECORD::default();
+        let header = &mut record.EventHeader;
+        header.Size = std::mem::size_of::<Etw::EVENT_HEADER>() as u16;
+        header.Flags = event.flags;
+        header.EventProperty = event.event_property;
+        header.ThreadId = event.thread_id;
+        header.ProcessId = event.process_id;
+        header.TimeStamp = event.timestamp;
+        header.ProviderId = event.provider_id;
+        header.EventDescriptor = Etw::EVENT_DESCRIPTOR {
+            Id: event.descriptor.id,
+            Version: event.descriptor.version,
+            Channel: event.descriptor.channel,
+            Level: event.descriptor.level,
+            Opcode: event.descriptor.opcode,
+            Task: event.descriptor.task,
+            Keyword: event.descriptor.keyword,
+        };
+        header.Anonymous.ProcessorTime = event.processor_time;
+        header.ActivityId = event.activity_id;
+        record.BufferContext.Anonymous.ProcessorIndex = event.processor_index;
+        record.BufferContext.LoggerId = event.logger_id;
+        record.ExtendedDataCount = extended_data.len() as u16;
+        if !extended_data.is_empty() {
+            record.ExtendedData = extended_data.as_mut_ptr();
+        }
+        record.UserDataLength = event.user_data.len() as u16;
+        record.UserData = event.user_data.as_ptr() as *mut _;
+        EtwRecord { record, _extended_data: extended_data, _event: PhantomData }
+    }
+}
+
+pub(crate) fn schema_from_tdh(event: &EtwRecord) -> TdhNativeResult<TraceEventInfoRaw> {
+    schema_from_etw_record(&event.record)
+}
+
+fn schema_from_etw_record(event: &Etw::EVENT_RECORD) -> TdhNativeResult<TraceEventInfoRaw> {
     let mut buffer_size = 0;
     unsafe {
         if Etw::TdhGetEventInformation(
@@ -57,7 +126,7 @@ pub fn schema_from_tdh(event: &Etw::EVENT_RECORD) -> TdhNativeResult<TraceEventI
     }
 }
 
-pub(crate) fn property_size(event: &EventRecord, name: &str) -> TdhNativeResult<u32> {
+pub(crate) fn property_size(event: &EtwRecord, name: &str) -> TdhNativeResult<u32> {
     let mut property_size = 0;
 
     let mut desc = Etw::PROPERTY_DATA_DESCRIPTOR::default();
@@ -65,18 +134,18 @@ pub(crate) fn property_size(event: &EventRecord, name: &str) -> TdhNativeResult<
     let utf16_name = name.as_utf16();
     desc.PropertyName = utf16_name.as_ptr() as u64;
 
-    unsafe {
-        let status = Etw::TdhGetPropertySize(
-            event.deref(),
+    let status = unsafe {
+        Etw::TdhGetPropertySize(
+            &event.record,
             None,
             &[desc],
             &mut property_size,
-        );
-        if status != 0 {
-            return Err(TdhNativeError::IoError(std::io::Error::from_raw_os_error(
-                status as i32,
-            )));
-        }
+        )
+    };
+    if status != 0 {
+        return Err(TdhNativeError::IoError(std::io::Error::from_raw_os_error(
+            status as i32,
+        )));
     }
 
     Ok(property_size)
@@ -106,6 +106,13 @@ impl Property {
     }
 }
 
+impl Property {
+    /// Returns true if the property is an array, even if it only has a single element
+    pub fn is_array(&self) -> bool {
+        self.count > 1 || self.flags.intersects(PropertyFlags::PROPERTY_PARAM_COUNT | PropertyFlags::PROPERTY_PARAM_FIXED_COUNT)
+    }
+}
+
 /// Represent a TDH_IN_TYPE
 #[repr(u16)]
 #[derive(Debug, Clone, Copy, FromPrimitive, ToPrimitive, PartialEq)]
@@ -0,0 +1,607 @@
+//! TraceLogging self-describing event decoding
+//!
+//! TraceLogging events carry their own schema in the `EVENT_HEADER_EXT_TYPE_EVENT_SCHEMA_TL`
+//! extended data item and the provider name in `EVENT_HEADER_EXT_TYPE_PROV_TRAITS`. The layout of
+//! both blobs is described in TraceLoggingProvider.h. This module decodes them into an
+//! [EventSchema] so that these events can be parsed without TDH.
+use windows::core::GUID;
+
+use crate::etw_types::{standard_opcode_name, DecodingSource, EventRecord, ExtendedDataItem};
+use crate::schema::EventSchema;
+use crate::tdh_types::{PrimitiveDesc, Property, PropertyDesc, PropertyFlags, PropertyLength, StructDesc, TdhInType, TdhOutType};
+use crate::utils;
+
+// TlgIn_t flags from TraceLoggingProvider.h
+const IN_CHAIN: u8 = 0x80;
+const IN_CCOUNT: u8 = 0x20;
+const IN_VCOUNT: u8 = 0x40;
+const IN_CUSTOM: u8 = 0x60;
+const IN_FLAG_MASK: u8 = 0x60;
+const IN_TYPE_MASK: u8 = 0x1f;
+const IN_BINARY: u8 = 14;
+const IN_STRUCT: u8 = 24;
+const IN_COUNTED_BINARY: u8 = 25;
+
+const OUT_CHAIN: u8 = 0x80;
+const OUT_TYPE_MASK: u8 = 0x7f;
+
+// ETW_PROVIDER_TRAIT_TYPE
+const PROVIDER_TRAIT_TYPE_GROUP: u8 = 1;
+const PROVIDER_TRAIT_DECODE_GUID: u8 = 2;
+
+struct Reader<'a> {
+    data: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> Reader<'a> {
+    fn u8(&mut self) -> Option<u8> {
+        let v = *self.data.get(self.pos)?;
+        self.pos += 1;
+        Some(v)
+    }
+
+    fn u16(&mut self) -> Option<u16> {
+        let v = self.data.get(self.pos..self.pos + 2)?;
+        self.pos += 2;
+        Some(u16::from_le_bytes([v[0], v[1]]))
+    }
+
+    fn bytes(&mut self, len: usize) -> Option<&'a [u8]> {
+        let v = self.data.get(self.pos..self.pos + len)?;
+        self.pos += len;
+        Some(v)
+    }
+
+    fn str(&mut self) -> Option<String> {
+        let rest = self.data.get(self.pos..)?;
+        let len = rest.iter().position(|&c| c == 0)?;
+        self.pos += len + 1;
+        Some(String::from_utf8_lossy(&rest[..len]).into_owned())
+    }
+
+    /// Reads a chain of bytes where the high bit of each byte says whether another byte follows
+    /// and returns the 28-bit tag value that they encode.
+    fn tags(&mut self) -> Option<u32> {
+        let mut tags = 0;
+        let mut shift: i32 = 21;
+        loop {
+            let b = self.u8()?;
+            if shift >= 0 {
+                tags |= ((b & 0x7f) as u32) << shift;
+                shift -= 7;
+            }
+            if b & 0x80 == 0 {
+                return Some(tags);
+            }
+        }
+    }
+
+    fn is_empty(&self) -> bool {
+        self.pos >= self.data.len()
+    }
+}
+
+/// The provider name and provider traits from an `EVENT_HEADER_EXT_TYPE_PROV_TRAITS` item
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct ProviderTraits {
+    pub name: String,
+    /// The provider group that this provider is a member of
+    pub group: Option<GUID>,
+    pub decode_guid: Option<GUID>,
+}
+
+impl ProviderTraits {
+    pub fn parse(data: &[u8]) -> Option<Self> {
+        let mut reader = Reader { data, pos: 0 };
+        let size = reader.u16()? as usize;
+        reader.data = data.get(..size)?;
+        let name = reader.str()?;
+        let mut traits = ProviderTraits { name, ..Default::default() };
+        while !reader.is_empty() {
+            let trait_size = reader.u16()? as usize;
+            let trait_type = reader.u8()?;
+            if trait_size < 3 {
+                return None;
+            }
+            let trait_data = reader.bytes(trait_size - 3)?;
+            match trait_type {
+                PROVIDER_TRAIT_TYPE_GROUP if trait_data.len() >= 16 => traits.group = Some(utils::parse_guid(trait_data)),
+                PROVIDER_TRAIT_DECODE_GUID if trait_data.len() >= 16 => traits.decode_guid = Some(utils::parse_guid(trait_data)),
+                _ => {}
+            }
+        }
+        Some(traits)
+    }
+}
+
+/// A single field from the event metadata before it has been flattened into [Property]s
+#[derive(Debug)]
+struct Field {
+    name: String,
+    in_type: u8,
+    out_type: u8,
+    tags: u32,
+    /// The number of elements for `IN_CCOUNT` arrays
+    count: u16,
+    members: Vec<Field>,
+}
+
+fn parse_field(reader: &mut Reader) -> Option<Field> {
+    let name = reader.str()?;
+    let in_type = reader.u8()?;
+    let mut out_type = 0;
+    let mut tags = 0;
+    if in_type & IN_CHAIN != 0 {
+        out_type = reader.u8()?;
+        if out_type & OUT_CHAIN != 0 {
+            tags = reader.tags()?;
+        }
+    }
+    let mut count = 1;
+    match in_type & IN_FLAG_MASK {
+        IN_CCOUNT => count = reader.u16()?,
+        IN_CUSTOM => {
+            // The type info for custom serializers (e.g. bond or protobuf) is opaque to us
+            let type_info_size = reader.u16()?;
+            reader.bytes(type_info_size as usize)?;
+        }
+        _ => {}
+    }
+    let mut members = Vec::new();
+    if in_type & IN_TYPE_MASK == IN_STRUCT {
+        // The out type of a struct is the number of fields that it contains
+        for _ in 0..(out_type & OUT_TYPE_MASK) {
+            members.push(parse_field(reader)?);
+        }
+    }
+    Some(Field { name, in_type, out_type: out_type & OUT_TYPE_MASK, tags, count, members })
+}
+
+fn tdh_in_type(in_type: u8) -> TdhInType {
+    use TdhInType::*;
+    match in_type & IN_TYPE_MASK {
+        1 => InTypeUnicodeString,
+        2 => InTypeAnsiString,
+        3 => InTypeInt8,
+        4 => InTypeUInt8,
+        5 => InTypeInt16,
+        6 => InTypeUInt16,
+        7 => InTypeInt32,
+        8 => InTypeUInt32,
+        9 => InTypeInt64,
+        10 => InTypeUInt64,
+        11 => InTypeFloat,
+        12 => InTypeDouble,
+        13 => InTypeBoolean,
+        14 | 25 => InTypeBinary,
+        15 => InTypeGuid,
+        16 => InTypePointer,
+        17 => InTypeFileTime,
+        18 => InTypeSystemTime,
+        19 => InTypeSid,
+        20 => InTypeHexInt32,
+        21 => InTypeHexInt64,
+        22 => InTypeCountedString,
+        23 => InTypeCountedAnsiString,
+        _ => InTypeNull,
+    }
+}
+
+/// The out type that TDH uses when the metadata doesn't specify one
+pub(crate) fn default_out_type(in_type: TdhInType) -> TdhOutType {
+    use TdhInType::*;
+    use TdhOutType::*;
+    match in_type {
+        InTypeUnicodeString | InTypeAnsiString | InTypeCountedString | InTypeCountedAnsiString | InTypeSid => OutTypeString,
+        InTypeInt8 => OutTypeInt8,
+        InTypeUInt8 => OutTypeUInt8,
+        InTypeInt16 => OutTypeInt16,
+        InTypeUInt16 => OutTypeUInt16,
+        InTypeInt32 => OutTypeInt32,
+        InTypeUInt32 => OutTypeUInt32,
+        InTypeInt64 => OutTypeInt64,
+        InTypeUInt64 => OutTypeUInt64,
+        InTypeFloat => OutTypeFloat,
+        InTypeDouble => OutTypeDouble,
+        InTypeBoolean => OutTypeBoolean,
+        InTypeBinary => OutTypeHexBinary,
+        InTypeGuid => OutTypeGuid,
+        InTypePointer | InTypeHexInt64 => OutTypeHexInt64,
+        InTypeFileTime | InTypeSystemTime => OutTypeDateTime,
+        InTypeHexInt32 => OutTypeHexInt32,
+        _ => OutTypeNull,
+    }
+}
+
+fn tdh_out_type(in_type: TdhInType, out_type: u8) -> TdhOutType {
+    use TdhInType::*;
+    use TdhOutType::*;
+    match out_type {
+        2 => OutTypeString,
+        3 => OutTypeBoolean,
+        4 => match in_type {
+            InTypeInt8 | InTypeUInt8 => OutTypeHexInt8,
+            InTypeInt16 | InTypeUInt16 => OutTypeHexInt16,
+            InTypeInt32 | InTypeUInt32 => OutTypeHexInt32,
+            InTypeInt64 | InTypeUInt64 => OutTypeHexInt64,
+            _ => OutTypeHexBinary,
+        },
+        5 => OutTypePid,
+        6 => OutTypeTid,
+        7 => OutTypePort,
+        8 => OutTypeIpv4,
+        9 => OutTypeIpv6,
+        // SOCKETADDRESS
+        10 => OutTypeHexBinary,
+        // XML
+        11 => OutTypeString,
+        12 => OutTypeJson,
+        13 => OutTypeWin32Error,
+        14 => OutTypeNtStatus,
+        15 => OutTypeHResult,
+        16 => OutTypeDateTime,
+        // SIGNED and UNSIGNED are used to print 8-bit chars as numbers
+        17 => match in_type {
+            InTypeUInt8 => OutTypeInt8,
+            InTypeUInt16 => OutTypeInt16,
+            InTypeUInt32 => OutTypeInt32,
+            InTypeUInt64 => OutTypeInt64,
+            _ => default_out_type(in_type),
+        },
+        18 => match in_type {
+            InTypeInt8 => OutTypeUInt8,
+            InTypeInt16 => OutTypeUInt16,
+            InTypeInt32 => OutTypeUInt32,
+            InTypeInt64 => OutTypeUInt64,
+            _ => default_out_type(in_type),
+        },
+        35 => OutTypeUtf8,
+        36 => OutTypePkcs7,
+        37 => OutTypeCodePointer,
+        38 => OutTypeDatetimeUtc,
+        _ => default_out_type(in_type),
+    }
+}
+
+/// An [EventSchema] decoded from TraceLogging metadata
+///
+/// Like TDH, the top level fields come first and are followed by the members of any structs.
+/// Variable length arrays and binary fields are prefixed by their element count or size in the
+/// event data, so each of them is preceded by a UInt16 property (e.g. `IdsCount` or `HashLength`)
+/// that its count or length refers to.
+#[derive(Debug, Clone)]
+pub struct TraceLoggingSchema {
+    provider_guid: GUID,
+    provider_name: String,
+    event_name: String,
+    tags: u32,
+    id: u16,
+    version: u8,
+    opcode: u8,
+    level: u8,
+    top_level_property_count: u32,
+    properties: Vec<Property>,
+}
+
+impl TraceLoggingSchema {
+    /// Decodes the TraceLogging metadata of `event`
+    ///
+    /// Returns `None` if the event doesn't have any metadata or if the metadata is malformed.
+    pub fn new(event: &EventRecord) -> Option<Self> {
+        let mut metadata = None;
+        let mut traits = None;
+        for item in &event.extended_data {
+            match item {
+                ExtendedDataItem::EventSchemaTl(data) => metadata = Some(data),
+                ExtendedDataItem::ProvTraits(data) => traits = ProviderTraits::parse(data),
+                _ => {}
+            }
+        }
+        let mut schema = Self::from_metadata(metadata?, traits.as_ref())?;
+        schema.provider_guid = event.provider_id;
+        if traits.is_none() {
+            schema.provider_name = format!("{:?}", event.provider_id);
+        }
+        schema.id = event.descriptor.id;
+        schema.version = event.descriptor.version;
+        schema.opcode = event.descriptor.opcode;
+        schema.level = event.descriptor.level;
+        Some(schema)
+    }
+
+    /// Decodes an `EVENT_HEADER_EXT_TYPE_EVENT_SCHEMA_TL` blob
+    pub fn from_metadata(metadata: &[u8], traits: Option<&ProviderTraits>) -> Option<Self> {
+        let mut reader = Reader { data: metadata, pos: 0 };
+        let size = reader.u16()? as usize;
+        reader.data = metadata.get(..size)?;
+        let tags = reader.tags()?;
+        let event_name = reader.str()?;
+        let mut fields = Vec::new();
+        while !reader.is_empty() {
+            fields.push(parse_field(&mut reader)?);
+        }
+
+        let mut properties = Vec::new();
+        let mut structs = Vec::new();
+        for field in &fields {
+            push_property(&mut properties, &mut structs, field);
+        }
+        let top_level_property_count = properties.len() as u32;
+        // Struct members go after all of the top level properties
+        let mut next = 0;
+        while next < structs.len() {
+            let (index, field): (usize, &Field) = structs[next];
+            next += 1;
+            let start_index = properties.len() as u16;
+            for member in &field.members {
+                push_property(&mut properties, &mut structs, member);
+            }
+            // The count and length properties of the members are members as well
+            let num_members = properties.len() as u16 - start_index;
+            if let PropertyDesc::Struct(desc) = &mut properties[index].desc {
+                desc.start_index = start_index;
+                desc.num_members = num_members;
+            }
+        }
+
+        Some(TraceLoggingSchema {
+            provider_guid: GUID::zeroed(),
+            provider_name: traits.map(|t| t.name.clone()).unwrap_or_default(),
+            event_name,
+            tags,
+            id: 0,
+            version: 0,
+            opcode: 0,
+            level: 0,
+            top_level_property_count,
+            properties,
+        })
+    }
+
+    /// The event tags from the metadata
+    pub fn tags(&self) -> u32 {
+        self.tags
+    }
+}
+
+/// Adds the UInt16 that holds the element count or size of the next property and returns its index
+fn push_size_property(properties: &mut Vec<Property>, name: String) -> u16 {
+    let index = properties.len() as u16;
+    properties.push(Property {
+        name,
+        flags: PropertyFlags::empty(),
+        length: PropertyLength::Length(0),
+        desc: PropertyDesc::Primitive(PrimitiveDesc { in_type: TdhInType::InTypeUInt16, out_type: TdhOutType::OutTypeUInt16 }),
+        map_info: None,
+        count: 1,
+    });
+    index
+}
+
+fn push_property<'a>(properties: &mut Vec<Property>, structs: &mut Vec<(usize, &'a Field)>, field: &'a Field) {
+    let mut flags = PropertyFlags::empty();
+    if field.tags != 0 {
+        flags |= PropertyFlags::PROPERTY_HAS_TAGS;
+    }
+    let mut count = 1;
+    let mut length = PropertyLength::Length(0);
+    match field.in_type & IN_FLAG_MASK {
+        IN_CCOUNT => {
+            flags |= PropertyFlags::PROPERTY_PARAM_FIXED_COUNT;
+            count = field.count;
+        }
+        IN_VCOUNT => {
+            flags |= PropertyFlags::PROPERTY_PARAM_COUNT;
+            count = push_size_property(properties, format!("{}Count", field.name));
+        }
+        IN_CUSTOM => flags |= PropertyFlags::PROPERTY_HAS_CUSTOM_SCHEMA,
+        _ => {
+            // Each element of an array of binary fields has its own size, which a length property
+            // can't describe, so only single binary fields get one.
+            if matches!(field.in_type & IN_TYPE_MASK, IN_BINARY | IN_COUNTED_BINARY) {
+                flags |= PropertyFlags::PROPERTY_PARAM_LENGTH;
+                length = PropertyLength::Index(push_size_property(properties, format!("{}Length", field.name)));
+            }
+        }
+    }
+    let index = properties.len();
+    let desc = if field.in_type & IN_TYPE_MASK == IN_STRUCT {
+        flags |= PropertyFlags::PROPERTY_STRUCT;
+        structs.push((index, field));
+        PropertyDesc::Struct(StructDesc { start_index: 0, num_members: field.members.len() as u16 })
+    } else {
+        let in_type = tdh_in_type(field.in_type);
+        PropertyDesc::Primitive(PrimitiveDesc { in_type, out_type: tdh_out_type(in_type, field.out_type) })
+    };
+    properties.push(Property {
+        name: field.name.clone(),
+        flags,
+        length,
+        desc,
+        map_info: None,
+        count,
+    });
+}
+
+impl EventSchema for TraceLoggingSchema {
+    fn decoding_source(&self) -> DecodingSource {
+        DecodingSource::DecodingSourceTlg
+    }
+
+    fn provider_guid(&self) -> GUID {
+        self.provider_guid
+    }
+
+    fn event_id(&self) -> u16 {
+        self.id
+    }
+
+    fn opcode(&self) -> u8 {
+        self.opcode
+    }
+
+    fn event_version(&self) -> u8 {
+        self.version
+    }
+
+    fn provider_name(&self) -> String {
+        self.provider_name.clone()
+    }
+
+    fn task_name(&self) -> String {
+        self.event_name.clone()
+    }
+
+    fn opcode_name(&self) -> String {
+        standard_opcode_name(self.opcode).unwrap_or_default().to_owned()
+    }
+
+    fn level(&self) -> u8 {
+        self.level
+    }
+
+    fn property_count(&self) -> u32 {
+        self.top_level_property_count
+    }
+
+    fn property(&self, index: u32) -> Property {
+        self.properties[index as usize].clone()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn metadata(tags: &[u8], name: &str, fields: &[u8]) -> Vec<u8> {
+        let mut data = Vec::new();
+        data.extend_from_slice(&[0, 0]);
+        data.extend_from_slice(tags);
+        data.extend_from_slice(name.as_bytes());
+        data.push(0);
+        data.extend_from_slice(fields);
+        let size = data.len() as u16;
+        data[0..2].copy_from_slice(&size.to_le_bytes());
+        data
+    }
+
+    #[test]
+    fn fields() {
+        let mut fields = Vec::new();
+        // an ANSI string
+        fields.extend_from_slice(b"MarkerName\0");
+        fields.push(2);
+        // a u64 printed as hex with tags
+        fields.extend_from_slice(b"Address\0");
+        fields.extend_from_slice(&[10 | IN_CHAIN, 4 | OUT_CHAIN, 0x01]);
+        // a variable length array of u32
+        fields.extend_from_slice(b"Ids\0");
+        fields.push(8 | IN_VCOUNT);
+        // a struct with two members
+        fields.extend_from_slice(b"Point\0");
+        fields.extend_from_slice(&[IN_STRUCT | IN_CHAIN, 2]);
+        fields.extend_from_slice(b"X\0");
+        fields.push(7);
+        fields.extend_from_slice(b"Y\0");
+        fields.push(7);
+        // a fixed length array of bytes
+        fields.extend_from_slice(b"Hash\0");
+        fields.push(4 | IN_CCOUNT);
+        fields.extend_from_slice(&20u16.to_le_bytes());
+
+        let schema = TraceLoggingSchema::from_metadata(&metadata(&[0x81, 0x80, 0x80, 0x01], "MyEvent", &fields), None).unwrap();
+        assert_eq!(schema.task_name(), "MyEvent");
+        assert_eq!(schema.tags(), 1 << 21 | 1);
+        assert_eq!(schema.property_count(), 6);
+
+        let address = schema.property(1);
+        assert!(matches!(address.desc, PropertyDesc::Primitive(PrimitiveDesc { in_type: TdhInType::InTypeUInt64, out_type: TdhOutType::OutTypeHexInt64 })));
+        assert!(address.flags.contains(PropertyFlags::PROPERTY_HAS_TAGS));
+
+        assert_eq!(schema.property(2).name, "IdsCount");
+        let ids = schema.property(3);
+        assert!(ids.flags.contains(PropertyFlags::PROPERTY_PARAM_COUNT));
+        assert_eq!(ids.count, 2);
+
+        let point = schema.property(4);
+        match point.desc {
+            PropertyDesc::Struct(desc) => {
+                assert_eq!(desc.num_members, 2);
+                assert_eq!(schema.property(desc.start_index as u32).name, "X");
+                assert_eq!(schema.property(desc.start_index as u32 + 1).name, "Y");
+            }
+            _ => panic!("expected a struct"),
+        }
+
+        let hash = schema.property(5);
+        assert!(hash.flags.contains(PropertyFlags::PROPERTY_PARAM_FIXED_COUNT));
+        assert_eq!(hash.count, 20);
+    }
+
+    #[test]
+    fn provider_traits() {
+        let mut data = Vec::new();
+        data.extend_from_slice(&[0, 0]);
+        data.extend_from_slice(b"Mozilla.FirefoxTraceLogger\0");
+        data.extend_from_slice(&19u16.to_le_bytes());
+        data.push(PROVIDER_TRAIT_TYPE_GROUP);
+        data.extend_from_slice(&0x1234_5678_9abc_def0_1122_3344_5566_7788u128.to_le_bytes());
+        let size = data.len() as u16;
+        data[0..2].copy_from_slice(&size.to_le_bytes());
+
+        let traits = ProviderTraits::parse(&data).unwrap();
+        assert_eq!(traits.name, "Mozilla.FirefoxTraceLogger");
+        assert!(traits.group.is_some());
+        assert_eq!(traits.decode_guid, None);
+    }
+
+    #[test]
+    fn truncated_metadata() {
+        let data = metadata(&[0], "MyEvent", b"Field\0");
+        assert!(TraceLoggingSchema::from_metadata(&data, None).is_none());
+    }
+
+    #[test]
+    fn parse_counted_fields() {
+        use crate::parser::{Parser, TryParse};
+        use crate::schema::SchemaLocator;
+        use std::borrow::Cow;
+
+        let mut fields = Vec::new();
+        fields.extend_from_slice(b"Ids\0");
+        fields.push(8 | IN_VCOUNT);
+        fields.extend_from_slice(b"Data\0");
+        fields.push(IN_BINARY);
+        // a struct whose member is a variable length array
+        fields.extend_from_slice(b"Item\0");
+        fields.extend_from_slice(&[IN_STRUCT | IN_CHAIN, 1]);
+        fields.extend_from_slice(b"Values\0");
+        fields.push(6 | IN_VCOUNT);
+
+        let mut user_data = Vec::new();
+        user_data.extend_from_slice(&2u16.to_le_bytes());
+        user_data.extend_from_slice(&7u32.to_le_bytes());
+        user_data.extend_from_slice(&9u32.to_le_bytes());
+        user_data.extend_from_slice(&3u16.to_le_bytes());
+        user_data.extend_from_slice(&[1, 2, 3]);
+        user_data.extend_from_slice(&1u16.to_le_bytes());
+        user_data.extend_from_slice(&5u16.to_le_bytes());
+        let record = EventRecord {
+            flags: windows::Win32::System::Diagnostics::Etw::EVENT_HEADER_FLAG_64_BIT_HEADER as u16,
+            user_data: Cow::Owned(user_data),
+            extended_data: vec![ExtendedDataItem::EventSchemaTl(Cow::Owned(metadata(&[0], "MyEvent", &fields)))],
+            ..Default::default()
+        };
+
+        let mut locator = SchemaLocator::new();
+        let event = locator.event_schema(&record).unwrap();
+        let mut parser = Parser::create(&event);
+        assert_eq!(TryParse::<Vec<u32>>::parse(&mut parser, "Ids"), [7, 9]);
+        assert_eq!(TryParse::<u16>::parse(&mut parser, "DataLength"), 3);
+        assert_eq!(TryParse::<Vec<u8>>::parse(&mut parser, "Data"), [1, 2, 3]);
+        assert_eq!(parser.array_len("Item.Values").unwrap(), 1);
+        assert_eq!(TryParse::<u16>::parse(&mut parser, "Item.Values[0]"), 5);
+        assert!(parser.buffer.is_empty());
+    }
+}
@@ -1,4 +1,6 @@
 
+use windows::core::GUID;
+
 fn is_aligned<T>(ptr: *const T) -> bool
 where
     T: Sized,
@@ -89,3 +91,53 @@ pub fn parse_utf16_guid(v: &[u8]) -> String {
     .trim_matches('}')
     .to_string()
 }
+
+/// Reads a GUID stored in its in-memory (little-endian) layout
+pub fn parse_guid(v: &[u8]) -> GUID {
+    GUID::from_values(
+        u32::from_le_bytes(v[0..4].try_into().unwrap()),
+        u16::from_le_bytes(v[4..6].try_into().unwrap()),
+        u16::from_le_bytes(v[6..8].try_into().unwrap()),
+        v[8..16].try_into().unwrap(),
+    )
+}
+
+/// Parses a GUID written as text, e.g. `{9e814aad-3204-11d2-9a82-006008a86939}`. The braces are optional.
+pub fn guid_from_str(s: &str) -> Option<GUID> {
+    let s = s.strip_prefix('{').and_then(|s| s.strip_suffix('}')).unwrap_or(s);
+    let dashes: Vec<usize> = s.match_indices('-').map(|(i, _)| i).collect();
+    if s.len() != 36 || dashes != [8, 13, 18, 23] {
+        return None;
+    }
+    let hex = s.replace('-', "");
+    if !hex.chars().all(|c| c.is_ascii_hexdigit()) {
+        return None;
+    }
+    u128::from_str_radix(&hex, 16).ok().map(GUID::from_u128)
+}
+
+/// Parses a decimal number or a hexadecimal one that starts with `0x`
+pub fn parse_number(s: &str) -> Option<u64> {
+    match s.strip_prefix("0x").or_else(|| s.strip_prefix("0X")) {
+        Some(hex) => u64::from_str_radix(hex, 16).ok(),
+        None => s.parse().ok(),
+    }
+}
+
+/// Returns the size of the SID at the start of `v`
+///
+/// A SID is 8 bytes of header followed by `SubAuthorityCount` u32 sub authorities.
+pub fn sid_size(v: &[u8]) -> usize {
+    match v.get(1) {
+        Some(&sub_authority_count) => (8 + 4 * sub_authority_count as usize).min(v.len()),
+        None => v.len(),
+    }
+}
+
+/// Returns the size of a value that is prefixed by a u16 byte count, including the count
+pub fn counted_size(v: &[u8]) -> usize {
+    match v.get(..2) {
+        Some(count) => (2 + u16::from_le_bytes([count[0], count[1]]) as usize).min(v.len()),
+        None => v.len(),
+    }
+}
@@ -0,0 +1,316 @@
+//! A generic representation of decoded events
+//!
+//! [Value] holds the properties of any event without knowing their names or types ahead of time
+//! and can be serialized with serde.
+use std::net::{IpAddr, Ipv4Addr};
+
+use serde::ser::{SerializeMap, SerializeSeq};
+use serde::{Serialize, Serializer};
+use windows::core::GUID;
+
+use crate::parser::{Parser, ParserError, TryParse};
+use crate::schema::TypedEvent;
+use crate::tdh_types::{Property, PropertyDesc, TdhInType, TdhOutType};
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Value {
+    Bool(bool),
+    Int(i64),
+    UInt(u64),
+    Float(f64),
+    String(String),
+    Guid(GUID),
+    Pointer(u64),
+    IpAddr(IpAddr),
+    Binary(Vec<u8>),
+    Array(Vec<Value>),
+    /// The members of a struct, or the properties of an event, in order
+    Struct(Vec<(String, Value)>),
+    /// A value from a value map, along with its name if the map has one
+    Enum { value: u32, name: Option<String> },
+    /// A value from a bitmap, along with the names of the bits that are set. Bits without a name
+    /// are left in `unnamed`.
+    Flags { value: u32, names: Vec<String>, unnamed: u32 },
+}
+
+impl Value {
+    /// Returns the member or property called `name` if this is a [Value::Struct]
+    pub fn get(&self, name: &str) -> Option<&Value> {
+        match self {
+            Value::Struct(members) => members.iter().find(|(n, _)| n == name).map(|(_, value)| value),
+            _ => None,
+        }
+    }
+
+    pub fn as_u64(&self) -> Option<u64> {
+        match *self {
+            Value::UInt(value) | Value::Pointer(value) => Some(value),
+            Value::Int(value) => value.try_into().ok(),
+            Value::Enum { value, .. } | Value::Flags { value, .. } => Some(value as u64),
+            _ => None,
+        }
+    }
+
+    pub fn as_str(&self) -> Option<&str> {
+        match self {
+            Value::String(s) => Some(s),
+            Value::Enum { name: Some(name), .. } => Some(name),
+            _ => None,
+        }
+    }
+}
+
+/// Pointers, GUIDs, IP addresses and binary blobs are written as strings. Mapped values are
+/// written as their name, or their number if they don't have one, and bitmaps as a list of names.
+impl Serialize for Value {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        match self {
+            Value::Bool(value) => serializer.serialize_bool(*value),
+            Value::Int(value) => serializer.serialize_i64(*value),
+            Value::UInt(value) => serializer.serialize_u64(*value),
+            Value::Float(value) => serializer.serialize_f64(*value),
+            Value::String(value) => serializer.serialize_str(value),
+            Value::Guid(guid) => serializer.collect_str(&format_args!("{:?}", guid)),
+            Value::Pointer(value) => serializer.collect_str(&format_args!("0x{:x}", value)),
+            Value::IpAddr(addr) => serializer.collect_str(addr),
+            Value::Binary(bytes) => serializer.serialize_str(&bytes.iter().map(|b| format!("{:02x}", b)).collect::<String>()),
+            Value::Array(values) => {
+                let mut seq = serializer.serialize_seq(Some(values.len()))?;
+                for value in values {
+                    seq.serialize_element(value)?;
+                }
+                seq.end()
+            }
+            Value::Struct(members) => {
+                let mut map = serializer.serialize_map(Some(members.len()))?;
+                for (name, value) in members {
+                    map.serialize_entry(name, value)?;
+                }
+                map.end()
+            }
+            Value::Enum { name: Some(name), .. } => serializer.serialize_str(name),
+            Value::Enum { value, name: None } => serializer.serialize_u32(*value),
+            Value::Flags { names, unnamed, .. } => {
+                let mut seq = serializer.serialize_seq(None)?;
+                for name in names {
+                    seq.serialize_element(name)?;
+                }
+                if *unnamed != 0 {
+                    seq.serialize_element(&format!("0x{:x}", unnamed))?;
+                }
+                seq.end()
+            }
+        }
+    }
+}
+
+/// Decodes all of the properties of `event` into a [Value::Struct]
+pub(crate) fn event_value(event: &TypedEvent) -> Result<Value, ParserError> {
+    let mut parser = Parser::create(event);
+    let mut properties = Vec::new();
+    for i in 0..event.property_count() {
+        let property = event.property(i);
+        let value = property_value(&mut parser, &property.name, &property)?;
+        properties.push((property.name, value));
+    }
+    Ok(Value::Struct(properties))
+}
+
+fn property_value(parser: &mut Parser, path: &str, property: &Property) -> Result<Value, ParserError> {
+    if property.is_array() {
+        let len = parser.array_len(path)?;
+        let elements = (0..len).map(|i| element_value(parser, &format!("{}[{}]", path, i), property));
+        return Ok(Value::Array(elements.collect::<Result<_, _>>()?));
+    }
+    element_value(parser, path, property)
+}
+
+/// Reads the value at `path` that's looked up in the property's value map or bitmap
+pub(crate) fn map_key(parser: &mut Parser, path: &str, in_type: TdhInType) -> Result<u32, ParserError> {
+    Ok(match in_type {
+        TdhInType::InTypeUInt32 | TdhInType::InTypeHexInt32 => TryParse::<u32>::try_parse(parser, path)
+            .or_else(|_| TryParse::<i32>::try_parse(parser, path).map(|x| x as u32))?,
+        TdhInType::InTypeUInt16 => TryParse::<u16>::try_parse(parser, path)? as u32,
+        TdhInType::InTypeUInt8 => TryParse::<u8>::try_parse(parser, path)? as u32,
+        _ => return Err(ParserError::InvalidType),
+    })
+}
+
+fn element_value(parser: &mut Parser, path: &str, property: &Property) -> Result<Value, ParserError> {
+    let desc = match &property.desc {
+        PropertyDesc::Struct(_) => {
+            let mut members = Vec::new();
+            for member in parser.struct_members(property) {
+                let value = property_value(parser, &format!("{}.{}", path, member.name), member)?;
+                members.push((member.name.clone(), value));
+            }
+            return Ok(Value::Struct(members));
+        }
+        PropertyDesc::Primitive(desc) => desc,
+    };
+
+    if let Some(map_info) = &property.map_info {
+        let value = map_key(parser, path, desc.in_type)?;
+        if map_info.is_bitmap {
+            // Name the bits from the lowest to the highest
+            let mut bits: Vec<_> = map_info.map.iter().filter(|(k, _)| value & *k != 0).collect();
+            bits.sort_by_key(|(k, _)| **k);
+            let mut names = Vec::new();
+            let mut unnamed = value;
+            for (k, v) in bits {
+                names.push(v.trim().to_owned());
+                unnamed &= !k;
+            }
+            return Ok(Value::Flags { value, names, unnamed });
+        }
+        let name = map_info.map.get(&value).map(|name| name.trim().to_owned());
+        return Ok(Value::Enum { value, name });
+    }
+
+    use TdhInType::*;
+    Ok(match desc.in_type {
+        InTypeUnicodeString | InTypeAnsiString | InTypeCountedString | InTypeCountedAnsiString |
+        InTypeReverseCountedString | InTypeReverseCountedAnsiString |
+        InTypeNonNullTerminatedString | InTypeNonNullTerminatedAnsiString |
+        InTypeSid | InTypeWBEMSID => Value::String(parser.try_parse(path)?),
+        InTypeUnicodeChar => {
+            let bytes: Vec<u8> = parser.try_parse(path)?;
+            Value::String(crate::utils::parse_null_utf16_string(&bytes))
+        }
+        InTypeAnsiChar => {
+            let bytes: Vec<u8> = parser.try_parse(path)?;
+            Value::String(bytes.iter().map(|&b| b as char).collect())
+        }
+        InTypeInt8 => Value::Int(TryParse::<i8>::try_parse(parser, path)? as i64),
+        InTypeInt16 => Value::Int(TryParse::<i16>::try_parse(parser, path)? as i64),
+        InTypeInt32 => Value::Int(TryParse::<i32>::try_parse(parser, path)? as i64),
+        InTypeInt64 => Value::Int(TryParse::<i64>::try_parse(parser, path)?),
+        InTypeUInt8 => Value::UInt(TryParse::<u8>::try_parse(parser, path)? as u64),
+        InTypeUInt16 => Value::UInt(TryParse::<u16>::try_parse(parser, path)? as u64),
+        InTypeUInt32 if desc.out_type == TdhOutType::OutTypeIpv4 => {
+            let addr = TryParse::<u32>::try_parse(parser, path)?;
+            Value::IpAddr(IpAddr::V4(Ipv4Addr::from(addr.to_ne_bytes())))
+        }
+        InTypeUInt32 => Value::UInt(TryParse::<u32>::try_parse(parser, path)? as u64),
+        InTypeUInt64 => Value::UInt(TryParse::<u64>::try_parse(parser, path)?),
+        InTypeHexInt32 => Value::UInt(TryParse::<i32>::try_parse(parser, path)? as u32 as u64),
+        InTypeHexInt64 => Value::UInt(TryParse::<i64>::try_parse(parser, path)? as u64),
+        InTypeFloat => Value::Float(TryParse::<f32>::try_parse(parser, path)? as f64),
+        InTypeDouble => Value::Float(TryParse::<f64>::try_parse(parser, path)?),
+        InTypeBoolean => Value::Bool(parser.try_parse(path)?),
+        InTypeGuid => Value::Guid(parser.try_parse(path)?),
+        InTypePointer | InTypeSizeT => Value::Pointer(TryParse::<u64>::try_parse(parser, path)?),
+        InTypeBinary if desc.out_type == TdhOutType::OutTypeIpv6 => Value::IpAddr(parser.try_parse(path)?),
+        InTypeFileTime => {
+            let buffer = parser.property_info(path)?.buffer;
+            Value::UInt(u64::from_ne_bytes(buffer.try_into()?))
+        }
+        // SYSTEMTIMEs and anything else we don't understand are left as bytes
+        _ => Value::Binary(parser.try_parse(path)?),
+    })
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::etw_types::{EventDescriptor, EventRecord};
+    use crate::manifest::parse_manifest;
+    use crate::schema::SchemaLocator;
+    use std::borrow::Cow;
+
+    const MANIFEST: &str = r#"<?xml version="1.0" encoding="UTF-8"?>
+<instrumentationManifest xmlns="http://schemas.microsoft.com/win/2004/08/events" xmlns:win="http://manifests.microsoft.com/win/2004/08/windows/events">
+  <instrumentation>
+    <events>
+      <provider name="Test-Provider" guid="{12345678-9abc-def0-1122-334455667788}" symbol="TEST_PROVIDER">
+        <events>
+          <event value="1" template="Args"/>
+          <event value="2" template="HexArgs"/>
+        </events>
+        <maps>
+          <valueMap name="Kind">
+            <map value="0" message="Full"/>
+            <map value="1" message="Partial"/>
+          </valueMap>
+          <bitMap name="Access">
+            <map value="0x1" message="Read "/>
+            <map value="0x2" message="Write"/>
+            <map value="0x4" message="Execute"/>
+          </bitMap>
+        </maps>
+        <templates>
+          <template tid="Args">
+            <data name="Kind" inType="win:UInt32" map="Kind"/>
+            <data name="UnknownKind" inType="win:UInt8" map="Kind"/>
+            <data name="Access" inType="win:UInt32" map="Access"/>
+          </template>
+          <template tid="HexArgs">
+            <data name="Kind" inType="win:HexInt32" map="Kind"/>
+          </template>
+        </templates>
+      </provider>
+    </events>
+  </instrumentation>
+</instrumentationManifest>
+"#;
+
+    #[test]
+    fn maps() {
+        let mut locator = SchemaLocator::new();
+        for event in parse_manifest(MANIFEST).unwrap() {
+            locator.add_custom_schema(Box::new(event));
+        }
+        let mut user_data = Vec::new();
+        user_data.extend_from_slice(&1u32.to_le_bytes());
+        user_data.push(7);
+        // Read, Execute and two bits without a name
+        user_data.extend_from_slice(&0x30005u32.to_le_bytes());
+        let record = EventRecord {
+            provider_id: GUID::from_u128(0x12345678_9abc_def0_1122_334455667788),
+            descriptor: EventDescriptor { id: 1, ..Default::default() },
+            user_data: Cow::Owned(user_data),
+            ..Default::default()
+        };
+        let event = locator.event_schema(&record).unwrap();
+
+        let value = event.to_value().unwrap();
+        assert_eq!(value.get("Kind"), Some(&Value::Enum { value: 1, name: Some("Partial".to_owned()) }));
+        assert_eq!(value.get("UnknownKind"), Some(&Value::Enum { value: 7, name: None }));
+        assert_eq!(
+            value.get("Access"),
+            Some(&Value::Flags { value: 0x30005, names: vec!["Read".to_owned(), "Execute".to_owned()], unnamed: 0x30000 })
+        );
+        assert_eq!(serde_json::to_string(&value).unwrap(), r#"{"Kind":"Partial","UnknownKind":7,"Access":["Read","Execute","0x30000"]}"#);
+    }
+
+    fn written_properties(locator: &mut SchemaLocator, record: &EventRecord) -> String {
+        let event = locator.event_schema(record).unwrap();
+        let mut parser = Parser::create(&event);
+        let mut text = String::new();
+        for i in 0..event.property_count() {
+            crate::write_property(&mut text, &mut parser, &event.property(i), false);
+        }
+        text
+    }
+
+    #[test]
+    fn hex_mapped() {
+        let mut locator = SchemaLocator::new();
+        for event in parse_manifest(MANIFEST).unwrap() {
+            locator.add_custom_schema(Box::new(event));
+        }
+        let mut record = EventRecord {
+            provider_id: GUID::from_u128(0x12345678_9abc_def0_1122_334455667788),
+            descriptor: EventDescriptor { id: 2, ..Default::default() },
+            user_data: Cow::Owned(0u32.to_le_bytes().to_vec()),
+            ..Default::default()
+        };
+        let value = locator.event_schema(&record).unwrap().to_value().unwrap();
+        assert_eq!(value.get("Kind"), Some(&Value::Enum { value: 0, name: Some("Full".to_owned()) }));
+        assert_eq!(written_properties(&mut locator, &record), "  Kind= Full");
+
+        // A truncated field is written as an error instead of panicking
+        record.user_data = Cow::Owned(vec![0, 0]);
+        assert!(written_properties(&mut locator, &record).starts_with("  Kind= Err("));
+    }
+}